@@ -0,0 +1,277 @@
+/*!
+The netdog library holds the pieces of netdog that other Bottlerocket tools may want without
+shelling out to the `netdog` binary, starting with the parser for wicked's DHCP lease files.
+*/
+
+#![deny(rust_2018_idioms)]
+
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use snafu::ResultExt;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+
+// Matches wicked's shell-like syntax for DHCP lease variables:
+//     FOO='BAR' -> key=FOO, val='BAR'
+// Keys may have digits and underscores, as in IP6ADDR or DNS1.  The value keeps its quotes, since
+// quotes escaped inside it need `unquote` to tell them apart.
+lazy_static! {
+    static ref LEASE_PARAM: Regex = Regex::new(r"^(?P<key>[A-Z0-9_]+)=(?P<val>'.*')\s*$").unwrap();
+}
+
+/// Removes the shell-like quoting wicked puts around lease values.  Values are single-quoted, and
+/// a single quote in a value is escaped the way a shell does it, by closing the quotes around a
+/// backslash-escaped quote, as in 'it'\''s'.  As in a shell, a backslash inside the quotes is just
+/// a backslash.  Returns None if the quoting isn't balanced, rather than guessing at the value.
+fn unquote(quoted: &str) -> Option<String> {
+    let mut value = String::new();
+    let mut in_quotes = false;
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => in_quotes = !in_quotes,
+            c if in_quotes => value.push(c),
+            // Outside the quotes, only an escaped quote is expected.
+            '\\' if chars.next()? == '\'' => value.push('\''),
+            _ => return None,
+        }
+    }
+    if in_quotes {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Stores fields extracted from a DHCP lease.
+#[derive(Debug, Deserialize)]
+pub struct LeaseInfo {
+    #[serde(rename = "ipaddr")]
+    pub ip_address: IpNet,
+    #[serde(rename = "dnsservers")]
+    pub dns_servers: BTreeSet<IpAddr>,
+    #[serde(rename = "dnsdomain")]
+    pub dns_domain: Option<String>,
+    #[serde(rename = "dnssearch")]
+    pub dns_search: Option<Vec<String>>,
+    #[serde(rename = "gateways", default, deserialize_with = "first_gateway")]
+    pub gateway: Option<IpAddr>,
+    /// The proxy autoconfig (WPAD) URL from DHCP option 252, unvalidated
+    #[serde(rename = "wpad")]
+    pub wpad: Option<String>,
+    /// The interface MTU from DHCP option 26
+    #[serde(rename = "mtu")]
+    pub mtu: Option<u32>,
+}
+
+/// wicked lists the routers from the lease in GATEWAYS; the first one is the default gateway.
+fn first_gateway<'de, D>(deserializer: D) -> std::result::Result<Option<IpAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let gateways: Option<Vec<IpAddr>> = Option::deserialize(deserializer)?;
+    Ok(gateways.and_then(|g| g.into_iter().next()))
+}
+
+/// Parse lease data file into a LeaseInfo structure.
+pub fn parse_lease_info<P>(lease_file: P) -> Result<LeaseInfo>
+where
+    P: AsRef<Path>,
+{
+    let lease_file = lease_file.as_ref();
+    let f = File::open(lease_file).context(error::LeaseReadFailedSnafu { path: lease_file })?;
+    parse_lease(BufReader::new(f), lease_file)
+}
+
+/// Parse lease data from a reader; `lease_file` is only used for error messages.
+fn parse_lease<R>(reader: R, lease_file: &Path) -> Result<LeaseInfo>
+where
+    R: BufRead,
+{
+    let env = lease_params(reader, lease_file)?;
+
+    // Envy implements a serde `Deserializer` for an iterator of key/value pairs. That lets us
+    // feed in the key/value pairs from the lease file and get a `LeaseInfo` struct. Envy matches
+    // keys to fields case-insensitively, so IPADDR fills the field renamed "ipaddr".  If not all
+    // expected values are present in the file, it will fail; any extra values are ignored.
+    let info = envy::from_iter::<_, LeaseInfo>(env)
+        .context(error::LeaseParseFailedSnafu { path: lease_file })?;
+    debug!(
+        "Parsed lease info from '{}': {:?}",
+        lease_file.display(),
+        info
+    );
+    Ok(info)
+}
+
+/// Reads every variable from lease data, as key/value pairs with list values joined by commas.
+fn lease_params<R>(reader: R, lease_file: &Path) -> Result<Vec<(String, String)>>
+where
+    R: BufRead,
+{
+    let mut env = Vec::new();
+    for line in reader.lines() {
+        let line = line.context(error::LeaseReadFailedSnafu { path: lease_file })?;
+        // We ignore any line that does not match the regex.
+        let cap = match LEASE_PARAM.captures(&line) {
+            Some(cap) => cap,
+            None => continue,
+        };
+        let key = &cap["key"];
+        let val = match unquote(&cap["val"]) {
+            Some(val) => val,
+            None => {
+                warn!("Ignoring lease parameter {} with unbalanced quotes", key);
+                continue;
+            }
+        };
+        // Lists are separated by spaces; join them with commas so Envy deserializes them into a
+        // list, leaving out any space around the value.
+        let val = val.split_whitespace().collect::<Vec<_>>().join(",");
+        if val.is_empty() {
+            continue;
+        }
+        debug!("Found lease parameter {}='{}'", key, val);
+        env.push((key.to_string(), val))
+    }
+    Ok(env)
+}
+
+/// Potential errors while reading lease data
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to read lease data in '{}': {}", path.display(), source))]
+        LeaseReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to parse lease data in '{}': {}", path.display(), source))]
+        LeaseParseFailed { path: PathBuf, source: envy::Error },
+    }
+}
+
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(lease: &str) -> Result<LeaseInfo> {
+        parse_lease(lease.as_bytes(), Path::new("test.lease"))
+    }
+
+    #[test]
+    fn parse_basic_lease() {
+        let info = parse(
+            "IPADDR='10.0.0.5/24'\n\
+             DNSSERVERS='10.0.0.2 10.0.0.3'\n\
+             DNSDOMAIN='example.com'\n\
+             GATEWAYS='10.0.0.1 10.0.0.254'\n\
+             WPAD='http://wpad.example.com/wpad.dat'\n\
+             MTU='9001'\n\
+             # comments and unknown lines are ignored\n\
+             LEASETIME='3600'\n",
+        )
+        .unwrap();
+        assert_eq!(info.ip_address, "10.0.0.5/24".parse::<IpNet>().unwrap());
+        assert_eq!(info.dns_servers.len(), 2);
+        assert_eq!(info.dns_domain.as_deref(), Some("example.com"));
+        assert_eq!(info.dns_search, None);
+        assert_eq!(info.gateway, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(
+            info.wpad.as_deref(),
+            Some("http://wpad.example.com/wpad.dat")
+        );
+        assert_eq!(info.mtu, Some(9001));
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(unquote("'example.com'").as_deref(), Some("example.com"));
+        assert_eq!(unquote("'it'\\''s'").as_deref(), Some("it's"));
+        assert_eq!(unquote("''").as_deref(), Some(""));
+        // Backslashes inside the quotes are kept as they are.
+        assert_eq!(unquote("'C:\\dir\\n'").as_deref(), Some("C:\\dir\\n"));
+        assert_eq!(unquote("'a\\'\\''b'").as_deref(), Some("a\\'b"));
+        // An unescaped quote in the middle leaves text outside the quotes.
+        assert_eq!(unquote("'it's'"), None);
+        assert_eq!(unquote("'unterminated"), None);
+        assert_eq!(unquote("'trailing\\"), None);
+        assert_eq!(unquote("'it'\\s'"), None);
+    }
+
+    #[test]
+    fn parse_values_with_apostrophes_and_whitespace() {
+        let info = parse(
+            "IPADDR='10.0.0.5/24'   \n\
+             DNSSERVERS=' 10.0.0.2  10.0.0.3 '\n\
+             DNSDOMAIN='o'\\''brien.example.com'\n\
+             DNSSEARCH='o'\\''brien.example.com example.com '\t\n\
+             WPAD=''\n\
+             MTU='it's'\n",
+        )
+        .unwrap();
+        assert_eq!(info.ip_address, "10.0.0.5/24".parse::<IpNet>().unwrap());
+        assert_eq!(info.dns_servers.len(), 2);
+        assert_eq!(info.dns_domain.as_deref(), Some("o'brien.example.com"));
+        assert_eq!(
+            info.dns_search,
+            Some(vec![
+                "o'brien.example.com".to_string(),
+                "example.com".to_string()
+            ])
+        );
+        // Empty values and values with unbalanced quotes are left out.
+        assert_eq!(info.wpad, None);
+        assert_eq!(info.mtu, None);
+    }
+
+    #[test]
+    fn parse_keys_with_digits() {
+        let lease = "IPADDR='10.0.0.5/24'\n\
+                     IP6ADDR='2001:db8::5/64'\n\
+                     DNSSERVERS='10.0.0.2'\n\
+                     DNS1='10.0.0.3'\n\
+                     DNS2='10.0.0.4'\n\
+                     NTP_SERVERS1='10.0.0.123'\n\
+                     lowercase='ignored'\n";
+        let params = lease_params(lease.as_bytes(), Path::new("test.lease")).unwrap();
+        let keys: Vec<&str> = params.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            keys,
+            [
+                "IPADDR",
+                "IP6ADDR",
+                "DNSSERVERS",
+                "DNS1",
+                "DNS2",
+                "NTP_SERVERS1"
+            ]
+        );
+        assert_eq!(params[1].1, "2001:db8::5/64");
+
+        // The new keys don't get mixed up with the fields we already read.
+        let info = parse(lease).unwrap();
+        assert_eq!(info.ip_address, "10.0.0.5/24".parse::<IpNet>().unwrap());
+        assert_eq!(
+            info.dns_servers.into_iter().collect::<Vec<_>>(),
+            ["10.0.0.2".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn missing_fields_fail() {
+        assert!(parse("DNSDOMAIN='example.com'\n").is_err());
+    }
+}
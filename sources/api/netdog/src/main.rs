@@ -2,14 +2,103 @@
 # Introduction
 
 netdog is a small helper program for wicked, to apply network settings received from DHCP.  It
-generates `/etc/resolv.conf`, generates and sets the hostname, and persists the current IP to a
-file.
+generates `/etc/resolv.conf`, generates and sets the hostname, and persists the current IP, default
+gateway, and domain name to files.
 
-It contains two subcommands meant for use as settings generators:
-* `node-ip`: returns the node's current IP address in JSON format
+It contains these subcommands meant for use as settings generators:
+* `node-ip`: returns the node's current IP address in JSON format; with `--cidr`, it's returned
+  with the prefix length from the lease, like `10.0.0.5/24`.  With `--wait <seconds>`, it waits
+  up to that long for `install` to persist the address, for callers that may run first at boot.
+  With `--all`, it returns every address `install` persisted, as a JSON list of objects like
+  `{"address": "10.0.0.5", "family": "ipv4", "interface": "eth0"}`, for dual-stack or multi-NIC
+  nodes.  Corrupt address files are skipped with a warning.
+* `node-gateway`: returns the node's current default gateway in JSON format; it fails if the last
+  lease had no gateway
+* `node-domain`: returns the domain name from DHCP (option 15) in JSON format
+* `node-dns-search`: returns the DNS search list from `/etc/resolv.conf` as a JSON list.  This
+  comes from DHCP's domain search option (119), and is distinct from the node's own domain name.
+* `node-wpad`: returns the proxy autoconfig URL from DHCP option 252 (the lease's `WPAD` value)
+  in JSON format, or `null` if the lease didn't have a valid one
+* `node-mtu`: returns the interface MTU from DHCP option 26 in JSON format, or `null` if the lease
+  didn't have one.  With `--check`, it fails if the interface's current MTU, from sysfs, differs
+  from the lease's; `install` logs a warning in that case too.
 * `generate-hostname`: returns the node's hostname in JSON format. If the lookup is unsuccessful, the IP of the node is used.
+  IPv6 addresses can't be used as hostnames, so they're turned into a name like `ip-2001-db8-0-0-0-0-0-1`.
+  With `--domain-suffix`, an IP-derived (or short) name like `ip-10-0-0-5` is qualified with the given domain.
+  With `--format object`, it returns `{"hostname": "...", "source": "reverse-dns" or "ip", "ip": "..."}` instead,
+  showing how the name was chosen.  Reverse DNS results are cached in `/var/lib/netdog/hostname_cache`
+  for repeated calls during boot; `--cache-ttl <seconds>` sets how long they're reused (default 60, 0 disables),
+  and a changed IP always gets a fresh lookup.  A failed lookup can be retried before falling back to the IP with
+  `--dns-retries <count>`; `--dns-retry-delay <ms>` sets the wait before the first retry (default 100), which doubles
+  for each retry after that.
 
-The subcommand `set-hostname` sets the hostname for the system.
+The subcommand `set-hostname` sets the hostname for the system, as given.  With `--check`, it
+doesn't set anything; instead it normalizes the hostname, trimming whitespace and a trailing dot
+and lowercasing it, validates the result, and prints it.  `--mode` chooses which hostname is set,
+in systemd's terms: `transient`, the default, only writes the kernel hostname, as set-hostname
+always has; `static` writes `/etc/hostname`, and `both` writes both, so the running system and the
+next boot agree.
+
+The subcommand `write-hosts` maps the current IP to the hostname in `/etc/hosts`, so the node can
+resolve its own name when DNS is unavailable.  The mapping is kept between
+`# BEGIN netdog managed block` and `# END netdog managed block` comments; later runs replace that
+block in place and leave every other line alone.  The hostname defaults to the system's current
+one, as set by `set-hostname`; give `--hostname` to use another.
+
+The subcommand `reset` clears netdog's persisted state for re-provisioning or testing: everything
+under `/var/lib/netdog`, like the current IP, gateway, domain, and MTU files, the hostname cache,
+and each interface's saved resolver settings and addresses.  It only lists what it would remove
+unless given `--confirm`, and prints what it removed as JSON.  With `--restore-resolv-conf`, it
+also replaces `/etc/resolv.conf` with a minimal one that has no name servers, until the next
+lease.
+
+The subcommand `test-lease` parses a lease file and prints what `install` would write to
+`/etc/resolv.conf` and the current IP, gateway, and domain files, without writing anything, so a
+representative lease can be checked safely.  Name servers are printed in sorted order, since
+`install` may reorder them.
+
+The subcommand `generate-networkd` translates a wicked lease file into a systemd-networkd `.network`
+drop-in with the lease's address, gateway, and DNS settings.  It only writes the file; it doesn't
+restart any services.
+
+The subcommand `generate-resolved-dropin` writes a systemd-resolved drop-in for split DNS, from a
+lease and a JSON policy like `[{"domain": "corp.example.com", "servers": ["10.0.0.2"]}]`.  Each
+policy domain becomes a routing-only domain (`Domains=~corp.example.com`) of resolved's global
+scope, with the policy's name servers as `DNS=`, so only queries in those domains go to them.
+Entries are validated, and netdog warns if a policy domain would take over the lease's own
+domain or search domains.  The global scope has one server list, so domains given different
+servers may be answered by any of the policy's servers.
+
+Logs go to stderr, so the JSON output of the settings generators stays machine-parseable on stdout;
+use `--log-level` to change how much is logged.
+
+The lease given to `install` can also be a directory of lease files, for bonded or multi-NIC
+setups where they should be combined.  Leases are taken in file name order: the first one gives
+the IP address and gateway, name servers and search domains from all of them are combined, and
+the domain name comes from the first lease that has one.
+
+`install` takes an IPv4 or IPv6 lease for any interface.  The eth0 IPv4 lease is the node's: it
+gives the current IP, gateway, domain, proxy URL, and MTU, and writes `/etc/resolv.conf`.  Leases
+for other interfaces and families only record the interface's address, for `node-ip --all`.
+
+With `install --reconcile`, each interface's name servers and search domains are also kept under
+`/var/lib/netdog/<interface>/`, per family, and every lease rebuilds `/etc/resolv.conf` from all
+of them, so multi-homed nodes get a stable, merged configuration instead of the last writer's.
+
+By default, `install` shuffles the name servers it writes to `/etc/resolv.conf` on every run.
+`--dns-order rotate` instead advances the list by one position on each run, using a counter in
+`/var/lib/netdog/dns_rotation`, and `--dns-order preserve` keeps a stable order.
+
+glibc historically only honors the first 6 domains of the `search` line, so `install` writes at
+most that many, warning about any it drops; use `--max-search-domains` to change the limit.
+
+`install` can also write a `sortlist` line, which legacy resolvers use to prefer addresses in
+given networks: `--sortlist-subnet` adds the lease's own IPv4 subnet, and `--sortlist
+<addr[/netmask]>`, which can be given more than once, adds other entries, like
+`130.155.160.0/255.255.240.0`.  No `sortlist` line is written unless one of these is given.
+
+The `install` subcommand accepts `--reload-service <unit>`, which can be given more than once, to
+reload or restart systemd units when the configuration netdog writes has changed.
 */
 
 #![deny(rust_2018_idioms)]
@@ -19,51 +108,109 @@ extern crate serde_plain;
 
 use argh::FromArgs;
 use dns_lookup::lookup_addr;
-use envy;
 use ipnet::IpNet;
 use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use netdog::{parse_lease_info, LeaseInfo};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use snafu::ResultExt;
-use std::collections::BTreeSet;
+use simplelog::{Config as LogConfig, LevelFilter, WriteLogger};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Write;
-use std::fs::{self, File};
-use std::io::{BufRead, BufReader};
-use std::net::IpAddr;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::{Path, PathBuf};
-use std::process;
+use std::process::{self, Command};
 use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use url::Url;
 
 static RESOLV_CONF: &str = "/etc/resolv.conf";
+// Written by `reset`; with no name servers, resolvers fall back to the local host until the next
+// lease replaces it.
+static MINIMAL_RESOLV_CONF: &str = "# Reset by netdog; name servers come from the next lease\n";
 static KERNEL_HOSTNAME: &str = "/proc/sys/kernel/hostname";
+static ETC_HOSTNAME: &str = "/etc/hostname";
+static ETC_HOSTS: &str = "/etc/hosts";
+static HOSTS_BLOCK_BEGIN: &str = "# BEGIN netdog managed block";
+static HOSTS_BLOCK_END: &str = "# END netdog managed block";
 static CURRENT_IP: &str = "/var/lib/netdog/current_ip";
+static CURRENT_IP_CIDR: &str = "/var/lib/netdog/current_ip_cidr";
+static CURRENT_GATEWAY: &str = "/var/lib/netdog/current_gateway";
+static CURRENT_DOMAIN: &str = "/var/lib/netdog/current_domain";
+static CURRENT_WPAD: &str = "/var/lib/netdog/current_wpad";
+static CURRENT_MTU: &str = "/var/lib/netdog/current_mtu";
+static DNS_ROTATION: &str = "/var/lib/netdog/dns_rotation";
+static NETDOG_STATE_DIR: &str = "/var/lib/netdog";
+// Each interface's resolver settings are kept in this file plus a family suffix, like
+// "resolver.ipv4.json", in the interface's state directory.
+static RESOLVER_STATE_FILE: &str = "resolver";
+// Each interface's address, with its prefix length, is kept in this file plus a family suffix,
+// like "current_ip_cidr.ipv4", in the interface's state directory.
+static INTERFACE_IP_CIDR_FILE: &str = "current_ip_cidr";
+static HOSTNAME_CACHE: &str = "/var/lib/netdog/hostname_cache";
+
+// How often `node-ip --wait` checks whether the current IP has been written.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// glibc historically ignores search domains after the sixth, so by default we don't write more.
+const DEFAULT_MAX_SEARCH_DOMAINS: usize = 6;
+
+// How long, in seconds, generate-hostname reuses a reverse DNS result before looking it up again.
+const DEFAULT_HOSTNAME_CACHE_TTL: u64 = 60;
 
-// Matches wicked's shell-like syntax for DHCP lease variables:
-//     FOO='BAR' -> key=FOO, val=BAR
+// How long, in milliseconds, generate-hostname waits before its first reverse DNS retry; the wait
+// doubles for each retry after that.
+const DEFAULT_DNS_RETRY_DELAY: u64 = 100;
+
+// The kernel limits interface names to 15 bytes, leaving room for a terminating null in 16.
+const MAX_INTERFACE_NAME_LEN: usize = 15;
+static PRIMARY_INTERFACE: &str = "eth0";
+
+// Matches a single hostname label per RFC 1123: alphanumerics and hyphens, 1-63 characters, not
+// starting or ending with a hyphen.
 lazy_static! {
-    static ref LEASE_PARAM: Regex = Regex::new(r"^(?P<key>[A-Z]+)='(?P<val>.+)'$").unwrap();
+    static ref HOSTNAME_LABEL: Regex =
+        Regex::new(r"^[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?$").unwrap();
 }
 
-/// Stores fields extracted from a DHCP lease.
-#[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-struct LeaseInfo {
-    #[serde(rename = "ipaddr")]
-    ip_address: IpNet,
-    #[serde(rename = "dnsservers")]
-    dns_servers: BTreeSet<IpAddr>,
-    #[serde(rename = "dnsdomain")]
-    dns_domain: Option<String>,
-    #[serde(rename = "dnssearch")]
-    dns_search: Option<Vec<String>>,
+/// A network interface name, like "eth0" or "ens5".  Names are checked the way the kernel checks
+/// them, since they're used in paths under the state directory.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct InterfaceName(String);
+
+impl InterfaceName {
+    /// The interface whose IPv4 lease gives the node's own address, gateway, and domain.
+    fn primary() -> Self {
+        InterfaceName(PRIMARY_INTERFACE.to_string())
+    }
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-enum InterfaceName {
-    Eth0,
+impl FromStr for InterfaceName {
+    type Err = error::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        ensure!(
+            !name.is_empty()
+                && name.len() <= MAX_INTERFACE_NAME_LEN
+                && name != "."
+                && name != ".."
+                && !name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()),
+            error::InvalidInterfaceNameSnafu { name }
+        );
+        Ok(InterfaceName(name.to_string()))
+    }
+}
+
+impl std::fmt::Display for InterfaceName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
@@ -72,21 +219,191 @@ enum InterfaceType {
     Dhcp,
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 enum InterfaceFamily {
     Ipv4,
     Ipv6,
 }
 
+/// How name servers are ordered when writing resolv.conf.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum DnsOrder {
+    /// Random order on every write
+    Shuffle,
+    /// Rotate by one position on every write, tracked in `DNS_ROTATION`
+    Rotate,
+    /// Stable, sorted order
+    Preserve,
+}
+
+/// How generate-hostname prints its result.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum HostnameFormat {
+    /// A bare JSON string, as sundog expects
+    String,
+    /// A JSON object with the hostname, where it came from, and the IP it was derived from
+    Object,
+}
+
+/// Which hostnames set-hostname writes, in systemd's terms.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum HostnameMode {
+    /// The kernel hostname, which lasts until reboot
+    Transient,
+    /// `/etc/hostname`, which is read at boot
+    Static,
+    /// Both, so the running system and the next boot agree
+    Both,
+}
+
+/// Where a generated hostname came from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum HostnameSource {
+    /// A reverse DNS lookup of the node's IP
+    ReverseDns,
+    /// The node's IP itself, because the lookup failed
+    Ip,
+}
+
+/// A reverse DNS result saved in `HOSTNAME_CACHE`, so repeated calls during boot don't each
+/// query the resolver.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct HostnameCacheEntry {
+    ip: IpAddr,
+    hostname: String,
+    /// When the lookup was done, in seconds since the Unix epoch
+    resolved_at: u64,
+}
+
+impl HostnameCacheEntry {
+    /// Returns the cached hostname if it's for the given IP and no older than `ttl` seconds at
+    /// time `now`.
+    fn lookup(&self, ip: &IpAddr, ttl: u64, now: u64) -> Option<&str> {
+        if &self.ip == ip && now.saturating_sub(self.resolved_at) < ttl {
+            Some(&self.hostname)
+        } else {
+            None
+        }
+    }
+}
+
+/// The result of generate-hostname, printed with `--format object`.
+#[derive(Debug, PartialEq, Serialize)]
+struct GeneratedHostname {
+    hostname: String,
+    source: HostnameSource,
+    ip: IpAddr,
+}
+
 // Implement `from_str()` so argh can attempt to deserialize args into their proper types
-derive_fromstr_from_deserialize!(InterfaceName);
 derive_fromstr_from_deserialize!(InterfaceType);
 derive_fromstr_from_deserialize!(InterfaceFamily);
+derive_fromstr_from_deserialize!(DnsOrder);
+derive_fromstr_from_deserialize!(HostnameFormat);
+derive_fromstr_from_deserialize!(HostnameMode);
+derive_display_from_serialize!(InterfaceFamily);
+
+/// An entry for resolv.conf's `sortlist`: an IPv4 address and optional netmask, written like
+/// `130.155.160.0/255.255.240.0`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SortlistEntry {
+    addr: Ipv4Addr,
+    netmask: Option<Ipv4Addr>,
+}
+
+impl SortlistEntry {
+    /// Returns the entry for the network containing the given address, or None for IPv6, which
+    /// sortlist doesn't support.
+    fn for_subnet(ip_net: &IpNet) -> Option<Self> {
+        match ip_net {
+            IpNet::V4(v4) => Some(Self {
+                addr: v4.network(),
+                netmask: Some(v4.netmask()),
+            }),
+            IpNet::V6(_) => None,
+        }
+    }
+}
+
+impl FromStr for SortlistEntry {
+    type Err = error::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        let (addr, netmask) = match input.split_once('/') {
+            Some((addr, netmask)) => (addr, Some(netmask)),
+            None => (input, None),
+        };
+        let addr = Ipv4Addr::from_str(addr).context(error::InvalidSortlistEntrySnafu {
+            entry: input,
+            reason: "address must be IPv4",
+        })?;
+        let netmask = netmask
+            .map(|netmask| {
+                let netmask =
+                    Ipv4Addr::from_str(netmask).context(error::InvalidSortlistEntrySnafu {
+                        entry: input,
+                        reason: "netmask must be dotted-quad, like 255.255.240.0",
+                    })?;
+                // The mask must be contiguous ones followed by zeros.
+                ensure!(
+                    {
+                        let mask = u32::from(netmask);
+                        mask.leading_ones() + mask.trailing_zeros() == 32
+                    },
+                    error::InvalidSortlistNetmaskSnafu { entry: input }
+                );
+                Ok(netmask)
+            })
+            .transpose()?;
+        Ok(Self { addr, netmask })
+    }
+}
+
+impl std::fmt::Display for SortlistEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.netmask {
+            Some(netmask) => write!(f, "{}/{}", self.addr, netmask),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+/// One entry of a split-DNS policy: a domain, and the name servers that answer for it
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DnsPolicyEntry {
+    domain: String,
+    servers: Vec<IpAddr>,
+}
+
+/// Resolver settings from one interface's lease, kept so resolv.conf can be rebuilt from all
+/// interfaces in `--reconcile` mode.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+struct ResolverState {
+    dns_servers: BTreeSet<IpAddr>,
+    dns_search: Vec<String>,
+}
+
+/// An address `install` persisted for an interface, as listed by `node-ip --all`.
+#[derive(Debug, PartialEq, Serialize)]
+struct NodeAddress {
+    address: String,
+    family: InterfaceFamily,
+    interface: String,
+}
 
 /// Stores user-supplied arguments.
 #[derive(FromArgs, PartialEq, Debug)]
 struct Args {
+    #[argh(option, default = "LevelFilter::Info")]
+    /// log-level trace|debug|info|warn|error
+    log_level: LevelFilter,
+
     #[argh(subcommand)]
     subcommand: SubCommand,
 }
@@ -97,13 +414,23 @@ enum SubCommand {
     Install(InstallArgs),
     Remove(RemoveArgs),
     NodeIp(NodeIpArgs),
+    NodeGateway(NodeGatewayArgs),
+    NodeDomain(NodeDomainArgs),
+    NodeDnsSearch(NodeDnsSearchArgs),
+    NodeWpad(NodeWpadArgs),
+    NodeMtu(NodeMtuArgs),
     GenerateHostname(GenerateHostnameArgs),
     SetHostname(SetHostnameArgs),
+    WriteHosts(WriteHostsArgs),
+    Reset(ResetArgs),
+    GenerateNetworkd(GenerateNetworkdArgs),
+    GenerateResolvedDropin(GenerateResolvedDropinArgs),
+    TestLease(TestLeaseArgs),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "install")]
-/// Write resolv.conf and current IP to disk
+/// Write resolv.conf, current IP, and current gateway to disk
 struct InstallArgs {
     #[argh(option, short = 'i')]
     /// name of the network interface
@@ -117,8 +444,33 @@ struct InstallArgs {
     /// network interface family (ipv4/6)
     interface_family: InterfaceFamily,
 
+    #[argh(option)]
+    /// systemd unit to reload or restart if the written configuration changed; may be repeated
+    reload_service: Vec<String>,
+
+    #[argh(switch)]
+    /// merge resolver settings from all interfaces into resolv.conf instead of replacing them
+    reconcile: bool,
+
+    #[argh(option)]
+    /// name server order: shuffle, rotate, or preserve; defaults to shuffle, or preserve with
+    /// --reconcile
+    dns_order: Option<DnsOrder>,
+
+    #[argh(option, default = "DEFAULT_MAX_SEARCH_DOMAINS")]
+    /// most search domains to write to resolv.conf; extras are dropped with a warning (default 6)
+    max_search_domains: usize,
+
+    #[argh(switch)]
+    /// add the lease's IPv4 subnet to resolv.conf's sortlist
+    sortlist_subnet: bool,
+
+    #[argh(option)]
+    /// entry like "130.155.160.0/255.255.240.0" to add to resolv.conf's sortlist; may be repeated
+    sortlist: Vec<SortlistEntry>,
+
     #[argh(positional)]
-    /// lease info data file
+    /// lease info data file, or a directory of lease files to merge
     data_file: PathBuf,
 
     #[argh(positional)]
@@ -150,221 +502,2288 @@ struct RemoveArgs {
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "node-ip")]
 /// Return the current IP address
-struct NodeIpArgs {}
+struct NodeIpArgs {
+    #[argh(switch)]
+    /// include the prefix length, e.g. "10.0.0.5/24"
+    cidr: bool,
+
+    #[argh(option)]
+    /// seconds to wait for the current IP to be written before failing; by default, don't wait
+    wait: Option<u64>,
+
+    #[argh(switch)]
+    /// return every persisted address, with its family and interface, as a JSON list
+    all: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "node-gateway")]
+/// Return the current default gateway
+struct NodeGatewayArgs {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "node-domain")]
+/// Return the node's domain name from DHCP option 15; see node-dns-search for the search list
+struct NodeDomainArgs {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "node-wpad")]
+/// Return the proxy autoconfig URL from DHCP option 252, or null if there isn't one
+struct NodeWpadArgs {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "node-mtu")]
+/// Return the interface MTU from DHCP option 26, or null if there isn't one
+struct NodeMtuArgs {
+    #[argh(switch)]
+    /// fail if the interface's current MTU differs from the DHCP-provided one
+    check: bool,
+
+    #[argh(option, short = 'i', default = "InterfaceName::primary()")]
+    /// name of the network interface to check (default eth0)
+    interface_name: InterfaceName,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "node-dns-search")]
+/// Return the DNS search list from resolv.conf; see node-domain for the node's own domain name
+struct NodeDnsSearchArgs {}
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "generate-hostname")]
 /// Generate hostname from DNS reverse lookup or use current IP
-struct GenerateHostnameArgs {}
+struct GenerateHostnameArgs {
+    #[argh(option)]
+    /// domain appended to IP-derived or short hostnames, e.g. "internal.example.com"
+    domain_suffix: Option<String>,
+
+    #[argh(option, default = "HostnameFormat::String")]
+    /// output format: string, the bare hostname, or object, with the hostname's source and the
+    /// IP it came from (default string)
+    format: HostnameFormat,
+
+    #[argh(option, default = "DEFAULT_HOSTNAME_CACHE_TTL")]
+    /// seconds to reuse a reverse DNS result for the same IP before looking it up again; 0
+    /// disables the cache (default 60)
+    cache_ttl: u64,
+
+    #[argh(option, default = "0")]
+    /// times to retry a failed reverse DNS lookup before falling back to the IP (default 0)
+    dns_retries: u32,
+
+    #[argh(option, default = "DEFAULT_DNS_RETRY_DELAY")]
+    /// milliseconds to wait before the first reverse DNS retry, doubling for each one after
+    /// (default 100)
+    dns_retry_delay: u64,
+}
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "set-hostname")]
 /// Sets the hostname
 struct SetHostnameArgs {
+    #[argh(switch)]
+    /// only normalize and validate the hostname and print it, without setting it
+    check: bool,
+
+    #[argh(option, default = "HostnameMode::Transient")]
+    /// which hostname to set: transient, the kernel's, static, in /etc/hostname, or both
+    /// (default transient)
+    mode: HostnameMode,
+
     #[argh(positional)]
     /// hostname for the system
     hostname: String,
 }
 
-/// Parse lease data file into a LeaseInfo structure.
-fn parse_lease_info<P>(lease_file: P) -> Result<LeaseInfo>
-where
-    P: AsRef<Path>,
-{
-    let lease_file = lease_file.as_ref();
-    let f = File::open(lease_file).context(error::LeaseReadFailedSnafu { path: lease_file })?;
-    let f = BufReader::new(f);
-
-    let mut env = Vec::new();
-    for line in f.lines() {
-        let line = line.context(error::LeaseReadFailedSnafu { path: lease_file })?;
-        // We ignore any line that does not match the regex.
-        for cap in LEASE_PARAM.captures_iter(&line) {
-            let key = cap.name("key").map(|k| k.as_str());
-            let val = cap.name("val").map(|v| v.as_str());
-            if let (Some(k), Some(v)) = (key, val) {
-                // If present, replace spaces with commas so Envy deserializes into a list.
-                env.push((k.to_string(), v.replace(" ", ",")))
-            }
-        }
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "write-hosts")]
+/// Map the current IP to the hostname in a managed block of /etc/hosts
+struct WriteHostsArgs {
+    #[argh(option)]
+    /// hostname to map the current IP to; defaults to the system's current hostname
+    hostname: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "reset")]
+/// Remove netdog's persisted state, listing what was removed
+struct ResetArgs {
+    #[argh(switch)]
+    /// actually remove the state; without this, only list what would be removed
+    confirm: bool,
+
+    #[argh(switch)]
+    /// also replace /etc/resolv.conf with a minimal one that has no name servers
+    restore_resolv_conf: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "generate-networkd")]
+/// Generate a systemd-networkd .network drop-in from a lease
+struct GenerateNetworkdArgs {
+    #[argh(option, short = 'o')]
+    /// path to write the drop-in to; if not given, it's printed to stdout
+    output: Option<PathBuf>,
+
+    #[argh(positional)]
+    /// lease info data file
+    data_file: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "generate-resolved-dropin")]
+/// Generate a systemd-resolved drop-in sending queries for given domains to their own name servers
+struct GenerateResolvedDropinArgs {
+    #[argh(option, short = 'p')]
+    /// JSON file listing each domain and the name servers for it
+    policy: PathBuf,
+
+    #[argh(option, short = 'o')]
+    /// path to write the drop-in to; if not given, it's printed to stdout
+    output: Option<PathBuf>,
+
+    #[argh(positional)]
+    /// lease info data file
+    data_file: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "test-lease")]
+/// Print the files install would write for a lease, without writing them
+struct TestLeaseArgs {
+    #[argh(option, default = "DEFAULT_MAX_SEARCH_DOMAINS")]
+    /// most search domains to write to resolv.conf, as for install (default 6)
+    max_search_domains: usize,
+
+    #[argh(switch)]
+    /// add the lease's IPv4 subnet to resolv.conf's sortlist, as for install
+    sortlist_subnet: bool,
+
+    #[argh(option)]
+    /// entry to add to resolv.conf's sortlist, as for install; may be repeated
+    sortlist: Vec<SortlistEntry>,
+
+    #[argh(positional)]
+    /// lease info data file, or a directory of lease files to merge, as for install
+    data_file: PathBuf,
+}
+
+/// Returns whether the file at the given path holds different lines than `contents`, ignoring
+/// their order.  Name servers may be reordered on every write, so order changes alone don't
+/// count.  A file that can't be read is considered changed.
+fn lines_changed<P: AsRef<Path>>(path: P, contents: &str) -> bool {
+    let existing = match fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(_) => return true,
+    };
+    let mut old: Vec<&str> = existing.lines().collect();
+    let mut new: Vec<&str> = contents.lines().collect();
+    old.sort_unstable();
+    new.sort_unstable();
+    old != new
+}
+
+/// Splits the search list into the domains we'll write and the ones over the limit, warning if
+/// any have to be dropped, so it's clear why the resolver won't use them.
+fn limit_search_domains(dns_search: &[String], max: usize) -> (&[String], &[String]) {
+    let (kept, dropped) = dns_search.split_at(dns_search.len().min(max));
+    if !dropped.is_empty() {
+        warn!(
+            "Only writing the first {} of {} search domains to {}; dropping: {}",
+            kept.len(),
+            dns_search.len(),
+            RESOLV_CONF,
+            dropped.join(" ")
+        );
     }
+    (kept, dropped)
+}
 
-    // Envy implements a serde `Deserializer` for an iterator of key/value pairs. That lets us
-    // feed in the key/value pairs from the lease file and get a `LeaseInfo` struct. If not all
-    // expected values are present in the file, it will fail; any extra values are ignored.
-    Ok(envy::from_iter::<_, LeaseInfo>(env)
-        .context(error::LeaseParseFailedSnafu { path: lease_file })?)
+/// Returns the sortlist entries to write: the lease's subnet first, if requested, then the given
+/// entries.
+fn sortlist_entries(
+    info: &LeaseInfo,
+    subnet: bool,
+    entries: &[SortlistEntry],
+) -> Vec<SortlistEntry> {
+    let mut sortlist = Vec::new();
+    if subnet {
+        match SortlistEntry::for_subnet(&info.ip_address) {
+            Some(entry) => sortlist.push(entry),
+            None => warn!(
+                "Not adding {} to sortlist; only IPv4 networks are supported",
+                info.ip_address
+            ),
+        }
+    }
+    for entry in entries {
+        if !sortlist.contains(entry) {
+            sortlist.push(*entry);
+        }
+    }
+    sortlist
 }
 
-/// Write resolver configuration for libc.
-fn write_resolv_conf(dns_servers: &[&IpAddr], dns_search: &Option<Vec<String>>) -> Result<()> {
+/// Build resolver configuration for libc.
+fn resolv_conf(
+    dns_servers: &[&IpAddr],
+    dns_search: &Option<Vec<String>>,
+    max_search_domains: usize,
+    sortlist: &[SortlistEntry],
+) -> Result<String> {
     let mut output = String::new();
 
     if let Some(s) = dns_search {
-        writeln!(output, "search {}", s.join(" ")).context(error::ResolvConfBuildFailedSnafu)?;
+        let (search, _) = limit_search_domains(s, max_search_domains);
+        writeln!(output, "search {}", search.join(" "))
+            .context(error::ResolvConfBuildFailedSnafu)?;
+    }
+
+    if !sortlist.is_empty() {
+        let entries: Vec<String> = sortlist.iter().map(|e| e.to_string()).collect();
+        writeln!(output, "sortlist {}", entries.join(" "))
+            .context(error::ResolvConfBuildFailedSnafu)?;
     }
 
     for n in dns_servers {
         writeln!(output, "nameserver {}", n).context(error::ResolvConfBuildFailedSnafu)?;
     }
 
+    Ok(output)
+}
+
+/// Write resolver configuration for libc.  Returns whether the configuration changed.
+fn write_resolv_conf(
+    dns_servers: &[&IpAddr],
+    dns_search: &Option<Vec<String>>,
+    max_search_domains: usize,
+    sortlist: &[SortlistEntry],
+) -> Result<bool> {
+    let output = resolv_conf(dns_servers, dns_search, max_search_domains, sortlist)?;
+    let changed = lines_changed(RESOLV_CONF, &output);
+    debug!(
+        "Writing {} (changed: {}):\n{}",
+        RESOLV_CONF, changed, output
+    );
     fs::write(RESOLV_CONF, output)
         .context(error::ResolvConfWriteFailedSnafu { path: RESOLV_CONF })?;
-    Ok(())
+    Ok(changed)
 }
 
-/// Persist the current IP address to file
-fn write_current_ip(ip: &IpAddr) -> Result<()> {
-    fs::write(CURRENT_IP, ip.to_string())
-        .context(error::CurrentIpWriteFailedSnafu { path: CURRENT_IP })
+/// Returns the name of the file holding an interface's resolver settings for one family.
+fn resolver_state_file(family: &InterfaceFamily) -> String {
+    format!("{}.{}.json", RESOLVER_STATE_FILE, family)
 }
 
-fn install(args: InstallArgs) -> Result<()> {
-    match (
-        &args.interface_name,
-        &args.interface_type,
-        &args.interface_family,
-    ) {
-        (InterfaceName::Eth0, InterfaceType::Dhcp, InterfaceFamily::Ipv4) => {
-            let info = parse_lease_info(&args.data_file)?;
-            // Randomize name server order, for libc implementations like musl that send
-            // queries to the first N servers.
-            let mut dns_servers: Vec<_> = info.dns_servers.iter().collect();
-            dns_servers.shuffle(&mut thread_rng());
-            write_resolv_conf(&dns_servers, &info.dns_search)?;
-            write_current_ip(&info.ip_address.addr())?;
+/// Save an interface's resolver settings from its lease of the given family under the state
+/// directory.
+fn write_resolver_state(
+    state_dir: &Path,
+    interface: &str,
+    family: &InterfaceFamily,
+    state: &ResolverState,
+) -> Result<()> {
+    let dir = state_dir.join(interface);
+    fs::create_dir_all(&dir).context(error::ResolverStateWriteFailedSnafu { path: &dir })?;
+    let path = dir.join(resolver_state_file(family));
+    let data = serde_json::to_string(state).context(error::JsonSerializeSnafu)?;
+    debug!("Writing resolver state to '{}': {}", path.display(), data);
+    fs::write(&path, data).context(error::ResolverStateWriteFailedSnafu { path })
+}
+
+/// Read the resolver settings saved for every interface and family, keyed by interface name and
+/// family, like "eth0.ipv4".
+fn read_resolver_states(state_dir: &Path) -> Result<BTreeMap<String, ResolverState>> {
+    let mut states = BTreeMap::new();
+    let entries =
+        fs::read_dir(state_dir).context(error::ResolverStateReadFailedSnafu { path: state_dir })?;
+    for entry in entries {
+        let entry = entry.context(error::ResolverStateReadFailedSnafu { path: state_dir })?;
+        let interface = entry.file_name().to_string_lossy().into_owned();
+        for family in [InterfaceFamily::Ipv4, InterfaceFamily::Ipv6] {
+            let path = entry.path().join(resolver_state_file(&family));
+            if !path.is_file() {
+                continue;
+            }
+            let data = fs::read_to_string(&path)
+                .context(error::ResolverStateReadFailedSnafu { path: &path })?;
+            let state = serde_json::from_str(&data)
+                .context(error::ResolverStateParseFailedSnafu { path })?;
+            states.insert(format!("{}.{}", interface, family), state);
         }
-        _ => eprintln!("Unhandled 'install' command: {:?}", &args),
     }
-    Ok(())
+    Ok(states)
 }
 
-fn remove(args: RemoveArgs) -> Result<()> {
-    match (
-        &args.interface_name,
-        &args.interface_type,
-        &args.interface_family,
-    ) {
-        _ => eprintln!("The 'remove' command is not implemented."),
+/// Merge resolver settings from all interfaces.  Name servers are deduplicated and sorted, and
+/// search domains are deduplicated, keeping each interface's order and taking interfaces in name
+/// order, so the result doesn't depend on which interface was installed last.
+fn merge_resolver_states(states: &BTreeMap<String, ResolverState>) -> ResolverState {
+    let mut merged = ResolverState::default();
+    for state in states.values() {
+        merged.dns_servers.extend(state.dns_servers.iter().cloned());
+        for domain in &state.dns_search {
+            if !merged.dns_search.contains(domain) {
+                merged.dns_search.push(domain.clone());
+            }
+        }
+    }
+    merged
+}
+
+/// Order name servers for libc implementations like musl that send queries to the first N
+/// servers.  Shuffling spreads load randomly; rotating spreads it predictably, advancing by one
+/// position on each write using a counter persisted in `DNS_ROTATION`.
+fn order_name_servers(dns_servers: &mut [&IpAddr], order: DnsOrder) -> Result<()> {
+    match order {
+        DnsOrder::Shuffle => dns_servers.shuffle(&mut thread_rng()),
+        DnsOrder::Rotate => {
+            // A missing or unreadable counter just starts the rotation over.
+            let counter = fs::read_to_string(DNS_ROTATION)
+                .ok()
+                .and_then(|c| c.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            let len = dns_servers.len();
+            if len > 0 {
+                dns_servers.rotate_left(counter % len);
+            }
+            let next = counter.wrapping_add(1).to_string();
+            debug!("Writing {} to {}", next, DNS_ROTATION);
+            fs::write(DNS_ROTATION, next)
+                .context(error::DnsRotationWriteFailedSnafu { path: DNS_ROTATION })?;
+        }
+        DnsOrder::Preserve => {}
     }
     Ok(())
 }
 
-/// Return the current IP address as JSON (intended for use as a settings generator)
-fn node_ip() -> Result<()> {
-    let ip_string = fs::read_to_string(CURRENT_IP)
-        .context(error::CurrentIpReadFailedSnafu { path: CURRENT_IP })?;
-    // Validate that we read a proper IP address
-    let _ = IpAddr::from_str(&ip_string).context(error::IpFromStringSnafu { ip: &ip_string })?;
+/// Writes the file by way of a temporary file in the same directory, so readers see either the
+/// old contents or the new ones, never a partial write.
+fn write_atomic<P: AsRef<Path>>(path: P, contents: &str) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)
+}
 
-    // sundog expects JSON-serialized output
-    Ok(print_json(ip_string)?)
+/// Persist the current IP address to file, alone and with its prefix length.  Returns whether
+/// either changed.
+fn write_current_ip(ip_net: &IpNet) -> Result<bool> {
+    let ip = ip_net.addr().to_string();
+    let mut changed = lines_changed(CURRENT_IP, &ip);
+    debug!("Writing {} to {} (changed: {})", ip, CURRENT_IP, changed);
+    write_atomic(CURRENT_IP, &ip).context(error::CurrentIpWriteFailedSnafu { path: CURRENT_IP })?;
+
+    let cidr = ip_net.to_string();
+    let cidr_changed = lines_changed(CURRENT_IP_CIDR, &cidr);
+    debug!(
+        "Writing {} to {} (changed: {})",
+        cidr, CURRENT_IP_CIDR, cidr_changed
+    );
+    write_atomic(CURRENT_IP_CIDR, &cidr).context(error::CurrentIpWriteFailedSnafu {
+        path: CURRENT_IP_CIDR,
+    })?;
+    changed |= cidr_changed;
+
+    Ok(changed)
 }
 
-/// Attempt to resolve assigned IP address, if unsuccessful use the IP as the hostname.
-///
-/// The result is returned as JSON. (intended for use as a settings generator)
-fn generate_hostname() -> Result<()> {
-    let ip_string = fs::read_to_string(CURRENT_IP)
-        .context(error::CurrentIpReadFailedSnafu { path: CURRENT_IP })?;
-    let ip = IpAddr::from_str(&ip_string).context(error::IpFromStringSnafu { ip: &ip_string })?;
-    let hostname = match lookup_addr(&ip) {
-        Ok(hostname) => hostname,
-        Err(e) => {
-            eprintln!("Reverse DNS lookup failed: {}", e);
-            ip_string
+/// Returns the family of the address.
+fn ip_family(ip: &IpAddr) -> InterfaceFamily {
+    match ip {
+        IpAddr::V4(_) => InterfaceFamily::Ipv4,
+        IpAddr::V6(_) => InterfaceFamily::Ipv6,
+    }
+}
+
+/// Returns the path of the file holding the interface's address of the given family.
+fn interface_ip_path(state_dir: &Path, interface: &str, family: &InterfaceFamily) -> PathBuf {
+    state_dir
+        .join(interface)
+        .join(format!("{}.{}", INTERFACE_IP_CIDR_FILE, family))
+}
+
+/// Persist the interface's address, with its prefix length, under the state directory, so
+/// `node-ip --all` can list every interface's addresses.  Returns whether it changed.
+fn write_interface_ip(state_dir: &Path, interface: &str, ip_net: &IpNet) -> Result<bool> {
+    let dir = state_dir.join(interface);
+    fs::create_dir_all(&dir).context(error::CurrentIpWriteFailedSnafu { path: &dir })?;
+    let path = interface_ip_path(state_dir, interface, &ip_family(&ip_net.addr()));
+    let cidr = ip_net.to_string();
+    let changed = lines_changed(&path, &cidr);
+    debug!(
+        "Writing {} to {} (changed: {})",
+        cidr,
+        path.display(),
+        changed
+    );
+    write_atomic(&path, &cidr).context(error::CurrentIpWriteFailedSnafu { path })?;
+    Ok(changed)
+}
+
+/// Read the addresses persisted for every interface, sorted by interface name and then family.
+/// Addresses include their prefix length if `cidr` is set.  Entries that aren't interfaces, and
+/// address files that don't parse, are skipped.
+fn read_interface_ips(state_dir: &Path, cidr: bool) -> Result<Vec<NodeAddress>> {
+    let entries =
+        fs::read_dir(state_dir).context(error::CurrentIpReadFailedSnafu { path: state_dir })?;
+    let mut interfaces = Vec::new();
+    for entry in entries {
+        let entry = entry.context(error::CurrentIpReadFailedSnafu { path: state_dir })?;
+        if !entry.path().is_dir() {
+            continue;
+        }
+        // Other state, like the resolver's, may live alongside; only interfaces are listed.
+        match entry.file_name().to_string_lossy().parse::<InterfaceName>() {
+            Ok(interface) => interfaces.push(interface.to_string()),
+            Err(e) => debug!("Skipping {}: {}", entry.path().display(), e),
+        }
+    }
+    interfaces.sort();
+
+    let mut addresses = Vec::new();
+    for interface in interfaces {
+        for family in [InterfaceFamily::Ipv4, InterfaceFamily::Ipv6] {
+            let path = interface_ip_path(state_dir, &interface, &family);
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context(error::CurrentIpReadFailedSnafu { path }),
+            };
+            // One bad file shouldn't hide every other interface's address.
+            match parse_interface_ip(&path, &interface, family, &contents, cidr) {
+                Ok(address) => addresses.push(address),
+                Err(e) => warn!("Skipping address of {}: {}", interface, e),
+            }
         }
+    }
+    Ok(addresses)
+}
+
+/// Parses the contents of an interface's address file, which should hold an address and prefix
+/// of the family in its name.  Like the current IP, anything else is reported as corrupt.
+fn parse_interface_ip(
+    path: &Path,
+    interface: &str,
+    family: InterfaceFamily,
+    contents: &str,
+    cidr: bool,
+) -> Result<NodeAddress> {
+    let trimmed = contents.trim();
+    let ip_net = IpNet::from_str(trimmed)
+        .ok()
+        .filter(|ip_net| ip_family(&ip_net.addr()) == family)
+        .context(error::CorruptCurrentIpSnafu {
+            path,
+            contents: trimmed,
+        })?;
+    let address = if cidr {
+        ip_net.to_string()
+    } else {
+        ip_net.addr().to_string()
     };
+    Ok(NodeAddress {
+        address,
+        family,
+        interface: interface.to_string(),
+    })
+}
 
-    // sundog expects JSON-serialized output
-    Ok(print_json(hostname)?)
+/// Persist the current default gateway to file, or remove the file if the lease has none, so a
+/// gateway from an old lease doesn't linger.  Returns whether the gateway changed.
+fn write_current_gateway(gateway: Option<&IpAddr>) -> Result<bool> {
+    write_gateway_file(Path::new(CURRENT_GATEWAY), gateway)
 }
 
-/// Helper function that serializes the input to JSON and prints it
-fn print_json<S>(val: S) -> Result<()>
-where
-    S: AsRef<str> + Serialize,
-{
-    let val = val.as_ref();
-    let output = serde_json::to_string(val).context(error::JsonSerializeSnafu { output: val })?;
-    println!("{}", output);
-    Ok(())
+fn write_gateway_file(path: &Path, gateway: Option<&IpAddr>) -> Result<bool> {
+    match gateway {
+        Some(gateway) => {
+            let gateway = gateway.to_string();
+            let changed = lines_changed(path, &gateway);
+            debug!(
+                "Writing {} to {} (changed: {})",
+                gateway,
+                path.display(),
+                changed
+            );
+            fs::write(path, gateway).context(error::CurrentGatewayWriteFailedSnafu { path })?;
+            Ok(changed)
+        }
+        None => match fs::remove_file(path) {
+            Ok(()) => {
+                debug!("Removed {}", path.display());
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).context(error::CurrentGatewayWriteFailedSnafu { path }),
+        },
+    }
 }
 
-/// Sets the hostname for the system
-fn set_hostname(args: SetHostnameArgs) -> Result<()> {
-    fs::write(KERNEL_HOSTNAME, args.hostname).context(error::HostnameWriteFailedSnafu {
-        path: KERNEL_HOSTNAME,
+/// Persist the domain name from DHCP to file.  Returns whether the domain changed.
+fn write_current_domain(domain: &str) -> Result<bool> {
+    let changed = lines_changed(CURRENT_DOMAIN, domain);
+    debug!(
+        "Writing {} to {} (changed: {})",
+        domain, CURRENT_DOMAIN, changed
+    );
+    fs::write(CURRENT_DOMAIN, domain).context(error::CurrentDomainWriteFailedSnafu {
+        path: CURRENT_DOMAIN,
     })?;
-    Ok(())
+    Ok(changed)
 }
 
-fn run() -> Result<()> {
-    let args: Args = argh::from_env();
-    match args.subcommand {
-        SubCommand::Install(args) => install(args)?,
-        SubCommand::Remove(args) => remove(args)?,
-        SubCommand::NodeIp(_) => node_ip()?,
-        SubCommand::GenerateHostname(_) => generate_hostname()?,
-        SubCommand::SetHostname(args) => set_hostname(args)?,
+/// Persist the proxy autoconfig URL from DHCP to file, or remove the file if there isn't one, so
+/// a URL from an old lease doesn't linger.  Returns whether the URL changed.
+fn write_current_wpad(wpad: Option<&Url>) -> Result<bool> {
+    match wpad {
+        Some(url) => {
+            let changed = lines_changed(CURRENT_WPAD, url.as_str());
+            debug!("Writing {} to {} (changed: {})", url, CURRENT_WPAD, changed);
+            fs::write(CURRENT_WPAD, url.as_str())
+                .context(error::CurrentWpadWriteFailedSnafu { path: CURRENT_WPAD })?;
+            Ok(changed)
+        }
+        None => match fs::remove_file(CURRENT_WPAD) {
+            Ok(()) => {
+                debug!("Removed {}", CURRENT_WPAD);
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).context(error::CurrentWpadWriteFailedSnafu { path: CURRENT_WPAD }),
+        },
     }
-    Ok(())
 }
 
-// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
-// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
-// https://github.com/shepmaster/snafu/issues/110
-fn main() {
-    if let Err(e) = run() {
-        eprintln!("{}", e);
-        process::exit(1);
+/// Persist the interface MTU from DHCP to file, or remove the file if there isn't one.  Returns
+/// whether the MTU changed.
+fn write_current_mtu(mtu: Option<u32>) -> Result<bool> {
+    match mtu {
+        Some(mtu) => {
+            let mtu = mtu.to_string();
+            let changed = lines_changed(CURRENT_MTU, &mtu);
+            debug!("Writing {} to {} (changed: {})", mtu, CURRENT_MTU, changed);
+            fs::write(CURRENT_MTU, mtu)
+                .context(error::CurrentMtuWriteFailedSnafu { path: CURRENT_MTU })?;
+            Ok(changed)
+        }
+        None => match fs::remove_file(CURRENT_MTU) {
+            Ok(()) => {
+                debug!("Removed {}", CURRENT_MTU);
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).context(error::CurrentMtuWriteFailedSnafu { path: CURRENT_MTU }),
+        },
     }
 }
 
-/// Potential errors during netdog execution
-mod error {
-    use envy;
-    use snafu::Snafu;
-    use std::io;
-    use std::path::PathBuf;
+/// Returns the path of the sysfs file holding the interface's current MTU.
+fn interface_mtu_path(interface: &str) -> PathBuf {
+    Path::new("/sys/class/net").join(interface).join("mtu")
+}
 
-    #[derive(Debug, Snafu)]
-    #[snafu(visibility(pub(super)))]
-    #[allow(clippy::enum_variant_names)]
-    pub(super) enum Error {
-        #[snafu(display("Failed to read lease data in '{}': {}", path.display(), source))]
-        LeaseReadFailed { path: PathBuf, source: io::Error },
+/// Read the interface's current MTU from sysfs.
+fn interface_mtu(interface: &str) -> Result<u32> {
+    let path = interface_mtu_path(interface);
+    let mtu =
+        fs::read_to_string(&path).context(error::InterfaceMtuReadFailedSnafu { path: &path })?;
+    mtu.trim()
+        .parse()
+        .context(error::InterfaceMtuParseFailedSnafu {
+            path,
+            mtu: mtu.trim(),
+        })
+}
 
-        #[snafu(display("Failed to parse lease data in '{}': {}", path.display(), source))]
-        LeaseParseFailed { path: PathBuf, source: envy::Error },
+/// Returns the lease's proxy autoconfig URL, if it has one that parses.  A bad URL shouldn't
+/// keep the node off the network, so it's only logged.
+fn wpad_url(info: &LeaseInfo) -> Option<Url> {
+    let wpad = info.wpad.as_deref()?;
+    match Url::parse(wpad) {
+        Ok(url) => Some(url),
+        Err(e) => {
+            warn!("Ignoring invalid proxy autoconfig URL '{}': {}", wpad, e);
+            None
+        }
+    }
+}
 
-        #[snafu(display("Failed to build resolver configuration: {}", source))]
-        ResolvConfBuildFailed { source: std::fmt::Error },
+/// Build a systemd-networkd .network drop-in from lease info.
+fn networkd_config(info: &LeaseInfo) -> Result<String> {
+    let mut output = String::new();
+
+    writeln!(output, "[Network]").context(error::NetworkdBuildFailedSnafu)?;
+    writeln!(output, "Address={}", info.ip_address).context(error::NetworkdBuildFailedSnafu)?;
+    for n in &info.dns_servers {
+        writeln!(output, "DNS={}", n).context(error::NetworkdBuildFailedSnafu)?;
+    }
+    // networkd uses Domains for the search list; if we only have the domain name, search that.
+    let domains = match (&info.dns_search, &info.dns_domain) {
+        (Some(search), _) => Some(search.join(" ")),
+        (None, Some(domain)) => Some(domain.clone()),
+        (None, None) => None,
+    };
+    if let Some(domains) = domains {
+        writeln!(output, "Domains={}", domains).context(error::NetworkdBuildFailedSnafu)?;
+    }
+
+    // The gateway is the lease's default route.
+    if let Some(gateway) = &info.gateway {
+        writeln!(output, "\n[Route]\nGateway={}", gateway)
+            .context(error::NetworkdBuildFailedSnafu)?;
+    }
+
+    Ok(output)
+}
+
+/// Write a systemd-networkd drop-in for the given lease
+fn generate_networkd(args: GenerateNetworkdArgs) -> Result<()> {
+    let info = parse_lease_info(&args.data_file).context(error::LeaseSnafu)?;
+    let config = networkd_config(&info)?;
+    match args.output {
+        Some(path) => fs::write(&path, config).context(error::NetworkdWriteFailedSnafu { path })?,
+        None => print!("{}", config),
+    }
+    Ok(())
+}
+
+/// Read the split-DNS policy at the given path; see `parse_dns_policy`.
+fn read_dns_policy(path: &Path) -> Result<Vec<DnsPolicyEntry>> {
+    let data = fs::read_to_string(path).context(error::DnsPolicyReadFailedSnafu { path })?;
+    parse_dns_policy(path, &data)
+}
+
+/// Parse a split-DNS policy: a JSON list of entries like
+/// `{"domain": "corp.example.com", "servers": ["10.0.0.2"]}`.  Domains are normalized to lower
+/// case without a trailing dot, and each entry is checked: the domain must be a valid name that
+/// isn't listed twice, and it must have at least one name server, none of them repeated,
+/// unspecified, or multicast.
+fn parse_dns_policy(path: &Path, data: &str) -> Result<Vec<DnsPolicyEntry>> {
+    let mut policy: Vec<DnsPolicyEntry> =
+        serde_json::from_str(data).context(error::DnsPolicyParseFailedSnafu { path })?;
+    ensure!(!policy.is_empty(), error::DnsPolicyEmptySnafu { path });
+
+    let mut domains = BTreeSet::new();
+    for entry in &mut policy {
+        entry.domain = entry.domain.trim_end_matches('.').to_lowercase();
+        let invalid = |reason: String| error::DnsPolicyInvalidSnafu {
+            domain: entry.domain.clone(),
+            reason,
+        };
+        ensure!(
+            valid_hostname(&entry.domain),
+            invalid("not a valid domain name".to_string())
+        );
+        ensure!(
+            domains.insert(entry.domain.clone()),
+            invalid("listed more than once".to_string())
+        );
+        ensure!(
+            !entry.servers.is_empty(),
+            invalid("no name servers given".to_string())
+        );
+        let mut servers = BTreeSet::new();
+        for server in &entry.servers {
+            ensure!(
+                !server.is_unspecified() && !server.is_multicast(),
+                invalid(format!("{} can't be a name server", server))
+            );
+            ensure!(
+                servers.insert(server),
+                invalid(format!("name server {} listed more than once", server))
+            );
+        }
+    }
+    Ok(policy)
+}
+
+/// Build a systemd-resolved drop-in from a split-DNS policy.  The policy's domains become
+/// routing-only domains (`~domain`) of resolved's global scope, whose name servers are the
+/// policy's, so queries in those domains go to them and everything else goes to the interfaces'
+/// own name servers, as before.  The global scope has a single server list, so if entries give
+/// different servers, each of their domains may be sent to any of them; we warn about that.
+/// The lease is used to warn about policy domains that take over the lease's own domain or
+/// search domains.
+fn resolved_dropin(info: &LeaseInfo, policy: &[DnsPolicyEntry]) -> Result<String> {
+    let mut servers: Vec<&IpAddr> = Vec::new();
+    for entry in policy {
+        for server in &entry.servers {
+            if !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+    }
+    if policy
+        .iter()
+        .any(|entry| entry.servers.len() != servers.len())
+    {
+        warn!(
+            "Policy entries list different name servers, but systemd-resolved's global scope \
+             has one list; queries in any policy domain may go to any of them"
+        );
+    }
+
+    let lease_domains = info
+        .dns_domain
+        .iter()
+        .chain(info.dns_search.iter().flatten());
+    for lease_domain in lease_domains {
+        let lease_domain = lease_domain.trim_end_matches('.').to_lowercase();
+        if let Some(entry) = policy.iter().find(|entry| {
+            lease_domain == entry.domain || lease_domain.ends_with(&format!(".{}", entry.domain))
+        }) {
+            warn!(
+                "Lease domain {} will be resolved by the name servers for {}, not the lease's",
+                lease_domain, entry.domain
+            );
+        }
+    }
+
+    let mut output = String::new();
+    writeln!(output, "[Resolve]").context(error::ResolvedBuildFailedSnafu)?;
+    let servers: Vec<_> = servers.iter().map(|s| s.to_string()).collect();
+    writeln!(output, "DNS={}", servers.join(" ")).context(error::ResolvedBuildFailedSnafu)?;
+    let domains: Vec<_> = policy
+        .iter()
+        .map(|entry| format!("~{}", entry.domain))
+        .collect();
+    writeln!(output, "Domains={}", domains.join(" ")).context(error::ResolvedBuildFailedSnafu)?;
+    Ok(output)
+}
+
+/// Write a systemd-resolved drop-in for the given lease and split-DNS policy
+fn generate_resolved_dropin(args: GenerateResolvedDropinArgs) -> Result<()> {
+    let info = parse_lease_info(&args.data_file).context(error::LeaseSnafu)?;
+    let policy = read_dns_policy(&args.policy)?;
+    let dropin = resolved_dropin(&info, &policy)?;
+    match args.output {
+        Some(path) => fs::write(&path, dropin).context(error::ResolvedWriteFailedSnafu { path })?,
+        None => print!("{}", dropin),
+    }
+    Ok(())
+}
+
+/// Parse the lease file at the given path, or if it's a directory, parse and merge every lease
+/// file in it.
+fn parse_leases(path: &Path) -> Result<LeaseInfo> {
+    if !path.is_dir() {
+        return parse_lease_info(path).context(error::LeaseSnafu);
+    }
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(path).context(error::LeaseDirReadFailedSnafu { path })? {
+        let entry = entry.context(error::LeaseDirReadFailedSnafu { path })?;
+        if entry.path().is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+
+    let mut leases = Vec::with_capacity(paths.len());
+    for lease_path in paths {
+        leases.push(parse_lease_info(&lease_path).context(error::LeaseSnafu)?);
+    }
+    merge_leases(leases).context(error::LeaseDirEmptySnafu { path })
+}
+
+/// Merge leases, given in precedence order.  The first lease gives the IP address and gateway;
+/// name servers and search domains are combined, without duplicates, keeping each lease's search
+/// order; the domain name and proxy autoconfig URL come from the first lease that has one.  The
+/// MTU only comes from the first lease, since it applies to that lease's interface.  Returns
+/// None if there are no leases.
+fn merge_leases(leases: Vec<LeaseInfo>) -> Option<LeaseInfo> {
+    let mut leases = leases.into_iter();
+    let mut merged = leases.next()?;
+    for lease in leases {
+        merged.dns_servers.extend(lease.dns_servers);
+        if let Some(search) = lease.dns_search {
+            let merged_search = merged.dns_search.get_or_insert_with(Vec::new);
+            for domain in search {
+                if !merged_search.contains(&domain) {
+                    merged_search.push(domain);
+                }
+            }
+        }
+        match (&merged.dns_domain, lease.dns_domain) {
+            (None, domain) => merged.dns_domain = domain,
+            (Some(kept), Some(other)) if kept != &other => {
+                warn!("Using domain name {} rather than {}", kept, other)
+            }
+            _ => {}
+        }
+        if merged.wpad.is_none() {
+            merged.wpad = lease.wpad;
+        }
+    }
+    Some(merged)
+}
+
+/// Build a listing of each file `install` would write for the given lease, and its contents.
+fn test_lease_output(
+    info: &LeaseInfo,
+    max_search_domains: usize,
+    sortlist: &[SortlistEntry],
+) -> Result<String> {
+    let dns_servers: Vec<_> = info.dns_servers.iter().collect();
+    let mut files = vec![
+        (
+            RESOLV_CONF,
+            resolv_conf(&dns_servers, &info.dns_search, max_search_domains, sortlist)?,
+        ),
+        (CURRENT_IP, info.ip_address.addr().to_string()),
+        (CURRENT_IP_CIDR, info.ip_address.to_string()),
+    ];
+    if let Some(gateway) = &info.gateway {
+        files.push((CURRENT_GATEWAY, gateway.to_string()));
+    }
+    if let Some(domain) = &info.dns_domain {
+        files.push((CURRENT_DOMAIN, domain.clone()));
+    }
+    if let Some(url) = wpad_url(info) {
+        files.push((CURRENT_WPAD, url.to_string()));
+    }
+    if let Some(mtu) = info.mtu {
+        files.push((CURRENT_MTU, mtu.to_string()));
+    }
+
+    let mut output = String::new();
+    for (path, contents) in files {
+        writeln!(output, "# {}\n{}", path, contents.trim_end())
+            .context(error::TestLeaseBuildFailedSnafu)?;
+    }
+    Ok(output)
+}
+
+/// Print what `install` would write for the given lease, without touching the filesystem
+fn test_lease(args: TestLeaseArgs) -> Result<()> {
+    let info = parse_leases(&args.data_file)?;
+    let sortlist = sortlist_entries(&info, args.sortlist_subnet, &args.sortlist);
+    print!(
+        "{}",
+        test_lease_output(&info, args.max_search_domains, &sortlist)?
+    );
+    Ok(())
+}
+
+/// Reload or restart the given systemd unit so it picks up new network configuration
+fn reload_service(service: &str) -> Result<()> {
+    let status = Command::new("systemctl")
+        .args(["reload-or-restart", service])
+        .status()
+        .context(error::ServiceReloadSnafu { service })?;
+    ensure!(
+        status.success(),
+        error::ServiceReloadFailedSnafu {
+            service,
+            status: status.to_string()
+        }
+    );
+    Ok(())
+}
+
+/// Records an interface's lease under the state directory: its address, for `node-ip --all`,
+/// and with `reconcile`, its resolver settings.  Returns whether the address changed, and when
+/// reconciling, the resolver settings merged from every interface's leases.
+fn record_interface(
+    state_dir: &Path,
+    interface: &InterfaceName,
+    family: &InterfaceFamily,
+    info: &LeaseInfo,
+    reconcile: bool,
+) -> Result<(bool, Option<ResolverState>)> {
+    let interface = interface.to_string();
+    let changed = write_interface_ip(state_dir, &interface, &info.ip_address)?;
+    if !reconcile {
+        return Ok((changed, None));
+    }
+    let state = ResolverState {
+        dns_servers: info.dns_servers.clone(),
+        dns_search: info.dns_search.clone().unwrap_or_default(),
+    };
+    write_resolver_state(state_dir, &interface, family, &state)?;
+    let merged = merge_resolver_states(&read_resolver_states(state_dir)?);
+    Ok((changed, Some(merged)))
+}
+
+fn install(args: InstallArgs) -> Result<()> {
+    let InterfaceType::Dhcp = args.interface_type;
+    let info = parse_leases(&args.data_file)?;
+    let sortlist = sortlist_entries(&info, args.sortlist_subnet, &args.sortlist);
+    // The primary interface's IPv4 lease is the node's: it gives the current IP, gateway, domain,
+    // and so on.  Other leases only add their address and, when reconciling, resolver settings.
+    let primary = args.interface_name == InterfaceName::primary()
+        && args.interface_family == InterfaceFamily::Ipv4;
+
+    // The interface's address is written first, so once the current IP exists, as
+    // `node-ip --wait` checks, `node-ip --all` has an address to list too.
+    let (mut changed, merged) = record_interface(
+        Path::new(NETDOG_STATE_DIR),
+        &args.interface_name,
+        &args.interface_family,
+        &info,
+        args.reconcile,
+    )?;
+    if let Some(merged) = merged {
+        // Build resolv.conf from every interface.
+        let mut dns_servers: Vec<_> = merged.dns_servers.iter().collect();
+        order_name_servers(
+            &mut dns_servers,
+            args.dns_order.unwrap_or(DnsOrder::Preserve),
+        )?;
+        let dns_search = Some(merged.dns_search).filter(|s| !s.is_empty());
+        changed |= write_resolv_conf(
+            &dns_servers,
+            &dns_search,
+            args.max_search_domains,
+            &sortlist,
+        )?;
+    } else if primary {
+        let mut dns_servers: Vec<_> = info.dns_servers.iter().collect();
+        order_name_servers(
+            &mut dns_servers,
+            args.dns_order.unwrap_or(DnsOrder::Shuffle),
+        )?;
+        changed |= write_resolv_conf(
+            &dns_servers,
+            &info.dns_search,
+            args.max_search_domains,
+            &sortlist,
+        )?;
+    } else {
+        info!(
+            "Not writing {} from the {} lease of {} without --reconcile",
+            RESOLV_CONF, args.interface_family, args.interface_name
+        );
+    }
+
+    if primary {
+        changed |= write_current_ip(&info.ip_address)?;
+        changed |= write_current_gateway(info.gateway.as_ref())?;
+        if let Some(domain) = &info.dns_domain {
+            changed |= write_current_domain(domain)?;
+        }
+        changed |= write_current_wpad(wpad_url(&info).as_ref())?;
+        changed |= write_current_mtu(info.mtu)?;
+
+        // netdog doesn't set the MTU itself, so tell operators if the interface didn't get
+        // the one DHCP advertised.  This shouldn't keep the node off the network.
+        if let Some(lease_mtu) = info.mtu {
+            let interface = args.interface_name.to_string();
+            match interface_mtu(&interface) {
+                Ok(mtu) if mtu != lease_mtu => warn!(
+                    "Interface {} has MTU {}, but DHCP advertised {}",
+                    interface, mtu, lease_mtu
+                ),
+                Ok(_) => {}
+                Err(e) => warn!("Unable to check MTU of {}: {}", interface, e),
+            }
+        }
+    }
+
+    // Only bother dependent services if there's something new for them to pick up.
+    if changed {
+        for service in &args.reload_service {
+            info!("Network configuration changed, reloading {}", service);
+            reload_service(service)?;
+        }
+    }
+    Ok(())
+}
+
+fn remove(args: RemoveArgs) -> Result<()> {
+    match (
+        &args.interface_name,
+        &args.interface_type,
+        &args.interface_family,
+    ) {
+        _ => info!("The 'remove' command is not implemented."),
+    }
+    Ok(())
+}
+
+/// Waits up to the given number of seconds for the file at the given path to exist.  Returns
+/// early with no error if it doesn't; reading the file then gives the usual not-found error.
+fn wait_for_file(path: &str, seconds: u64) {
+    let deadline = Instant::now() + Duration::from_secs(seconds);
+    while !Path::new(path).exists() {
+        if Instant::now() >= deadline {
+            warn!("Gave up waiting {}s for {} to exist", seconds, path);
+            return;
+        }
+        debug!("Waiting for {} to exist", path);
+        thread::sleep(WAIT_POLL_INTERVAL);
+    }
+}
+
+/// Reads the IP address persisted by `install`, making sure it's a proper address before anyone
+/// trusts it.
+fn read_current_ip() -> Result<IpAddr> {
+    let contents = fs::read_to_string(CURRENT_IP)
+        .context(error::CurrentIpReadFailedSnafu { path: CURRENT_IP })?;
+    parse_current_ip(CURRENT_IP, &contents)
+}
+
+/// Parses the contents of a current IP file, ignoring surrounding whitespace.  Anything that
+/// isn't an address, including an empty file, is reported as corrupt rather than as a bad
+/// address, since the file only ever holds what `install` wrote.
+fn parse_current_ip(path: &str, contents: &str) -> Result<IpAddr> {
+    let trimmed = contents.trim();
+    parse_ip(trimmed)
+        .ok()
+        .context(error::CorruptCurrentIpSnafu {
+            path,
+            contents: trimmed,
+        })
+}
+
+/// Return the current IP address as JSON (intended for use as a settings generator).  With
+/// `--all`, return every interface's persisted addresses instead.
+fn node_ip(args: NodeIpArgs) -> Result<()> {
+    if let Some(seconds) = args.wait {
+        wait_for_file(
+            if args.cidr && !args.all {
+                CURRENT_IP_CIDR
+            } else {
+                CURRENT_IP
+            },
+            seconds,
+        );
+    }
+
+    if args.all {
+        return print_json(read_interface_ips(Path::new(NETDOG_STATE_DIR), args.cidr)?);
+    }
+    if args.cidr {
+        let cidr_string =
+            fs::read_to_string(CURRENT_IP_CIDR).context(error::CurrentIpReadFailedSnafu {
+                path: CURRENT_IP_CIDR,
+            })?;
+        // Validate that we read a proper address and prefix
+        let cidr = IpNet::from_str(cidr_string.trim()).context(error::CidrFromStringSnafu {
+            cidr: cidr_string.trim(),
+        })?;
+        return print_json(cidr.to_string());
+    }
+
+    let ip = read_current_ip()?;
+
+    // sundog expects JSON-serialized output
+    print_json(ip.to_string())
+}
+
+/// Return the current default gateway as JSON (intended for use as a settings generator)
+fn node_gateway() -> Result<()> {
+    let gateway_string = match fs::read_to_string(CURRENT_GATEWAY) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return error::CurrentGatewayMissingSnafu {
+                path: CURRENT_GATEWAY,
+            }
+            .fail()
+        }
+        Err(e) => {
+            return Err(e).context(error::CurrentGatewayReadFailedSnafu {
+                path: CURRENT_GATEWAY,
+            })
+        }
+    };
+    // Validate that we read a proper IP address
+    let gateway = parse_ip(&gateway_string)?;
+
+    // sundog expects JSON-serialized output
+    print_json(gateway.to_string())
+}
+
+/// Return the domain name from DHCP as JSON (intended for use as a settings generator)
+fn node_domain() -> Result<()> {
+    let domain = match fs::read_to_string(CURRENT_DOMAIN) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return error::CurrentDomainMissingSnafu {
+                path: CURRENT_DOMAIN,
+            }
+            .fail()
+        }
+        Err(e) => {
+            return Err(e).context(error::CurrentDomainReadFailedSnafu {
+                path: CURRENT_DOMAIN,
+            })
+        }
+    };
+
+    // sundog expects JSON-serialized output
+    print_json(domain.trim())
+}
+
+/// Return the proxy autoconfig URL from DHCP as JSON, or null if DHCP didn't provide a valid one
+/// (intended for use as a settings generator)
+fn node_wpad() -> Result<()> {
+    let wpad = match fs::read_to_string(CURRENT_WPAD) {
+        Ok(s) => Some(s),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context(error::CurrentWpadReadFailedSnafu { path: CURRENT_WPAD }),
+    };
+
+    // sundog expects JSON-serialized output
+    print_json(wpad.as_deref().map(str::trim))
+}
+
+/// Return the interface MTU from DHCP as JSON, or null if DHCP didn't provide one (intended for
+/// use as a settings generator).  With `--check`, fails if the interface's MTU differs from it.
+fn node_mtu(args: NodeMtuArgs) -> Result<()> {
+    let mtu = match fs::read_to_string(CURRENT_MTU) {
+        Ok(s) => Some(
+            s.trim()
+                .parse::<u32>()
+                .context(error::CurrentMtuParseFailedSnafu {
+                    path: CURRENT_MTU,
+                    mtu: s.trim(),
+                })?,
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context(error::CurrentMtuReadFailedSnafu { path: CURRENT_MTU }),
+    };
+
+    if args.check {
+        if let Some(lease_mtu) = mtu {
+            let interface = args.interface_name.to_string();
+            let current = interface_mtu(&interface)?;
+            ensure!(
+                current == lease_mtu,
+                error::MtuMismatchSnafu {
+                    interface,
+                    current,
+                    lease_mtu
+                }
+            );
+        }
+    }
+
+    // sundog expects JSON-serialized output
+    print_json(mtu)
+}
+
+/// Return the DNS search list from resolv.conf as a JSON list (intended for use as a settings
+/// generator).  The list is empty if DHCP didn't provide one.
+fn node_dns_search() -> Result<()> {
+    let resolv_conf = fs::read_to_string(RESOLV_CONF)
+        .context(error::ResolvConfReadFailedSnafu { path: RESOLV_CONF })?;
+    let search: Vec<&str> = resolv_conf
+        .lines()
+        .filter_map(|line| line.strip_prefix("search "))
+        .flat_map(|domains| domains.split_whitespace())
+        .collect();
+
+    // sundog expects JSON-serialized output
+    print_json(search)
+}
+
+/// Attempt to resolve assigned IP address, if unsuccessful use the IP as the hostname.
+///
+/// The result is returned as JSON. (intended for use as a settings generator)
+fn generate_hostname(args: GenerateHostnameArgs) -> Result<()> {
+    let ip = read_current_ip()?;
+    let retry_delay = Duration::from_millis(args.dns_retry_delay);
+    let (hostname, source) =
+        match cached_lookup_addr(&ip, args.cache_ttl, args.dns_retries, retry_delay) {
+            Ok(hostname) => (hostname, HostnameSource::ReverseDns),
+            Err(e) => {
+                warn!("Reverse DNS lookup failed: {}", e);
+                // IPv4 addresses happen to be valid hostnames, but IPv6 addresses aren't.
+                let fallback = match ip {
+                    IpAddr::V4(_) => ip.to_string(),
+                    IpAddr::V6(_) => ip_label(&ip),
+                };
+                (fallback, HostnameSource::Ip)
+            }
+        };
+    let ip_derived = source == HostnameSource::Ip;
+
+    // If the user asked for a domain, qualify names that don't already have one, so the
+    // hostname is a stable FQDN.  A bare IP can't take a suffix, so it's turned into a label
+    // like "ip-10-0-0-5" first.
+    let hostname = match args.domain_suffix {
+        Some(suffix) if ip_derived || !hostname.contains('.') => {
+            let name = if ip_derived { ip_label(&ip) } else { hostname };
+            let fqdn = format!("{}.{}", name, suffix.trim_matches('.'));
+            ensure!(
+                valid_hostname(&fqdn),
+                error::InvalidHostnameSnafu { hostname: fqdn }
+            );
+            fqdn
+        }
+        _ => hostname,
+    };
+
+    // sundog expects JSON-serialized output
+    let generated = GeneratedHostname {
+        hostname,
+        source,
+        ip,
+    };
+    match args.format {
+        HostnameFormat::String => print_json(generated.hostname),
+        HostnameFormat::Object => print_json(generated),
+    }
+}
+
+/// Looks up the hostname for the given IP, reusing the result in `HOSTNAME_CACHE` if it's for the
+/// same IP and younger than `ttl` seconds.  Only successful lookups are cached, so a resolver
+/// that's not ready yet is asked again next time.  The cache is just an optimization, so problems
+/// reading or writing it are logged and otherwise ignored.  Failed lookups are retried as for
+/// `with_retries`.
+fn cached_lookup_addr(
+    ip: &IpAddr,
+    ttl: u64,
+    retries: u32,
+    retry_delay: Duration,
+) -> io::Result<String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    if ttl > 0 {
+        let cached = fs::read_to_string(HOSTNAME_CACHE)
+            .ok()
+            .and_then(|data| serde_json::from_str::<HostnameCacheEntry>(&data).ok());
+        if let Some(hostname) = cached.as_ref().and_then(|c| c.lookup(ip, ttl, now)) {
+            debug!("Using cached hostname for {}: {}", ip, hostname);
+            return Ok(hostname.to_string());
+        }
+    }
+
+    // lookup_addr takes care of querying in-addr.arpa or ip6.arpa, as appropriate.
+    let hostname = with_retries(retries, retry_delay, |attempt| {
+        debug!("Looking up {} (attempt {})", reverse_dns_name(ip), attempt);
+        lookup_addr(ip)
+    })?;
+    if ttl > 0 {
+        let entry = HostnameCacheEntry {
+            ip: *ip,
+            hostname: hostname.clone(),
+            resolved_at: now,
+        };
+        match serde_json::to_string(&entry) {
+            Ok(data) => {
+                if let Err(e) = fs::write(HOSTNAME_CACHE, data) {
+                    warn!("Failed to write hostname cache '{}': {}", HOSTNAME_CACHE, e);
+                }
+            }
+            Err(e) => warn!("Failed to serialize hostname cache: {}", e),
+        }
+    }
+    Ok(hostname)
+}
+
+/// Calls `f`, given the attempt number starting at 1, until it succeeds or has been retried
+/// `retries` times, returning its last result.  We sleep `delay` before the first retry, and
+/// twice as long before each one after that.
+fn with_retries<T, F>(retries: u32, delay: Duration, mut f: F) -> io::Result<T>
+where
+    F: FnMut(u32) -> io::Result<T>,
+{
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        match f(attempt) {
+            Err(e) if attempt <= retries => {
+                debug!("Attempt {} failed, retrying in {:?}: {}", attempt, delay, e);
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Parses an IP address as we persist or receive it, accepting the bracketed form and zone IDs
+/// that IPv6 addresses can carry, e.g. "[fe80::1%eth0]".  The zone is dropped, since it's only
+/// meaningful to the local routing table.
+fn parse_ip(input: &str) -> Result<IpAddr> {
+    let trimmed = input.trim();
+    let unbracketed = trimmed
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(trimmed);
+    let address = unbracketed.split('%').next().unwrap_or(unbracketed);
+    IpAddr::from_str(address).context(error::IpFromStringSnafu { ip: input })
+}
+
+/// Returns a hostname label made from an IP address, like "ip-10-0-0-5" or, using every segment
+/// of an IPv6 address so the label never starts or ends with a hyphen,
+/// "ip-2001-db8-0-0-0-0-0-1".
+fn ip_label(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => format!("ip-{}", v4.to_string().replace('.', "-")),
+        IpAddr::V6(v6) => {
+            let segments: Vec<String> = v6.segments().iter().map(|s| format!("{:x}", s)).collect();
+            format!("ip-{}", segments.join("-"))
+        }
+    }
+}
+
+/// Returns the name queried for a reverse lookup of the given IP, in in-addr.arpa for IPv4 or
+/// ip6.arpa for IPv6.
+fn reverse_dns_name(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let mut octets: Vec<String> = v4.octets().iter().map(|o| o.to_string()).collect();
+            octets.reverse();
+            format!("{}.in-addr.arpa", octets.join("."))
+        }
+        IpAddr::V6(v6) => {
+            let nibbles: Vec<String> = v6
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|o| vec![o & 0xf, o >> 4])
+                .map(|n| format!("{:x}", n))
+                .collect();
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    }
+}
+
+/// Checks that the given name is a valid hostname per RFC 1123: at most 253 characters, made of
+/// dot-separated valid labels.
+fn valid_hostname(hostname: &str) -> bool {
+    hostname.len() <= 253 && hostname.split('.').all(|l| HOSTNAME_LABEL.is_match(l))
+}
+
+/// Helper function that serializes the input to JSON and prints it
+fn print_json<S>(val: S) -> Result<()>
+where
+    S: Serialize,
+{
+    let output = serde_json::to_string(&val).context(error::JsonSerializeSnafu)?;
+    println!("{}", output);
+    Ok(())
+}
+
+/// Sets the hostname for the system
+fn set_hostname(args: SetHostnameArgs) -> Result<()> {
+    if args.check {
+        // Hostnames are case-insensitive and a trailing dot only marks an FQDN as absolute, so
+        // normalize those away before validating.
+        let hostname = args.hostname.trim().trim_end_matches('.').to_lowercase();
+        ensure!(
+            valid_hostname(&hostname),
+            error::InvalidHostnameSnafu { hostname }
+        );
+        println!("{}", hostname);
+        return Ok(());
+    }
+
+    // Without --check, the hostname is set as given, as it always has been.
+    let hostname = args.hostname;
+    match args.mode {
+        HostnameMode::Transient => write_kernel_hostname(&hostname),
+        HostnameMode::Static => write_atomic(ETC_HOSTNAME, &format!("{}\n", hostname))
+            .context(error::HostnameWriteFailedSnafu { path: ETC_HOSTNAME }),
+        HostnameMode::Both => set_both_hostnames(&hostname),
+    }
+}
+
+fn write_kernel_hostname(hostname: &str) -> Result<()> {
+    fs::write(KERNEL_HOSTNAME, hostname).context(error::HostnameWriteFailedSnafu {
+        path: KERNEL_HOSTNAME,
+    })
+}
+
+/// Sets the kernel hostname and `/etc/hostname` together.  The new `/etc/hostname` is staged
+/// before the kernel hostname changes and only moved into place afterward, and the kernel
+/// hostname is put back if that fails, so a failure doesn't leave the two disagreeing.
+fn set_both_hostnames(hostname: &str) -> Result<()> {
+    let staged = format!("{}.tmp", ETC_HOSTNAME);
+    fs::write(&staged, format!("{}\n", hostname))
+        .context(error::HostnameWriteFailedSnafu { path: &staged })?;
+
+    let previous = fs::read_to_string(KERNEL_HOSTNAME).ok();
+    if let Err(e) = write_kernel_hostname(hostname) {
+        let _ = fs::remove_file(&staged);
+        return Err(e);
+    }
+
+    if let Err(e) = fs::rename(&staged, ETC_HOSTNAME) {
+        let _ = fs::remove_file(&staged);
+        if let Some(previous) = previous {
+            if let Err(restore_error) = write_kernel_hostname(previous.trim()) {
+                warn!("Failed to restore kernel hostname: {}", restore_error);
+            }
+        }
+        return Err(e).context(error::HostnameWriteFailedSnafu { path: ETC_HOSTNAME });
+    }
+    Ok(())
+}
+
+/// Returns the hosts file contents with netdog's managed block holding the given lines.  A block
+/// from an earlier run is replaced where it is; otherwise the block is added at the end.
+/// Everything outside the block is kept as is.  Returns None if the existing block isn't closed,
+/// since we can't tell where it ends.
+fn hosts_with_block(existing: &str, lines: &[String]) -> Option<String> {
+    let mut block = format!("{}\n", HOSTS_BLOCK_BEGIN);
+    for line in lines {
+        block.push_str(line);
+        block.push('\n');
+    }
+    block.push_str(HOSTS_BLOCK_END);
+    block.push('\n');
+
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+    let mut found = false;
+    let mut in_block = false;
+    for line in existing.lines() {
+        match line.trim() {
+            l if l == HOSTS_BLOCK_BEGIN && !found => in_block = true,
+            l if l == HOSTS_BLOCK_END && in_block => {
+                in_block = false;
+                found = true;
+            }
+            _ if in_block => {}
+            _ if found => after.push(line),
+            _ => before.push(line),
+        }
+    }
+    if in_block {
+        return None;
+    }
+
+    let mut output = String::new();
+    for line in before {
+        output.push_str(line);
+        output.push('\n');
+    }
+    output.push_str(&block);
+    for line in after {
+        output.push_str(line);
+        output.push('\n');
+    }
+    Some(output)
+}
+
+/// Writes a managed block to /etc/hosts mapping the current IP to the hostname, so the node can
+/// resolve its own name when DNS can't.  Short names are listed too for FQDNs.
+fn write_hosts(args: WriteHostsArgs) -> Result<()> {
+    let ip = read_current_ip()?;
+    let hostname = match args.hostname {
+        Some(hostname) => hostname,
+        None => fs::read_to_string(KERNEL_HOSTNAME).context(error::HostnameReadFailedSnafu {
+            path: KERNEL_HOSTNAME,
+        })?,
+    };
+    let hostname = hostname.trim().trim_end_matches('.').to_lowercase();
+    ensure!(
+        valid_hostname(&hostname),
+        error::InvalidHostnameSnafu { hostname }
+    );
+
+    let mut names = vec![hostname.as_str()];
+    if let Some((short, _)) = hostname.split_once('.') {
+        names.push(short);
+    }
+    let lines = vec![format!("{} {}", ip, names.join(" "))];
+
+    let existing = match fs::read_to_string(ETC_HOSTS) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context(error::HostsReadFailedSnafu { path: ETC_HOSTS }),
+    };
+    let hosts = hosts_with_block(&existing, &lines)
+        .context(error::HostsBlockUnclosedSnafu { path: ETC_HOSTS })?;
+    if hosts == existing {
+        debug!("{} is already up to date", ETC_HOSTS);
+        return Ok(());
+    }
+    info!("Mapping {} to {} in {}", ip, hostname, ETC_HOSTS);
+    write_atomic(ETC_HOSTS, &hosts).context(error::HostsWriteFailedSnafu { path: ETC_HOSTS })
+}
+
+/// What `reset` removed, or would remove without `--confirm`
+#[derive(Debug, Serialize)]
+struct ResetSummary {
+    confirmed: bool,
+    removed: Vec<PathBuf>,
+    resolv_conf_restored: bool,
+}
+
+/// Lists everything under the state directory, which netdog owns, so files added by later
+/// versions are cleared too.  A missing directory has nothing to clear.
+fn state_entries(state_dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(state_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(error::StateReadFailedSnafu { path: state_dir }),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry.context(error::StateReadFailedSnafu { path: state_dir })?;
+        paths.push(entry.path());
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Removes netdog's persisted state, and optionally resets resolv.conf, printing what was removed.
+fn reset(args: ResetArgs) -> Result<()> {
+    let paths = state_entries(Path::new(NETDOG_STATE_DIR))?;
+    if !args.confirm {
+        warn!("Not removing anything without --confirm");
+        return print_json(ResetSummary {
+            confirmed: false,
+            removed: paths,
+            resolv_conf_restored: false,
+        });
+    }
+
+    for path in &paths {
+        info!("Removing {}", path.display());
+        // Interfaces' resolver settings are kept in directories; everything else is a file.
+        let result = if path.is_dir() && !path.is_symlink() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+        result.context(error::StateRemoveFailedSnafu { path })?;
+    }
+
+    if args.restore_resolv_conf {
+        info!("Replacing {} with a minimal configuration", RESOLV_CONF);
+        write_atomic(RESOLV_CONF, MINIMAL_RESOLV_CONF)
+            .context(error::ResolvConfWriteFailedSnafu { path: RESOLV_CONF })?;
+    }
+
+    print_json(ResetSummary {
+        confirmed: true,
+        removed: paths,
+        resolv_conf_restored: args.restore_resolv_conf,
+    })
+}
+
+fn run() -> Result<()> {
+    let args: Args = argh::from_env();
+    // Settings generators print their result as JSON to stdout, so logs go to stderr.
+    WriteLogger::init(args.log_level, LogConfig::default(), io::stderr())
+        .context(error::LoggerSnafu)?;
+    match args.subcommand {
+        SubCommand::Install(args) => install(args)?,
+        SubCommand::Remove(args) => remove(args)?,
+        SubCommand::NodeIp(args) => node_ip(args)?,
+        SubCommand::NodeGateway(_) => node_gateway()?,
+        SubCommand::NodeDomain(_) => node_domain()?,
+        SubCommand::NodeWpad(_) => node_wpad()?,
+        SubCommand::NodeMtu(args) => node_mtu(args)?,
+        SubCommand::NodeDnsSearch(_) => node_dns_search()?,
+        SubCommand::GenerateHostname(args) => generate_hostname(args)?,
+        SubCommand::SetHostname(args) => set_hostname(args)?,
+        SubCommand::WriteHosts(args) => write_hosts(args)?,
+        SubCommand::Reset(args) => reset(args)?,
+        SubCommand::GenerateNetworkd(args) => generate_networkd(args)?,
+        SubCommand::GenerateResolvedDropin(args) => generate_resolved_dropin(args)?,
+        SubCommand::TestLease(args) => test_lease(args)?,
+    }
+    Ok(())
+}
+
+// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
+// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
+// https://github.com/shepmaster/snafu/issues/110
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Potential errors during netdog execution
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    #[allow(clippy::enum_variant_names)]
+    pub(super) enum Error {
+        #[snafu(display("Failed to setup logger: {}", source))]
+        Logger { source: log::SetLoggerError },
+
+        #[snafu(display("Invalid interface name '{}'", name))]
+        InvalidInterfaceName { name: String },
+
+        #[snafu(display("{}", source))]
+        Lease { source: netdog::Error },
+
+        #[snafu(display("No lease files found in '{}'", path.display()))]
+        LeaseDirEmpty { path: PathBuf },
+
+        #[snafu(display("Failed to read lease directory '{}': {}", path.display(), source))]
+        LeaseDirReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to read resolver state in '{}': {}", path.display(), source))]
+        ResolverStateReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to parse resolver state in '{}': {}", path.display(), source))]
+        ResolverStateParseFailed {
+            path: PathBuf,
+            source: serde_json::error::Error,
+        },
+
+        #[snafu(display("Failed to write resolver state to '{}': {}", path.display(), source))]
+        ResolverStateWriteFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to build resolver configuration: {}", source))]
+        ResolvConfBuildFailed { source: std::fmt::Error },
+
+        #[snafu(display("Failed to read resolver configuration from '{}': {}", path.display(), source))]
+        ResolvConfReadFailed { path: PathBuf, source: io::Error },
 
         #[snafu(display("Failed to write resolver configuration to '{}': {}", path.display(), source))]
         ResolvConfWriteFailed { path: PathBuf, source: io::Error },
 
+        #[snafu(display("Failed to run systemctl for '{}': {}", service, source))]
+        ServiceReload { service: String, source: io::Error },
+
+        #[snafu(display("Failed to reload or restart '{}': systemctl {}", service, status))]
+        ServiceReloadFailed { service: String, status: String },
+
+        #[snafu(display("Failed to build networkd configuration: {}", source))]
+        NetworkdBuildFailed { source: std::fmt::Error },
+
+        #[snafu(display("Failed to write networkd configuration to '{}': {}", path.display(), source))]
+        NetworkdWriteFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to read DNS policy from '{}': {}", path.display(), source))]
+        DnsPolicyReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to parse DNS policy in '{}': {}", path.display(), source))]
+        DnsPolicyParseFailed {
+            path: PathBuf,
+            source: serde_json::error::Error,
+        },
+
+        #[snafu(display("DNS policy in '{}' has no entries", path.display()))]
+        DnsPolicyEmpty { path: PathBuf },
+
+        #[snafu(display("Invalid DNS policy entry for '{}': {}", domain, reason))]
+        DnsPolicyInvalid { domain: String, reason: String },
+
+        #[snafu(display("Failed to build systemd-resolved configuration: {}", source))]
+        ResolvedBuildFailed { source: std::fmt::Error },
+
+        #[snafu(display("Failed to write systemd-resolved configuration to '{}': {}", path.display(), source))]
+        ResolvedWriteFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to build test-lease output: {}", source))]
+        TestLeaseBuildFailed { source: std::fmt::Error },
+
+        #[snafu(display("Failed to write DNS rotation counter to '{}': {}", path.display(), source))]
+        DnsRotationWriteFailed { path: PathBuf, source: io::Error },
+
         #[snafu(display("Failed to write hostname to '{}': {}", path.display(), source))]
         HostnameWriteFailed { path: PathBuf, source: io::Error },
 
+        #[snafu(display("Failed to read hostname from '{}': {}", path.display(), source))]
+        HostnameReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to read hosts file '{}': {}", path.display(), source))]
+        HostsReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to write hosts file '{}': {}", path.display(), source))]
+        HostsWriteFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("The netdog block in '{}' has no end marker; fix it by hand", path.display()))]
+        HostsBlockUnclosed { path: PathBuf },
+
+        #[snafu(display("Failed to read netdog state in '{}': {}", path.display(), source))]
+        StateReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to remove netdog state '{}': {}", path.display(), source))]
+        StateRemoveFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Hostname '{}' is not a valid RFC 1123 hostname", hostname))]
+        InvalidHostname { hostname: String },
+
+        #[snafu(display("Invalid sortlist entry '{}', {}: {}", entry, reason, source))]
+        InvalidSortlistEntry {
+            entry: String,
+            reason: String,
+            source: std::net::AddrParseError,
+        },
+
+        #[snafu(display("Invalid sortlist entry '{}', netmask must be contiguous", entry))]
+        InvalidSortlistNetmask { entry: String },
+
         #[snafu(display("Invalid IP address '{}': {}", ip, source))]
         IpFromString {
             ip: String,
             source: std::net::AddrParseError,
         },
 
+        #[snafu(display("Invalid IP address and prefix '{}': {}", cidr, source))]
+        CidrFromString {
+            cidr: String,
+            source: ipnet::AddrParseError,
+        },
+
         #[snafu(display("Failed to write current IP to '{}': {}", path.display(), source))]
         CurrentIpWriteFailed { path: PathBuf, source: io::Error },
 
+        #[snafu(display(
+            "Current IP in '{}' is corrupt ({:?}); run `netdog install` again to rewrite it",
+            path.display(),
+            contents
+        ))]
+        CorruptCurrentIp { path: PathBuf, contents: String },
+
         #[snafu(display("Failed to read current IP data in '{}': {}", path.display(), source))]
         CurrentIpReadFailed { path: PathBuf, source: io::Error },
 
-        #[snafu(display("Error serializing to JSON: '{}': {}", output, source))]
-        JsonSerialize {
-            output: String,
-            source: serde_json::error::Error,
+        #[snafu(display("Failed to write current gateway to '{}': {}", path.display(), source))]
+        CurrentGatewayWriteFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to read current gateway data in '{}': {}", path.display(), source))]
+        CurrentGatewayReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to write current domain to '{}': {}", path.display(), source))]
+        CurrentDomainWriteFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to read current domain data in '{}': {}", path.display(), source))]
+        CurrentDomainReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("No domain name was received from DHCP; '{}' does not exist", path.display()))]
+        CurrentDomainMissing { path: PathBuf },
+
+        #[snafu(display("Failed to write current proxy autoconfig URL to '{}': {}", path.display(), source))]
+        CurrentWpadWriteFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to read current proxy autoconfig URL in '{}': {}", path.display(), source))]
+        CurrentWpadReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to write current MTU to '{}': {}", path.display(), source))]
+        CurrentMtuWriteFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to read current MTU in '{}': {}", path.display(), source))]
+        CurrentMtuReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Invalid MTU '{}' in '{}': {}", mtu, path.display(), source))]
+        CurrentMtuParseFailed {
+            path: PathBuf,
+            mtu: String,
+            source: std::num::ParseIntError,
         },
+
+        #[snafu(display("Failed to read interface MTU from '{}': {}", path.display(), source))]
+        InterfaceMtuReadFailed { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Invalid interface MTU '{}' in '{}': {}", mtu, path.display(), source))]
+        InterfaceMtuParseFailed {
+            path: PathBuf,
+            mtu: String,
+            source: std::num::ParseIntError,
+        },
+
+        #[snafu(display(
+            "Interface {} has MTU {}, but DHCP advertised {}",
+            interface,
+            current,
+            lease_mtu
+        ))]
+        MtuMismatch {
+            interface: String,
+            current: u32,
+            lease_mtu: u32,
+        },
+
+        #[snafu(display("No default gateway was received from DHCP; '{}' does not exist", path.display()))]
+        CurrentGatewayMissing { path: PathBuf },
+
+        #[snafu(display("Error serializing to JSON: {}", source))]
+        JsonSerialize { source: serde_json::error::Error },
     }
 }
 
 type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state(servers: &[&str], search: &[&str]) -> ResolverState {
+        ResolverState {
+            dns_servers: servers.iter().map(|s| s.parse().unwrap()).collect(),
+            dns_search: search.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_ipv6_forms() {
+        let expected: IpAddr = "fe80::1".parse().unwrap();
+        for input in &[
+            "fe80::1",
+            "fe80:0:0:0:0:0:0:1",
+            "[fe80::1]",
+            "fe80::1%eth0",
+            "[fe80::1%eth0]\n",
+        ] {
+            assert_eq!(parse_ip(input).unwrap(), expected, "parsing {}", input);
+        }
+        assert!(parse_ip("fe80::1::2").is_err());
+    }
+
+    #[test]
+    fn current_ip_trailing_whitespace() {
+        let expected: IpAddr = "10.0.0.5".parse().unwrap();
+        for contents in &["10.0.0.5", "10.0.0.5\n", "  10.0.0.5 \n\n"] {
+            assert_eq!(
+                parse_current_ip(CURRENT_IP, contents).unwrap(),
+                expected,
+                "parsing {:?}",
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn interface_ips() {
+        let path = Path::new("current_ip_cidr.ipv6");
+        let address = parse_interface_ip(
+            path,
+            "eth1",
+            InterfaceFamily::Ipv6,
+            "2001:db8::5/64\n",
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_string(&address).unwrap(),
+            r#"{"address":"2001:db8::5","family":"ipv6","interface":"eth1"}"#
+        );
+        let address =
+            parse_interface_ip(path, "eth1", InterfaceFamily::Ipv6, "2001:db8::5/64", true)
+                .unwrap();
+        assert_eq!(address.address, "2001:db8::5/64");
+
+        // The file's address has to be of the family in its name.
+        for contents in &["", "10.0.0.5/24", "2001:db8::5"] {
+            match parse_interface_ip(path, "eth1", InterfaceFamily::Ipv6, contents, false) {
+                Err(error::Error::CorruptCurrentIp { .. }) => {}
+                other => panic!("parsing {:?} gave {:?}", contents, other),
+            }
+        }
+    }
+
+    #[test]
+    fn current_ip_garbage() {
+        for contents in &["", "\n", "10.0.", "not an address", "10.0.0.5 10.0.0.6"] {
+            match parse_current_ip(CURRENT_IP, contents) {
+                Err(error::Error::CorruptCurrentIp { .. }) => {}
+                other => panic!("parsing {:?} gave {:?}", contents, other),
+            }
+        }
+    }
+
+    #[test]
+    fn canonical_ipv6() {
+        let ip = parse_ip("2001:0DB8:0000:0000:0000:0000:0000:0001").unwrap();
+        assert_eq!(ip.to_string(), "2001:db8::1");
+    }
+
+    #[test]
+    fn ipv6_label() {
+        let ip = parse_ip("2001:db8::1").unwrap();
+        let label = ip_label(&ip);
+        assert_eq!(label, "ip-2001-db8-0-0-0-0-0-1");
+        assert!(valid_hostname(&label));
+        assert!(valid_hostname(&ip_label(&parse_ip("::").unwrap())));
+        assert!(valid_hostname(&format!("{}.example.com", label)));
+    }
+
+    #[test]
+    fn ipv4_label() {
+        let ip = parse_ip("10.0.0.5").unwrap();
+        assert_eq!(ip_label(&ip), "ip-10-0-0-5");
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            reverse_dns_name(&parse_ip("10.0.0.5").unwrap()),
+            "5.0.0.10.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_dns_name(&parse_ip("2001:db8::1").unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+
+    #[test]
+    fn search_domains_limited() {
+        let search: Vec<String> = (1..=8).map(|i| format!("d{}.example.com", i)).collect();
+        let (kept, dropped) = limit_search_domains(&search, DEFAULT_MAX_SEARCH_DOMAINS);
+        assert_eq!(kept, &search[..6]);
+        // Dropped domains are what we warn about
+        assert_eq!(dropped, &["d7.example.com", "d8.example.com"]);
+    }
+
+    #[test]
+    fn short_search_list_kept() {
+        let search = vec!["example.com".to_string()];
+        let (kept, dropped) = limit_search_domains(&search, DEFAULT_MAX_SEARCH_DOMAINS);
+        assert_eq!(kept, &search[..]);
+        assert!(dropped.is_empty());
+    }
+
+    #[test]
+    fn sortlist_entries_parsed() {
+        assert_eq!(
+            SortlistEntry::from_str("130.155.160.0/255.255.240.0")
+                .unwrap()
+                .to_string(),
+            "130.155.160.0/255.255.240.0"
+        );
+        assert_eq!(
+            SortlistEntry::from_str("130.155.0.0").unwrap().to_string(),
+            "130.155.0.0"
+        );
+        for bad in &[
+            "fe80::1",
+            "10.0.0.0/24",
+            "10.0.0.0/255.0.255.0",
+            "10.0.0.0/",
+        ] {
+            assert!(SortlistEntry::from_str(bad).is_err(), "parsing {}", bad);
+        }
+    }
+
+    #[test]
+    fn sortlist_from_lease_subnet() {
+        let entry = SortlistEntry::for_subnet(&"10.0.3.5/22".parse().unwrap()).unwrap();
+        assert_eq!(entry.to_string(), "10.0.0.0/255.255.252.0");
+        assert!(SortlistEntry::for_subnet(&"2001:db8::1/64".parse().unwrap()).is_none());
+    }
+
+    fn lease(ip: &str, servers: &[&str], domain: Option<&str>, search: &[&str]) -> LeaseInfo {
+        LeaseInfo {
+            ip_address: ip.parse().unwrap(),
+            dns_servers: servers.iter().map(|s| s.parse().unwrap()).collect(),
+            dns_domain: domain.map(|d| d.to_string()),
+            dns_search: Some(search.iter().map(|s| s.to_string()).collect()),
+            gateway: None,
+            wpad: None,
+            mtu: None,
+        }
+    }
+
+    #[test]
+    fn merge_lease_files() {
+        let merged = merge_leases(vec![
+            lease("10.0.0.5/24", &["10.0.0.2"], None, &["a.example.com"]),
+            lease(
+                "10.1.0.5/24",
+                &["10.1.0.2", "10.0.0.2"],
+                Some("one.example.com"),
+                &["b.example.com", "a.example.com"],
+            ),
+            lease("10.2.0.5/24", &[], Some("two.example.com"), &[]),
+        ])
+        .unwrap();
+        // The first lease is primary, but the domain comes from the first that has one
+        assert_eq!(merged.ip_address, "10.0.0.5/24".parse::<IpNet>().unwrap());
+        assert_eq!(merged.dns_domain.as_deref(), Some("one.example.com"));
+        assert_eq!(merged.dns_servers.len(), 2);
+        assert_eq!(
+            merged.dns_search.unwrap(),
+            vec!["a.example.com", "b.example.com"]
+        );
+        assert!(merge_leases(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn merge_two_interfaces() {
+        let mut states = BTreeMap::new();
+        states.insert(
+            "eth1".to_string(),
+            state(
+                &["10.0.1.2", "10.0.0.2"],
+                &["eth1.example.com", "example.com"],
+            ),
+        );
+        states.insert(
+            "eth0".to_string(),
+            state(
+                &["10.0.0.2", "10.0.0.3"],
+                &["eth0.example.com", "example.com"],
+            ),
+        );
+
+        let merged = merge_resolver_states(&states);
+        assert_eq!(
+            merged,
+            state(
+                &["10.0.0.2", "10.0.0.3", "10.0.1.2"],
+                &["eth0.example.com", "example.com", "eth1.example.com"]
+            )
+        );
+    }
+
+    #[test]
+    fn test_lease_prints_files() {
+        let info = LeaseInfo {
+            ip_address: "10.0.0.5/24".parse().unwrap(),
+            dns_servers: ["10.0.0.3", "10.0.0.2"]
+                .iter()
+                .map(|s| s.parse().unwrap())
+                .collect(),
+            dns_domain: Some("example.com".to_string()),
+            dns_search: Some(vec![
+                "a.example.com".to_string(),
+                "b.example.com".to_string(),
+            ]),
+            gateway: Some("10.0.0.1".parse().unwrap()),
+            wpad: Some("http://wpad.example.com/wpad.dat".to_string()),
+            mtu: Some(9001),
+        };
+        assert_eq!(
+            test_lease_output(&info, 1, &[]).unwrap(),
+            format!(
+                "# {}\nsearch a.example.com\nnameserver 10.0.0.2\nnameserver 10.0.0.3\n\
+                 # {}\n10.0.0.5\n# {}\n10.0.0.5/24\n# {}\n10.0.0.1\n# {}\nexample.com\n\
+                 # {}\nhttp://wpad.example.com/wpad.dat\n# {}\n9001\n",
+                RESOLV_CONF,
+                CURRENT_IP,
+                CURRENT_IP_CIDR,
+                CURRENT_GATEWAY,
+                CURRENT_DOMAIN,
+                CURRENT_WPAD,
+                CURRENT_MTU
+            )
+        );
+    }
+
+    #[test]
+    fn generated_hostname_object() {
+        let generated = GeneratedHostname {
+            hostname: "ip-10-0-0-5.example.com".to_string(),
+            source: HostnameSource::Ip,
+            ip: "10.0.0.5".parse().unwrap(),
+        };
+        assert_eq!(
+            serde_json::to_string(&generated).unwrap(),
+            r#"{"hostname":"ip-10-0-0-5.example.com","source":"ip","ip":"10.0.0.5"}"#
+        );
+        assert_eq!(
+            "object".parse::<HostnameFormat>().unwrap(),
+            HostnameFormat::Object
+        );
+    }
+
+    #[test]
+    fn hostname_cache_expires() {
+        let entry = HostnameCacheEntry {
+            ip: "10.0.0.5".parse().unwrap(),
+            hostname: "node.example.com".to_string(),
+            resolved_at: 1000,
+        };
+        let ip = "10.0.0.5".parse().unwrap();
+        assert_eq!(entry.lookup(&ip, 60, 1059), Some("node.example.com"));
+        assert_eq!(entry.lookup(&ip, 60, 1060), None);
+        assert_eq!(entry.lookup(&"10.0.0.6".parse().unwrap(), 60, 1000), None);
+    }
+
+    #[test]
+    fn resolved_dropin_from_policy() {
+        let policy = parse_dns_policy(
+            Path::new("policy.json"),
+            r#"[
+                {"domain": "Corp.Example.com.", "servers": ["10.0.0.2", "10.0.0.3"]},
+                {"domain": "lab.internal", "servers": ["10.0.0.3", "fd00::53"]}
+            ]"#,
+        )
+        .unwrap();
+        let info = lease("10.0.0.5/24", &["10.0.0.1"], None, &[]);
+        assert_eq!(
+            resolved_dropin(&info, &policy).unwrap(),
+            "[Resolve]\nDNS=10.0.0.2 10.0.0.3 fd00::53\nDomains=~corp.example.com ~lab.internal\n"
+        );
+    }
+
+    #[test]
+    fn dns_policy_invalid() {
+        for data in &[
+            r#"[]"#,
+            r#"{"domain": "corp", "servers": ["10.0.0.2"]}"#,
+            r#"[{"domain": "corp", "servers": ["10.0.0.2"], "extra": 1}]"#,
+            r#"[{"domain": "corp", "servers": ["not an address"]}]"#,
+            r#"[{"domain": "-corp", "servers": ["10.0.0.2"]}]"#,
+            r#"[{"domain": "corp", "servers": []}]"#,
+            r#"[{"domain": "corp", "servers": ["0.0.0.0"]}]"#,
+            r#"[{"domain": "corp", "servers": ["10.0.0.2", "10.0.0.2"]}]"#,
+            r#"[{"domain": "corp", "servers": ["10.0.0.2"]},
+                {"domain": "CORP.", "servers": ["10.0.0.3"]}]"#,
+        ] {
+            assert!(
+                parse_dns_policy(Path::new("policy.json"), data).is_err(),
+                "parsing {}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn lookup_retries() {
+        let fail = |_| Err::<(), _>(io::Error::new(io::ErrorKind::TimedOut, "no answer"));
+        let mut attempts = Vec::new();
+        assert!(with_retries(2, Duration::from_millis(0), |attempt| {
+            attempts.push(attempt);
+            fail(attempt)
+        })
+        .is_err());
+        assert_eq!(attempts, [1, 2, 3]);
+
+        // Retrying stops at the first success, and no retries means one attempt.
+        let result = with_retries(5, Duration::from_millis(0), |attempt| match attempt {
+            2 => Ok(attempt),
+            _ => fail(attempt).map(|_| 0),
+        });
+        assert_eq!(result.unwrap(), 2);
+        let mut attempts = 0;
+        assert!(with_retries(0, Duration::from_millis(0), |attempt| {
+            attempts += 1;
+            fail(attempt)
+        })
+        .is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn hosts_block_replaced() {
+        let lines = vec!["10.0.0.5 node.example.com node".to_string()];
+        let block = "# BEGIN netdog managed block\n10.0.0.5 node.example.com node\n\
+                     # END netdog managed block\n";
+
+        assert_eq!(hosts_with_block("", &lines).unwrap(), block);
+        let existing = "127.0.0.1 localhost\n192.168.0.9 db # mine";
+        let updated = hosts_with_block(existing, &lines).unwrap();
+        assert_eq!(
+            updated,
+            format!("127.0.0.1 localhost\n192.168.0.9 db # mine\n{}", block)
+        );
+
+        // An earlier block is replaced in place, keeping the lines around it.
+        let existing = "127.0.0.1 localhost\n# BEGIN netdog managed block\n10.0.0.4 old\n\
+                        # END netdog managed block\n::1 localhost\n";
+        assert_eq!(
+            hosts_with_block(existing, &lines).unwrap(),
+            format!("127.0.0.1 localhost\n{}::1 localhost\n", block)
+        );
+        assert_eq!(hosts_with_block(&updated, &lines).unwrap(), updated);
+
+        let unclosed = "127.0.0.1 localhost\n# BEGIN netdog managed block\n10.0.0.4 old\n";
+        assert!(hosts_with_block(unclosed, &lines).is_none());
+    }
+
+    #[test]
+    fn reset_without_state_dir() {
+        // A node that never got a lease has no state directory, which isn't an error.
+        let paths = state_entries(Path::new("/nonexistent/var/lib/netdog")).unwrap();
+        assert!(paths.is_empty());
+        assert!(!MINIMAL_RESOLV_CONF.contains("nameserver"));
+    }
+
+    #[test]
+    fn interface_names() {
+        for name in &["eth0", "ens5", "enp0s31f6", "eth0.100"] {
+            assert_eq!(name.parse::<InterfaceName>().unwrap().to_string(), *name);
+        }
+        for name in &[
+            "",
+            ".",
+            "..",
+            "../eth0",
+            "eth 0",
+            "eth0:1",
+            "averylonginterface",
+        ] {
+            assert!(name.parse::<InterfaceName>().is_err(), "parsing {:?}", name);
+        }
+    }
+
+    #[test]
+    fn record_two_interfaces() {
+        let state_dir = tempfile::tempdir().unwrap();
+        let eth0 = InterfaceName::primary();
+        let eth1: InterfaceName = "eth1".parse().unwrap();
+        let v4 = lease("10.0.0.5/24", &["10.0.0.2"], None, &["a.example.com"]);
+        let v6 = lease("2001:db8::5/64", &["2001:db8::2"], None, &["b.example.com"]);
+
+        let (changed, _) =
+            record_interface(state_dir.path(), &eth0, &InterfaceFamily::Ipv4, &v4, true).unwrap();
+        assert!(changed);
+        let (changed, merged) =
+            record_interface(state_dir.path(), &eth1, &InterfaceFamily::Ipv6, &v6, true).unwrap();
+        assert!(changed);
+        assert_eq!(
+            merged.unwrap(),
+            state(
+                &["10.0.0.2", "2001:db8::2"],
+                &["a.example.com", "b.example.com"]
+            )
+        );
+
+        // Renewing the same lease changes nothing.
+        let (changed, _) =
+            record_interface(state_dir.path(), &eth1, &InterfaceFamily::Ipv6, &v6, true).unwrap();
+        assert!(!changed);
+
+        let addresses = read_interface_ips(state_dir.path(), true).unwrap();
+        let listed: Vec<_> = addresses
+            .iter()
+            .map(|a| (a.interface.as_str(), a.address.as_str()))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![("eth0", "10.0.0.5/24"), ("eth1", "2001:db8::5/64")]
+        );
+
+        // Entries that aren't interfaces, or whose address doesn't parse, are left out.
+        fs::create_dir(state_dir.path().join("not an interface")).unwrap();
+        fs::create_dir(state_dir.path().join("eth2")).unwrap();
+        fs::write(
+            interface_ip_path(state_dir.path(), "eth2", &InterfaceFamily::Ipv4),
+            "garbage",
+        )
+        .unwrap();
+        let addresses = read_interface_ips(state_dir.path(), false).unwrap();
+        let listed: Vec<_> = addresses.iter().map(|a| a.interface.as_str()).collect();
+        assert_eq!(listed, vec!["eth0", "eth1"]);
+    }
+
+    #[test]
+    fn set_hostname_defaults_to_transient() {
+        let args = SetHostnameArgs::from_args(&["set-hostname"], &["node"]).unwrap();
+        assert_eq!(args.mode, HostnameMode::Transient);
+        let args =
+            SetHostnameArgs::from_args(&["set-hostname"], &["--mode", "both", "node"]).unwrap();
+        assert_eq!(args.mode, HostnameMode::Both);
+    }
+
+    #[test]
+    fn gateway_removed_without_lease_gateway() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("current_gateway");
+        let gateway: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert!(write_gateway_file(&path, Some(&gateway)).unwrap());
+        assert!(!write_gateway_file(&path, Some(&gateway)).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "10.0.0.1");
+
+        // A lease without a gateway removes the old one, and then there's nothing to change.
+        assert!(write_gateway_file(&path, None).unwrap());
+        assert!(!path.exists());
+        assert!(!write_gateway_file(&path, None).unwrap());
+    }
+}
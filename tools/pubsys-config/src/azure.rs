@@ -0,0 +1,20 @@
+//! The azure module owns the definition of our Azure configuration sources.
+use serde::{Deserialize, Serialize};
+
+/// Azure-specific infrastructure configuration, describing the Shared Image Gallery that images
+/// are published to and the storage account VHDs are uploaded to on the way.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AzureConfig {
+    pub subscription_id: Option<String>,
+    pub resource_group: Option<String>,
+    pub gallery: Option<String>,
+    pub image_definition: Option<String>,
+    pub storage_account: Option<String>,
+    pub storage_container: Option<String>,
+    /// Resource group of the storage account, if it's not the gallery's resource group
+    pub storage_resource_group: Option<String>,
+    /// Regions to replicate image versions to; if empty, Azure only uses the gallery's region
+    #[serde(default)]
+    pub target_regions: Vec<String>,
+}
@@ -1,17 +1,20 @@
 //! The config module owns the definition and loading process for our configuration sources.
+pub mod azure;
 pub mod vmware;
 
+use crate::azure::AzureConfig;
 use crate::vmware::VmwareConfig;
 use chrono::Duration;
 use log::info;
 use parse_datetime::parse_offset;
 use serde::{Deserialize, Deserializer, Serialize};
-use snafu::{OptionExt, ResultExt};
+use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::fs;
-use std::num::NonZeroUsize;
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use url::Url;
 
 /// Configuration needed to load and create repos
@@ -26,6 +29,9 @@ pub struct InfraConfig {
 
     // Config for VMware specific subcommands
     pub vmware: Option<VmwareConfig>,
+
+    // Config for Azure specific subcommands
+    pub azure: Option<AzureConfig>,
 }
 
 impl InfraConfig {
@@ -65,15 +71,15 @@ impl InfraConfig {
     /// Deserializes an InfraConfig from Infra.lock, if it exists, otherwise uses Infra.toml
     /// If the default flag is true, will create a default config if Infra.toml doesn't exist
     pub fn from_path_or_lock(path: &Path, default: bool) -> Result<Self> {
-        let lock_path = Self::compute_lock_path(&path)?;
+        let lock_path = Self::compute_lock_path(path)?;
         if lock_path.exists() {
             info!("Found infra config at path: {}", lock_path.display());
             Self::from_lock_path(lock_path)
         } else if default {
-            Self::from_path_or_default(&path)
+            Self::from_path_or_default(path)
         } else {
             info!("Found infra config at path: {}", path.display());
-            Self::from_path(&path)
+            Self::from_path(path)
         }
     }
 
@@ -102,6 +108,154 @@ impl InfraConfig {
             })?
             .join("Infra.lock"))
     }
+
+    /// Applies the given overrides to the config, in order.  Each value takes the type of the
+    /// value it replaces; values for keys the config doesn't set are read as TOML, falling back
+    /// to a string, so numbers and lists work too.  Keys the config doesn't know are errors.
+    pub fn with_overrides(self, overrides: &[ConfigOverride]) -> Result<Self> {
+        if overrides.is_empty() {
+            return Ok(self);
+        }
+        let mut root = toml::Value::try_from(&self).context(error::SerializeSnafu)?;
+        for config_override in overrides {
+            info!("Overriding {} in the infra config", config_override.key);
+            let existing = config_override.lookup(&mut root)?;
+            let coerced = config_override.coerce(existing.as_ref())?;
+            config_override.set(&mut root, coerced.clone())?;
+            if let Err(e) = root.clone().try_into::<Self>() {
+                // A value we guessed the type of may still be meant as a string, like an
+                // account ID.
+                let as_string = toml::Value::String(config_override.value.clone());
+                if existing.is_some() || coerced == as_string {
+                    return Err(e).context(error::OverrideSnafu {
+                        key: &config_override.key,
+                    });
+                }
+                config_override.set(&mut root, as_string)?;
+                root.clone()
+                    .try_into::<Self>()
+                    .context(error::OverrideSnafu {
+                        key: &config_override.key,
+                    })?;
+            }
+        }
+        root.try_into().context(error::OverrideSnafu {
+            key: &overrides[overrides.len() - 1].key,
+        })
+    }
+}
+
+/// A replacement value for one key in the infra config, given like "aws.ssm_prefix=/test", with
+/// dots separating the names of nested tables
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigOverride {
+    pub key: String,
+    pub value: String,
+}
+
+impl ConfigOverride {
+    /// Returns the table holding our key, creating any tables that aren't there yet
+    fn table<'a>(&self, root: &'a mut toml::Value) -> Result<&'a mut toml::value::Table> {
+        let mut table = root.as_table_mut().context(error::OverrideTableSnafu {
+            key: &self.key,
+            table: "",
+        })?;
+        let mut names: Vec<&str> = self.key.split('.').collect();
+        names.pop();
+        for (i, name) in names.iter().enumerate() {
+            table = table
+                .entry(name.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+                .as_table_mut()
+                .context(error::OverrideTableSnafu {
+                    key: &self.key,
+                    table: names[..=i].join("."),
+                })?;
+        }
+        Ok(table)
+    }
+
+    /// The last name in the key, which we set in its table
+    fn name(&self) -> &str {
+        self.key.rsplit('.').next().unwrap_or(&self.key)
+    }
+
+    fn lookup(&self, root: &mut toml::Value) -> Result<Option<toml::Value>> {
+        Ok(self.table(root)?.get(self.name()).cloned())
+    }
+
+    fn set(&self, root: &mut toml::Value, value: toml::Value) -> Result<()> {
+        self.table(root)?.insert(self.name().to_string(), value);
+        Ok(())
+    }
+
+    /// Converts our value to the type of the value it replaces, if any
+    fn coerce(&self, existing: Option<&toml::Value>) -> Result<toml::Value> {
+        let raw = &self.value;
+        let mismatch = |expected: &str| {
+            error::OverrideTypeSnafu {
+                key: &self.key,
+                value: raw,
+                expected,
+            }
+            .fail()
+        };
+        match existing {
+            Some(toml::Value::String(_)) => Ok(toml::Value::String(raw.clone())),
+            Some(toml::Value::Integer(_)) => match raw.parse() {
+                Ok(i) => Ok(toml::Value::Integer(i)),
+                Err(_) => mismatch("an integer"),
+            },
+            Some(toml::Value::Float(_)) => match raw.parse() {
+                Ok(f) => Ok(toml::Value::Float(f)),
+                Err(_) => mismatch("a number"),
+            },
+            Some(toml::Value::Boolean(_)) => match raw.parse() {
+                Ok(b) => Ok(toml::Value::Boolean(b)),
+                Err(_) => mismatch("true or false"),
+            },
+            // Lists can be given in TOML, or as comma-separated strings like regions usually are.
+            Some(toml::Value::Array(_)) => match parse_toml_value(raw) {
+                Some(array @ toml::Value::Array(_)) => Ok(array),
+                _ => Ok(toml::Value::Array(
+                    raw.split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(|s| toml::Value::String(s.to_string()))
+                        .collect(),
+                )),
+            },
+            Some(toml::Value::Table(_)) => mismatch("one of the keys in this table"),
+            Some(toml::Value::Datetime(_)) | None => {
+                Ok(parse_toml_value(raw).unwrap_or_else(|| toml::Value::String(raw.clone())))
+            }
+        }
+    }
+}
+
+impl FromStr for ConfigOverride {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        let (key, value) = input
+            .split_once('=')
+            .context(error::ParseOverrideSnafu { input })?;
+        let key = key.trim();
+        ensure!(
+            !key.split('.').any(str::is_empty),
+            error::ParseOverrideSnafu { input }
+        );
+        Ok(Self {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Parses the given string as a TOML value, like `5`, `true`, or `["a", "b"]`
+fn parse_toml_value(input: &str) -> Option<toml::Value> {
+    let mut table: toml::value::Table = toml::from_str(&format!("value = {}", input)).ok()?;
+    table.remove("value")
 }
 
 /// S3-specific TUF infrastructure configuration
@@ -126,7 +280,148 @@ pub struct AwsConfig {
     #[serde(default)]
     pub region: HashMap<String, AwsRegionConfig>,
     pub ssm_prefix: Option<String>,
+    pub ssm: Option<SsmConfig>,
     pub s3: Option<HashMap<String, S3Config>>,
+    pub ami: Option<AmiConfig>,
+    /// A role every client assumes, using the base credentials, before `role` and the regional
+    /// roles; it comes from the command line rather than Infra.toml
+    #[serde(skip)]
+    pub base_role: Option<AssumeRoleConfig>,
+}
+
+/// A role to assume, and the parameters to give STS when assuming it
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssumeRoleConfig {
+    pub arn: String,
+    pub external_id: Option<String>,
+    pub session_name: String,
+}
+
+/// AMI registration configuration
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AmiConfig {
+    /// Device name of the root volume, e.g. "/dev/xvda"
+    pub root_device_name: Option<String>,
+    /// EBS volume type of the root (and data) volume, e.g. "gp3"
+    pub volume_type: Option<String>,
+    /// Provisioned IOPS of the root (and data) volume
+    pub iops: Option<i64>,
+    /// Provisioned throughput of the root (and data) volume, in MiB/s; gp3 only
+    pub throughput: Option<i64>,
+    /// Additional block device mappings to register with the AMI
+    #[serde(default)]
+    pub block_devices: Vec<BlockDeviceConfig>,
+    /// Template for AMI descriptions, like "Bottlerocket {variant} {version}"
+    pub description_template: Option<String>,
+}
+
+/// An additional block device mapping for registered AMIs: either an instance store volume, given
+/// by `virtual_name`, or an empty EBS volume
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BlockDeviceConfig {
+    pub device_name: String,
+    /// Instance store volume name, like "ephemeral0"
+    pub virtual_name: Option<String>,
+    /// Size of the EBS volume in gibibytes
+    pub volume_size: Option<i64>,
+    pub volume_type: Option<String>,
+    pub iops: Option<i64>,
+    pub throughput: Option<i64>,
+}
+
+/// SSM-specific configuration
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SsmConfig {
+    /// Path under which parameters are published; parameter names from the template file are
+    /// placed below it
+    pub path_template: Option<SsmPathTemplate>,
+    /// Maximum PutParameter requests per second in each region
+    pub put_rate_limit: Option<NonZeroU32>,
+}
+
+/// A template for SSM parameter paths, like "/my/org/{variant}/{arch}/{version}".  Placeholders
+/// are checked when the config is parsed, so we know it can be rendered.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct SsmPathTemplate(String);
+
+impl SsmPathTemplate {
+    /// Placeholders the template may contain
+    const KNOWN_PLACEHOLDERS: &'static [&'static str] = &["variant", "arch", "version", "region"];
+    /// Placeholders the template must contain, so that parameters from different builds don't
+    /// collide
+    const REQUIRED_PLACEHOLDERS: &'static [&'static str] = &["variant", "arch", "version"];
+
+    /// Returns the names of the placeholders in the given template, in order
+    fn placeholders(template: &str) -> Result<Vec<&str>> {
+        let mut placeholders = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find(&['{', '}'][..]) {
+            ensure!(
+                rest[start..].starts_with('{'),
+                error::SsmTemplateSnafu {
+                    template,
+                    reason: "found '}' without matching '{'",
+                }
+            );
+            let end = rest[start..].find('}').context(error::SsmTemplateSnafu {
+                template,
+                reason: "found '{' without matching '}'",
+            })? + start;
+            placeholders.push(&rest[start + 1..end]);
+            rest = &rest[end + 1..];
+        }
+        Ok(placeholders)
+    }
+
+    /// Renders the template with the given values
+    pub fn render(&self, variant: &str, arch: &str, version: &str, region: &str) -> String {
+        self.0
+            .replace("{variant}", variant)
+            .replace("{arch}", arch)
+            .replace("{version}", version)
+            .replace("{region}", region)
+    }
+}
+
+impl TryFrom<String> for SsmPathTemplate {
+    type Error = Error;
+
+    fn try_from(template: String) -> Result<Self> {
+        let placeholders = Self::placeholders(&template)?;
+        for placeholder in &placeholders {
+            ensure!(
+                Self::KNOWN_PLACEHOLDERS.contains(placeholder),
+                error::SsmTemplateSnafu {
+                    template: &template,
+                    reason: format!(
+                        "unknown placeholder '{{{}}}', expected one of: {}",
+                        placeholder,
+                        Self::KNOWN_PLACEHOLDERS.join(", ")
+                    ),
+                }
+            );
+        }
+        for required in Self::REQUIRED_PLACEHOLDERS {
+            ensure!(
+                placeholders.contains(required),
+                error::SsmTemplateSnafu {
+                    template: &template,
+                    reason: format!("missing required placeholder '{{{}}}'", required),
+                }
+            );
+        }
+        Ok(Self(template))
+    }
+}
+
+impl From<SsmPathTemplate> for String {
+    fn from(template: SsmPathTemplate) -> Self {
+        template.0
+    }
 }
 
 /// AWS region-specific configuration
@@ -272,9 +567,146 @@ mod error {
         #[snafu(display("Missing config: {}", what))]
         MissingConfig { what: String },
 
+        #[snafu(display("Invalid config override for '{}': {}", key, source))]
+        Override {
+            key: String,
+            source: toml::de::Error,
+        },
+
+        #[snafu(display(
+            "Can't override '{}', '{}' in the infra config isn't a table",
+            key,
+            table
+        ))]
+        OverrideTable { key: String, table: String },
+
+        #[snafu(display("Can't override '{}' with '{}', expected {}", key, value, expected))]
+        OverrideType {
+            key: String,
+            value: String,
+            expected: String,
+        },
+
         #[snafu(display("Failed to get parent of path: {}", path.display()))]
         Parent { path: PathBuf },
+
+        #[snafu(display("Invalid config override '{}', expected dotted.key=value", input))]
+        ParseOverride { input: String },
+
+        #[snafu(display("Failed to serialize infra config: {}", source))]
+        Serialize { source: toml::ser::Error },
+
+        #[snafu(display("Invalid SSM path template '{}': {}", template, reason))]
+        SsmTemplate { template: String, reason: String },
     }
 }
 pub use error::Error;
 pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{ConfigOverride, InfraConfig, SsmConfig, SsmPathTemplate};
+    use std::convert::TryFrom;
+
+    fn overridden(config: &str, overrides: &[&str]) -> super::Result<InfraConfig> {
+        let config: InfraConfig = toml::from_str(config).unwrap();
+        let overrides: Vec<ConfigOverride> = overrides.iter().map(|o| o.parse().unwrap()).collect();
+        config.with_overrides(&overrides)
+    }
+
+    #[test]
+    fn overrides_take_existing_types() {
+        let config = overridden(
+            "[aws]\nregions = [\"us-west-2\"]\n[aws.ssm]\nput_rate_limit = 3\n",
+            &[
+                "aws.regions=us-east-1, us-east-2",
+                "aws.ssm.put_rate_limit=10",
+                "aws.ssm_prefix=/test",
+            ],
+        )
+        .unwrap();
+        let aws = config.aws.unwrap();
+        assert_eq!(aws.regions, vec!["us-east-1", "us-east-2"]);
+        assert_eq!(aws.ssm.unwrap().put_rate_limit.unwrap().get(), 10);
+        assert_eq!(aws.ssm_prefix.unwrap(), "/test");
+    }
+
+    #[test]
+    fn overrides_guess_new_types() {
+        // Both the number and the number-like string end up with the type the config needs.
+        let config = overridden(
+            "",
+            &[
+                "aws.ssm.put_rate_limit=5",
+                "aws.region.us-west-2.endpoint=https://example.com",
+                "aws.ami.iops=3000",
+                "aws.role=123456789012",
+            ],
+        )
+        .unwrap();
+        let aws = config.aws.unwrap();
+        assert_eq!(aws.ssm.unwrap().put_rate_limit.unwrap().get(), 5);
+        assert_eq!(aws.ami.unwrap().iops, Some(3000));
+        assert_eq!(aws.role.unwrap(), "123456789012");
+    }
+
+    #[test]
+    fn bad_overrides() {
+        assert!(overridden("", &["aws.no_such_key=1"]).is_err());
+        assert!(overridden(
+            "[aws.ssm]\nput_rate_limit = 3\n",
+            &["aws.ssm.put_rate_limit=x"]
+        )
+        .is_err());
+        assert!(overridden("[aws]\nrole = \"r\"\n", &["aws.role.name=x"]).is_err());
+        assert!("aws..role=x".parse::<ConfigOverride>().is_err());
+        assert!("aws.role".parse::<ConfigOverride>().is_err());
+    }
+
+    fn ssm_template(template: &str) -> super::Result<SsmPathTemplate> {
+        SsmPathTemplate::try_from(template.to_string())
+    }
+
+    #[test]
+    fn ssm_template_renders_every_placeholder() {
+        let template = ssm_template("/org/{region}/{variant}/{arch}/{version}/{arch}").unwrap();
+        assert_eq!(
+            template.render("aws-k8s-1.24", "x86_64", "1.2.3", "us-west-2"),
+            "/org/us-west-2/aws-k8s-1.24/x86_64/1.2.3/x86_64"
+        );
+
+        // Templates from Infra.toml are checked the same way.
+        let config: SsmConfig =
+            toml::from_str("path_template = \"/org/{variant}/{arch}/{version}\"\n").unwrap();
+        assert_eq!(
+            config.path_template.unwrap().render("v", "a", "1", "r"),
+            "/org/v/a/1"
+        );
+        assert!(toml::from_str::<SsmConfig>("path_template = \"/org/{variant}\"\n").is_err());
+    }
+
+    #[test]
+    fn ssm_template_rejects_bad_placeholders() {
+        for (template, reason) in &[
+            (
+                "/org/{variant}/{arch}/{version}/{build}",
+                "unknown placeholder '{build}'",
+            ),
+            (
+                "/org/{variant}/{arch}",
+                "missing required placeholder '{version}'",
+            ),
+            (
+                "/org/{variant}/{arch}/{version",
+                "found '{' without matching '}'",
+            ),
+            (
+                "/org/{variant}/{arch}/version}",
+                "found '}' without matching '{'",
+            ),
+        ] {
+            let err = ssm_template(template).unwrap_err().to_string();
+            assert!(err.contains(reason), "{}: {}", template, err);
+        }
+    }
+}
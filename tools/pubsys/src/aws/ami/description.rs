@@ -0,0 +1,165 @@
+//! The description module renders AMI description templates, so AMIs from every release get
+//! descriptions in the same form.
+
+use snafu::{ensure, OptionExt};
+
+/// The description template used when registering an AMI without one, matching the default of
+/// describing AMIs by their name
+pub(crate) const DEFAULT_TEMPLATE: &str = "{name}";
+
+/// The longest description EC2 accepts
+const MAX_LENGTH: usize = 255;
+
+/// Placeholders a template may contain, and the argument that gives each one's value
+const PLACEHOLDERS: &[(&str, &str)] = &[
+    ("name", "--name"),
+    ("arch", "--arch"),
+    ("variant", "--variant"),
+    ("version", "--image-version"),
+    ("commit", "--commit"),
+];
+
+/// The build details that fill in description templates
+pub(crate) struct DescriptionContext<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) arch: &'a str,
+    pub(crate) variant: Option<&'a str>,
+    pub(crate) version: Option<&'a str>,
+    pub(crate) commit: Option<&'a str>,
+}
+
+impl DescriptionContext<'_> {
+    fn value(&self, placeholder: &str) -> Option<&str> {
+        match placeholder {
+            "name" => Some(self.name),
+            "arch" => Some(self.arch),
+            "variant" => self.variant,
+            "version" => self.version,
+            "commit" => self.commit,
+            _ => None,
+        }
+    }
+}
+
+/// Renders the template, replacing each placeholder like `{version}` with its value.  Unknown
+/// placeholders, placeholders we have no value for, and descriptions longer than EC2 allows are
+/// errors, so we find out before registering anything.
+pub(crate) fn render(template: &str, context: &DescriptionContext<'_>) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find(&['{', '}'][..]) {
+        ensure!(
+            rest[start..].starts_with('{'),
+            error::TemplateSnafu {
+                template,
+                reason: "found '}' without matching '{'",
+            }
+        );
+        let end = rest[start..].find('}').context(error::TemplateSnafu {
+            template,
+            reason: "found '{' without matching '}'",
+        })? + start;
+        let placeholder = &rest[start + 1..end];
+        let (_, arg) = PLACEHOLDERS
+            .iter()
+            .find(|(known, _)| *known == placeholder)
+            .context(error::TemplateSnafu {
+                template,
+                reason: format!(
+                    "unknown placeholder '{{{}}}', expected one of: {}",
+                    placeholder,
+                    PLACEHOLDERS
+                        .iter()
+                        .map(|(known, _)| *known)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            })?;
+        let value = context.value(placeholder).context(error::TemplateSnafu {
+            template,
+            reason: format!("'{{{}}}' needs {}", placeholder, arg),
+        })?;
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(value);
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    check_length(&rendered)?;
+    Ok(rendered)
+}
+
+/// Makes sure EC2 will accept the description's length
+pub(crate) fn check_length(description: &str) -> Result<()> {
+    ensure!(
+        description.chars().count() <= MAX_LENGTH,
+        error::TooLongSnafu {
+            description,
+            length: description.chars().count(),
+            max: MAX_LENGTH,
+        }
+    );
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Invalid AMI description template '{}': {}", template, reason))]
+        Template { template: String, reason: String },
+
+        #[snafu(display(
+            "AMI description is {} characters, more than EC2's limit of {}: {}",
+            length,
+            max,
+            description
+        ))]
+        TooLong {
+            description: String,
+            length: usize,
+            max: usize,
+        },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{render, DescriptionContext, DEFAULT_TEMPLATE};
+
+    fn context() -> DescriptionContext<'static> {
+        DescriptionContext {
+            name: "bottlerocket-aws-k8s-1.21-x86_64-v1.5.0-abcd1234",
+            arch: "x86_64",
+            variant: Some("aws-k8s-1.21"),
+            version: Some("1.5.0"),
+            commit: None,
+        }
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        assert_eq!(
+            render(DEFAULT_TEMPLATE, &context()).unwrap(),
+            "bottlerocket-aws-k8s-1.21-x86_64-v1.5.0-abcd1234"
+        );
+        assert_eq!(
+            render("Bottlerocket {variant} {version} ({arch})", &context()).unwrap(),
+            "Bottlerocket aws-k8s-1.21 1.5.0 (x86_64)"
+        );
+    }
+
+    #[test]
+    fn bad_templates() {
+        // No value given for the commit
+        assert!(render("{version}-{commit}", &context()).is_err());
+        assert!(render("{unknown}", &context()).is_err());
+        assert!(render("{version", &context()).is_err());
+        assert!(render("version}", &context()).is_err());
+        assert!(render(&"x".repeat(256), &context()).is_err());
+        assert!(render(&"x".repeat(255), &context()).is_ok());
+    }
+}
@@ -1,39 +1,99 @@
 //! The ami module owns the 'ami' subcommand and controls the process of registering and copying
 //! EC2 AMIs.
 
+mod description;
 mod register;
 mod snapshot;
 pub(crate) mod wait;
 
 use crate::aws::publish_ami::{get_snapshots, modify_image, modify_snapshots};
-use crate::aws::{client::build_client, parse_arch, region_from_string};
-use crate::Args;
+use crate::aws::{
+    client::{build_client, request_tags},
+    exclude_regions, parse_arch, parse_boot_mode, region_from_string, regions_from_file,
+    RegionAccount,
+};
+use crate::{normalize_version, output, progress, Args};
+use description::DescriptionContext;
 use futures::future::{join, lazy, ready, FutureExt};
 use futures::stream::{self, StreamExt};
 use log::{error, info, trace, warn};
-use pubsys_config::{AwsConfig, InfraConfig};
-use register::{get_ami_id, register_image, RegisteredIds};
+use pubsys_config::AwsConfig;
+use register::{deregister_image, get_ami_id, register_image, NewImage, RegisteredIds};
 use rusoto_core::{Region, RusotoError};
 use rusoto_ebs::EbsClient;
-use rusoto_ec2::{CopyImageError, CopyImageRequest, CopyImageResult, Ec2, Ec2Client};
+use rusoto_ec2::{
+    CopyImageError, CopyImageRequest, CopyImageResult, CreateTagsRequest, DescribeImagesRequest,
+    Ec2, Ec2Client, Tag,
+};
 use rusoto_sts::{
     GetCallerIdentityError, GetCallerIdentityRequest, GetCallerIdentityResponse, Sts, StsClient,
 };
 use serde::{Deserialize, Serialize};
-use snafu::{ensure, OptionExt, ResultExt};
+use snafu::{ensure, IntoError, OptionExt, ResultExt};
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 use wait::wait_for_ami;
 
+/// What to do when an AMI with the requested name already exists in a region
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OnExists {
+    /// Use the existing AMI, so publishing can be re-run safely
+    Skip,
+    /// Fail in that region
+    Error,
+    /// Deregister the existing AMI and register or copy a new one
+    Replace,
+}
+
+impl FromStr for OnExists {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        match input {
+            "skip" => Ok(OnExists::Skip),
+            "error" => Ok(OnExists::Error),
+            "replace" => Ok(OnExists::Replace),
+            _ => error::ParseOnExistsSnafu { input }.fail(),
+        }
+    }
+}
+
 /// Builds Bottlerocket AMIs using latest build artifacts
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
 pub(crate) struct AmiArgs {
     /// Path to the image containing the root volume
-    #[structopt(short = "r", long, parse(from_os_str))]
-    root_image: PathBuf,
+    #[structopt(
+        short = "r",
+        long,
+        parse(from_os_str),
+        required_unless_one = &["image-s3-uri", "source-ami"]
+    )]
+    root_image: Option<PathBuf>,
+
+    /// S3 URI (s3://bucket/key) of the root volume image, to import with EC2 ImportSnapshot
+    /// instead of uploading --root-image
+    #[structopt(long, conflicts_with = "root-image")]
+    image_s3_uri: Option<String>,
+
+    /// ID of an existing AMI to copy to the given regions, instead of registering a new one
+    #[structopt(
+        long,
+        requires = "source-region",
+        conflicts_with_all = &["root-image", "image-s3-uri", "data-image"]
+    )]
+    source_ami: Option<String>,
+
+    /// The region holding --source-ami
+    #[structopt(long, requires = "source-ami")]
+    source_region: Option<String>,
+
+    /// IAM role that EC2 uses to read --image-s3-uri; defaults to "vmimport"
+    #[structopt(long, requires = "image-s3-uri")]
+    import_role_name: Option<String>,
 
     /// Path to the image containing the data volume
     #[structopt(short = "d", long, parse(from_os_str))]
@@ -51,14 +111,38 @@ pub(crate) struct AmiArgs {
     #[structopt(short = "a", long, parse(try_from_str = parse_arch))]
     arch: String,
 
-    /// The desired AMI name
-    #[structopt(short = "n", long)]
-    name: String,
+    /// The boot mode of the machine image: legacy-bios, uefi, or uefi-preferred; if not given,
+    /// EC2 uses the default for the architecture
+    #[structopt(long, parse(try_from_str = parse_boot_mode))]
+    boot_mode: Option<String>,
 
-    /// The desired AMI description
-    #[structopt(long)]
+    /// The desired AMI name; defaults to the name of --source-ami when copying
+    #[structopt(short = "n", long, required_unless = "source-ami")]
+    name: Option<String>,
+
+    /// The desired AMI description; defaults to the description of --source-ami when copying
+    #[structopt(long, conflicts_with = "description-template")]
     description: Option<String>,
 
+    /// Template for the AMI description, overriding aws.ami.description_template in Infra.toml;
+    /// may use {name}, {arch}, {variant}, {version}, and {commit}.  New AMIs are described by
+    /// "{name}" if neither is given.
+    #[structopt(long)]
+    description_template: Option<String>,
+
+    /// The variant of the image, for {variant} in description templates
+    #[structopt(long)]
+    variant: Option<String>,
+
+    /// The version of the image, for {version} in description templates; where the AMI name has
+    /// it after a 'v', it's put in canonical form
+    #[structopt(long)]
+    image_version: Option<String>,
+
+    /// The commit the image was built from, for {commit} in description templates
+    #[structopt(long)]
+    commit: Option<String>,
+
     /// Don't display progress bars
     #[structopt(long)]
     no_progress: bool,
@@ -67,44 +151,168 @@ pub(crate) struct AmiArgs {
     #[structopt(long, use_delimiter = true)]
     regions: Vec<String>,
 
+    /// File listing regions to use instead of --regions, separated by newlines or commas
+    #[structopt(long, parse(from_os_str), conflicts_with = "regions")]
+    regions_file: Option<PathBuf>,
+
+    /// Region to leave out of the regions given or from Infra.toml; can be given more than once
+    #[structopt(long = "exclude-region", number_of_values = 1)]
+    exclude_regions: Vec<String>,
+
     /// If specified, save created regional AMI IDs in JSON at this path.
     #[structopt(long)]
     ami_output: Option<PathBuf>,
+
+    /// What to do when an AMI with the same name already exists in a region: "skip" to use it,
+    /// "error" to fail in that region, or "replace" to deregister it, leaving its snapshots, and
+    /// register or copy a new one.  EC2 doesn't allow two AMIs with the same name, so the old one
+    /// is deregistered first.
+    #[structopt(long, default_value = "skip")]
+    on_exists: OnExists,
 }
 
 /// Common entrypoint from main()
 pub(crate) async fn run(args: &Args, ami_args: &AmiArgs) -> Result<()> {
-    match _run(args, ami_args).await {
-        Ok(amis) => {
-            // Write the AMI IDs to file if requested
-            if let Some(ref path) = ami_args.ami_output {
-                let file = File::create(path).context(error::FileCreateSnafu { path })?;
-                serde_json::to_writer_pretty(file, &amis)
-                    .context(error::SerializeSnafu { path })?;
-                info!("Wrote AMI data to {}", path.display());
-            }
-            Ok(())
-        }
-        Err(e) => Err(e),
+    let results = _run(args, ami_args).await?;
+
+    // Write the AMI IDs to file if requested; if some copies failed, this still includes the
+    // regions that succeeded, so they don't need to be redone.
+    if let Some(ref path) = ami_args.ami_output {
+        let file = File::create(path).context(error::FileCreateSnafu { path })?;
+        serde_json::to_writer_pretty(file, &results.amis)
+            .context(error::SerializeSnafu { path })?;
+        info!("Wrote AMI data to {}", path.display());
     }
+    output::report(args.output, &results)?;
+
+    if !results.failed.is_empty() {
+        let mut succeeded: Vec<&str> = results.amis.keys().map(|s| s.as_str()).collect();
+        succeeded.sort_unstable();
+        let mut failed: Vec<String> = results.failed.keys().cloned().collect();
+        failed.sort();
+        info!(
+            "AMI '{}' is available in: {}",
+            results.name,
+            succeeded.join(", ")
+        );
+        error!(
+            "Failed to copy AMI '{}' ({} in {}) to: {}",
+            results.name,
+            results.source_image_id,
+            results.source_region,
+            failed.join(", ")
+        );
+        let failures: Vec<String> = failed
+            .iter()
+            .map(|region| results.failed[region].clone())
+            .collect();
+        return error::AmiCopySnafu { failures }.fail();
+    }
+
+    Ok(())
 }
 
-async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>> {
+/// Puts the image version embedded in an AMI name in canonical form, so AMI names agree with the
+/// SSM parameters that point to them.  By convention, the version follows a 'v' in the name, so a
+/// version given as "v1.2.3" would otherwise appear as "vv1.2.3".  Names without the version are
+/// returned as-is.
+fn ami_name(name: &str, image_version: Option<&str>) -> String {
+    match image_version {
+        Some(version) => name.replace(
+            &format!("v{}", version),
+            &format!("v{}", normalize_version(version)),
+        ),
+        None => name.to_string(),
+    }
+}
+
+/// Returns the description to give the AMI: --description as given, or the rendered template
+/// from --description-template or Infra.toml.  If there's neither, new AMIs get the default
+/// template, and copies get no description here, so they keep the source AMI's.
+fn ami_description(
+    ami_args: &AmiArgs,
+    aws: &AwsConfig,
+    name: &str,
+    registering: bool,
+) -> Result<Option<String>> {
+    if let Some(description) = &ami_args.description {
+        description::check_length(description).context(error::DescriptionSnafu)?;
+        return Ok(Some(description.clone()));
+    }
+    let template = match ami_args.description_template.as_deref().or_else(|| {
+        aws.ami
+            .as_ref()
+            .and_then(|ami| ami.description_template.as_deref())
+    }) {
+        Some(template) => template,
+        None if registering => description::DEFAULT_TEMPLATE,
+        None => return Ok(None),
+    };
+    let version = ami_args.image_version.as_deref().map(normalize_version);
+    let context = DescriptionContext {
+        name,
+        arch: &ami_args.arch,
+        variant: ami_args.variant.as_deref(),
+        version: version.as_deref(),
+        commit: ami_args.commit.as_deref(),
+    };
+    description::render(template, &context)
+        .map(Some)
+        .context(error::DescriptionSnafu)
+}
+
+/// The outcome of registering an AMI and copying it to other regions.  Copies can fail in some
+/// regions without stopping the others, so we track failures separately and report them together.
+#[derive(Debug, Serialize)]
+struct AmiResults {
+    /// The name of the AMI in every region
+    name: String,
+    /// The region we registered in and copied from
+    source_region: String,
+    /// The ID of the AMI we copied from
+    source_image_id: String,
+    /// Map of region name to the AMI available there
+    amis: HashMap<String, Image>,
+    /// Map of region name to the reason we couldn't copy the AMI there
+    failed: HashMap<String, String>,
+    /// Map of region name to the existing AMI we deregistered there, with --on-exists replace
+    replaced: HashMap<String, String>,
+}
+
+async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<AmiResults> {
     let mut amis = HashMap::new();
+    let mut failed = HashMap::new();
+    let mut replaced = HashMap::new();
 
     // If a lock file exists, use that, otherwise use Infra.toml or default
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, true)
-        .context(error::ConfigSnafu)?;
+    let infra_config = args.infra_config(true).context(error::ConfigSnafu)?;
     trace!("Using infra config: {:?}", infra_config);
 
     let aws = infra_config.aws.unwrap_or_else(|| Default::default());
 
-    // If the user gave an override list of regions, use that, otherwise use what's in the config.
-    let mut regions = if !ami_args.regions.is_empty() {
-        ami_args.regions.clone()
-    } else {
-        aws.regions.clone().into()
+    // arm64 instances only boot with UEFI, so anything else would register an unusable AMI.
+    if let Some(boot_mode) = &ami_args.boot_mode {
+        ensure!(
+            ami_args.arch != "arm64" || boot_mode == "uefi",
+            error::IncompatibleBootModeSnafu {
+                arch: &ami_args.arch,
+                boot_mode,
+            }
+        );
     }
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config;
+    // either way, leave out any excluded regions.
+    let mut regions = exclude_regions(
+        if let Some(path) = &ami_args.regions_file {
+            regions_from_file(path, &aws).context(error::RegionsFileSnafu)?
+        } else if !ami_args.regions.is_empty() {
+            ami_args.regions.clone()
+        } else {
+            aws.regions.clone().into()
+        },
+        &ami_args.exclude_regions,
+    )
     .into_iter()
     .map(|name| region_from_string(&name, &aws).context(error::ParseRegionSnafu))
     .collect::<Result<Vec<Region>>>()?;
@@ -116,8 +324,16 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
         }
     );
 
-    // We register in this base region first, then copy from there to any other regions.
-    let base_region = regions.remove(0);
+    // We register in this base region first, then copy from there to any other regions.  If we
+    // were given an existing AMI, its region is the base instead, and we copy to all the others.
+    let base_region = match &ami_args.source_region {
+        Some(name) => {
+            let source_region = region_from_string(name, &aws).context(error::ParseRegionSnafu)?;
+            regions.retain(|region| region != &source_region);
+            source_region
+        }
+        None => regions.remove(0),
+    };
 
     // Build EBS client for snapshot management, and EC2 client for registration
     let base_ebs_client = build_client::<EbsClient>(&base_region, &base_region, &aws).context(
@@ -133,25 +349,82 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
         },
     )?;
 
+    // If we were given an AMI to copy, we take its details and reuse its tags for the copies.
+    let mut tags = Vec::new();
+    let (name, description) = if let Some(source_ami) = &ami_args.source_ami {
+        let source =
+            describe_source_ami(source_ami, &ami_args.arch, &base_region, &base_ec2_client).await?;
+        info!(
+            "Copying '{}' from {}: {}",
+            source.name,
+            base_region.name(),
+            source_ami
+        );
+        tags = source.tags;
+        let name = ami_name(
+            &ami_args.name.clone().unwrap_or(source.name),
+            ami_args.image_version.as_deref(),
+        );
+        let description = ami_description(ami_args, &aws, &name, false)?.or(source.description);
+        (name, description)
+    } else {
+        let name = ami_args
+            .name
+            .as_deref()
+            .context(error::MissingArgSnafu { missing: "--name" })?;
+        let name = ami_name(name, ami_args.image_version.as_deref());
+        let description = ami_description(ami_args, &aws, &name, true)?;
+        (name, description)
+    };
+
     // Check if the AMI already exists, in which case we can use the existing ID, otherwise we
-    // register a new one.
-    let maybe_id = get_ami_id(
-        &ami_args.name,
-        &ami_args.arch,
-        base_region.name(),
-        &base_ec2_client,
-    )
-    .await
-    .context(error::GetAmiIdSnafu {
-        name: &ami_args.name,
-        arch: &ami_args.arch,
-        region: base_region.name(),
-    })?;
+    // register a new one.  An AMI we were given to copy naturally exists already.
+    let maybe_id = match &ami_args.source_ami {
+        Some(source_ami) => Some(source_ami.clone()),
+        None => get_ami_id(&name, &ami_args.arch, base_region.name(), &base_ec2_client)
+            .await
+            .context(error::GetAmiIdSnafu {
+                name: &name,
+                arch: &ami_args.arch,
+                region: RegionAccount::from_config(base_region.name(), &aws),
+            })?,
+    };
+
+    // The AMI we were given to copy is never replaced; --on-exists applies to its copies.
+    let maybe_id = match maybe_id {
+        Some(found_id) if ami_args.source_ami.is_none() => match ami_args.on_exists {
+            OnExists::Skip => Some(found_id),
+            OnExists::Error => {
+                return error::AmiExistsSnafu {
+                    name: &name,
+                    image_id: found_id,
+                    region: RegionAccount::from_config(base_region.name(), &aws),
+                }
+                .fail()
+            }
+            OnExists::Replace => {
+                info!(
+                    "Deregistering existing '{}' in {} to replace it: {}",
+                    name,
+                    base_region.name(),
+                    found_id
+                );
+                deregister_image(&found_id, base_region.name(), &base_ec2_client)
+                    .await
+                    .context(error::ReplaceImageSnafu {
+                        region: RegionAccount::from_config(base_region.name(), &aws),
+                    })?;
+                replaced.insert(base_region.name().to_string(), found_id);
+                None
+            }
+        },
+        maybe_id => maybe_id,
+    };
 
     let (ids_of_image, already_registered) = if let Some(found_id) = maybe_id {
         warn!(
             "Found '{}' already registered in {}: {}",
-            ami_args.name,
+            name,
             base_region.name(),
             found_id
         );
@@ -169,33 +442,56 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     } else {
         let new_ids = register_image(
             ami_args,
+            &NewImage {
+                name: &name,
+                description: description.as_deref(),
+            },
+            &aws.ami.clone().unwrap_or_default(),
             base_region.name(),
             base_ebs_client,
             &base_ec2_client,
         )
         .await
         .context(error::RegisterImageSnafu {
-            name: &ami_args.name,
+            name: &name,
             arch: &ami_args.arch,
             region: base_region.name(),
         })?;
         info!(
             "Registered AMI '{}' in {}: {}",
-            ami_args.name,
+            name,
             base_region.name(),
             new_ids.image_id
         );
+        progress::record(format!(
+            "Registered {} in {}",
+            new_ids.image_id,
+            base_region.name()
+        ));
+        tag_registered(&new_ids, &base_region, &base_ec2_client, &aws).await?;
         (new_ids, false)
     };
 
+    // Copies get the same request tags as the AMIs we register.
+    tags.extend(ec2_request_tags());
+
     amis.insert(
         base_region.name().to_string(),
-        Image::new(&ids_of_image.image_id, &ami_args.name),
+        Image::new(&ids_of_image.image_id, &name),
     );
+    let source_region = base_region.name().to_string();
+    let source_image_id = ids_of_image.image_id.clone();
 
     // If we don't need to copy AMIs, we're done.
     if regions.is_empty() {
-        return Ok(amis);
+        return Ok(AmiResults {
+            name,
+            source_region,
+            source_image_id,
+            amis,
+            failed,
+            replaced,
+        });
     }
 
     // Wait for AMI to be available so it can be copied
@@ -219,7 +515,8 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     // First we need to find the account IDs for any given roles, so we can grant access to those
     // accounts to copy the AMI and snapshots.
     info!("Getting account IDs for target regions so we can grant access to copy source AMI");
-    let mut account_ids = get_account_ids(&regions, &base_region, &aws).await?;
+    let mut accounts = get_account_ids(&regions, &base_region, &aws).await?;
+    let mut account_ids: HashSet<String> = accounts.values().cloned().collect();
 
     // Get the account ID used in the base region; we don't need to grant to it so we can remove it
     // from the list.
@@ -240,6 +537,10 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
         missing: "account",
     })?;
     account_ids.remove(&base_account_id);
+    accounts.insert(base_region.name().to_string(), base_account_id);
+    // Say which account each region is in when reporting its failures.
+    let region_account =
+        |region: &Region| RegionAccount::new(region.name(), accounts.get(region.name()).cloned());
 
     // If we have any accounts other than the base account, grant them access.
     if !account_ids.is_empty() {
@@ -280,7 +581,7 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     let mut ec2_clients = HashMap::with_capacity(regions.len());
     for region in regions.iter() {
         let ec2_client =
-            build_client::<Ec2Client>(&region, &base_region, &aws).context(error::ClientSnafu {
+            build_client::<Ec2Client>(region, &base_region, &aws).context(error::ClientSnafu {
                 client_type: "EC2",
                 region: region.name(),
             })?;
@@ -292,7 +593,7 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     let mut get_requests = Vec::with_capacity(regions.len());
     for region in regions.iter() {
         let ec2_client = &ec2_clients[region];
-        let get_request = get_ami_id(&ami_args.name, &ami_args.arch, region.name(), ec2_client);
+        let get_request = get_ami_id(&name, &ami_args.arch, region.name(), ec2_client);
         let info_future = ready(region.clone());
         get_requests.push(join(info_future, get_request));
     }
@@ -303,26 +604,68 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     // If an AMI already existed, just add it to our list, otherwise prepare a copy request.
     let mut copy_requests = Vec::with_capacity(regions.len());
     for (region, get_response) in get_responses {
-        let get_response = get_response.context(error::GetAmiIdSnafu {
-            name: &ami_args.name,
+        // If we can't tell whether the AMI exists in a region, don't try to copy there, but keep
+        // going so we can report on all regions.
+        let get_response = match get_response.context(error::GetAmiIdSnafu {
+            name: &name,
             arch: &ami_args.arch,
-            region: region.name(),
-        })?;
+            region: region_account(&region),
+        }) {
+            Ok(response) => response,
+            Err(e) => {
+                error!("{}", e);
+                failed.insert(region.name().to_string(), e.to_string());
+                continue;
+            }
+        };
+        let ec2_client = &ec2_clients[&region];
         if let Some(id) = get_response {
-            info!(
-                "Found '{}' already registered in {}: {}",
-                ami_args.name,
-                region.name(),
-                id
-            );
-            amis.insert(region.name().to_string(), Image::new(&id, &ami_args.name));
-            continue;
+            match ami_args.on_exists {
+                OnExists::Skip => {
+                    info!(
+                        "Found '{}' already registered in {}: {}",
+                        name,
+                        region.name(),
+                        id
+                    );
+                    amis.insert(region.name().to_string(), Image::new(&id, &name));
+                    continue;
+                }
+                OnExists::Error => {
+                    let e = error::AmiExistsSnafu {
+                        name: &name,
+                        image_id: id,
+                        region: region_account(&region),
+                    }
+                    .build();
+                    error!("{}", e);
+                    failed.insert(region.name().to_string(), e.to_string());
+                    continue;
+                }
+                OnExists::Replace => {
+                    info!(
+                        "Deregistering existing '{}' in {} to replace it: {}",
+                        name,
+                        region.name(),
+                        id
+                    );
+                    if let Err(e) = deregister_image(&id, region.name(), ec2_client).await {
+                        let e = error::ReplaceImageSnafu {
+                            region: region_account(&region),
+                        }
+                        .into_error(e);
+                        error!("{}", e);
+                        failed.insert(region.name().to_string(), e.to_string());
+                        continue;
+                    }
+                    replaced.insert(region.name().to_string(), id);
+                }
+            }
         }
 
-        let ec2_client = &ec2_clients[&region];
         let copy_request = CopyImageRequest {
-            description: ami_args.description.clone(),
-            name: ami_args.name.clone(),
+            description: description.clone(),
+            name: name.clone(),
             source_image_id: ids_of_image.image_id.clone(),
             source_region: base_region.name().to_string(),
             ..Default::default()
@@ -345,7 +688,15 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
 
     // If all target regions already have the AMI, we're done.
     if copy_requests.is_empty() {
-        return Ok(amis);
+        tag_copies(&tags, &ec2_clients, &accounts, &mut amis, &mut failed).await;
+        return Ok(AmiResults {
+            name,
+            source_region,
+            source_image_id,
+            amis,
+            failed,
+            replaced,
+        });
     }
 
     // Start requests; they return almost immediately and the copying work is done by the service
@@ -354,48 +705,71 @@ async fn _run(args: &Args, ami_args: &AmiArgs) -> Result<HashMap<String, Image>>
     // over the number of requests going out in case we need it later, but this will effectively
     // spin through all regions quickly because the requests return before any copying is done.)
     let request_stream = stream::iter(copy_requests).buffer_unordered(4);
-    // Run through the stream and collect results into a list.
+    // Run through the stream and collect results into a list, recording each copy as it starts
+    // so an interrupted run can say which regions got one.
     let copy_responses: Vec<(
         Region,
         std::result::Result<CopyImageResult, RusotoError<CopyImageError>>,
-    )> = request_stream.collect().await;
+    )> = request_stream
+        .inspect(|(region, copy_response)| {
+            if let Ok(CopyImageResult {
+                image_id: Some(image_id),
+            }) = copy_response
+            {
+                progress::record(format!("Started copy {} in {}", image_id, region.name()));
+            }
+        })
+        .collect()
+        .await;
 
-    // Report on successes and errors; don't fail immediately if we see an error so we can report
-    // all successful IDs.
-    let mut saw_error = false;
+    // Record successes and errors; don't fail immediately if we see an error so we can report
+    // on all regions.
     for (region, copy_response) in copy_responses {
         match copy_response {
             Ok(success) => {
                 if let Some(image_id) = success.image_id {
                     info!(
                         "Registered AMI '{}' in {}: {}",
-                        ami_args.name,
+                        name,
                         region.name(),
                         image_id,
                     );
-                    amis.insert(
-                        region.name().to_string(),
-                        Image::new(&image_id, &ami_args.name),
-                    );
+                    amis.insert(region.name().to_string(), Image::new(&image_id, &name));
                 } else {
-                    saw_error = true;
                     error!(
                         "Registered AMI '{}' in {} but didn't receive an AMI ID!",
-                        ami_args.name,
-                        region.name(),
+                        name,
+                        region_account(&region),
+                    );
+                    failed.insert(
+                        region.name().to_string(),
+                        format!(
+                            "Copy to {} succeeded but no AMI ID was returned",
+                            region_account(&region)
+                        ),
                     );
                 }
             }
             Err(e) => {
-                saw_error = true;
-                error!("Copy to {} failed: {}", region.name(), e);
+                let e = error::CopyImageSnafu {
+                    region: region_account(&region),
+                }
+                .into_error(e);
+                error!("{}", e);
+                failed.insert(region.name().to_string(), e.to_string());
             }
         }
     }
-
-    ensure!(!saw_error, error::AmiCopySnafu);
-
-    Ok(amis)
+    tag_copies(&tags, &ec2_clients, &accounts, &mut amis, &mut failed).await;
+
+    Ok(AmiResults {
+        name,
+        source_region,
+        source_image_id,
+        amis,
+        failed,
+        replaced,
+    })
 }
 
 /// If JSON output was requested, we serialize out a mapping of region to AMI information; this
@@ -416,20 +790,156 @@ impl Image {
     }
 }
 
-/// Returns the set of account IDs associated with the roles configured for the given regions.
+/// The details of an existing AMI that we carry over to its copies.
+struct SourceAmi {
+    name: String,
+    description: Option<String>,
+    tags: Vec<Tag>,
+}
+
+/// Looks up the given AMI so we can copy it, making sure it matches the requested architecture.
+async fn describe_source_ami(
+    image_id: &str,
+    arch: &str,
+    region: &Region,
+    ec2_client: &Ec2Client,
+) -> Result<SourceAmi> {
+    let describe_request = DescribeImagesRequest {
+        image_ids: Some(vec![image_id.to_string()]),
+        ..Default::default()
+    };
+    let image = ec2_client
+        .describe_images(describe_request)
+        .await
+        .context(error::DescribeSourceSnafu {
+            image_id,
+            region: region.name(),
+        })?
+        .images
+        .and_then(|images| images.into_iter().next())
+        .context(error::MissingSourceSnafu {
+            image_id,
+            region: region.name(),
+        })?;
+
+    let image_arch = image.architecture.unwrap_or_default();
+    ensure!(
+        image_arch == arch,
+        error::SourceArchSnafu {
+            image_id,
+            image_arch,
+            arch,
+        }
+    );
+
+    Ok(SourceAmi {
+        name: image.name.context(error::MissingInResponseSnafu {
+            request_type: "DescribeImages",
+            missing: "name",
+        })?,
+        description: image.description,
+        // Tags with the reserved "aws:" prefix are set by AWS and can't be applied by us.
+        tags: image
+            .tags
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|tag| !tag.key.as_deref().unwrap_or_default().starts_with("aws:"))
+            .collect(),
+    })
+}
+
+/// Returns the tags given with --request-tag, in the form EC2 wants.
+fn ec2_request_tags() -> Vec<Tag> {
+    request_tags()
+        .into_iter()
+        .map(|(key, value)| Tag {
+            key: Some(key),
+            value: Some(value),
+        })
+        .collect()
+}
+
+/// Applies the tags given with --request-tag to a newly registered AMI and its snapshots.
+/// RegisterImage doesn't accept tags itself, so we tag them afterward.
+async fn tag_registered(
+    ids: &RegisteredIds,
+    region: &Region,
+    ec2_client: &Ec2Client,
+    aws: &AwsConfig,
+) -> Result<()> {
+    let tags = ec2_request_tags();
+    if tags.is_empty() {
+        return Ok(());
+    }
+    let mut resources = vec![ids.image_id.clone()];
+    resources.extend(ids.snapshot_ids.iter().cloned());
+    info!("Adding request tags to {}", resources.join(", "));
+    ec2_client
+        .create_tags(CreateTagsRequest {
+            resources,
+            tags,
+            ..Default::default()
+        })
+        .await
+        .context(error::TagImageSnafu {
+            image_id: &ids.image_id,
+            region: RegionAccount::from_config(region.name(), aws),
+        })?;
+    Ok(())
+}
+
+/// Applies the source AMI's tags, and any request tags, to its copies in the target regions,
+/// since CopyImage doesn't carry them over.  Regions where tagging fails are moved from `amis` to
+/// `failed`, so the copy isn't reported as done.
+async fn tag_copies(
+    tags: &[Tag],
+    ec2_clients: &HashMap<Region, Ec2Client>,
+    accounts: &HashMap<String, String>,
+    amis: &mut HashMap<String, Image>,
+    failed: &mut HashMap<String, String>,
+) {
+    if tags.is_empty() {
+        return;
+    }
+    for (region, ec2_client) in ec2_clients {
+        let image_id = match amis.get(region.name()) {
+            Some(image) => image.id.clone(),
+            None => continue,
+        };
+        info!("Tagging {} in {}", image_id, region.name());
+        let tag_request = CreateTagsRequest {
+            resources: vec![image_id.clone()],
+            tags: tags.to_vec(),
+            ..Default::default()
+        };
+        if let Err(e) = ec2_client.create_tags(tag_request).await {
+            let e = error::TagImageSnafu {
+                image_id: &image_id,
+                region: RegionAccount::new(region.name(), accounts.get(region.name()).cloned()),
+            }
+            .into_error(e);
+            error!("{}", e);
+            amis.remove(region.name());
+            failed.insert(region.name().to_string(), e.to_string());
+        }
+    }
+}
+
+/// Returns the account ID associated with the roles configured for each of the given regions,
+/// keyed by region name.
 async fn get_account_ids(
     regions: &[Region],
     base_region: &Region,
     aws: &AwsConfig,
-) -> Result<HashSet<String>> {
-    let mut grant_accounts = HashSet::new();
+) -> Result<HashMap<String, String>> {
+    let mut grant_accounts = HashMap::new();
 
     // We make a map storing our regional clients because they're used in a future and need to
     // live until the future is resolved.
     let mut sts_clients = HashMap::with_capacity(regions.len());
     for region in regions.iter() {
         let sts_client =
-            build_client::<StsClient>(&region, &base_region, &aws).context(error::ClientSnafu {
+            build_client::<StsClient>(region, base_region, aws).context(error::ClientSnafu {
                 client_type: "STS",
                 region: region.name(),
             })?;
@@ -461,7 +971,7 @@ async fn get_account_ids(
             request_type: "GetCallerIdentity",
             missing: "account",
         })?;
-        grant_accounts.insert(account_id);
+        grant_accounts.insert(region.name().to_string(), account_id);
     }
     trace!("Found account IDs {:?}", grant_accounts);
 
@@ -469,8 +979,9 @@ async fn get_account_ids(
 }
 
 mod error {
-    use crate::aws::{self, ami, publish_ami};
+    use crate::aws::{self, ami, publish_ami, RegionAccount};
     use rusoto_core::RusotoError;
+    use rusoto_ec2::{CopyImageError, CreateTagsError, DescribeImagesError};
     use rusoto_sts::GetCallerIdentityError;
     use snafu::Snafu;
     use std::path::PathBuf;
@@ -478,8 +989,26 @@ mod error {
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub(crate) enum Error {
-        #[snafu(display("Some AMIs failed to copy, see above"))]
-        AmiCopy,
+        #[snafu(display(
+            "AMI failed to copy to {} regions:\n{}",
+            failures.len(),
+            failures.join("\n")
+        ))]
+        AmiCopy {
+            failures: Vec<String>,
+        },
+
+        #[snafu(display(
+            "AMI '{}' already exists in {}: {}; use --on-exists skip or replace to allow this",
+            name,
+            region,
+            image_id
+        ))]
+        AmiExists {
+            name: String,
+            image_id: String,
+            region: RegionAccount,
+        },
 
         #[snafu(display("Error creating {} client in {}: {}", client_type, region, source))]
         Client {
@@ -493,6 +1022,26 @@ mod error {
             source: pubsys_config::Error,
         },
 
+        #[snafu(display("Failed to make AMI description: {}", source))]
+        Description {
+            source: ami::description::Error,
+        },
+
+        #[snafu(display("Failed to copy AMI to {}: {}", region, source))]
+        CopyImage {
+            region: RegionAccount,
+            #[snafu(source(from(RusotoError<CopyImageError>, Box::new)))]
+            source: Box<RusotoError<CopyImageError>>,
+        },
+
+        #[snafu(display("Failed to describe source AMI {} in {}: {}", image_id, region, source))]
+        DescribeSource {
+            image_id: String,
+            region: String,
+            #[snafu(source(from(RusotoError<DescribeImagesError>, Box::new)))]
+            source: Box<RusotoError<DescribeImagesError>>,
+        },
+
         #[snafu(display("Failed to create file '{}': {}", path.display(), source))]
         FileCreate {
             path: PathBuf,
@@ -503,14 +1052,16 @@ mod error {
         GetAmiId {
             name: String,
             arch: String,
-            region: String,
-            source: ami::register::Error,
+            region: RegionAccount,
+            #[snafu(source(from(ami::register::Error, Box::new)))]
+            source: Box<ami::register::Error>,
         },
 
         #[snafu(display("Error getting account ID in {}: {}", region, source))]
         GetCallerIdentity {
             region: String,
-            source: RusotoError<GetCallerIdentityError>,
+            #[snafu(source(from(RusotoError<GetCallerIdentityError>, Box::new)))]
+            source: Box<RusotoError<GetCallerIdentityError>>,
         },
 
         #[snafu(display(
@@ -522,14 +1073,27 @@ mod error {
         GetSnapshots {
             image_id: String,
             region: String,
-            source: publish_ami::Error,
+            #[snafu(source(from(publish_ami::Error, Box::new)))]
+            source: Box<publish_ami::Error>,
         },
 
         #[snafu(display("Failed to grant access to {} in {}: {}", thing, region, source))]
         GrantAccess {
             thing: String,
             region: String,
-            source: publish_ami::Error,
+            #[snafu(source(from(publish_ami::Error, Box::new)))]
+            source: Box<publish_ami::Error>,
+        },
+
+        #[snafu(display("Boot mode {} isn't supported for {} images", boot_mode, arch))]
+        IncompatibleBootMode {
+            arch: String,
+            boot_mode: String,
+        },
+
+        #[snafu(display("Missing required argument {}", missing))]
+        MissingArg {
+            missing: String,
         },
 
         #[snafu(display("Infra.toml is missing {}", missing))]
@@ -543,16 +1107,45 @@ mod error {
             missing: String,
         },
 
+        #[snafu(display("Source AMI {} not found in {}", image_id, region))]
+        MissingSource {
+            image_id: String,
+            region: String,
+        },
+
+        #[snafu(context(false), display("{}", source))]
+        Output {
+            source: crate::output::Error,
+        },
+
+        #[snafu(display("Invalid --on-exists '{}', expected skip, error, or replace", input))]
+        ParseOnExists {
+            input: String,
+        },
+
         ParseRegion {
             source: crate::aws::Error,
         },
 
+        #[snafu(display("Failed to read regions file: {}", source))]
+        RegionsFile {
+            source: crate::aws::Error,
+        },
+
         #[snafu(display("Error registering {} {} in {}: {}", arch, name, region, source))]
         RegisterImage {
             name: String,
             arch: String,
             region: String,
-            source: ami::register::Error,
+            #[snafu(source(from(ami::register::Error, Box::new)))]
+            source: Box<ami::register::Error>,
+        },
+
+        #[snafu(display("Failed to replace existing AMI in {}: {}", region, source))]
+        ReplaceImage {
+            region: RegionAccount,
+            #[snafu(source(from(ami::register::Error, Box::new)))]
+            source: Box<ami::register::Error>,
         },
 
         #[snafu(display("Failed to serialize output to '{}': {}", path.display(), source))]
@@ -561,13 +1154,64 @@ mod error {
             source: serde_json::Error,
         },
 
+        #[snafu(display(
+            "Source AMI {} has architecture {}, but {} was requested",
+            image_id,
+            image_arch,
+            arch
+        ))]
+        SourceArch {
+            image_id: String,
+            image_arch: String,
+            arch: String,
+        },
+
+        #[snafu(display("Failed to tag {} in {}: {}", image_id, region, source))]
+        TagImage {
+            image_id: String,
+            region: RegionAccount,
+            #[snafu(source(from(RusotoError<CreateTagsError>, Box::new)))]
+            source: Box<RusotoError<CreateTagsError>>,
+        },
+
         #[snafu(display("AMI '{}' in {} did not become available: {}", id, region, source))]
         WaitAmi {
             id: String,
             region: String,
-            source: ami::wait::Error,
+            #[snafu(source(from(ami::wait::Error, Box::new)))]
+            source: Box<ami::wait::Error>,
         },
     }
 }
 pub(crate) use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::ami_name;
+
+    #[test]
+    fn ami_names_use_canonical_versions() {
+        for version in &["1.2.3", "v1.2.3"] {
+            assert_eq!(
+                ami_name(
+                    &format!("bottlerocket-aws-k8s-1.24-x86_64-v{}-abcdef", version),
+                    Some(version)
+                ),
+                "bottlerocket-aws-k8s-1.24-x86_64-v1.2.3-abcdef"
+            );
+        }
+        assert_eq!(
+            ami_name(
+                "bottlerocket-x86_64-vv1.2.3-rc.1+build.5",
+                Some("v1.2.3-rc.1+build.5")
+            ),
+            "bottlerocket-x86_64-v1.2.3-rc.1+build.5"
+        );
+        assert_eq!(
+            ami_name("bottlerocket-x86_64-custom", Some("v1.2.3")),
+            "bottlerocket-x86_64-custom"
+        );
+        assert_eq!(ami_name("bottlerocket-v1.2.3", None), "bottlerocket-v1.2.3");
+    }
+}
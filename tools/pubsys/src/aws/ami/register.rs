@@ -1,10 +1,12 @@
-use super::{snapshot::snapshot_from_image, AmiArgs};
+use super::snapshot::{snapshot_from_image, snapshot_from_s3};
+use super::AmiArgs;
 use coldsnap::{SnapshotUploader, SnapshotWaiter};
 use log::{debug, info, warn};
+use pubsys_config::{AmiConfig, BlockDeviceConfig};
 use rusoto_ebs::EbsClient;
 use rusoto_ec2::{
-    BlockDeviceMapping, DeleteSnapshotRequest, DescribeImagesRequest, EbsBlockDevice, Ec2,
-    Ec2Client, Filter, RegisterImageRequest,
+    BlockDeviceMapping, DeleteSnapshotRequest, DeregisterImageRequest, DescribeImagesRequest,
+    EbsBlockDevice, Ec2, Ec2Client, Filter, RegisterImageRequest,
 };
 use snafu::{ensure, OptionExt, ResultExt};
 
@@ -17,30 +19,136 @@ const VOLUME_TYPE: &str = "gp2";
 const SRIOV: &str = "simple";
 const ENA: bool = true;
 
+// Volume types that accept provisioned IOPS and throughput settings.
+const IOPS_VOLUME_TYPES: &[&str] = &["gp3", "io1", "io2"];
+const THROUGHPUT_VOLUME_TYPES: &[&str] = &["gp3"];
+
+/// The name and description to register an AMI with
+pub(crate) struct NewImage<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) description: Option<&'a str>,
+}
+
 #[derive(Debug)]
 pub(crate) struct RegisteredIds {
     pub(crate) image_id: String,
     pub(crate) snapshot_ids: Vec<String>,
 }
 
+/// Makes sure provisioned IOPS and throughput are only requested for volume types that support
+/// them, so we fail before uploading anything rather than at registration.
+fn check_volume_settings(
+    device_name: &str,
+    volume_type: &str,
+    iops: Option<i64>,
+    throughput: Option<i64>,
+) -> Result<()> {
+    ensure!(
+        iops.is_none() || IOPS_VOLUME_TYPES.contains(&volume_type),
+        error::VolumeSettingSnafu {
+            device_name,
+            setting: "iops",
+            volume_type,
+        }
+    );
+    ensure!(
+        throughput.is_none() || THROUGHPUT_VOLUME_TYPES.contains(&volume_type),
+        error::VolumeSettingSnafu {
+            device_name,
+            setting: "throughput",
+            volume_type,
+        }
+    );
+    Ok(())
+}
+
+/// Builds the mapping for an additional block device from Infra.toml.
+fn extra_block_device(config: &BlockDeviceConfig) -> Result<BlockDeviceMapping> {
+    if config.virtual_name.is_some() {
+        // Instance store volumes come with the instance; they have no EBS settings.
+        ensure!(
+            config.volume_size.is_none()
+                && config.volume_type.is_none()
+                && config.iops.is_none()
+                && config.throughput.is_none(),
+            error::InstanceStoreSettingsSnafu {
+                device_name: &config.device_name,
+            }
+        );
+        return Ok(BlockDeviceMapping {
+            device_name: Some(config.device_name.clone()),
+            virtual_name: config.virtual_name.clone(),
+            ..Default::default()
+        });
+    }
+
+    let volume_type = config.volume_type.as_deref().unwrap_or(VOLUME_TYPE);
+    check_volume_settings(
+        &config.device_name,
+        volume_type,
+        config.iops,
+        config.throughput,
+    )?;
+    Ok(BlockDeviceMapping {
+        device_name: Some(config.device_name.clone()),
+        ebs: Some(EbsBlockDevice {
+            delete_on_termination: Some(true),
+            iops: config.iops,
+            throughput: config.throughput,
+            volume_size: config.volume_size,
+            volume_type: Some(volume_type.to_string()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
 /// Helper for `register_image`.  Inserts registered snapshot IDs into `cleanup_snapshot_ids` so
 /// they can be cleaned up on failure if desired.
 async fn _register_image(
     ami_args: &AmiArgs,
+    image: &NewImage<'_>,
+    ami_config: &AmiConfig,
     region: &str,
     ebs_client: EbsClient,
     ec2_client: &Ec2Client,
     cleanup_snapshot_ids: &mut Vec<String>,
 ) -> Result<RegisteredIds> {
+    let root_device_name = ami_config
+        .root_device_name
+        .as_deref()
+        .unwrap_or(ROOT_DEVICE_NAME);
+    let volume_type = ami_config.volume_type.as_deref().unwrap_or(VOLUME_TYPE);
+    check_volume_settings(
+        root_device_name,
+        volume_type,
+        ami_config.iops,
+        ami_config.throughput,
+    )?;
+    let extra_bdms = ami_config
+        .block_devices
+        .iter()
+        .map(extra_block_device)
+        .collect::<Result<Vec<_>>>()?;
+
     debug!("Uploading images into EBS snapshots in {}", region);
     let uploader = SnapshotUploader::new(ebs_client);
-    let root_snapshot =
-        snapshot_from_image(&ami_args.root_image, &uploader, None, ami_args.no_progress)
+    let root_snapshot = if let Some(uri) = &ami_args.image_s3_uri {
+        snapshot_from_s3(uri, ami_args.import_role_name.as_deref(), ec2_client)
+            .await
+            .context(error::ImportSnapshotSnafu { uri, region })?
+    } else {
+        let root_image = ami_args
+            .root_image
+            .as_ref()
+            .context(error::MissingRootImageSnafu)?;
+        snapshot_from_image(root_image, &uploader, None, ami_args.no_progress)
             .await
             .context(error::SnapshotSnafu {
-                path: &ami_args.root_image,
+                path: root_image,
                 region,
-            })?;
+            })?
+    };
     cleanup_snapshot_ids.push(root_snapshot.clone());
 
     let mut data_snapshot = None;
@@ -48,7 +156,7 @@ async fn _register_image(
         let snapshot = snapshot_from_image(data_image, &uploader, None, ami_args.no_progress)
             .await
             .context(error::SnapshotSnafu {
-                path: &ami_args.root_image,
+                path: data_image,
                 region,
             })?;
         cleanup_snapshot_ids.push(snapshot.clone());
@@ -75,11 +183,13 @@ async fn _register_image(
 
     // Prepare parameters for AMI registration request
     let root_bdm = BlockDeviceMapping {
-        device_name: Some(ROOT_DEVICE_NAME.to_string()),
+        device_name: Some(root_device_name.to_string()),
         ebs: Some(EbsBlockDevice {
             delete_on_termination: Some(true),
+            iops: ami_config.iops,
             snapshot_id: Some(root_snapshot.clone()),
-            volume_type: Some(VOLUME_TYPE.to_string()),
+            throughput: ami_config.throughput,
+            volume_type: Some(volume_type.to_string()),
             volume_size: ami_args.root_volume_size,
             ..Default::default()
         }),
@@ -101,14 +211,16 @@ async fn _register_image(
     if let Some(data_bdm) = data_bdm {
         block_device_mappings.push(data_bdm);
     }
+    block_device_mappings.extend(extra_bdms);
 
     let register_request = RegisterImageRequest {
         architecture: Some(ami_args.arch.clone()),
         block_device_mappings: Some(block_device_mappings),
-        description: ami_args.description.clone(),
+        boot_mode: ami_args.boot_mode.clone(),
+        description: image.description.map(str::to_string),
         ena_support: Some(ENA),
-        name: ami_args.name.clone(),
-        root_device_name: Some(ROOT_DEVICE_NAME.to_string()),
+        name: image.name.to_string(),
+        root_device_name: Some(root_device_name.to_string()),
         sriov_net_support: Some(SRIOV.to_string()),
         virtualization_type: Some(VIRT_TYPE.to_string()),
         ..Default::default()
@@ -139,14 +251,18 @@ async fn _register_image(
 /// mapping.  Deletes snapshots on failure.
 pub(crate) async fn register_image(
     ami_args: &AmiArgs,
+    image: &NewImage<'_>,
+    ami_config: &AmiConfig,
     region: &str,
     ebs_client: EbsClient,
     ec2_client: &Ec2Client,
 ) -> Result<RegisteredIds> {
-    info!("Registering '{}' in {}", ami_args.name, region);
+    info!("Registering '{}' in {}", image.name, region);
     let mut cleanup_snapshot_ids = Vec::new();
     let register_result = _register_image(
         ami_args,
+        image,
+        ami_config,
         region,
         ebs_client,
         ec2_client,
@@ -233,30 +349,73 @@ where
     }
 }
 
+/// Deregisters the given AMI, so its name can be used for a new one.  Its snapshots are left in
+/// place.
+pub(crate) async fn deregister_image(
+    image_id: &str,
+    region: &str,
+    ec2_client: &Ec2Client,
+) -> Result<()> {
+    ec2_client
+        .deregister_image(DeregisterImageRequest {
+            image_id: image_id.to_string(),
+            ..Default::default()
+        })
+        .await
+        .context(error::DeregisterImageSnafu { image_id, region })
+}
+
 mod error {
     use crate::aws::ami;
+    use rusoto_core::RusotoError;
     use snafu::Snafu;
     use std::path::PathBuf;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub(crate) enum Error {
+        #[snafu(display("Failed to deregister {} in {}: {}", image_id, region, source))]
+        DeregisterImage {
+            image_id: String,
+            region: String,
+            #[snafu(source(from(RusotoError<rusoto_ec2::DeregisterImageError>, Box::new)))]
+            source: Box<RusotoError<rusoto_ec2::DeregisterImageError>>,
+        },
+
         #[snafu(display("Failed to describe images in {}: {}", region, source))]
         DescribeImages {
             region: String,
-            source: rusoto_core::RusotoError<rusoto_ec2::DescribeImagesError>,
+            #[snafu(source(from(RusotoError<rusoto_ec2::DescribeImagesError>, Box::new)))]
+            source: Box<RusotoError<rusoto_ec2::DescribeImagesError>>,
+        },
+
+        #[snafu(display(
+            "Block device {} is an instance store volume and can't have EBS settings",
+            device_name
+        ))]
+        InstanceStoreSettings { device_name: String },
+
+        #[snafu(display("Failed to import snapshot from {} in {}: {}", uri, region, source))]
+        ImportSnapshot {
+            uri: String,
+            region: String,
+            source: ami::snapshot::Error,
         },
 
         #[snafu(display("Image response in {} did not include image ID", region))]
         MissingImageId { region: String },
 
+        #[snafu(display("No root image given; use --root-image or --image-s3-uri"))]
+        MissingRootImage,
+
         #[snafu(display("DescribeImages with unique filters returned multiple results: {}", images.join(", ")))]
         MultipleImages { images: Vec<String> },
 
         #[snafu(display("Failed to register image in {}: {}", region, source))]
         RegisterImage {
             region: String,
-            source: rusoto_core::RusotoError<rusoto_ec2::RegisterImageError>,
+            #[snafu(source(from(RusotoError<rusoto_ec2::RegisterImageError>, Box::new)))]
+            source: Box<RusotoError<rusoto_ec2::RegisterImageError>>,
         },
 
         #[snafu(display("Failed to upload snapshot from {} in {}: {}", path.display(),region, source))]
@@ -266,10 +425,23 @@ mod error {
             source: ami::snapshot::Error,
         },
 
+        #[snafu(display(
+            "Block device {} can't set {} with volume type {}",
+            device_name,
+            setting,
+            volume_type
+        ))]
+        VolumeSetting {
+            device_name: String,
+            setting: String,
+            volume_type: String,
+        },
+
         #[snafu(display("{} snapshot did not become available: {}", snapshot_type, source))]
         WaitSnapshot {
             snapshot_type: String,
-            source: coldsnap::WaitError,
+            #[snafu(source(from(coldsnap::WaitError, Box::new)))]
+            source: Box<coldsnap::WaitError>,
         },
     }
 }
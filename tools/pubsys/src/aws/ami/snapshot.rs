@@ -1,7 +1,16 @@
 use coldsnap::SnapshotUploader;
 use indicatif::{ProgressBar, ProgressStyle};
-use snafu::{OptionExt, ResultExt};
+use log::{debug, info};
+use rusoto_ec2::{
+    DescribeImportSnapshotTasksRequest, Ec2, Ec2Client, ImportSnapshotRequest,
+    SnapshotDiskContainer,
+};
+use snafu::{ensure, OptionExt, ResultExt};
 use std::path::Path;
+use std::time::Duration;
+
+/// The role EC2 uses to read images from S3 during import, unless told otherwise.
+const DEFAULT_IMPORT_ROLE: &str = "vmimport";
 
 /// Create a progress bar to show status of snapshot blocks, if wanted.
 fn build_progress_bar(no_progress: bool, verb: &str) -> Option<ProgressBar> {
@@ -40,18 +49,152 @@ where
         .context(error::UploadSnapshotSnafu)
 }
 
+/// Imports the image at the given S3 URI into a snapshot using EC2 ImportSnapshot, and waits for
+/// the import to finish.
+pub(crate) async fn snapshot_from_s3(
+    uri: &str,
+    role_name: Option<&str>,
+    ec2_client: &Ec2Client,
+) -> Result<String> {
+    ensure!(uri.starts_with("s3://"), error::InvalidS3UriSnafu { uri });
+    // ImportSnapshot wants to know the format; our images are raw unless named otherwise.
+    let format = match uri.rsplit('.').next() {
+        Some("vhd") => "VHD",
+        Some("vmdk") => "VMDK",
+        _ => "RAW",
+    };
+
+    let import_request = ImportSnapshotRequest {
+        description: Some(format!("Imported from {}", uri)),
+        disk_container: Some(SnapshotDiskContainer {
+            format: Some(format.to_string()),
+            url: Some(uri.to_string()),
+            ..Default::default()
+        }),
+        role_name: Some(role_name.unwrap_or(DEFAULT_IMPORT_ROLE).to_string()),
+        ..Default::default()
+    };
+    let import_response = ec2_client
+        .import_snapshot(import_request)
+        .await
+        .context(error::ImportSnapshotSnafu { uri })?;
+    let task_id = import_response
+        .import_task_id
+        .context(error::MissingTaskIdSnafu { uri })?;
+    info!("Started snapshot import task {} from {}", task_id, uri);
+
+    // Imports of full disk images take a while, so check in infrequently.
+    let max_attempts: u32 = 240;
+    let seconds_between_attempts = 15;
+    for _ in 0..max_attempts {
+        let describe_request = DescribeImportSnapshotTasksRequest {
+            import_task_ids: Some(vec![task_id.clone()]),
+            ..Default::default()
+        };
+        let describe_response = ec2_client
+            .describe_import_snapshot_tasks(describe_request)
+            .await
+            .context(error::DescribeImportSnafu { task_id: &task_id })?;
+        let detail = describe_response
+            .import_snapshot_tasks
+            .unwrap_or_default()
+            .into_iter()
+            .find(|task| task.import_task_id.as_ref() == Some(&task_id))
+            .and_then(|task| task.snapshot_task_detail)
+            .context(error::MissingTaskSnafu { task_id: &task_id })?;
+
+        let status = detail.status.as_deref().unwrap_or("unknown");
+        match status {
+            "completed" => {
+                let snapshot_id = detail
+                    .snapshot_id
+                    .context(error::MissingSnapshotIdSnafu { task_id: &task_id })?;
+                info!("Import task {} created snapshot {}", task_id, snapshot_id);
+                return Ok(snapshot_id);
+            }
+            "deleting" | "deleted" => {
+                return error::ImportFailedSnafu {
+                    task_id: &task_id,
+                    status,
+                    message: detail.status_message.unwrap_or_default(),
+                }
+                .fail();
+            }
+            _ => debug!(
+                "Import task {} is {}, {}% done: {}",
+                task_id,
+                status,
+                detail.progress.as_deref().unwrap_or("0"),
+                detail.status_message.as_deref().unwrap_or("")
+            ),
+        }
+        tokio::time::sleep(Duration::from_secs(seconds_between_attempts)).await;
+    }
+
+    error::ImportTimeoutSnafu {
+        task_id,
+        max_attempts,
+    }
+    .fail()
+}
+
 mod error {
+    use rusoto_core::RusotoError;
+    use rusoto_ec2::DescribeImportSnapshotTasksError;
     use snafu::Snafu;
     use std::path::PathBuf;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub(crate) enum Error {
+        #[snafu(display("Failed to describe snapshot import task {}: {}", task_id, source))]
+        DescribeImport {
+            task_id: String,
+            #[snafu(source(from(RusotoError<DescribeImportSnapshotTasksError>, Box::new)))]
+            source: Box<RusotoError<DescribeImportSnapshotTasksError>>,
+        },
+
+        #[snafu(display("Snapshot import task {} is {}: {}", task_id, status, message))]
+        ImportFailed {
+            task_id: String,
+            status: String,
+            message: String,
+        },
+
+        #[snafu(display("Failed to start snapshot import from {}: {}", uri, source))]
+        ImportSnapshot {
+            uri: String,
+            #[snafu(source(from(RusotoError<rusoto_ec2::ImportSnapshotError>, Box::new)))]
+            source: Box<RusotoError<rusoto_ec2::ImportSnapshotError>>,
+        },
+
+        #[snafu(display(
+            "Snapshot import task {} did not finish after {} checks",
+            task_id,
+            max_attempts
+        ))]
+        ImportTimeout { task_id: String, max_attempts: u32 },
+
         #[snafu(display("Invalid image path '{}'", path.display()))]
         InvalidImagePath { path: PathBuf },
 
+        #[snafu(display("Invalid S3 URI '{}', expected s3://bucket/key", uri))]
+        InvalidS3Uri { uri: String },
+
+        #[snafu(display("Import task {} completed without a snapshot ID", task_id))]
+        MissingSnapshotId { task_id: String },
+
+        #[snafu(display("Snapshot import task {} was not found", task_id))]
+        MissingTask { task_id: String },
+
+        #[snafu(display("ImportSnapshot response for {} did not include task ID", uri))]
+        MissingTaskId { uri: String },
+
         #[snafu(display("Failed to upload snapshot: {}", source))]
-        UploadSnapshot { source: coldsnap::UploadError },
+        UploadSnapshot {
+            #[snafu(source(from(coldsnap::UploadError, Box::new)))]
+            source: Box<coldsnap::UploadError>,
+        },
     }
 }
 pub(crate) use error::Error;
@@ -41,7 +41,7 @@ pub(crate) async fn wait_for_ami(
         // Use a new client each time so we have more confidence that different endpoints can see
         // the new AMI.
         let ec2_client =
-            build_client::<Ec2Client>(&region, &sts_region, &aws).context(error::ClientSnafu {
+            build_client::<Ec2Client>(region, sts_region, aws).context(error::ClientSnafu {
                 client_type: "EC2",
                 region: region.name(),
             })?;
@@ -108,6 +108,7 @@ pub(crate) async fn wait_for_ami(
 
 mod error {
     use crate::aws;
+    use rusoto_core::RusotoError;
     use snafu::Snafu;
 
     #[derive(Debug, Snafu)]
@@ -123,7 +124,8 @@ mod error {
         #[snafu(display("Failed to describe images in {}: {}", region, source))]
         DescribeImages {
             region: String,
-            source: rusoto_core::RusotoError<rusoto_ec2::DescribeImagesError>,
+            #[snafu(source(from(RusotoError<rusoto_ec2::DescribeImagesError>, Box::new)))]
+            source: Box<RusotoError<rusoto_ec2::DescribeImagesError>>,
         },
 
         #[snafu(display(
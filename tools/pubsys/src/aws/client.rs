@@ -1,17 +1,89 @@
+use crate::aws::partition::Partition;
+use crate::proxy::{ProxyConnector, ProxySettings};
 use async_trait::async_trait;
-use pubsys_config::AwsConfig;
-use rusoto_core::{request::DispatchSignedRequest, HttpClient, Region};
+use hyper_rustls::HttpsConnector;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use pubsys_config::{AssumeRoleConfig, AwsConfig};
+use rusoto_core::request::{DispatchSignedRequest, DispatchSignedRequestFuture};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{HttpClient, Region};
 use rusoto_credential::{
     AutoRefreshingProvider, AwsCredentials, CredentialsError, DefaultCredentialsProvider,
     ProfileProvider, ProvideAwsCredentials,
 };
 use rusoto_ebs::EbsClient;
 use rusoto_ec2::Ec2Client;
+use rusoto_kms::KmsClient;
+use rusoto_s3::S3Client;
 use rusoto_ssm::SsmClient;
 use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
-use snafu::ResultExt;
+use snafu::{ensure, ResultExt};
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+/// The session name we use when assuming roles, unless the user gives one for --assume-role
+pub(crate) const DEFAULT_SESSION_NAME: &str = "pubsys";
+
+lazy_static! {
+    /// Tags given with the global --request-tag argument, set once at startup.  They're added to
+    /// the resources we create, where the API lets us tag them, so they can be found in
+    /// CloudTrail.
+    static ref REQUEST_TAGS: RwLock<Vec<(String, String)>> = RwLock::new(Vec::new());
+
+    /// Clients we've built, so each region and service gets one client, and one connection pool,
+    /// for the whole run rather than one per call.  Rusoto clients are cheap handles to shared
+    /// state, so we hand out clones.
+    static ref CLIENTS: Mutex<HashMap<ClientKey, Box<dyn Any + Send + Sync>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Everything that makes one cached client differ from another
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ClientKey {
+    service: &'static str,
+    region: Region,
+    sts_region: Region,
+    profile: Option<String>,
+    roles: Vec<String>,
+}
+
+/// Roles from Infra.toml are given by ARN alone, and assumed with our default session name.
+fn role_from_arn(arn: String) -> AssumeRoleConfig {
+    AssumeRoleConfig {
+        arn,
+        external_id: None,
+        session_name: DEFAULT_SESSION_NAME.to_string(),
+    }
+}
+
+/// Sets the tags to add to the resources we create; see `request_tags`.
+pub(crate) fn set_request_tags(tags: Vec<(String, String)>) {
+    *REQUEST_TAGS.write().expect("request tags lock poisoned") = tags;
+}
+
+/// Returns the key and value of each tag given with --request-tag.
+pub(crate) fn request_tags() -> Vec<(String, String)> {
+    REQUEST_TAGS
+        .read()
+        .expect("request tags lock poisoned")
+        .clone()
+}
+
+/// Parses a KEY=VALUE argument to --request-tag.  The value may be empty, but the key may not.
+pub(crate) fn parse_request_tag(input: &str) -> Result<(String, String)> {
+    match input.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => error::RequestTagSnafu { input }.fail(),
+    }
+}
+
+pub(crate) trait NewWith: Clone + Send + Sync + 'static {
+    /// The service's name as used in its endpoint, e.g. "ec2" in "ec2.us-west-2.amazonaws.com"
+    const SERVICE: &'static str;
 
-pub(crate) trait NewWith {
     fn new_with<P, D>(request_dispatcher: D, credentials_provider: P, region: Region) -> Self
     where
         P: ProvideAwsCredentials + Send + Sync + 'static,
@@ -19,6 +91,8 @@ pub(crate) trait NewWith {
 }
 
 impl NewWith for EbsClient {
+    const SERVICE: &'static str = "ebs";
+
     fn new_with<P, D>(request_dispatcher: D, credentials_provider: P, region: Region) -> Self
     where
         P: ProvideAwsCredentials + Send + Sync + 'static,
@@ -29,6 +103,32 @@ impl NewWith for EbsClient {
 }
 
 impl NewWith for Ec2Client {
+    const SERVICE: &'static str = "ec2";
+
+    fn new_with<P, D>(request_dispatcher: D, credentials_provider: P, region: Region) -> Self
+    where
+        P: ProvideAwsCredentials + Send + Sync + 'static,
+        D: DispatchSignedRequest + Send + Sync + 'static,
+    {
+        Self::new_with(request_dispatcher, credentials_provider, region)
+    }
+}
+
+impl NewWith for KmsClient {
+    const SERVICE: &'static str = "kms";
+
+    fn new_with<P, D>(request_dispatcher: D, credentials_provider: P, region: Region) -> Self
+    where
+        P: ProvideAwsCredentials + Send + Sync + 'static,
+        D: DispatchSignedRequest + Send + Sync + 'static,
+    {
+        Self::new_with(request_dispatcher, credentials_provider, region)
+    }
+}
+
+impl NewWith for S3Client {
+    const SERVICE: &'static str = "s3";
+
     fn new_with<P, D>(request_dispatcher: D, credentials_provider: P, region: Region) -> Self
     where
         P: ProvideAwsCredentials + Send + Sync + 'static,
@@ -39,6 +139,8 @@ impl NewWith for Ec2Client {
 }
 
 impl NewWith for SsmClient {
+    const SERVICE: &'static str = "ssm";
+
     fn new_with<P, D>(request_dispatcher: D, credentials_provider: P, region: Region) -> Self
     where
         P: ProvideAwsCredentials + Send + Sync + 'static,
@@ -49,6 +151,8 @@ impl NewWith for SsmClient {
 }
 
 impl NewWith for StsClient {
+    const SERVICE: &'static str = "sts";
+
     fn new_with<P, D>(request_dispatcher: D, credentials_provider: P, region: Region) -> Self
     where
         P: ProvideAwsCredentials + Send + Sync + 'static,
@@ -58,24 +162,189 @@ impl NewWith for StsClient {
     }
 }
 
-/// Create a rusoto client of the given type using the given region and configuration.
+/// Create a rusoto client of the given type using the given region and configuration.  Clients
+/// are cached, so asking again for the same service, regions, and credentials returns a clone of
+/// the first client, reusing its connections.
 pub(crate) fn build_client<T: NewWith>(
     region: &Region,
     sts_region: &Region,
     aws: &AwsConfig,
 ) -> Result<T> {
     let maybe_regional_role = aws.region.get(region.name()).and_then(|r| r.role.clone());
-    let assume_roles = aws.role.iter().chain(maybe_regional_role.iter()).cloned();
+    // The base role from --assume-role, if any, lets one CI identity reach the publishing
+    // account's roles.
+    let assume_roles = aws.base_role.clone().into_iter().chain(
+        aws.role
+            .iter()
+            .chain(maybe_regional_role.iter())
+            .cloned()
+            .map(role_from_arn),
+    );
+
+    let key = ClientKey {
+        service: T::SERVICE,
+        region: region.clone(),
+        sts_region: sts_region.clone(),
+        profile: aws.profile.clone(),
+        roles: assume_roles.clone().map(|role| role.arn).collect(),
+    };
+    // Regions are set up concurrently, so hold the lock while building; that way two callers
+    // can't both miss and build duplicate clients.
+    let mut clients = CLIENTS.lock().expect("client cache lock poisoned");
+    if let Some(client) = clients.get(&key).and_then(|c| c.downcast_ref::<T>()) {
+        debug!("Reusing {} client for {}", T::SERVICE, region.name());
+        return Ok(client.clone());
+    }
+
+    check_partition::<T>(
+        region,
+        sts_region,
+        assume_roles.clone().map(|role| role.arn),
+    )?;
     let provider = build_provider(
-        &sts_region,
+        sts_region,
         assume_roles.clone(),
         base_provider(&aws.profile)?,
     )?;
-    Ok(T::new_with(
-        rusoto_core::HttpClient::new().context(error::HttpClientSnafu)?,
-        provider,
-        region.clone(),
-    ))
+    let client = T::new_with(http_client()?, provider, region.clone());
+    clients.insert(key, Box::new(client.clone()));
+    Ok(client)
+}
+
+/// Returns the account that clients for the given region act in, if the roles they assume tell
+/// us: the account in the ARN of the last role in the chain.  With no roles, it's the account of
+/// the base credentials, which only STS can tell us.
+pub(crate) fn role_account(region: &str, aws: &AwsConfig) -> Option<String> {
+    let base_role = aws.base_role.as_ref().map(|role| role.arn.clone());
+    let role = aws
+        .region
+        .get(region)
+        .and_then(|r| r.role.clone())
+        .or_else(|| aws.role.clone())
+        .or(base_role)?;
+    account_from_arn(&role)
+}
+
+/// Returns the account ID in an IAM ARN, like "123456789012" in
+/// "arn:aws:iam::123456789012:role/publish"
+pub(crate) fn account_from_arn(arn: &str) -> Option<String> {
+    arn.split(':')
+        .nth(4)
+        .filter(|account| account.len() == 12 && account.chars().all(|c| c.is_ascii_digit()))
+        .map(str::to_string)
+}
+
+/// The rusoto version we build against, for our User-Agent; keep in line with Cargo.toml
+const RUSOTO_VERSION: &str = "0.47";
+
+/// Dispatches AWS requests with our own HTTP client, identifying us in the User-Agent of every
+/// request, e.g. "pubsys/0.1.0 rusoto/0.47 linux", so our calls stand out in CloudTrail.  rusoto
+/// only lets its default client, which can't use a proxy, add to the User-Agent itself.
+struct Dispatcher {
+    http_client: HttpClient<HttpsConnector<ProxyConnector>>,
+    user_agent: String,
+}
+
+impl DispatchSignedRequest for Dispatcher {
+    fn dispatch(
+        &self,
+        mut request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        // Requests are already signed, and the User-Agent isn't part of the signature.
+        request.add_header("user-agent", &self.user_agent);
+        self.http_client.dispatch(request, timeout)
+    }
+}
+
+/// Creates a request dispatcher whose connections go through the proxy from the environment, or
+/// from --proxy, if there is one.
+fn http_client() -> Result<Dispatcher> {
+    let proxy_settings = ProxySettings::from_env().context(error::ProxySnafu)?;
+
+    // This is the TLS setup rusoto uses, trusting the system's root certificates.
+    let mut tls_config = rustls::ClientConfig::new();
+    tls_config.root_store = match rustls_native_certs::load_native_certs() {
+        Ok(store) => store,
+        Err((Some(store), e)) => {
+            warn!("Could not load all system certificates: {}", e);
+            store
+        }
+        Err((None, e)) => return Err(e).context(error::CertificatesSnafu),
+    };
+    tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    let connector = HttpsConnector::from((ProxyConnector::new(proxy_settings), tls_config));
+    Ok(Dispatcher {
+        http_client: HttpClient::from_connector(connector),
+        user_agent: format!(
+            "pubsys/{} rusoto/{} {}",
+            env!("CARGO_PKG_VERSION"),
+            RUSOTO_VERSION,
+            std::env::consts::OS
+        ),
+    })
+}
+
+/// Makes sure a client for the given region will stay within one partition: we get credentials
+/// from STS in `sts_region`, and assume roles by ARN, so both must be in the same partition as the
+/// region.  Custom endpoints that don't look like they're in the region's partition get a warning,
+/// since they may be intentional, e.g. for testing.
+fn check_partition<T: NewWith>(
+    region: &Region,
+    sts_region: &Region,
+    assume_roles: impl Iterator<Item = String>,
+) -> Result<()> {
+    let partition = Partition::from_region(region.name());
+    let sts_partition = Partition::from_region(sts_region.name());
+    ensure!(
+        partition == sts_partition,
+        error::PartitionMismatchSnafu {
+            region: region.name(),
+            partition,
+            sts_region: sts_region.name(),
+            sts_partition,
+        }
+    );
+
+    for role in assume_roles {
+        if let Some(role_partition) = Partition::from_arn(&role) {
+            ensure!(
+                role_partition == partition,
+                error::RolePartitionSnafu {
+                    role,
+                    region: region.name(),
+                    partition,
+                }
+            );
+        }
+    }
+
+    match region {
+        Region::Custom { endpoint, .. } => {
+            let host = endpoint
+                .split("://")
+                .last()
+                .and_then(|rest| rest.split(&['/', ':'][..]).next())
+                .unwrap_or_default();
+            if !host.ends_with(partition.dns_suffix()) {
+                warn!(
+                    "Custom endpoint '{}' for {} is not in the {} partition's domain, {}",
+                    endpoint,
+                    region.name(),
+                    partition,
+                    partition.dns_suffix()
+                );
+            }
+        }
+        _ => debug!(
+            "Using {} endpoint {}",
+            T::SERVICE,
+            partition.endpoint(T::SERVICE, region.name())
+        ),
+    }
+
+    Ok(())
 }
 
 /// Wrapper for trait object that implements ProvideAwsCredentials to simplify return values.
@@ -89,7 +358,8 @@ impl ProvideAwsCredentials for CredentialsProvider {
     }
 }
 
-/// Chains credentials providers to assume the given roles in order.
+/// Chains credentials providers to assume the given roles in order.  Each assumed role's
+/// credentials are refreshed automatically shortly before they expire, so long runs keep working.
 /// The region given should be the one in which you want to talk to STS to get temporary
 /// credentials, not the region in which you want to talk to a service endpoint like EC2.  This is
 /// needed because you may be assuming a role in an opt-in region from an account that has not
@@ -97,7 +367,7 @@ impl ProvideAwsCredentials for CredentialsProvider {
 /// region to which you have access in the base account.
 fn build_provider<P>(
     sts_region: &Region,
-    assume_roles: impl Iterator<Item = String>,
+    assume_roles: impl Iterator<Item = AssumeRoleConfig>,
     base_provider: P,
 ) -> Result<CredentialsProvider>
 where
@@ -105,19 +375,15 @@ where
 {
     let mut provider = CredentialsProvider(Box::new(base_provider));
     for assume_role in assume_roles {
-        let sts = StsClient::new_with(
-            HttpClient::new().context(error::HttpClientSnafu)?,
-            provider,
-            sts_region.clone(),
-        );
+        let sts = StsClient::new_with(http_client()?, provider, sts_region.clone());
         let expiring_provider = StsAssumeRoleSessionCredentialsProvider::new(
             sts,
-            assume_role,
-            "pubsys".to_string(), // session name
-            None,                 // external ID
-            None,                 // session duration
-            None,                 // scope down policy
-            None,                 // MFA serial
+            assume_role.arn,
+            assume_role.session_name,
+            assume_role.external_id,
+            None, // session duration
+            None, // scope down policy
+            None, // MFA serial
         );
         provider = CredentialsProvider(Box::new(
             AutoRefreshingProvider::new(expiring_provider).context(error::ProviderSnafu)?,
@@ -141,20 +407,52 @@ fn base_provider(maybe_profile: &Option<String>) -> Result<CredentialsProvider>
 }
 
 pub(crate) mod error {
+    use crate::aws::partition::Partition;
     use snafu::Snafu;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub(crate) enum Error {
-        #[snafu(display("Failed to create HTTP client: {}", source))]
-        HttpClient {
-            source: rusoto_core::request::TlsError,
+        #[snafu(display("Failed to load system root certificates: {}", source))]
+        Certificates { source: std::io::Error },
+
+        #[snafu(display(
+            "Region {} is in the {} partition, but credentials come from {} in the {} partition; \
+             regions from different partitions can't be used together",
+            region,
+            partition,
+            sts_region,
+            sts_partition
+        ))]
+        PartitionMismatch {
+            region: String,
+            partition: Partition,
+            sts_region: String,
+            sts_partition: Partition,
         },
 
         #[snafu(display("Failed to create AWS credentials provider: {}", source))]
         Provider {
             source: rusoto_credential::CredentialsError,
         },
+
+        #[snafu(display("Failed to read proxy settings: {}", source))]
+        Proxy { source: crate::proxy::Error },
+
+        #[snafu(display("Invalid request tag '{}', expected KEY=VALUE", input))]
+        RequestTag { input: String },
+
+        #[snafu(display(
+            "Role {} can't be used in {}, which is in the {} partition",
+            role,
+            region,
+            partition
+        ))]
+        RolePartition {
+            role: String,
+            region: String,
+            partition: Partition,
+        },
     }
 }
 pub(crate) use error::Error;
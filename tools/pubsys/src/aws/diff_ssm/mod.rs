@@ -0,0 +1,458 @@
+//! The diff_ssm module owns the 'diff-ssm' subcommand and compares the SSM parameters of two
+//! versions across regions, so you can check before promoting that the source version has the
+//! same parameters in the same regions as the target, and see which values will change.
+
+use crate::aws::client::build_client;
+use crate::aws::ssm::{ssm, template, BuildContext, ParameterPrefix, SsmKey};
+use crate::aws::{exclude_regions, parse_arch, region_from_string, regions_from_file};
+use crate::output::{self, OutputFormat};
+use crate::{normalize_version, Args};
+use log::{info, trace, warn};
+use rusoto_core::Region;
+use rusoto_ssm::SsmClient;
+use serde::Serialize;
+use snafu::{ensure, ResultExt};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Compares the SSM parameters of two versions
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct DiffSsmArgs {
+    /// The architecture of the machine image
+    #[structopt(long, parse(try_from_str = parse_arch))]
+    arch: String,
+
+    /// The variant name for the current build
+    #[structopt(long)]
+    variant: String,
+
+    /// Version number (or string, like 'latest') to compare from, like the source of a promotion
+    #[structopt(long)]
+    source: String,
+
+    /// Version number (or string, like 'latest') to compare to, like the target of a promotion
+    #[structopt(long)]
+    target: String,
+
+    /// Comma-separated list of regions to compare, overriding Infra.toml
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// File listing regions to use instead of --regions, separated by newlines or commas
+    #[structopt(long, parse(from_os_str), conflicts_with = "regions")]
+    regions_file: Option<PathBuf>,
+
+    /// Region to leave out of the regions given or from Infra.toml; can be given more than once
+    #[structopt(long = "exclude-region", number_of_values = 1)]
+    exclude_regions: Vec<String>,
+
+    /// File holding the parameter templates
+    #[structopt(long)]
+    template_path: PathBuf,
+}
+
+/// One parameter, from the same template and in the same region, in both versions
+#[derive(Debug, PartialEq, Serialize)]
+struct ParameterDiff {
+    region: String,
+    source_name: String,
+    target_name: String,
+    source_value: Option<String>,
+    target_value: Option<String>,
+}
+
+/// The differences between the parameters of the two versions, for reporting results
+#[derive(Debug, Default, Serialize)]
+struct SsmDiff {
+    /// Parameters the source version has, but the target doesn't
+    only_in_source: Vec<ParameterDiff>,
+    /// Parameters the target version has, but the source doesn't
+    only_in_target: Vec<ParameterDiff>,
+    /// Parameters neither version has
+    missing_in_both: Vec<ParameterDiff>,
+    /// Parameters both versions have, with different values
+    changed: Vec<ParameterDiff>,
+    /// The number of parameters both versions have with the same value
+    unchanged: usize,
+}
+
+impl SsmDiff {
+    /// Returns whether both versions have the same parameters in the same regions, whatever
+    /// their values.
+    fn same_shape(&self) -> bool {
+        self.only_in_source.is_empty()
+            && self.only_in_target.is_empty()
+            && self.missing_in_both.is_empty()
+    }
+}
+
+/// Summary of the comparison, for reporting results
+#[derive(Debug, Serialize)]
+struct DiffSsmSummary<'a> {
+    source: &'a str,
+    target: &'a str,
+    #[serde(flatten)]
+    diff: &'a SsmDiff,
+}
+
+/// Compares the parameters of each pair of source and target keys, which were rendered from the
+/// same template in the same region.
+fn diff_parameters(
+    pairs: &[(SsmKey, SsmKey)],
+    source_parameters: &HashMap<SsmKey, String>,
+    target_parameters: &HashMap<SsmKey, String>,
+) -> SsmDiff {
+    let mut diff = SsmDiff::default();
+    for (source_key, target_key) in pairs {
+        let source_value = source_parameters.get(source_key);
+        let target_value = target_parameters.get(target_key);
+        if source_value.is_some() && source_value == target_value {
+            diff.unchanged += 1;
+            continue;
+        }
+        let list = match (source_value, target_value) {
+            (Some(_), Some(_)) => &mut diff.changed,
+            (Some(_), None) => &mut diff.only_in_source,
+            (None, Some(_)) => &mut diff.only_in_target,
+            (None, None) => &mut diff.missing_in_both,
+        };
+        list.push(ParameterDiff {
+            region: source_key.region.name().to_string(),
+            source_name: source_key.name.clone(),
+            target_name: target_key.name.clone(),
+            source_value: source_value.cloned(),
+            target_value: target_value.cloned(),
+        });
+    }
+    diff
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, diff_args: &DiffSsmArgs) -> Result<()> {
+    // Setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:#?}", infra_config);
+    let aws = infra_config.aws.unwrap_or_else(Default::default);
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config;
+    // either way, leave out any excluded regions.
+    let regions = exclude_regions(
+        if let Some(path) = &diff_args.regions_file {
+            regions_from_file(path, &aws).context(error::RegionsFileSnafu)?
+        } else if !diff_args.regions.is_empty() {
+            diff_args.regions.clone()
+        } else {
+            aws.regions.clone().into()
+        },
+        &diff_args.exclude_regions,
+    )
+    .into_iter()
+    .map(|name| region_from_string(&name, &aws).context(error::ParseRegionSnafu))
+    .collect::<Result<Vec<Region>>>()?;
+
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = &regions[0];
+
+    let mut ssm_clients = HashMap::with_capacity(regions.len());
+    for region in &regions {
+        let ssm_client =
+            build_client::<SsmClient>(region, base_region, &aws).context(error::ClientSnafu {
+                client_type: "SSM",
+                region: region.name(),
+            })?;
+        ssm_clients.insert(region.clone(), ssm_client);
+    }
+
+    // Template setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    // Versions are normalized so we look up parameters under the same name `pubsys ssm` wrote them.
+    let source_version = normalize_version(&diff_args.source);
+    let source_build_context = BuildContext {
+        variant: &diff_args.variant,
+        arch: &diff_args.arch,
+        image_version: &source_version,
+    };
+    let target_version = normalize_version(&diff_args.target);
+    let target_build_context = BuildContext {
+        variant: &diff_args.variant,
+        arch: &diff_args.arch,
+        image_version: &target_version,
+    };
+
+    info!(
+        "Parsing SSM parameter templates from {}",
+        diff_args.template_path.display()
+    );
+    // Doesn't matter which build context we use to find template files because version isn't used
+    // in their naming
+    let template_parameters =
+        template::get_parameters(&diff_args.template_path, &source_build_context)
+            .context(error::FindTemplatesSnafu)?;
+
+    // Names can include the region, so render them for each region, pairing the source and target
+    // names rendered from the same template.
+    let prefix = ParameterPrefix::new(&aws);
+    let mut pairs = Vec::new();
+    for region in &regions {
+        let source_names = template::render_parameter_names(
+            &template_parameters,
+            &prefix,
+            &source_build_context,
+            region,
+        )
+        .context(error::RenderTemplatesSnafu)?;
+        let mut target_names: Vec<(String, String)> = template::render_parameter_names(
+            &template_parameters,
+            &prefix,
+            &target_build_context,
+            region,
+        )
+        .context(error::RenderTemplatesSnafu)?
+        .into_iter()
+        .collect();
+        target_names.sort();
+        for (template_name, target_name) in target_names {
+            let source_name = source_names[&template_name].clone();
+            pairs.push((
+                SsmKey::new(region.clone(), source_name),
+                SsmKey::new(region.clone(), target_name),
+            ));
+        }
+    }
+
+    // SSM get   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    info!("Getting SSM parameters for version {}", source_version);
+    let source_keys: Vec<&SsmKey> = pairs.iter().map(|(source, _)| source).collect();
+    let source_parameters = ssm::get_parameters(&source_keys, &ssm_clients, &aws)
+        .await
+        .context(error::FetchSsmSnafu)?;
+    trace!("Source SSM parameters: {:#?}", source_parameters);
+
+    info!("Getting SSM parameters for version {}", target_version);
+    let target_keys: Vec<&SsmKey> = pairs.iter().map(|(_, target)| target).collect();
+    let target_parameters = ssm::get_parameters(&target_keys, &ssm_clients, &aws)
+        .await
+        .context(error::FetchSsmSnafu)?;
+    trace!("Target SSM parameters: {:#?}", target_parameters);
+
+    let diff = diff_parameters(&pairs, &source_parameters, &target_parameters);
+
+    // Report   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    match args.output {
+        OutputFormat::Text => print_table(&diff),
+        OutputFormat::Json => {
+            let summary = DiffSsmSummary {
+                source: &source_version,
+                target: &target_version,
+                diff: &diff,
+            };
+            output::report(args.output, &summary)?;
+        }
+    }
+
+    for (list, version) in &[
+        (&diff.only_in_source, &target_version),
+        (&diff.only_in_target, &source_version),
+    ] {
+        for parameter in list.iter() {
+            warn!(
+                "Version {} is missing a parameter in {}: {}",
+                version, parameter.region, parameter.target_name
+            );
+        }
+    }
+    for parameter in &diff.missing_in_both {
+        warn!(
+            "Neither version has a parameter in {}: {}",
+            parameter.region, parameter.target_name
+        );
+    }
+    info!(
+        "{} parameters differ in value between {} and {}, and {} are the same",
+        diff.changed.len(),
+        source_version,
+        target_version,
+        diff.unchanged
+    );
+    ensure!(
+        diff.same_shape(),
+        error::ShapeMismatchSnafu {
+            source_version: &source_version,
+            target_version: &target_version,
+            count: diff.only_in_source.len()
+                + diff.only_in_target.len()
+                + diff.missing_in_both.len(),
+        }
+    );
+
+    Ok(())
+}
+
+/// Prints a table of the parameters that differ between the versions, one row per parameter,
+/// named as in the target version.
+fn print_table(diff: &SsmDiff) {
+    let mut rows = vec![("REGION", "NAME", "SOURCE", "TARGET")];
+    for parameter in diff
+        .only_in_source
+        .iter()
+        .chain(&diff.only_in_target)
+        .chain(&diff.missing_in_both)
+        .chain(&diff.changed)
+    {
+        rows.push((
+            &parameter.region,
+            &parameter.target_name,
+            parameter.source_value.as_deref().unwrap_or("<missing>"),
+            parameter.target_value.as_deref().unwrap_or("<missing>"),
+        ));
+    }
+
+    let region_width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0);
+    let name_width = rows.iter().map(|r| r.1.len()).max().unwrap_or(0);
+    let source_width = rows.iter().map(|r| r.2.len()).max().unwrap_or(0);
+    for (region, name, source, target) in rows {
+        println!(
+            "{:region_width$}  {:name_width$}  {:source_width$}  {}",
+            region,
+            name,
+            source,
+            target,
+            region_width = region_width,
+            name_width = name_width,
+            source_width = source_width
+        );
+    }
+}
+
+mod error {
+    use crate::aws;
+    use crate::aws::ssm::{ssm, template};
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Error creating {} client in {}: {}", client_type, region, source))]
+        Client {
+            client_type: String,
+            region: String,
+            source: aws::client::Error,
+        },
+
+        #[snafu(display("Error reading config: {}", source))]
+        Config {
+            source: pubsys_config::Error,
+        },
+
+        #[snafu(display("Failed to fetch parameters from SSM: {}", source))]
+        FetchSsm {
+            source: ssm::Error,
+        },
+
+        #[snafu(display("Failed to find templates: {}", source))]
+        FindTemplates {
+            source: template::Error,
+        },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig {
+            missing: String,
+        },
+
+        #[snafu(context(false), display("{}", source))]
+        Output {
+            source: crate::output::Error,
+        },
+
+        ParseRegion {
+            source: crate::aws::Error,
+        },
+
+        #[snafu(display("Failed to read regions file: {}", source))]
+        RegionsFile {
+            source: crate::aws::Error,
+        },
+
+        #[snafu(display("Failed to render templates: {}", source))]
+        RenderTemplates {
+            source: template::Error,
+        },
+
+        #[snafu(display(
+            "{} parameters exist for only one of versions {} and {}, or neither, see above",
+            count,
+            source_version,
+            target_version
+        ))]
+        ShapeMismatch {
+            source_version: String,
+            target_version: String,
+            count: usize,
+        },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::diff_parameters;
+    use crate::aws::ssm::SsmKey;
+    use rusoto_core::Region;
+    use std::collections::HashMap;
+
+    #[test]
+    fn diff_shapes_and_values() {
+        let key = |region: &Region, version: &str, name: &str| {
+            SsmKey::new(
+                region.clone(),
+                format!("/aws/service/bottlerocket/{}/{}", version, name),
+            )
+        };
+        let (west, east) = (Region::UsWest2, Region::UsEast1);
+        let pairs: Vec<_> = [&west, &east]
+            .iter()
+            .flat_map(|region| {
+                ["image_id", "image_version"]
+                    .iter()
+                    .map(move |name| (key(region, "1.2.0", name), key(region, "latest", name)))
+            })
+            .collect();
+
+        let mut source = HashMap::new();
+        let mut target = HashMap::new();
+        // Same value, and a new image ID, in us-west-2.
+        source.insert(key(&west, "1.2.0", "image_version"), "1.2.0".to_string());
+        target.insert(key(&west, "latest", "image_version"), "1.2.0".to_string());
+        source.insert(key(&west, "1.2.0", "image_id"), "ami-new".to_string());
+        target.insert(key(&west, "latest", "image_id"), "ami-old".to_string());
+        // The source version was missed in us-east-1's set step.
+        target.insert(key(&east, "latest", "image_id"), "ami-old".to_string());
+
+        let diff = diff_parameters(&pairs, &source, &target);
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].source_value.as_deref(), Some("ami-new"));
+        assert_eq!(diff.changed[0].target_value.as_deref(), Some("ami-old"));
+        assert!(diff.only_in_source.is_empty());
+        assert_eq!(diff.only_in_target.len(), 1);
+        assert_eq!(diff.only_in_target[0].region, "us-east-1");
+        assert_eq!(
+            diff.only_in_target[0].target_name,
+            "/aws/service/bottlerocket/latest/image_id"
+        );
+        assert_eq!(diff.missing_in_both.len(), 1);
+        assert!(!diff.same_shape());
+    }
+}
@@ -0,0 +1,276 @@
+//! The get_ssm module owns the 'get-ssm' subcommand and controls the process of reading back the
+//! SSM parameters for a given version, so you can check that a release or promotion landed in
+//! every region.
+
+use crate::aws::client::build_client;
+use crate::aws::ssm::{ssm, template, BuildContext, ParameterPrefix, SsmKey, SsmSummary};
+use crate::aws::{exclude_regions, parse_arch, region_from_string, regions_from_file};
+use crate::output::{self, OutputFormat};
+use crate::{normalize_version, Args};
+use log::{info, trace, warn};
+use rusoto_core::Region;
+use rusoto_ssm::SsmClient;
+use serde::Serialize;
+use snafu::{ensure, ResultExt};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Fetches the current values of SSM parameters
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct GetSsmArgs {
+    /// The architecture of the machine image
+    #[structopt(long, parse(try_from_str = parse_arch))]
+    arch: String,
+
+    /// The variant name for the current build
+    #[structopt(long)]
+    variant: String,
+
+    /// Version number (or string, like 'latest') to fetch
+    #[structopt(long)]
+    version: String,
+
+    /// Comma-separated list of regions to check, overriding Infra.toml
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// File listing regions to use instead of --regions, separated by newlines or commas
+    #[structopt(long, parse(from_os_str), conflicts_with = "regions")]
+    regions_file: Option<PathBuf>,
+
+    /// Region to leave out of the regions given or from Infra.toml; can be given more than once
+    #[structopt(long = "exclude-region", number_of_values = 1)]
+    exclude_regions: Vec<String>,
+
+    /// File holding the parameter templates
+    #[structopt(long)]
+    template_path: PathBuf,
+}
+
+/// Summary of the parameters we found, and didn't find, for reporting results
+#[derive(Debug, Serialize)]
+struct GetSsmSummary<'a> {
+    version: &'a str,
+    #[serde(flatten)]
+    found: SsmSummary,
+    /// Map of parameter name to the regions where it doesn't exist
+    missing: BTreeMap<&'a str, Vec<&'a str>>,
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, get_args: &GetSsmArgs) -> Result<()> {
+    // Setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:#?}", infra_config);
+    let aws = infra_config.aws.unwrap_or_else(Default::default);
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config;
+    // either way, leave out any excluded regions.
+    let regions = exclude_regions(
+        if let Some(path) = &get_args.regions_file {
+            regions_from_file(path, &aws).context(error::RegionsFileSnafu)?
+        } else if !get_args.regions.is_empty() {
+            get_args.regions.clone()
+        } else {
+            aws.regions.clone().into()
+        },
+        &get_args.exclude_regions,
+    )
+    .into_iter()
+    .map(|name| region_from_string(&name, &aws).context(error::ParseRegionSnafu))
+    .collect::<Result<Vec<Region>>>()?;
+
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = &regions[0];
+
+    let mut ssm_clients = HashMap::with_capacity(regions.len());
+    for region in &regions {
+        let ssm_client =
+            build_client::<SsmClient>(region, base_region, &aws).context(error::ClientSnafu {
+                client_type: "SSM",
+                region: region.name(),
+            })?;
+        ssm_clients.insert(region.clone(), ssm_client);
+    }
+
+    // Template setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    let version = normalize_version(&get_args.version);
+    let build_context = BuildContext {
+        variant: &get_args.variant,
+        arch: &get_args.arch,
+        image_version: &version,
+    };
+
+    info!(
+        "Parsing SSM parameter templates from {}",
+        get_args.template_path.display()
+    );
+    let template_parameters = template::get_parameters(&get_args.template_path, &build_context)
+        .context(error::FindTemplatesSnafu)?;
+
+    // SSM get   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    // Names can include the region, so render them for each region.
+    let prefix = ParameterPrefix::new(&aws);
+    let mut keys = Vec::new();
+    for region in &regions {
+        let mut names: Vec<String> =
+            template::render_parameter_names(&template_parameters, &prefix, &build_context, region)
+                .context(error::RenderTemplatesSnafu)?
+                .into_values()
+                .collect();
+        names.sort();
+        keys.extend(
+            names
+                .into_iter()
+                .map(|name| SsmKey::new(region.clone(), name)),
+        );
+    }
+
+    info!("Getting current SSM parameters for version {}", version);
+    let parameters = ssm::get_parameters(&keys, &ssm_clients, &aws)
+        .await
+        .context(error::FetchSsmSnafu)?;
+    trace!("Current SSM parameters: {:#?}", parameters);
+
+    // Find which regions lack each parameter, so the user knows where to look.
+    let mut missing: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for key in &keys {
+        if !parameters.contains_key(key) {
+            missing
+                .entry(key.name.as_str())
+                .or_default()
+                .push(key.region.name());
+        }
+    }
+
+    // Report   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+    match args.output {
+        OutputFormat::Text => print_table(&keys, &parameters),
+        OutputFormat::Json => {
+            let summary = GetSsmSummary {
+                version: &version,
+                found: SsmSummary::from(&parameters),
+                missing: missing.clone(),
+            };
+            output::report(args.output, &summary)?;
+        }
+    }
+
+    for (name, regions) in &missing {
+        warn!("{} is missing in: {}", name, regions.join(", "));
+    }
+    ensure!(
+        missing.is_empty(),
+        error::MissingParametersSnafu {
+            version: &version,
+            count: missing.len(),
+        }
+    );
+
+    Ok(())
+}
+
+/// Prints a table of parameter values, one row per parameter key, in the order given.
+fn print_table(keys: &[SsmKey], parameters: &HashMap<SsmKey, String>) {
+    let mut rows = vec![("REGION", "NAME", "VALUE")];
+    for key in keys {
+        let value = parameters
+            .get(key)
+            .map(|v| v.as_str())
+            .unwrap_or("<missing>");
+        rows.push((key.region.name(), &key.name, value));
+    }
+
+    let region_width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0);
+    let name_width = rows.iter().map(|r| r.1.len()).max().unwrap_or(0);
+    for (region, name, value) in rows {
+        println!(
+            "{:region_width$}  {:name_width$}  {}",
+            region,
+            name,
+            value,
+            region_width = region_width,
+            name_width = name_width
+        );
+    }
+}
+
+mod error {
+    use crate::aws;
+    use crate::aws::ssm::{ssm, template};
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Error creating {} client in {}: {}", client_type, region, source))]
+        Client {
+            client_type: String,
+            region: String,
+            source: aws::client::Error,
+        },
+
+        #[snafu(display("Error reading config: {}", source))]
+        Config {
+            source: pubsys_config::Error,
+        },
+
+        #[snafu(display("Failed to fetch parameters from SSM: {}", source))]
+        FetchSsm {
+            source: ssm::Error,
+        },
+
+        #[snafu(display("Failed to find templates: {}", source))]
+        FindTemplates {
+            source: template::Error,
+        },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig {
+            missing: String,
+        },
+
+        #[snafu(display(
+            "{} parameters for version {} are missing in some regions, see above",
+            count,
+            version
+        ))]
+        MissingParameters {
+            version: String,
+            count: usize,
+        },
+
+        #[snafu(context(false), display("{}", source))]
+        Output {
+            source: crate::output::Error,
+        },
+
+        ParseRegion {
+            source: crate::aws::Error,
+        },
+
+        #[snafu(display("Failed to read regions file: {}", source))]
+        RegionsFile {
+            source: crate::aws::Error,
+        },
+
+        #[snafu(display("Failed to render templates: {}", source))]
+        RenderTemplates {
+            source: template::Error,
+        },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
@@ -0,0 +1,386 @@
+//! The latest_ami module owns the 'latest-ami' subcommand and controls the process of moving a
+//! "latest" tag to newly published AMIs, so the current image in each region can be found by tag
+//! the way SSM's named 'latest' parameters find it by name.
+
+use crate::aws::ami::Image;
+use crate::aws::client::build_client;
+use crate::aws::{exclude_regions, region_from_string, regions_from_file};
+use crate::{output, Args};
+use futures::future::{join, ready};
+use futures::stream::{self, StreamExt};
+use log::{error, info, trace};
+use rusoto_core::Region;
+use rusoto_ec2::{
+    CreateTagsRequest, DeleteTagsRequest, DescribeImagesRequest, Ec2, Ec2Client, Filter, Tag,
+};
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Moves a "latest" tag to the given AMIs, removing it from the AMIs that had it before
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct LatestAmiArgs {
+    /// Path to the JSON file containing regional AMI IDs to tag
+    #[structopt(long)]
+    ami_input: PathBuf,
+
+    /// Comma-separated list of regions to tag in, overriding Infra.toml; given regions must be
+    /// in the --ami-input file
+    #[structopt(long, use_delimiter = true)]
+    regions: Vec<String>,
+
+    /// File listing regions to use instead of --regions, separated by newlines or commas
+    #[structopt(long, parse(from_os_str), conflicts_with = "regions")]
+    regions_file: Option<PathBuf>,
+
+    /// Region to leave out of the regions given or from Infra.toml; can be given more than once
+    #[structopt(long = "exclude-region", number_of_values = 1)]
+    exclude_regions: Vec<String>,
+
+    /// Key of the tag marking the latest AMI; use a different key for each variant and arch
+    #[structopt(long, default_value = "bottlerocket-latest")]
+    tag_key: String,
+
+    /// Value of the tag marking the latest AMI
+    #[structopt(long, default_value = "true")]
+    tag_value: String,
+
+    /// Only report which AMIs would gain or lose the tag, without changing anything
+    #[structopt(long)]
+    dry_run: bool,
+}
+
+/// The tag changes for one region
+#[derive(Debug, Serialize)]
+struct TagChange {
+    /// The AMI that's now latest
+    image_id: String,
+    /// Whether that AMI gained the tag; false if it already had it
+    gained: bool,
+    /// Other AMIs that lost the tag
+    lost: Vec<String>,
+}
+
+/// Summary of the tag changes we made, or would make in a dry run, for reporting results
+#[derive(Debug, Serialize)]
+struct LatestAmiSummary<'a> {
+    tag_key: &'a str,
+    tag_value: &'a str,
+    dry_run: bool,
+    /// Map of region name to the tag changes there
+    regions: HashMap<&'a str, &'a TagChange>,
+}
+
+/// Common entrypoint from main()
+pub(crate) async fn run(args: &Args, latest_args: &LatestAmiArgs) -> Result<()> {
+    info!(
+        "Using AMI data from path: {}",
+        latest_args.ami_input.display()
+    );
+    let file = File::open(&latest_args.ami_input).context(error::FileSnafu {
+        op: "open",
+        path: &latest_args.ami_input,
+    })?;
+    let mut ami_input: HashMap<String, Image> =
+        serde_json::from_reader(file).context(error::DeserializeSnafu {
+            path: &latest_args.ami_input,
+        })?;
+    trace!("Parsed AMI input: {:?}", ami_input);
+    ensure!(
+        !ami_input.is_empty(),
+        error::InputSnafu {
+            path: &latest_args.ami_input
+        }
+    );
+
+    // If a lock file exists, use that, otherwise use Infra.toml or default
+    let infra_config = args.infra_config(true).context(error::ConfigSnafu)?;
+    trace!("Using infra config: {:?}", infra_config);
+    let aws = infra_config.aws.unwrap_or_else(Default::default);
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config;
+    // either way, leave out any excluded regions.
+    let regions = exclude_regions(
+        if let Some(path) = &latest_args.regions_file {
+            regions_from_file(path, &aws).context(error::RegionsFileSnafu)?
+        } else if !latest_args.regions.is_empty() {
+            latest_args.regions.clone()
+        } else {
+            aws.regions.clone().into()
+        },
+        &latest_args.exclude_regions,
+    );
+    ensure!(
+        !regions.is_empty(),
+        error::MissingConfigSnafu {
+            missing: "aws.regions"
+        }
+    );
+    let base_region = region_from_string(&regions[0], &aws).context(error::ParseRegionSnafu)?;
+
+    let mut amis = HashMap::with_capacity(regions.len());
+    for name in regions {
+        let image = ami_input
+            .remove(&name)
+            .context(error::UnknownRegionSnafu { region: &name })?;
+        let region = region_from_string(&name, &aws).context(error::ParseRegionSnafu)?;
+        amis.insert(region, image);
+    }
+
+    let mut ec2_clients = HashMap::with_capacity(amis.len());
+    for region in amis.keys() {
+        let ec2_client =
+            build_client::<Ec2Client>(region, &base_region, &aws).context(error::ClientSnafu {
+                client_type: "EC2",
+                region: region.name(),
+            })?;
+        ec2_clients.insert(region.clone(), ec2_client);
+    }
+
+    let tag = Tag {
+        key: Some(latest_args.tag_key.clone()),
+        value: Some(latest_args.tag_value.clone()),
+    };
+    info!(
+        "{} tag {}={} to the given AMIs",
+        if latest_args.dry_run {
+            "Dry run: would move"
+        } else {
+            "Moving"
+        },
+        latest_args.tag_key,
+        latest_args.tag_value
+    );
+    let mut requests = Vec::with_capacity(amis.len());
+    for (region, image) in &amis {
+        let move_future = move_tag(
+            &image.id,
+            &tag,
+            latest_args.dry_run,
+            region,
+            &ec2_clients[region],
+        );
+        let info_future = ready(region.clone());
+        requests.push(join(info_future, move_future));
+    }
+
+    // Send requests in parallel and wait for responses, collecting results into a list.
+    let request_stream = stream::iter(requests).buffer_unordered(4);
+    let responses: Vec<(Region, Result<TagChange>)> = request_stream.collect().await;
+
+    let mut changes = HashMap::with_capacity(responses.len());
+    let mut error_count = 0u16;
+    for (region, response) in responses {
+        match response {
+            Ok(change) => {
+                info!(
+                    "In {}, {} is latest{}{}",
+                    region.name(),
+                    change.image_id,
+                    if change.gained { " (newly tagged)" } else { "" },
+                    if change.lost.is_empty() {
+                        String::new()
+                    } else {
+                        format!(", untagged {}", change.lost.join(", "))
+                    }
+                );
+                changes.insert(region, change);
+            }
+            Err(e) => {
+                error_count += 1;
+                error!("Failed to move latest tag in {}: {}", region.name(), e);
+            }
+        }
+    }
+    ensure!(
+        error_count == 0,
+        error::MoveTagSnafu {
+            error_count,
+            total: amis.len(),
+        }
+    );
+
+    let summary = LatestAmiSummary {
+        tag_key: &latest_args.tag_key,
+        tag_value: &latest_args.tag_value,
+        dry_run: latest_args.dry_run,
+        regions: changes
+            .iter()
+            .map(|(region, change)| (region.name(), change))
+            .collect(),
+    };
+    output::report(args.output, &summary)?;
+    Ok(())
+}
+
+/// Moves the tag in one region: tags the given AMI first, then removes the tag from any other AMI
+/// we own that has it, so there's never a moment with no latest AMI.
+async fn move_tag(
+    image_id: &str,
+    tag: &Tag,
+    dry_run: bool,
+    region: &Region,
+    ec2_client: &Ec2Client,
+) -> Result<TagChange> {
+    let key = tag.key.as_deref().unwrap_or_default();
+    let describe_request = DescribeImagesRequest {
+        owners: Some(vec!["self".to_string()]),
+        filters: Some(vec![Filter {
+            name: Some(format!("tag:{}", key)),
+            values: tag.value.clone().map(|v| vec![v]),
+        }]),
+        ..Default::default()
+    };
+    let tagged: Vec<String> = ec2_client
+        .describe_images(describe_request)
+        .await
+        .context(error::DescribeImagesSnafu {
+            region: region.name(),
+        })?
+        .images
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|image| image.image_id)
+        .collect();
+
+    let gained = !tagged.iter().any(|id| id == image_id);
+    let lost: Vec<String> = tagged.into_iter().filter(|id| id != image_id).collect();
+
+    if !dry_run {
+        if gained {
+            ec2_client
+                .create_tags(CreateTagsRequest {
+                    resources: vec![image_id.to_string()],
+                    tags: vec![tag.clone()],
+                    ..Default::default()
+                })
+                .await
+                .context(error::CreateTagsSnafu {
+                    image_id,
+                    region: region.name(),
+                })?;
+        }
+        if !lost.is_empty() {
+            // Giving the value means we only delete the tag if it still has that value.
+            ec2_client
+                .delete_tags(DeleteTagsRequest {
+                    resources: lost.clone(),
+                    tags: Some(vec![tag.clone()]),
+                    ..Default::default()
+                })
+                .await
+                .context(error::DeleteTagsSnafu {
+                    image_ids: lost.clone(),
+                    region: region.name(),
+                })?;
+        }
+    }
+
+    Ok(TagChange {
+        image_id: image_id.to_string(),
+        gained,
+        lost,
+    })
+}
+
+mod error {
+    use crate::aws;
+    use rusoto_core::RusotoError;
+    use rusoto_ec2::{CreateTagsError, DeleteTagsError, DescribeImagesError};
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Error creating {} client in {}: {}", client_type, region, source))]
+        Client {
+            client_type: String,
+            region: String,
+            source: aws::client::Error,
+        },
+
+        #[snafu(display("Error reading config: {}", source))]
+        Config {
+            source: pubsys_config::Error,
+        },
+
+        #[snafu(display("Failed to tag {} in {}: {}", image_id, region, source))]
+        CreateTags {
+            image_id: String,
+            region: String,
+            #[snafu(source(from(RusotoError<CreateTagsError>, Box::new)))]
+            source: Box<RusotoError<CreateTagsError>>,
+        },
+
+        #[snafu(display("Failed to untag {} in {}: {}", image_ids.join(", "), region, source))]
+        DeleteTags {
+            image_ids: Vec<String>,
+            region: String,
+            #[snafu(source(from(RusotoError<DeleteTagsError>, Box::new)))]
+            source: Box<RusotoError<DeleteTagsError>>,
+        },
+
+        #[snafu(display("Failed to describe tagged images in {}: {}", region, source))]
+        DescribeImages {
+            region: String,
+            #[snafu(source(from(RusotoError<DescribeImagesError>, Box::new)))]
+            source: Box<RusotoError<DescribeImagesError>>,
+        },
+
+        #[snafu(display("Failed to deserialize input from '{}': {}", path.display(), source))]
+        Deserialize {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to {} '{}': {}", op, path.display(), source))]
+        File {
+            op: String,
+            path: PathBuf,
+            source: io::Error,
+        },
+
+        #[snafu(display("Input '{}' is empty", path.display()))]
+        Input {
+            path: PathBuf,
+        },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig {
+            missing: String,
+        },
+
+        #[snafu(display("Failed to move latest tag in {} of {} regions", error_count, total))]
+        MoveTag {
+            error_count: u16,
+            total: usize,
+        },
+
+        #[snafu(context(false), display("{}", source))]
+        Output {
+            source: crate::output::Error,
+        },
+
+        ParseRegion {
+            source: crate::aws::Error,
+        },
+
+        #[snafu(display("Failed to read regions file: {}", source))]
+        RegionsFile {
+            source: crate::aws::Error,
+        },
+
+        #[snafu(display("Region {} is not in the AMI input file", region))]
+        UnknownRegion {
+            region: String,
+        },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
@@ -1,15 +1,55 @@
+use log::warn;
 use pubsys_config::AwsConfig;
 use rusoto_core::Region;
 use snafu::ResultExt;
+use std::fmt;
+use std::fs;
+use std::path::Path;
 
 #[macro_use]
 pub(crate) mod client;
 
 pub(crate) mod ami;
+pub(crate) mod diff_ssm;
+pub(crate) mod get_ssm;
+pub(crate) mod latest_ami;
+pub(crate) mod partition;
 pub(crate) mod promote_ssm;
 pub(crate) mod publish_ami;
+pub(crate) mod s3;
 pub(crate) mod ssm;
 
+/// A region, and the account we act in there if we know it, so errors from operations that span
+/// many regions say where they happened, like "us-west-2 (acct 123456789012)"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RegionAccount {
+    pub(crate) region: String,
+    pub(crate) account: Option<String>,
+}
+
+impl RegionAccount {
+    pub(crate) fn new(region: &str, account: Option<String>) -> Self {
+        Self {
+            region: region.to_string(),
+            account,
+        }
+    }
+
+    /// Uses the account of the roles configured for the region, if any; see `role_account`.
+    pub(crate) fn from_config(region: &str, aws: &AwsConfig) -> Self {
+        Self::new(region, client::role_account(region, aws))
+    }
+}
+
+impl fmt::Display for RegionAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.account {
+            Some(account) => write!(f, "{} (acct {})", self.region, account),
+            None => f.write_str(&self.region),
+        }
+    }
+}
+
 /// Builds a Region from the given region name, and uses the custom endpoint from the AWS config,
 /// if specified in aws.region.REGION.endpoint.
 fn region_from_string(name: &str, aws: &AwsConfig) -> Result<Region> {
@@ -23,6 +63,36 @@ fn region_from_string(name: &str, aws: &AwsConfig) -> Result<Region> {
     })
 }
 
+/// Reads region names from the given file, separated by newlines or commas.  Blank entries and
+/// duplicates are dropped, keeping the first occurrence so the base region stays first, and each
+/// name is checked the same way as names given in Infra.toml.
+pub(crate) fn regions_from_file(path: &Path, aws: &AwsConfig) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path).context(error::RegionsFileSnafu { path })?;
+    let mut regions: Vec<String> = Vec::new();
+    for name in contents.split(&['\n', ','][..]).map(str::trim) {
+        if name.is_empty() || regions.iter().any(|r| r == name) {
+            continue;
+        }
+        region_from_string(name, aws)?;
+        regions.push(name.to_string());
+    }
+    Ok(regions)
+}
+
+/// Removes the excluded region names from the given list, keeping the order of the rest.  We
+/// warn about excluded names that weren't in the list, since they're probably typos.
+pub(crate) fn exclude_regions(regions: Vec<String>, excluded: &[String]) -> Vec<String> {
+    for name in excluded {
+        if !regions.contains(name) {
+            warn!("Excluded region '{}' isn't in the list of regions", name);
+        }
+    }
+    regions
+        .into_iter()
+        .filter(|name| !excluded.contains(name))
+        .collect()
+}
+
 /// Parses the given string as an architecture, mapping values to the ones used in EC2.
 pub(crate) fn parse_arch(input: &str) -> Result<String> {
     match input {
@@ -36,8 +106,18 @@ pub(crate) fn parse_arch(input: &str) -> Result<String> {
     }
 }
 
+/// Parses the given string as an EC2 boot mode.
+pub(crate) fn parse_boot_mode(input: &str) -> Result<String> {
+    match input {
+        "legacy-bios" | "uefi" | "uefi-preferred" => Ok(input.to_string()),
+        _ => error::ParseBootModeSnafu { input }.fail(),
+    }
+}
+
 mod error {
     use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
@@ -45,12 +125,44 @@ mod error {
         #[snafu(display("Failed to parse arch '{}': {}", input, msg))]
         ParseArch { input: String, msg: String },
 
+        #[snafu(display(
+            "Unknown boot mode '{}', expected legacy-bios, uefi, or uefi-preferred",
+            input
+        ))]
+        ParseBootMode { input: String },
+
         #[snafu(display("Failed to parse region '{}': {}", name, source))]
         ParseRegion {
             name: String,
             source: rusoto_signature::region::ParseRegionError,
         },
+
+        #[snafu(display("Failed to read regions from '{}': {}", path.display(), source))]
+        RegionsFile { path: PathBuf, source: io::Error },
     }
 }
 pub(crate) use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::client::account_from_arn;
+    use super::RegionAccount;
+
+    #[test]
+    fn region_account_context() {
+        assert_eq!(
+            account_from_arn("arn:aws-us-gov:iam::123456789012:role/publish").as_deref(),
+            Some("123456789012")
+        );
+        assert!(account_from_arn("publish").is_none());
+        assert!(account_from_arn("arn:aws:iam::1234:role/publish").is_none());
+
+        let region = RegionAccount::new("us-west-2", Some("123456789012".to_string()));
+        assert_eq!(region.to_string(), "us-west-2 (acct 123456789012)");
+        assert_eq!(
+            RegionAccount::new("us-west-2", None).to_string(),
+            "us-west-2"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! The partition module maps regions to the AWS partitions they live in.  Each partition has its
+//! own endpoints, ARNs, and accounts, so everything we do in one call has to stay within a single
+//! partition.
+
+use std::fmt;
+
+/// An AWS partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Partition {
+    Aws,
+    AwsCn,
+    AwsUsGov,
+}
+
+impl Partition {
+    /// Returns the partition that the named region belongs to
+    pub(crate) fn from_region(name: &str) -> Self {
+        if name.starts_with("cn-") {
+            Partition::AwsCn
+        } else if name.starts_with("us-gov-") {
+            Partition::AwsUsGov
+        } else {
+            Partition::Aws
+        }
+    }
+
+    /// The partition's ID as used in ARNs, e.g. "aws-us-gov" in "arn:aws-us-gov:iam::..."
+    pub(crate) fn id(self) -> &'static str {
+        match self {
+            Partition::Aws => "aws",
+            Partition::AwsCn => "aws-cn",
+            Partition::AwsUsGov => "aws-us-gov",
+        }
+    }
+
+    /// The DNS suffix of the partition's service endpoints
+    pub(crate) fn dns_suffix(self) -> &'static str {
+        match self {
+            Partition::Aws | Partition::AwsUsGov => "amazonaws.com",
+            Partition::AwsCn => "amazonaws.com.cn",
+        }
+    }
+
+    /// Returns the endpoint for the given service in the given region of this partition
+    pub(crate) fn endpoint(self, service: &str, region: &str) -> String {
+        format!("https://{}.{}.{}", service, region, self.dns_suffix())
+    }
+
+    /// Returns the partition named in the given ARN, if it's an ARN for a known partition
+    pub(crate) fn from_arn(arn: &str) -> Option<Self> {
+        match arn.split(':').nth(1)? {
+            "aws" => Some(Partition::Aws),
+            "aws-cn" => Some(Partition::AwsCn),
+            "aws-us-gov" => Some(Partition::AwsUsGov),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.id())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Partition;
+
+    #[test]
+    fn regions_map_to_partitions() {
+        for (region, partition, ec2, ssm) in &[
+            (
+                "us-west-2",
+                Partition::Aws,
+                "https://ec2.us-west-2.amazonaws.com",
+                "https://ssm.us-west-2.amazonaws.com",
+            ),
+            (
+                "ap-south-1",
+                Partition::Aws,
+                "https://ec2.ap-south-1.amazonaws.com",
+                "https://ssm.ap-south-1.amazonaws.com",
+            ),
+            (
+                "us-gov-west-1",
+                Partition::AwsUsGov,
+                "https://ec2.us-gov-west-1.amazonaws.com",
+                "https://ssm.us-gov-west-1.amazonaws.com",
+            ),
+            (
+                "us-gov-east-1",
+                Partition::AwsUsGov,
+                "https://ec2.us-gov-east-1.amazonaws.com",
+                "https://ssm.us-gov-east-1.amazonaws.com",
+            ),
+            (
+                "cn-north-1",
+                Partition::AwsCn,
+                "https://ec2.cn-north-1.amazonaws.com.cn",
+                "https://ssm.cn-north-1.amazonaws.com.cn",
+            ),
+            (
+                "cn-northwest-1",
+                Partition::AwsCn,
+                "https://ec2.cn-northwest-1.amazonaws.com.cn",
+                "https://ssm.cn-northwest-1.amazonaws.com.cn",
+            ),
+        ] {
+            let actual = Partition::from_region(region);
+            assert_eq!(actual, *partition, "partition of {}", region);
+            assert_eq!(actual.endpoint("ec2", region), *ec2);
+            assert_eq!(actual.endpoint("ssm", region), *ssm);
+        }
+    }
+
+    #[test]
+    fn arns_map_to_partitions() {
+        for (arn, partition) in &[
+            ("arn:aws:iam::012345678901:role/r", Some(Partition::Aws)),
+            (
+                "arn:aws-cn:iam::012345678901:role/r",
+                Some(Partition::AwsCn),
+            ),
+            (
+                "arn:aws-us-gov:iam::012345678901:role/r",
+                Some(Partition::AwsUsGov),
+            ),
+            ("arn:aws-iso:iam::012345678901:role/r", None),
+            ("not-an-arn", None),
+        ] {
+            assert_eq!(Partition::from_arn(arn), *partition, "partition of {}", arn);
+        }
+    }
+}
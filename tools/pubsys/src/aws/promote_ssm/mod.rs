@@ -2,15 +2,21 @@
 //! SSM parameters from one version to another
 
 use crate::aws::client::build_client;
-use crate::aws::ssm::{key_difference, ssm, template, BuildContext, SsmKey};
-use crate::aws::{parse_arch, region_from_string};
-use crate::Args;
-use log::{info, trace};
-use pubsys_config::InfraConfig;
+use crate::aws::ssm::{
+    key_difference, ssm, template, BuildContext, ParameterPrefix, SsmKey, SsmSummary,
+};
+use crate::aws::{
+    exclude_regions, parse_arch, region_from_string, regions_from_file, RegionAccount,
+};
+use crate::{normalize_version, output, progress, Args};
+use futures::future::{join, ready};
+use futures::stream::{self, StreamExt};
+use log::{error, info, trace};
 use rusoto_core::Region;
 use rusoto_ssm::SsmClient;
 use snafu::{ensure, ResultExt};
 use std::collections::HashMap;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -27,8 +33,13 @@ pub(crate) struct PromoteArgs {
     variant: String,
 
     /// Version number (or string) to copy from
+    #[structopt(long, required_unless = "rollback-to", conflicts_with = "rollback-to")]
+    source: Option<String>,
+
+    /// Roll the target back to this previous version, after making sure its parameters still
+    /// exist in every region
     #[structopt(long)]
-    source: String,
+    rollback_to: Option<String>,
 
     /// Version number (or string) to copy to
     #[structopt(long)]
@@ -38,34 +49,76 @@ pub(crate) struct PromoteArgs {
     #[structopt(long, use_delimiter = true)]
     regions: Vec<String>,
 
+    /// File listing regions to use instead of --regions, separated by newlines or commas
+    #[structopt(long, parse(from_os_str), conflicts_with = "regions")]
+    regions_file: Option<PathBuf>,
+
+    /// Region to leave out of the regions given or from Infra.toml; can be given more than once
+    #[structopt(long = "exclude-region", number_of_values = 1)]
+    exclude_regions: Vec<String>,
+
     /// File holding the parameter templates
     #[structopt(long)]
     template_path: PathBuf,
+
+    /// Promote in at most this many regions at once, setting and validating each region's
+    /// parameters on their own and reporting every region that failed; by default, all regions
+    /// are promoted together
+    #[structopt(long)]
+    parallel_regions: Option<NonZeroUsize>,
+
+    /// Make sure every source parameter exists in every region before changing any target
+    /// parameters, so no region is left behind; this is always done with --rollback-to
+    #[structopt(long)]
+    verify_first: bool,
 }
 
 /// Common entrypoint from main()
 pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
-    info!(
-        "Promoting SSM parameters from {} to {}",
-        promote_args.source, promote_args.target
-    );
+    let parameters = _run(args, promote_args).await?;
+    output::report(args.output, &SsmSummary::from(&parameters))?;
+    Ok(())
+}
+
+async fn _run(args: &Args, promote_args: &PromoteArgs) -> Result<HashMap<SsmKey, String>> {
+    // A rollback is a promotion from the given previous version, with extra checks.
+    let (source, rollback) = match (&promote_args.source, &promote_args.rollback_to) {
+        (None, Some(rollback_to)) => (rollback_to, true),
+        (Some(source), None) => (source, false),
+        _ => unreachable!("developer error: --source and --rollback-to not required/exclusive"),
+    };
+    if rollback {
+        info!(
+            "Rolling back SSM parameters for {} to {}",
+            promote_args.target, source
+        );
+    } else {
+        info!(
+            "Promoting SSM parameters from {} to {}",
+            source, promote_args.target
+        );
+    }
 
     // Setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(error::ConfigSnafu)?;
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
 
     trace!("Parsed infra config: {:#?}", infra_config);
     let aws = infra_config.aws.unwrap_or_else(Default::default);
-    let ssm_prefix = aws.ssm_prefix.as_deref().unwrap_or_else(|| "");
 
-    // If the user gave an override list of regions, use that, otherwise use what's in the config.
-    let regions = if !promote_args.regions.is_empty() {
-        promote_args.regions.clone()
-    } else {
-        aws.regions.clone().into()
-    }
+    // If the user gave an override list of regions, use that, otherwise use what's in the config;
+    // either way, leave out any excluded regions.
+    let regions = exclude_regions(
+        if let Some(path) = &promote_args.regions_file {
+            regions_from_file(path, &aws).context(error::RegionsFileSnafu)?
+        } else if !promote_args.regions.is_empty() {
+            promote_args.regions.clone()
+        } else {
+            aws.regions.clone().into()
+        },
+        &promote_args.exclude_regions,
+    )
     .into_iter()
     .map(|name| region_from_string(&name, &aws).context(error::ParseRegionSnafu))
     .collect::<Result<Vec<Region>>>()?;
@@ -81,7 +134,7 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
     let mut ssm_clients = HashMap::with_capacity(regions.len());
     for region in &regions {
         let ssm_client =
-            build_client::<SsmClient>(region, &base_region, &aws).context(error::ClientSnafu {
+            build_client::<SsmClient>(region, base_region, &aws).context(error::ClientSnafu {
                 client_type: "SSM",
                 region: region.name(),
             })?;
@@ -90,17 +143,20 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
 
     // Template setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
-    // Non-image-specific context for building and rendering templates
+    // Non-image-specific context for building and rendering templates.  Versions are normalized
+    // so we look up parameters under the same name `pubsys ssm` wrote them.
+    let source_version = normalize_version(source);
     let source_build_context = BuildContext {
         variant: &promote_args.variant,
         arch: &promote_args.arch,
-        image_version: &promote_args.source,
+        image_version: &source_version,
     };
 
+    let target_version = normalize_version(&promote_args.target);
     let target_build_context = BuildContext {
         variant: &promote_args.variant,
         arch: &promote_args.arch,
-        image_version: &promote_args.target,
+        image_version: &target_version,
     };
 
     info!(
@@ -118,42 +174,49 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
             "No parameters for this arch/variant in {}",
             promote_args.template_path.display()
         );
-        return Ok(());
+        return Ok(HashMap::new());
     }
 
     // Render parameter names into maps of {template string => rendered value}.  We need the
     // template strings so we can associate source parameters with target parameters that came
-    // from the same template, so we know what to copy.
-    let source_parameter_map =
-        template::render_parameter_names(&template_parameters, ssm_prefix, &source_build_context)
-            .context(error::RenderTemplatesSnafu)?;
-    let target_parameter_map =
-        template::render_parameter_names(&template_parameters, ssm_prefix, &target_build_context)
-            .context(error::RenderTemplatesSnafu)?;
-
-    // Parameters are the same in each region, so we need to associate each region with each of
-    // the parameter names so we can fetch them.
-    let source_keys: Vec<SsmKey> = regions
-        .iter()
-        .flat_map(|region| {
-            source_parameter_map
-                .values()
-                .map(move |name| SsmKey::new(region.clone(), name.clone()))
-        })
-        .collect();
-    let target_keys: Vec<SsmKey> = regions
-        .iter()
-        .flat_map(|region| {
-            target_parameter_map
-                .values()
-                .map(move |name| SsmKey::new(region.clone(), name.clone()))
-        })
-        .collect();
+    // from the same template, so we know what to copy.  Names can include the region, so we
+    // render them for each region, and build a map of source key to target name so we can find
+    // which target parameters to set based on the source parameters we get back from SSM.
+    let prefix = ParameterPrefix::new(&aws);
+    let mut source_keys = Vec::new();
+    let mut target_keys = Vec::new();
+    let mut source_target_map = HashMap::new();
+    for region in &regions {
+        let source_parameter_map = template::render_parameter_names(
+            &template_parameters,
+            &prefix,
+            &source_build_context,
+            region,
+        )
+        .context(error::RenderTemplatesSnafu)?;
+        let target_parameter_map = template::render_parameter_names(
+            &template_parameters,
+            &prefix,
+            &target_build_context,
+            region,
+        )
+        .context(error::RenderTemplatesSnafu)?;
+
+        for (template_name, source_name) in source_parameter_map {
+            let target_name = &target_parameter_map[&template_name];
+            source_keys.push(SsmKey::new(region.clone(), source_name.clone()));
+            target_keys.push(SsmKey::new(region.clone(), target_name.clone()));
+            source_target_map.insert(
+                SsmKey::new(region.clone(), source_name),
+                target_name.clone(),
+            );
+        }
+    }
 
     // SSM get/compare   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     info!("Getting current SSM parameters for source and target names");
-    let current_source_parameters = ssm::get_parameters(&source_keys, &ssm_clients)
+    let current_source_parameters = ssm::get_parameters(&source_keys, &ssm_clients, &aws)
         .await
         .context(error::FetchSsmSnafu)?;
     trace!(
@@ -163,11 +226,31 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
     ensure!(
         !current_source_parameters.is_empty(),
         error::EmptySourceSnafu {
-            version: &promote_args.source
+            version: &source_version
         }
     );
 
-    let current_target_parameters = ssm::get_parameters(&target_keys, &ssm_clients)
+    // Before rolling back, or promoting with --verify-first, make sure every source parameter
+    // exists in every region, so we don't leave any region pointing at a mix of versions.
+    if rollback || promote_args.verify_first {
+        info!("Verifying source parameters exist in every region");
+        let mut missing: Vec<String> = source_keys
+            .iter()
+            .filter(|key| !current_source_parameters.contains_key(key))
+            .map(|key| format!("{} in {}", key.name, key.region.name()))
+            .collect();
+        missing.sort();
+        ensure!(
+            missing.is_empty(),
+            error::SourceMissingSnafu {
+                action: if rollback { "roll back to" } else { "promote" },
+                version: &source_version,
+                missing,
+            }
+        );
+    }
+
+    let current_target_parameters = ssm::get_parameters(&target_keys, &ssm_clients, &aws)
         .await
         .context(error::FetchSsmSnafu)?;
     trace!(
@@ -175,14 +258,6 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
         current_target_parameters
     );
 
-    // Build a map of rendered source parameter names to rendered target parameter names.  This
-    // will let us find which target parameters to set based on the source parameter names we get
-    // back from SSM.
-    let source_target_map: HashMap<&String, &String> = source_parameter_map
-        .iter()
-        .map(|(k, v)| (v, &target_parameter_map[k]))
-        .collect();
-
     // Show the difference between source and target parameters in SSM.  We use the
     // source_target_map we built above to map source keys to target keys (generated from the same
     // template) so that the diff code has common keys to compare.
@@ -191,31 +266,112 @@ pub(crate) async fn run(args: &Args, promote_args: &PromoteArgs) -> Result<()> {
             .into_iter()
             .map(|(key, value)| {
                 (
-                    SsmKey::new(key.region, source_target_map[&key.name].to_string()),
+                    SsmKey::new(key.region.clone(), source_target_map[&key].to_string()),
                     value,
                 )
             })
             .collect(),
         &current_target_parameters,
+        args.output,
     );
     if set_parameters.is_empty() {
         info!("No changes necessary.");
-        return Ok(());
+        return Ok(HashMap::new());
     }
 
     // SSM set   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
-    info!("Setting updated SSM parameters.");
-    ssm::set_parameters(&set_parameters, &ssm_clients)
-        .await
-        .context(error::SetSsmSnafu)?;
-
-    info!("Validating whether live parameters in SSM reflect changes.");
-    ssm::validate_parameters(&set_parameters, &ssm_clients)
-        .await
-        .context(error::ValidateSsmSnafu)?;
+    if let Some(parallel_regions) = promote_args.parallel_regions {
+        promote_regions(&set_parameters, &ssm_clients, &aws, parallel_regions).await?;
+    } else {
+        info!("Setting updated SSM parameters.");
+        ssm::set_parameters(&set_parameters, &ssm_clients, &aws)
+            .await
+            .context(error::SetSsmSnafu)?;
+
+        info!("Validating whether live parameters in SSM reflect changes.");
+        ssm::validate_parameters(&set_parameters, &ssm_clients, &aws)
+            .await
+            .context(error::ValidateSsmSnafu)?;
+    }
 
     info!("All parameters match requested values.");
+    if rollback {
+        info!(
+            "Rolled back {} parameters for {} to {}",
+            set_parameters.len(),
+            promote_args.target,
+            source_version
+        );
+    }
+    Ok(set_parameters)
+}
+
+/// Sets and validates the given parameters one region at a time, in up to `parallel_regions`
+/// regions at once.  A failure in one region doesn't stop the others; every failed region is
+/// logged and reported in the returned error.
+async fn promote_regions(
+    set_parameters: &HashMap<SsmKey, String>,
+    ssm_clients: &HashMap<Region, SsmClient>,
+    aws: &pubsys_config::AwsConfig,
+    parallel_regions: NonZeroUsize,
+) -> Result<()> {
+    let mut regional_parameters: HashMap<Region, HashMap<SsmKey, String>> = HashMap::new();
+    for (key, value) in set_parameters {
+        regional_parameters
+            .entry(key.region.clone())
+            .or_default()
+            .insert(
+                SsmKey::new(key.region.clone(), key.name.clone()),
+                value.clone(),
+            );
+    }
+    let region_count = regional_parameters.len();
+
+    info!(
+        "Setting updated SSM parameters in {} regions, {} at a time.",
+        region_count, parallel_regions
+    );
+    let requests = regional_parameters.into_iter().map(|(region, parameters)| {
+        let promote_future = async move {
+            ssm::set_parameters(&parameters, ssm_clients, aws)
+                .await
+                .context(error::SetSsmSnafu)?;
+            ssm::validate_parameters(&parameters, ssm_clients, aws)
+                .await
+                .context(error::ValidateSsmSnafu)
+        };
+        join(ready(region), promote_future)
+    });
+    let responses: Vec<(Region, Result<()>)> = stream::iter(requests)
+        .buffer_unordered(parallel_regions.get())
+        .inspect(|(region, response)| {
+            if response.is_ok() {
+                progress::record(format!("Promoted parameters in {}", region.name()));
+            }
+        })
+        .collect()
+        .await;
+
+    let mut failures = Vec::new();
+    for (region, response) in responses {
+        match response {
+            Ok(()) => info!("Promoted parameters in {}", region.name()),
+            Err(e) => {
+                let region = RegionAccount::from_config(region.name(), aws);
+                error!("Failed to promote parameters in {}: {}", region, e);
+                failures.push(format!("{}: {}", region, e));
+            }
+        }
+    }
+    failures.sort();
+    ensure!(
+        failures.is_empty(),
+        error::RegionsFailedSnafu {
+            promoted: region_count - failures.len(),
+            failures,
+        }
+    );
     Ok(())
 }
 
@@ -259,10 +415,30 @@ mod error {
             missing: String,
         },
 
+        #[snafu(context(false), display("{}", source))]
+        Output {
+            source: crate::output::Error,
+        },
+
         ParseRegion {
             source: crate::aws::Error,
         },
 
+        #[snafu(display("Failed to read regions file: {}", source))]
+        RegionsFile {
+            source: crate::aws::Error,
+        },
+
+        #[snafu(display(
+            "Failed to promote parameters in {} regions:\n{}",
+            failures.len(),
+            failures.join("\n")
+        ))]
+        RegionsFailed {
+            failures: Vec<String>,
+            promoted: usize,
+        },
+
         #[snafu(display("Failed to render templates: {}", source))]
         RenderTemplates {
             source: template::Error,
@@ -273,6 +449,18 @@ mod error {
             source: ssm::Error,
         },
 
+        #[snafu(display(
+            "Refusing to {} {}, its parameters are missing: {}",
+            action,
+            version,
+            missing.join(", ")
+        ))]
+        SourceMissing {
+            action: String,
+            version: String,
+            missing: Vec<String>,
+        },
+
         ValidateSsm {
             source: ssm::Error,
         },
@@ -0,0 +1,289 @@
+//! The kms module manages KMS grants for AMIs whose snapshots are encrypted with a customer
+//! managed key.  Accounts we share such an AMI with can't launch it unless they can use the key,
+//! so when granting launch permission we also grant those accounts access to the key.
+
+use crate::aws::partition::Partition;
+use log::{debug, info, warn};
+use rusoto_core::Region;
+use rusoto_ec2::{DescribeSnapshotsRequest, Ec2, Ec2Client};
+use rusoto_kms::{
+    CreateGrantRequest, DescribeKeyRequest, Kms, KmsClient, ListGrantsRequest, RevokeGrantRequest,
+};
+use serde::Serialize;
+use snafu::{OptionExt, ResultExt};
+use std::collections::BTreeSet;
+
+/// The KMS operations another account needs to launch an instance from an encrypted snapshot;
+/// EC2 creates its own grants on the account's behalf, hence CreateGrant.
+const GRANT_OPERATIONS: &[&str] = &[
+    "CreateGrant",
+    "Decrypt",
+    "DescribeKey",
+    "GenerateDataKeyWithoutPlaintext",
+    "ReEncryptFrom",
+    "ReEncryptTo",
+];
+
+/// A KMS grant we created or revoked, for reporting results
+#[derive(Debug, Serialize)]
+pub(crate) struct KeyGrant {
+    pub(crate) region: String,
+    pub(crate) account_id: String,
+    pub(crate) key_id: String,
+    pub(crate) grant_id: String,
+}
+
+/// The name we give grants, so we can find them again to revoke them.
+fn grant_name(account_id: &str) -> String {
+    format!("pubsys-launch-{}", account_id)
+}
+
+/// Returns the ARNs of the customer managed keys used to encrypt the given snapshots.  Snapshots
+/// encrypted with the AWS managed key are skipped with a warning, since that key can't be shared.
+pub(crate) async fn customer_keys(
+    snapshot_ids: &[String],
+    region: &Region,
+    ec2_client: &Ec2Client,
+    kms_client: &KmsClient,
+) -> Result<BTreeSet<String>> {
+    let describe_request = DescribeSnapshotsRequest {
+        snapshot_ids: Some(snapshot_ids.to_vec()),
+        ..Default::default()
+    };
+    let snapshots = ec2_client
+        .describe_snapshots(describe_request)
+        .await
+        .context(error::DescribeSnapshotsSnafu {
+            region: region.name(),
+        })?
+        .snapshots
+        .unwrap_or_default();
+
+    let mut keys = BTreeSet::new();
+    for snapshot in snapshots {
+        let key_id = match (snapshot.encrypted, snapshot.kms_key_id) {
+            (Some(true), Some(key_id)) => key_id,
+            _ => continue,
+        };
+        let describe_request = DescribeKeyRequest {
+            key_id: key_id.clone(),
+            ..Default::default()
+        };
+        let metadata = kms_client
+            .describe_key(describe_request)
+            .await
+            .context(error::DescribeKeySnafu {
+                key_id: &key_id,
+                region: region.name(),
+            })?
+            .key_metadata
+            .context(error::MissingInResponseSnafu {
+                request_type: "DescribeKey",
+                missing: "key_metadata",
+            })?;
+        if metadata.key_manager.as_deref() == Some("CUSTOMER") {
+            keys.insert(key_id);
+        } else {
+            warn!(
+                "Snapshot {} in {} is encrypted with an AWS managed key, which can't be shared",
+                snapshot.snapshot_id.unwrap_or_default(),
+                region.name()
+            );
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Grants each account use of each key, so they can launch AMIs encrypted with them.
+pub(crate) async fn create_grants(
+    key_ids: &BTreeSet<String>,
+    account_ids: &[String],
+    region: &Region,
+    kms_client: &KmsClient,
+) -> Result<Vec<KeyGrant>> {
+    let mut grants = Vec::new();
+    for key_id in key_ids {
+        for account_id in account_ids {
+            // Grants with the same name and parameters are only created once, so reruns are safe.
+            let grant_request = CreateGrantRequest {
+                key_id: key_id.clone(),
+                grantee_principal: format!(
+                    "arn:{}:iam::{}:root",
+                    Partition::from_region(region.name()).id(),
+                    account_id
+                ),
+                name: Some(grant_name(account_id)),
+                operations: GRANT_OPERATIONS.iter().map(|op| op.to_string()).collect(),
+                ..Default::default()
+            };
+            let response =
+                kms_client
+                    .create_grant(grant_request)
+                    .await
+                    .context(error::CreateGrantSnafu {
+                        key_id,
+                        account_id,
+                        region: region.name(),
+                    })?;
+            let grant_id = response.grant_id.context(error::MissingInResponseSnafu {
+                request_type: "CreateGrant",
+                missing: "grant_id",
+            })?;
+            info!(
+                "Granted {} use of key {} in {}: {}",
+                account_id,
+                key_id,
+                region.name(),
+                grant_id
+            );
+            grants.push(KeyGrant {
+                region: region.name().to_string(),
+                account_id: account_id.clone(),
+                key_id: key_id.clone(),
+                grant_id,
+            });
+        }
+    }
+
+    Ok(grants)
+}
+
+/// Revokes the grants we made to each account for each key.
+pub(crate) async fn revoke_grants(
+    key_ids: &BTreeSet<String>,
+    account_ids: &[String],
+    region: &Region,
+    kms_client: &KmsClient,
+) -> Result<Vec<KeyGrant>> {
+    let mut revoked = Vec::new();
+    for key_id in key_ids {
+        let mut marker = None;
+        loop {
+            let list_request = ListGrantsRequest {
+                key_id: key_id.clone(),
+                marker: marker.take(),
+                ..Default::default()
+            };
+            let response =
+                kms_client
+                    .list_grants(list_request)
+                    .await
+                    .context(error::ListGrantsSnafu {
+                        key_id,
+                        region: region.name(),
+                    })?;
+
+            for grant in response.grants.unwrap_or_default() {
+                let account_id = match account_ids
+                    .iter()
+                    .find(|id| grant.name.as_deref() == Some(&grant_name(id)))
+                {
+                    Some(account_id) => account_id,
+                    None => continue,
+                };
+                let grant_id = grant.grant_id.context(error::MissingInResponseSnafu {
+                    request_type: "ListGrants",
+                    missing: "grant_id",
+                })?;
+                let revoke_request = RevokeGrantRequest {
+                    key_id: key_id.clone(),
+                    grant_id: grant_id.clone(),
+                };
+                kms_client
+                    .revoke_grant(revoke_request)
+                    .await
+                    .context(error::RevokeGrantSnafu {
+                        grant_id: &grant_id,
+                        region: region.name(),
+                    })?;
+                info!(
+                    "Revoked {} use of key {} in {}: {}",
+                    account_id,
+                    key_id,
+                    region.name(),
+                    grant_id
+                );
+                revoked.push(KeyGrant {
+                    region: region.name().to_string(),
+                    account_id: account_id.clone(),
+                    key_id: key_id.clone(),
+                    grant_id,
+                });
+            }
+
+            if response.truncated != Some(true) {
+                break;
+            }
+            marker = response.next_marker;
+            debug!("Listing more grants for key {}", key_id);
+        }
+    }
+
+    Ok(revoked)
+}
+
+mod error {
+    use rusoto_core::RusotoError;
+    use rusoto_ec2::DescribeSnapshotsError;
+    use rusoto_kms::{CreateGrantError, DescribeKeyError, ListGrantsError, RevokeGrantError};
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display(
+            "Failed to grant {} use of key {} in {}: {}",
+            account_id,
+            key_id,
+            region,
+            source
+        ))]
+        CreateGrant {
+            key_id: String,
+            account_id: String,
+            region: String,
+            #[snafu(source(from(RusotoError<CreateGrantError>, Box::new)))]
+            source: Box<RusotoError<CreateGrantError>>,
+        },
+
+        #[snafu(display("Failed to describe key {} in {}: {}", key_id, region, source))]
+        DescribeKey {
+            key_id: String,
+            region: String,
+            #[snafu(source(from(RusotoError<DescribeKeyError>, Box::new)))]
+            source: Box<RusotoError<DescribeKeyError>>,
+        },
+
+        #[snafu(display("Failed to describe snapshots in {}: {}", region, source))]
+        DescribeSnapshots {
+            region: String,
+            #[snafu(source(from(RusotoError<DescribeSnapshotsError>, Box::new)))]
+            source: Box<RusotoError<DescribeSnapshotsError>>,
+        },
+
+        #[snafu(display("Failed to list grants of key {} in {}: {}", key_id, region, source))]
+        ListGrants {
+            key_id: String,
+            region: String,
+            #[snafu(source(from(RusotoError<ListGrantsError>, Box::new)))]
+            source: Box<RusotoError<ListGrantsError>>,
+        },
+
+        #[snafu(display("Response to {} was missing {}", request_type, missing))]
+        MissingInResponse {
+            request_type: String,
+            missing: String,
+        },
+
+        #[snafu(display("Failed to revoke grant {} in {}: {}", grant_id, region, source))]
+        RevokeGrant {
+            grant_id: String,
+            region: String,
+            #[snafu(source(from(RusotoError<RevokeGrantError>, Box::new)))]
+            source: Box<RusotoError<RevokeGrantError>>,
+        },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
@@ -1,26 +1,33 @@
 //! The publish_ami module owns the 'publish-ami' subcommand and controls the process of granting
 //! and revoking access to EC2 AMIs.
 
+mod kms;
+
 use crate::aws::ami::wait::{self, wait_for_ami};
 use crate::aws::ami::Image;
 use crate::aws::client::build_client;
-use crate::aws::region_from_string;
-use crate::Args;
+use crate::aws::{exclude_regions, region_from_string, regions_from_file};
+use crate::{output, progress, Args};
 use futures::future::{join, ready};
 use futures::stream::{self, StreamExt};
-use log::{debug, error, info, trace};
-use pubsys_config::InfraConfig;
+use kms::KeyGrant;
+use log::{debug, error, info, trace, warn};
+use pubsys_config::AwsConfig;
 use rusoto_core::{Region, RusotoError};
 use rusoto_ec2::{
     DescribeImagesRequest, Ec2, Ec2Client, ModifyImageAttributeRequest,
     ModifySnapshotAttributeError, ModifySnapshotAttributeRequest,
 };
+use rusoto_kms::KmsClient;
+use serde::Serialize;
 use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::iter::FromIterator;
 use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
+use tokio::time::sleep;
 
 /// Grants or revokes permissions to Bottlerocket AMIs
 #[derive(Debug, StructOpt)]
@@ -37,6 +44,14 @@ pub(crate) struct PublishArgs {
     #[structopt(long, use_delimiter = true)]
     regions: Vec<String>,
 
+    /// File listing regions to use instead of --regions, separated by newlines or commas
+    #[structopt(long, parse(from_os_str), conflicts_with = "regions")]
+    regions_file: Option<PathBuf>,
+
+    /// Region to leave out of the regions given or from Infra.toml; can be given more than once
+    #[structopt(long = "exclude-region", number_of_values = 1)]
+    exclude_regions: Vec<String>,
+
     /// Grant access to the given users/groups
     #[structopt(long, group = "mode")]
     grant: bool,
@@ -50,6 +65,34 @@ pub(crate) struct PublishArgs {
     /// Group names to give/remove access
     #[structopt(long, use_delimiter = true, group = "who")]
     group_names: Vec<String>,
+
+    /// When granting access to group "all", don't fail if images still aren't public afterward,
+    /// which happens when the account has EC2 image block public access turned on; the launch
+    /// permissions are kept either way
+    #[structopt(long)]
+    allow_blocked: bool,
+}
+
+/// The group name that makes an image public
+const PUBLIC_GROUP: &str = "all";
+
+/// How many times, and how many seconds apart, we check whether images became public; EC2 can
+/// take a moment to reflect new launch permissions
+const PUBLIC_CHECK_ATTEMPTS: u32 = 5;
+const PUBLIC_CHECK_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Summary of the permission changes we made, for reporting results
+#[derive(Debug, Serialize)]
+struct PublishSummary<'a> {
+    operation: &'a str,
+    user_ids: &'a [String],
+    group_names: &'a [String],
+    /// Map of region name to the ID of the AMI we modified there
+    images: HashMap<&'a str, &'a str>,
+    /// KMS grants we created or revoked for images encrypted with customer managed keys
+    key_grants: Vec<KeyGrant>,
+    /// Regions where an image granted to group "all" still isn't public
+    blocked_regions: Vec<&'a str>,
 }
 
 /// Common entrypoint from main()
@@ -86,18 +129,23 @@ pub(crate) async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
     );
 
     // If a lock file exists, use that, otherwise use Infra.toml or default
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, true)
-        .context(error::ConfigSnafu)?;
+    let infra_config = args.infra_config(true).context(error::ConfigSnafu)?;
     trace!("Using infra config: {:?}", infra_config);
 
     let aws = infra_config.aws.unwrap_or_else(Default::default);
 
-    // If the user gave an override list of regions, use that, otherwise use what's in the config.
-    let regions = if !publish_args.regions.is_empty() {
-        publish_args.regions.clone()
-    } else {
-        aws.regions.clone().into()
-    };
+    // If the user gave an override list of regions, use that, otherwise use what's in the config;
+    // either way, leave out any excluded regions.
+    let regions = exclude_regions(
+        if let Some(path) = &publish_args.regions_file {
+            regions_from_file(path, &aws).context(error::RegionsFileSnafu)?
+        } else if !publish_args.regions.is_empty() {
+            publish_args.regions.clone()
+        } else {
+            aws.regions.clone().into()
+        },
+        &publish_args.exclude_regions,
+    );
     ensure!(
         !regions.is_empty(),
         error::MissingConfigSnafu {
@@ -138,7 +186,7 @@ pub(crate) async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
     let mut ec2_clients = HashMap::with_capacity(amis.len());
     for region in amis.keys() {
         let ec2_client =
-            build_client::<Ec2Client>(&region, &base_region, &aws).context(error::ClientSnafu {
+            build_client::<Ec2Client>(region, &base_region, &aws).context(error::ClientSnafu {
                 client_type: "EC2",
                 region: region.name(),
             })?;
@@ -150,7 +198,7 @@ pub(crate) async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
     info!("Waiting for AMIs to be available...");
     let mut wait_requests = Vec::with_capacity(amis.len());
     for (region, image) in &amis {
-        let wait_future = wait_for_ami(&image.id, &region, &base_region, "available", 1, &aws);
+        let wait_future = wait_for_ami(&image.id, region, &base_region, "available", 1, &aws);
         // Store the region and ID so we can include it in errors
         let info_future = ready((region.clone(), image.id.clone()));
         wait_requests.push(join(info_future, wait_future));
@@ -181,6 +229,23 @@ pub(crate) async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
     )
     .await?;
 
+    // Accounts can't launch an AMI encrypted with a customer managed key unless they can use the
+    // key, so grant (or revoke) that too.  Groups like "all" can't be given access to keys.
+    let key_grants = if publish_args.user_ids.is_empty() {
+        Vec::new()
+    } else {
+        info!("Updating KMS key grants - {}", description);
+        modify_regional_key_grants(
+            &publish_args.user_ids,
+            &operation,
+            &snapshots,
+            &ec2_clients,
+            &base_region,
+            &aws,
+        )
+        .await?
+    };
+
     info!("Updating image permissions - {}", description);
     let ami_ids = amis
         .into_iter()
@@ -195,9 +260,106 @@ pub(crate) async fn run(args: &Args, publish_args: &PublishArgs) -> Result<()> {
     )
     .await?;
 
+    // If the account has EC2 image block public access turned on, granting launch permission to
+    // "all" doesn't make the image public, so check that it worked rather than report success.
+    let blocked_regions = if publish_args.grant
+        && publish_args.group_names.iter().any(|g| g == PUBLIC_GROUP)
+    {
+        info!("Checking that images are public");
+        let blocked = find_blocked_images(&ami_ids, &ec2_clients).await?;
+        if !blocked.is_empty() {
+            let regions: Vec<String> = blocked.iter().map(|r| r.name().to_string()).collect();
+            ensure!(
+                publish_args.allow_blocked,
+                error::PublicAccessBlockedSnafu { regions }
+            );
+            warn!(
+                "Images in {} aren't public, likely due to block public access; keeping their launch permissions because of --allow-blocked",
+                regions.join(", ")
+            );
+        }
+        blocked
+    } else {
+        Vec::new()
+    };
+
+    let summary = PublishSummary {
+        operation: &operation,
+        user_ids: &publish_args.user_ids,
+        group_names: &publish_args.group_names,
+        images: ami_ids
+            .iter()
+            .map(|(region, id)| (region.name(), id.as_str()))
+            .collect(),
+        key_grants,
+        blocked_regions: blocked_regions.iter().map(|r| r.name()).collect(),
+    };
+    output::report(args.output, &summary)?;
     Ok(())
 }
 
+/// Returns whether EC2 reports the given image as public.
+async fn image_is_public(image_id: &str, region: &Region, ec2_client: &Ec2Client) -> Result<bool> {
+    let describe_request = DescribeImagesRequest {
+        image_ids: Some(vec![image_id.to_string()]),
+        ..Default::default()
+    };
+    let images = ec2_client
+        .describe_images(describe_request)
+        .await
+        .context(error::DescribeImagesSnafu {
+            region: region.name(),
+        })?
+        .images
+        .unwrap_or_default();
+    let image = images.first().context(error::MissingImageSnafu {
+        region: region.name(),
+        image_id,
+    })?;
+    Ok(image.public.unwrap_or(false))
+}
+
+/// Returns the regions whose image isn't public, checking a few times so EC2 has a chance to
+/// reflect the new launch permissions.
+async fn find_blocked_images(
+    images: &HashMap<Region, String>,
+    clients: &HashMap<Region, Ec2Client>,
+) -> Result<Vec<Region>> {
+    let mut pending: Vec<&Region> = images.keys().collect();
+    for attempt in 1..=PUBLIC_CHECK_ATTEMPTS {
+        let mut requests = Vec::with_capacity(pending.len());
+        for region in &pending {
+            let public_future = image_is_public(&images[*region], region, &clients[*region]);
+            let info_future = ready(*region);
+            requests.push(join(info_future, public_future));
+        }
+        let request_stream = stream::iter(requests).buffer_unordered(4);
+        let responses: Vec<(&Region, Result<bool>)> = request_stream.collect().await;
+
+        let mut still_pending = Vec::new();
+        for (region, public) in responses {
+            if public? {
+                debug!("Image {} in {} is public", images[region], region.name());
+            } else {
+                still_pending.push(region);
+            }
+        }
+        pending = still_pending;
+        if pending.is_empty() {
+            break;
+        }
+        if attempt < PUBLIC_CHECK_ATTEMPTS {
+            debug!(
+                "{} images aren't public yet, checking again in {:?}",
+                pending.len(),
+                PUBLIC_CHECK_INTERVAL
+            );
+            sleep(PUBLIC_CHECK_INTERVAL).await;
+        }
+    }
+    Ok(pending.into_iter().cloned().collect())
+}
+
 /// Returns the snapshot IDs associated with the given AMI.
 pub(crate) async fn get_snapshots(
     image_id: &str,
@@ -410,6 +572,84 @@ pub(crate) async fn modify_regional_snapshots(
     Ok(())
 }
 
+/// Grants or revokes the given accounts use of the customer managed KMS keys that encrypt the
+/// snapshots in the given regional mapping.  The `operation` should be "add" or "remove".
+async fn modify_regional_key_grants(
+    account_ids: &[String],
+    operation: &str,
+    snapshots: &HashMap<Region, Vec<String>>,
+    ec2_clients: &HashMap<Region, Ec2Client>,
+    base_region: &Region,
+    aws: &AwsConfig,
+) -> Result<Vec<KeyGrant>> {
+    let mut kms_clients = HashMap::with_capacity(snapshots.len());
+    for region in snapshots.keys() {
+        let kms_client =
+            build_client::<KmsClient>(region, base_region, aws).context(error::ClientSnafu {
+                client_type: "KMS",
+                region: region.name(),
+            })?;
+        kms_clients.insert(region.clone(), kms_client);
+    }
+
+    let mut requests = Vec::with_capacity(snapshots.len());
+    for (region, snapshot_ids) in snapshots {
+        let ec2_client = &ec2_clients[region];
+        let kms_client = &kms_clients[region];
+        let grant_future = async move {
+            let key_ids = kms::customer_keys(snapshot_ids, region, ec2_client, kms_client).await?;
+            if key_ids.is_empty() {
+                debug!("No customer managed keys to share in {}", region.name());
+                Ok(Vec::new())
+            } else if operation == "add" {
+                kms::create_grants(&key_ids, account_ids, region, kms_client).await
+            } else {
+                kms::revoke_grants(&key_ids, account_ids, region, kms_client).await
+            }
+        };
+
+        // Store the region so we can include it in errors
+        let info_future = ready(region.clone());
+        requests.push(join(info_future, grant_future));
+    }
+
+    // Send requests in parallel and wait for responses, collecting results into a list.
+    let request_stream = stream::iter(requests).buffer_unordered(4);
+    let responses: Vec<(Region, std::result::Result<Vec<KeyGrant>, kms::Error>)> =
+        request_stream.collect().await;
+
+    // Count up successes and failures so we can give a clear total in the final error message.
+    let mut key_grants = Vec::new();
+    let mut error_count = 0u16;
+    let mut success_count = 0u16;
+    for (region, response) in responses {
+        match response {
+            Ok(grants) => {
+                success_count += 1;
+                key_grants.extend(grants);
+            }
+            Err(e) => {
+                error_count += 1;
+                error!(
+                    "Failed to update KMS key grants in {}: {}",
+                    region.name(),
+                    e
+                );
+            }
+        }
+    }
+
+    ensure!(
+        error_count == 0,
+        error::ModifyKeyGrantsSnafu {
+            error_count,
+            success_count,
+        }
+    );
+
+    Ok(key_grants)
+}
+
 /// Modify launchPermission for the given users/groups on the given images.  The `operation`
 /// should be "add" or "remove" to allow/deny permission.
 pub(crate) async fn modify_image(
@@ -467,7 +707,17 @@ pub(crate) async fn modify_regional_images(
 
     // Send requests in parallel and wait for responses, collecting results into a list.
     let request_stream = stream::iter(requests).buffer_unordered(4);
-    let responses: Vec<((String, String), Result<()>)> = request_stream.collect().await;
+    let responses: Vec<((String, String), Result<()>)> = request_stream
+        .inspect(|((region, image_id), response)| {
+            if response.is_ok() {
+                progress::record(format!(
+                    "Modified permissions of {} in {}",
+                    image_id, region
+                ));
+            }
+        })
+        .collect()
+        .await;
 
     // Count up successes and failures so we can give a clear total in the final error message.
     let mut error_count = 0u16;
@@ -525,7 +775,8 @@ mod error {
         #[snafu(display("Failed to describe images in {}: {}", region, source))]
         DescribeImages {
             region: String,
-            source: rusoto_core::RusotoError<rusoto_ec2::DescribeImagesError>,
+            #[snafu(source(from(RusotoError<rusoto_ec2::DescribeImagesError>, Box::new)))]
+            source: Box<RusotoError<rusoto_ec2::DescribeImagesError>>,
         },
 
         #[snafu(display("Failed to deserialize input from '{}': {}", path.display(), source))]
@@ -572,7 +823,8 @@ mod error {
         ModifyImageAttribute {
             snapshot_id: String,
             region: String,
-            source: RusotoError<ModifySnapshotAttributeError>,
+            #[snafu(source(from(RusotoError<ModifySnapshotAttributeError>, Box::new)))]
+            source: Box<RusotoError<ModifySnapshotAttributeError>>,
         },
 
         #[snafu(display(
@@ -593,7 +845,17 @@ mod error {
         ModifyImageAttributes {
             image_id: String,
             region: String,
-            source: RusotoError<ModifyImageAttributeError>,
+            #[snafu(source(from(RusotoError<ModifyImageAttributeError>, Box::new)))]
+            source: Box<RusotoError<ModifyImageAttributeError>>,
+        },
+
+        #[snafu(display(
+            "Failed to update KMS key grants in {} of {} regions",
+            error_count, error_count + success_count,
+        ))]
+        ModifyKeyGrants {
+            error_count: u16,
+            success_count: u16,
         },
 
         #[snafu(display(
@@ -611,10 +873,30 @@ mod error {
             images: Vec<String>,
         },
 
+        #[snafu(context(false), display("{}", source))]
+        Output {
+            source: crate::output::Error,
+        },
+
         ParseRegion {
             source: crate::aws::Error,
         },
 
+        #[snafu(display(
+            "Images in {} aren't public after granting launch permission to 'all'; the account \
+             likely has EC2 image block public access turned on.  Use --allow-blocked to keep \
+             the launch permissions anyway",
+            regions.join(", ")
+        ))]
+        PublicAccessBlocked {
+            regions: Vec<String>,
+        },
+
+        #[snafu(display("Failed to read regions file: {}", source))]
+        RegionsFile {
+            source: crate::aws::Error,
+        },
+
         #[snafu(display(
             "Given region(s) in Infra.toml / regions argument that are not in --ami-input file: {}",
             regions.join(", ")
@@ -627,7 +909,8 @@ mod error {
         WaitAmi {
             id: String,
             region: String,
-            source: ami::wait::Error,
+            #[snafu(source(from(ami::wait::Error, Box::new)))]
+            source: Box<ami::wait::Error>,
         },
     }
 }
@@ -0,0 +1,120 @@
+//! The s3 module lets pubsys read its Infra.toml from an `s3://bucket/key` URL, so a team can
+//! share one canonical config instead of copying it to every machine that publishes.
+
+use crate::aws::client::build_client;
+use log::info;
+use pubsys_config::AwsConfig;
+use rusoto_core::Region;
+use rusoto_s3::{GetObjectRequest, S3Client, S3};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fs;
+use std::path::PathBuf;
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+use url::Url;
+
+/// The name given to the downloaded config.  It's written to its own temporary directory, so
+/// `InfraConfig::from_path_or_lock` won't find an unrelated Infra.lock next to it.
+const INFRA_CONFIG_NAME: &str = "Infra.toml";
+
+/// Returns the parsed URL if the given Infra.toml path is an `s3://` URL, or None if it should be
+/// treated as a local path.
+pub(crate) fn infra_config_url(path: &str) -> Option<Url> {
+    Url::parse(path).ok().filter(|url| url.scheme() == "s3")
+}
+
+/// Downloads the Infra.toml at the given `s3://bucket/key` URL into a new temporary directory.
+/// The directory is deleted when the returned TempDir is dropped, so hold it for the whole run.
+///
+/// We don't have an Infra.toml yet to tell us about profiles or roles, so this uses the default
+/// AWS credential chain, and the base role in `aws` if there is one, talking to S3 in the default
+/// region from the environment.
+pub(crate) async fn fetch_infra_config(url: &Url, aws: &AwsConfig) -> Result<(TempDir, PathBuf)> {
+    let bucket = url
+        .host_str()
+        .filter(|b| !b.is_empty())
+        .context(error::InvalidUrlSnafu {
+            url: url.as_str(),
+            msg: "missing bucket name",
+        })?;
+    let key = url.path().trim_start_matches('/');
+    ensure!(
+        !key.is_empty(),
+        error::InvalidUrlSnafu {
+            url: url.as_str(),
+            msg: "missing object key",
+        }
+    );
+
+    let region = Region::default();
+    let s3_client =
+        build_client::<S3Client>(&region, &region, aws).context(error::ClientSnafu {
+            region: region.name(),
+        })?;
+
+    info!("Downloading infra config from {}", url);
+    let response = s3_client
+        .get_object(GetObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        })
+        .await
+        .context(error::GetObjectSnafu { url: url.as_str() })?;
+    let body = response
+        .body
+        .context(error::EmptyObjectSnafu { url: url.as_str() })?;
+    let mut contents = Vec::new();
+    body.into_async_read()
+        .read_to_end(&mut contents)
+        .await
+        .context(error::ReadObjectSnafu { url: url.as_str() })?;
+
+    let dir = TempDir::new().context(error::TempDirSnafu)?;
+    let path = dir.path().join(INFRA_CONFIG_NAME);
+    fs::write(&path, contents).context(error::WriteConfigSnafu { path: &path })?;
+    Ok((dir, path))
+}
+
+mod error {
+    use crate::aws;
+    use rusoto_core::RusotoError;
+    use rusoto_s3::GetObjectError;
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Error creating S3 client in {}: {}", region, source))]
+        Client {
+            region: String,
+            source: aws::client::Error,
+        },
+
+        #[snafu(display("S3 returned no content for {}", url))]
+        EmptyObject { url: String },
+
+        #[snafu(display("Failed to download {}: {}", url, source))]
+        GetObject {
+            url: String,
+            #[snafu(source(from(RusotoError<GetObjectError>, Box::new)))]
+            source: Box<RusotoError<GetObjectError>>,
+        },
+
+        #[snafu(display("Invalid S3 URL '{}': {}", url, msg))]
+        InvalidUrl { url: String, msg: String },
+
+        #[snafu(display("Failed to read {}: {}", url, source))]
+        ReadObject { url: String, source: io::Error },
+
+        #[snafu(display("Failed to create temporary directory: {}", source))]
+        TempDir { source: io::Error },
+
+        #[snafu(display("Failed to write infra config to '{}': {}", path.display(), source))]
+        WriteConfig { path: PathBuf, source: io::Error },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
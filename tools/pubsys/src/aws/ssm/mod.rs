@@ -4,10 +4,14 @@
 pub(crate) mod ssm;
 pub(crate) mod template;
 
-use crate::aws::{ami::Image, client::build_client, parse_arch, region_from_string};
-use crate::Args;
+use crate::aws::{
+    ami::Image, client::build_client, exclude_regions, parse_arch, region_from_string,
+    regions_from_file,
+};
+use crate::output::{self, OutputFormat};
+use crate::{normalize_version, Args};
 use log::{info, trace};
-use pubsys_config::{AwsConfig, InfraConfig};
+use pubsys_config::{AwsConfig, SsmPathTemplate};
 use rusoto_core::Region;
 use rusoto_ssm::SsmClient;
 use serde::Serialize;
@@ -43,6 +47,14 @@ pub(crate) struct SsmArgs {
     #[structopt(long, use_delimiter = true)]
     regions: Vec<String>,
 
+    /// File listing regions to use instead of --regions, separated by newlines or commas
+    #[structopt(long, parse(from_os_str), conflicts_with = "regions")]
+    regions_file: Option<PathBuf>,
+
+    /// Region to leave out of the regions given or from Infra.toml; can be given more than once
+    #[structopt(long = "exclude-region", number_of_values = 1)]
+    exclude_regions: Vec<String>,
+
     /// File holding the parameter templates
     #[structopt(long)]
     template_path: PathBuf,
@@ -54,21 +66,32 @@ pub(crate) struct SsmArgs {
 
 /// Common entrypoint from main()
 pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
+    let parameters = _run(args, ssm_args).await?;
+    output::report(args.output, &SsmSummary::from(&parameters))?;
+    Ok(())
+}
+
+async fn _run(args: &Args, ssm_args: &SsmArgs) -> Result<SsmParameters> {
     // Setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(error::ConfigSnafu)?;
+    let infra_config = args.infra_config(false).context(error::ConfigSnafu)?;
     trace!("Parsed infra config: {:#?}", infra_config);
     let aws = infra_config.aws.unwrap_or_else(Default::default);
-    let ssm_prefix = aws.ssm_prefix.as_deref().unwrap_or_else(|| "");
-
-    // If the user gave an override list of regions, use that, otherwise use what's in the config.
-    let regions = if !ssm_args.regions.is_empty() {
-        ssm_args.regions.clone()
-    } else {
-        aws.regions.clone().into()
-    };
+    let prefix = ParameterPrefix::new(&aws);
+
+    // If the user gave an override list of regions, use that, otherwise use what's in the config;
+    // either way, leave out any excluded regions.
+    let regions = exclude_regions(
+        if let Some(path) = &ssm_args.regions_file {
+            regions_from_file(path, &aws).context(error::RegionsFileSnafu)?
+        } else if !ssm_args.regions.is_empty() {
+            ssm_args.regions.clone()
+        } else {
+            aws.regions.clone().into()
+        },
+        &ssm_args.exclude_regions,
+    );
     ensure!(
         !regions.is_empty(),
         error::MissingConfigSnafu {
@@ -77,12 +100,12 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
     );
     let base_region = region_from_string(&regions[0], &aws).context(error::ParseRegionSnafu)?;
 
-    let amis = parse_ami_input(&regions, &ssm_args, &aws)?;
+    let amis = parse_ami_input(&regions, ssm_args, &aws)?;
 
     let mut ssm_clients = HashMap::with_capacity(amis.len());
     for region in amis.keys() {
         let ssm_client =
-            build_client::<SsmClient>(&region, &base_region, &aws).context(error::ClientSnafu {
+            build_client::<SsmClient>(region, &base_region, &aws).context(error::ClientSnafu {
                 client_type: "SSM",
                 region: region.name(),
             })?;
@@ -92,10 +115,11 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
     // Template setup   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     // Non-image-specific context for building and rendering templates
+    let image_version = normalize_version(&ssm_args.version);
     let build_context = BuildContext {
         variant: &ssm_args.variant,
         arch: &ssm_args.arch,
-        image_version: &ssm_args.version,
+        image_version: &image_version,
     };
 
     info!(
@@ -110,11 +134,11 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
             "No parameters for this arch/variant in {}",
             ssm_args.template_path.display()
         );
-        return Ok(());
+        return Ok(HashMap::new());
     }
 
     let new_parameters =
-        template::render_parameters(template_parameters, amis, ssm_prefix, &build_context)
+        template::render_parameters(template_parameters, amis, &prefix, &build_context)
             .context(error::RenderTemplatesSnafu)?;
     trace!("Generated templated parameters: {:#?}", new_parameters);
 
@@ -122,16 +146,16 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
 
     info!("Getting current SSM parameters");
     let new_parameter_names: Vec<&SsmKey> = new_parameters.keys().collect();
-    let current_parameters = ssm::get_parameters(&new_parameter_names, &ssm_clients)
+    let current_parameters = ssm::get_parameters(&new_parameter_names, &ssm_clients, &aws)
         .await
         .context(error::FetchSsmSnafu)?;
     trace!("Current SSM parameters: {:#?}", current_parameters);
 
     // Show the difference between source and target parameters in SSM.
-    let parameters_to_set = key_difference(&new_parameters, &current_parameters);
+    let parameters_to_set = key_difference(&new_parameters, &current_parameters, args.output);
     if parameters_to_set.is_empty() {
         info!("No changes necessary.");
-        return Ok(());
+        return Ok(HashMap::new());
     }
 
     // Unless the user wants to allow it, make sure we're not going to overwrite any existing
@@ -145,17 +169,17 @@ pub(crate) async fn run(args: &Args, ssm_args: &SsmArgs) -> Result<()> {
     // SSM set   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     info!("Setting updated SSM parameters.");
-    ssm::set_parameters(&parameters_to_set, &ssm_clients)
+    ssm::set_parameters(&parameters_to_set, &ssm_clients, &aws)
         .await
         .context(error::SetSsmSnafu)?;
 
     info!("Validating whether live parameters in SSM reflect changes.");
-    ssm::validate_parameters(&parameters_to_set, &ssm_clients)
+    ssm::validate_parameters(&parameters_to_set, &ssm_clients, &aws)
         .await
         .context(error::ValidateSsmSnafu)?;
 
     info!("All parameters match requested values.");
-    Ok(())
+    Ok(parameters_to_set)
 }
 
 /// The key to a unique SSM parameter
@@ -185,9 +209,70 @@ pub(crate) struct BuildContext<'a> {
     pub(crate) image_version: &'a str,
 }
 
+/// The path placed before every parameter name: the `ssm_prefix` from Infra.toml, followed by
+/// the rendered `ssm.path_template`, if given
+pub(crate) struct ParameterPrefix<'a> {
+    ssm_prefix: &'a str,
+    path_template: Option<&'a SsmPathTemplate>,
+}
+
+impl<'a> ParameterPrefix<'a> {
+    pub(crate) fn new(aws: &'a AwsConfig) -> Self {
+        Self {
+            ssm_prefix: aws.ssm_prefix.as_deref().unwrap_or(""),
+            path_template: aws.ssm.as_ref().and_then(|ssm| ssm.path_template.as_ref()),
+        }
+    }
+
+    /// Renders the prefix for parameters of the given build in the given region
+    pub(crate) fn render(&self, build_context: &BuildContext<'_>, region: &Region) -> String {
+        match self.path_template {
+            Some(path_template) => template::join_name(
+                self.ssm_prefix,
+                &path_template.render(
+                    build_context.variant,
+                    build_context.arch,
+                    build_context.image_version,
+                    region.name(),
+                ),
+            ),
+            None => self.ssm_prefix.to_string(),
+        }
+    }
+}
+
 /// A map of SsmKey to its value
 type SsmParameters = HashMap<SsmKey, String>;
 
+/// Summary of the SSM parameters a subcommand set, for reporting results
+#[derive(Debug, Serialize)]
+pub(crate) struct SsmSummary {
+    parameters: Vec<ParameterSummary>,
+}
+
+/// A single SSM parameter that was set
+#[derive(Debug, Serialize)]
+struct ParameterSummary {
+    region: String,
+    name: String,
+    value: String,
+}
+
+impl From<&SsmParameters> for SsmSummary {
+    fn from(parameters: &SsmParameters) -> Self {
+        let mut parameters: Vec<ParameterSummary> = parameters
+            .iter()
+            .map(|(key, value)| ParameterSummary {
+                region: key.region.name().to_string(),
+                name: key.name.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        parameters.sort_by(|a, b| (&a.region, &a.name).cmp(&(&b.region, &b.name)));
+        Self { parameters }
+    }
+}
+
 /// Parse the AMI input file
 fn parse_ami_input(
     regions: &[String],
@@ -237,7 +322,7 @@ fn parse_ami_input(
             .with_context(|| error::UnknownRegionsSnafu {
                 regions: vec![name.clone()],
             })?;
-        let region = region_from_string(&name, &aws).context(error::ParseRegionSnafu)?;
+        let region = region_from_string(name, aws).context(error::ParseRegionSnafu)?;
         amis.insert(region, image);
     }
 
@@ -246,8 +331,17 @@ fn parse_ami_input(
 
 /// Shows the user the difference between two sets of parameters.  We look for parameters in
 /// `wanted` that are either missing or changed in `current`.  We print these differences for the
-/// user, then return the `wanted` values.
-pub(crate) fn key_difference(wanted: &SsmParameters, current: &SsmParameters) -> SsmParameters {
+/// user, then return the `wanted` values.  With JSON output, stdout is kept for the summary, so
+/// the differences are logged instead.
+pub(crate) fn key_difference(
+    wanted: &SsmParameters,
+    current: &SsmParameters,
+    output: OutputFormat,
+) -> SsmParameters {
+    let show = |line: String| match output {
+        OutputFormat::Text => println!("{}", line),
+        OutputFormat::Json => info!("{}", line),
+    };
     let mut parameters_to_set = HashMap::new();
 
     let wanted_keys: HashSet<&SsmKey> = wanted.keys().collect();
@@ -255,12 +349,12 @@ pub(crate) fn key_difference(wanted: &SsmParameters, current: &SsmParameters) ->
 
     for key in wanted_keys.difference(&current_keys) {
         let new_value = &wanted[key];
-        println!(
+        show(format!(
             "{} - {} - new parameter:\n   new value: {}",
             key.name,
             key.region.name(),
             new_value,
-        );
+        ));
         parameters_to_set.insert(
             SsmKey::new(key.region.clone(), key.name.clone()),
             new_value.clone(),
@@ -272,15 +366,15 @@ pub(crate) fn key_difference(wanted: &SsmParameters, current: &SsmParameters) ->
         let new_value = &wanted[key];
 
         if current_value == new_value {
-            println!("{} - {} - no change", key.name, key.region.name());
+            show(format!("{} - {} - no change", key.name, key.region.name()));
         } else {
-            println!(
+            show(format!(
                 "{} - {} - changing value:\n   old value: {}\n   new value: {}",
                 key.name,
                 key.region.name(),
                 current_value,
                 new_value
-            );
+            ));
             parameters_to_set.insert(
                 SsmKey::new(key.region.clone(), key.name.clone()),
                 new_value.clone(),
@@ -351,10 +445,20 @@ mod error {
         #[snafu(display("Cowardly refusing to overwrite parameters without ALLOW_CLOBBER"))]
         NoClobber,
 
+        #[snafu(context(false), display("{}", source))]
+        Output {
+            source: crate::output::Error,
+        },
+
         ParseRegion {
             source: crate::aws::Error,
         },
 
+        #[snafu(display("Failed to read regions file: {}", source))]
+        RegionsFile {
+            source: crate::aws::Error,
+        },
+
         #[snafu(display("Failed to render templates: {}", source))]
         RenderTemplates {
             source: template::Error,
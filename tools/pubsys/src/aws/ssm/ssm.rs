@@ -1,9 +1,11 @@
 //! The ssm module owns the getting and setting of parameters in SSM.
 
 use super::{SsmKey, SsmParameters};
+use crate::aws::RegionAccount;
 use futures::future::{join, ready};
 use futures::stream::{self, StreamExt};
 use log::{debug, error, trace, warn};
+use pubsys_config::AwsConfig;
 use rusoto_core::{Region, RusotoError};
 use rusoto_ssm::{
     GetParametersError, GetParametersRequest, GetParametersResult, PutParameterError,
@@ -11,15 +13,66 @@ use rusoto_ssm::{
 };
 use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::{HashMap, HashSet};
-use std::time::Duration;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// SSM's default PutParameter throughput, in transactions per second per region
+const DEFAULT_PUT_RATE: u32 = 3;
+
+/// The number of put requests we'll have in flight at once in each region
+const REGIONAL_CONCURRENCY: usize = 4;
+
+/// Returns the PutParameter rate limit from the given config, or SSM's default
+fn put_rate(aws: &AwsConfig) -> NonZeroU32 {
+    aws.ssm
+        .as_ref()
+        .and_then(|ssm| ssm.put_rate_limit)
+        .unwrap_or_else(|| NonZeroU32::new(DEFAULT_PUT_RATE).expect("default rate is nonzero"))
+}
+
+/// A token bucket that allows up to `rate` requests per second on average, with bursts of up to
+/// `rate` requests.
+struct TokenBucket {
+    rate: f64,
+    // Available tokens and the time they were last refilled.  Tokens can go negative, which
+    // represents requests that have been promised a future slot.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Takes a token, returning how long the caller must wait before using it.
+    fn reserve(&self) -> Duration {
+        let mut state = self.state.lock().expect("token bucket lock poisoned");
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+        *last = now;
+        *tokens -= 1.0;
+        if *tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
 
-/// Fetches the values of the given SSM keys using the given clients
+/// Fetches the values of the given SSM keys using the given clients; `aws` is only used to say
+/// which account a failed region is in
 // TODO: We can batch GET requests so throttling is less likely here, but if we need to handle
 // hundreds of parameters for a given build, we could use the throttling logic from
 // `set_parameters`
 pub(crate) async fn get_parameters<K>(
     requested: &[K],
     clients: &HashMap<Region, SsmClient>,
+    aws: &AwsConfig,
 ) -> Result<SsmParameters>
 where
     K: AsRef<SsmKey>,
@@ -81,7 +134,7 @@ where
                     continue;
                 } else {
                     return Err(e).context(error::GetParametersSnafu {
-                        region: region.name(),
+                        region: RegionAccount::from_config(region.name(), aws),
                     });
                 }
             }
@@ -96,7 +149,7 @@ where
         ensure!(
             total_count == expected_len,
             error::MissingInResponseSnafu {
-                region: region.name(),
+                region: RegionAccount::from_config(region.name(), aws),
                 request_type: "GetParameters",
                 missing: format!(
                     "parameters - got {}, expected {}",
@@ -110,12 +163,12 @@ where
             if !valid_parameters.is_empty() {
                 for parameter in valid_parameters {
                     let name = parameter.name.context(error::MissingInResponseSnafu {
-                        region: region.name(),
+                        region: RegionAccount::from_config(region.name(), aws),
                         request_type: "GetParameters",
                         missing: "parameter name",
                     })?;
                     let value = parameter.value.context(error::MissingInResponseSnafu {
-                        region: region.name(),
+                        region: RegionAccount::from_config(region.name(), aws),
                         request_type: "GetParameters",
                         missing: format!("value for parameter {}", name),
                     })?;
@@ -135,16 +188,18 @@ where
     Ok(parameters)
 }
 
-/// Sets the values of the given SSM keys using the given clients
+/// Sets the values of the given SSM keys using the given clients, making at most the configured
+/// PutParameter rate of requests per second in each region
 pub(crate) async fn set_parameters(
     parameters_to_set: &SsmParameters,
     ssm_clients: &HashMap<Region, SsmClient>,
+    aws: &AwsConfig,
 ) -> Result<()> {
-    // Start with a small delay between requests, and increase if we get throttled.
-    let mut request_interval = Duration::from_millis(100);
-    let max_interval = Duration::from_millis(1600);
-    let interval_factor = 2;
-    let mut should_increase_interval = false;
+    // Start at the requested rate, and back off if we get throttled anyway.
+    let mut request_rate = f64::from(put_rate(aws).get());
+    let min_rate = request_rate / 16.0;
+    let rate_factor = 2.0;
+    let mut should_decrease_rate = false;
 
     // We run all requests in a batch, and any failed requests are added to the next batch for
     // retry
@@ -176,28 +231,24 @@ pub(crate) async fn set_parameters(
     while !contexts.is_empty() {
         debug!("Starting {} SSM put requests", contexts.len());
 
-        if should_increase_interval {
-            request_interval *= interval_factor;
+        if should_decrease_rate {
+            request_rate /= rate_factor;
             warn!(
-                "Requests were throttled, increasing interval to {:?}",
-                request_interval
+                "Requests were throttled, decreasing rate to {:.2} per second",
+                request_rate
             );
         }
-        should_increase_interval = false;
+        should_decrease_rate = false;
 
-        ensure!(
-            request_interval <= max_interval,
-            error::ThrottledSnafu { max_interval }
-        );
+        ensure!(request_rate >= min_rate, error::ThrottledSnafu { min_rate });
 
         // Build requests for parameters.  We need to group them by region so we can run each
-        // region in parallel.  Each region's stream will be throttled to run one request per
-        // request_interval.
+        // region in parallel.  Each region's stream will be limited to request_rate.
         let mut regional_requests = HashMap::new();
         // Remove contexts from the list with drain; they get added back in if we retry the
         // request.
         for context in contexts.drain(..) {
-            let ssm_client = &ssm_clients[&context.region];
+            let ssm_client = &ssm_clients[context.region];
             let put_request = PutParameterRequest {
                 name: context.name.to_string(),
                 value: context.value.to_string(),
@@ -214,19 +265,27 @@ pub(crate) async fn set_parameters(
             regional_list.push(join(ready(context), put_future));
         }
 
-        // Create a throttled stream per region; throttling applies per region.  (Request futures
-        // are already regional, by virtue of being created with a regional client, so we don't
-        // need the region again here.)
-        let mut throttled_streams = Vec::new();
+        // Create a rate-limited stream per region; limits apply per region.  Each request waits
+        // for a token from its region's bucket before it's sent, and each region has a bounded
+        // number of requests in flight.  (Request futures are already regional, by virtue of
+        // being created with a regional client, so we don't need the region again here.)
+        let mut limited_streams = Vec::new();
         for (_region, request_list) in regional_requests {
-            throttled_streams.push(Box::pin(tokio_stream::StreamExt::throttle(
-                stream::iter(request_list),
-                request_interval,
-            )));
+            let bucket = Arc::new(TokenBucket::new(request_rate));
+            let limited = stream::iter(request_list)
+                .map(move |request| {
+                    let bucket = Arc::clone(&bucket);
+                    async move {
+                        tokio::time::sleep(bucket.reserve()).await;
+                        request.await
+                    }
+                })
+                .buffer_unordered(REGIONAL_CONCURRENCY);
+            limited_streams.push(Box::pin(limited));
         }
 
         // Run all regions in parallel and wait for responses.
-        let parallel_requests = stream::select_all(throttled_streams).buffer_unordered(4);
+        let parallel_requests = stream::select_all(limited_streams);
         let responses: Vec<(
             RequestContext<'_>,
             std::result::Result<PutParameterResult, RusotoError<PutParameterError>>,
@@ -240,10 +299,10 @@ pub(crate) async fn set_parameters(
                 // Even if we were to do a structural match, we would still have to string match
                 // the body of the error.  Simpler to match the string form.
                 if e.to_string().contains("ThrottlingException") {
-                    // We only want to increase the interval once per loop, not once per error,
+                    // We only want to decrease the rate once per loop, not once per error,
                     // because when you get throttled you're likely to get a bunch of throttling
                     // errors at once.
-                    should_increase_interval = true;
+                    should_decrease_rate = true;
                     // Retry the request without increasing the failure counter; the request didn't
                     // fail, a throttle means we couldn't even make the request.
                     contexts.push(context);
@@ -274,19 +333,19 @@ pub(crate) async fn set_parameters(
     }
 
     if !failed_parameters.is_empty() {
-        for (region, failures) in &failed_parameters {
-            for (parameter, error) in failures {
-                error!(
-                    "Failed to set {} in {}: {}",
-                    parameter,
-                    region.name(),
-                    error
-                );
+        let mut failures = Vec::new();
+        for (region, region_failures) in &failed_parameters {
+            let region = RegionAccount::from_config(region.name(), aws);
+            for (parameter, error) in region_failures {
+                error!("Failed to set {} in {}: {}", parameter, region, error);
+                failures.push(format!("{} in {}: {}", parameter, region, error));
             }
         }
+        failures.sort();
         return error::SetParametersSnafu {
-            failure_count: failed_parameters.len(),
+            failure_count: failures.len(),
             total_count,
+            failures,
         }
         .fail();
     }
@@ -298,83 +357,88 @@ pub(crate) async fn set_parameters(
 pub(crate) async fn validate_parameters(
     expected_parameters: &SsmParameters,
     ssm_clients: &HashMap<Region, SsmClient>,
+    aws: &AwsConfig,
 ) -> Result<()> {
     // Fetch the given parameter names
     let expected_parameter_names: Vec<&SsmKey> = expected_parameters.keys().collect();
-    let updated_parameters = get_parameters(&expected_parameter_names, &ssm_clients).await?;
+    let updated_parameters = get_parameters(&expected_parameter_names, ssm_clients, aws).await?;
 
     // Walk through and check each value
-    let mut success = true;
+    let mut failures = Vec::new();
     for (expected_key, expected_value) in expected_parameters {
         let SsmKey {
             region: expected_region,
             name: expected_name,
         } = expected_key;
+        let region = RegionAccount::from_config(expected_region.name(), aws);
         // All parameters should have a value, and it should match the given value, otherwise the
         // parameter wasn't updated / created.
-        if let Some(updated_value) = updated_parameters.get(expected_key) {
-            if updated_value != expected_value {
-                error!(
-                    "Failed to set {} in {}",
-                    expected_name,
-                    expected_region.name()
-                );
-                success = false;
+        let failure = match updated_parameters.get(expected_key) {
+            Some(updated_value) if updated_value != expected_value => {
+                format!("{} in {} has a different value", expected_name, region)
             }
-        } else {
-            error!(
-                "{} in {} still doesn't exist",
-                expected_name,
-                expected_region.name()
-            );
-            success = false;
-        }
+            Some(_) => continue,
+            None => format!("{} in {} still doesn't exist", expected_name, region),
+        };
+        error!("{}", failure);
+        failures.push(failure);
     }
-    ensure!(success, error::ValidateParametersSnafu);
+    failures.sort();
+    ensure!(
+        failures.is_empty(),
+        error::ValidateParametersSnafu { failures }
+    );
 
     Ok(())
 }
 
 mod error {
+    use crate::aws::RegionAccount;
     use rusoto_core::RusotoError;
     use rusoto_ssm::GetParametersError;
     use snafu::Snafu;
-    use std::time::Duration;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub(crate) enum Error {
         #[snafu(display("Failed to fetch SSM parameters in {}: {}", region, source))]
         GetParameters {
-            region: String,
-            source: RusotoError<GetParametersError>,
+            region: RegionAccount,
+            #[snafu(source(from(RusotoError<GetParametersError>, Box::new)))]
+            source: Box<RusotoError<GetParametersError>>,
         },
 
-        #[snafu(display("Response to {} was missing {}", request_type, missing))]
+        #[snafu(display("Response to {} in {} was missing {}", request_type, region, missing))]
         MissingInResponse {
-            region: String,
+            region: RegionAccount,
             request_type: String,
             missing: String,
         },
 
         #[snafu(display(
-            "Failed to set {} of {} parameters; see above",
+            "Failed to set {} of {} parameters:\n{}",
             failure_count,
-            total_count
+            total_count,
+            failures.join("\n")
         ))]
         SetParameters {
             failure_count: usize,
             total_count: usize,
+            failures: Vec<String>,
         },
 
         #[snafu(display(
-            "SSM requests throttled too many times, went beyond our max interval {:?}",
-            max_interval
+            "SSM requests throttled too many times, went below our min rate of {:.2} per second",
+            min_rate
         ))]
-        Throttled { max_interval: Duration },
+        Throttled { min_rate: f64 },
 
-        #[snafu(display("Failed to validate all changes; see above."))]
-        ValidateParameters,
+        #[snafu(display(
+            "Failed to validate {} parameters:\n{}",
+            failures.len(),
+            failures.join("\n")
+        ))]
+        ValidateParameters { failures: Vec<String> },
     }
 }
 pub(crate) use error::Error;
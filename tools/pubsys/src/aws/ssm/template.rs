@@ -1,7 +1,7 @@
 //! The template module owns the finding and rendering of parameter templates that used to generate
 //! SSM parameter names and values.
 
-use super::{BuildContext, SsmKey, SsmParameters};
+use super::{BuildContext, ParameterPrefix, SsmKey, SsmParameters};
 use crate::aws::ami::Image;
 use log::trace;
 use rusoto_core::Region;
@@ -40,7 +40,7 @@ pub(crate) fn get_parameters(
     template_path: &Path,
     build_context: &BuildContext<'_>,
 ) -> Result<TemplateParameters> {
-    let templates_str = fs::read_to_string(&template_path).context(error::FileSnafu {
+    let templates_str = fs::read_to_string(template_path).context(error::FileSnafu {
         op: "read",
         path: &template_path,
     })?;
@@ -74,7 +74,7 @@ pub(crate) fn get_parameters(
 pub(crate) fn render_parameters(
     template_parameters: TemplateParameters,
     amis: HashMap<Region, Image>,
-    ssm_prefix: &str,
+    prefix: &ParameterPrefix<'_>,
     build_context: &BuildContext<'_>,
 ) -> Result<SsmParameters> {
     /// Values that we allow as template variables
@@ -89,6 +89,7 @@ pub(crate) fn render_parameters(
     }
     let mut new_parameters = HashMap::new();
     for (region, image) in amis {
+        let region_prefix = prefix.render(build_context, &region);
         let context = TemplateContext {
             variant: build_context.variant,
             arch: build_context.arch,
@@ -116,7 +117,7 @@ pub(crate) fn render_parameters(
                 })?;
 
             new_parameters.insert(
-                SsmKey::new(region.clone(), join_name(ssm_prefix, &name_suffix)),
+                SsmKey::new(region.clone(), join_name(&region_prefix, &name_suffix)),
                 value,
             );
         }
@@ -125,14 +126,16 @@ pub(crate) fn render_parameters(
     Ok(new_parameters)
 }
 
-/// Render the names of the given template parameters using the fixed data about the current build.
-/// Returns a mapping of templated name to rendered name, so we can associate rendered names to a
-/// common source name
+/// Render the names of the given template parameters using the fixed data about the current build,
+/// for the given region.  Returns a mapping of templated name to rendered name, so we can
+/// associate rendered names to a common source name
 pub(crate) fn render_parameter_names(
     template_parameters: &TemplateParameters,
-    ssm_prefix: &str,
+    prefix: &ParameterPrefix<'_>,
     build_context: &BuildContext<'_>,
+    region: &Region,
 ) -> Result<HashMap<String, String>> {
+    let region_prefix = prefix.render(build_context, region);
     let mut new_parameters = HashMap::new();
     for tp in &template_parameters.parameters {
         let mut tt = TinyTemplate::new();
@@ -141,14 +144,14 @@ pub(crate) fn render_parameter_names(
         let name_suffix = tt
             .render("name", &build_context)
             .context(error::RenderTemplateSnafu { template: &tp.name })?;
-        new_parameters.insert(tp.name.clone(), join_name(ssm_prefix, &name_suffix));
+        new_parameters.insert(tp.name.clone(), join_name(&region_prefix, &name_suffix));
     }
 
     Ok(new_parameters)
 }
 
 /// Make sure prefix and parameter name are separated by one slash
-fn join_name(ssm_prefix: &str, name_suffix: &str) -> String {
+pub(super) fn join_name(ssm_prefix: &str, name_suffix: &str) -> String {
     if ssm_prefix.ends_with('/') && name_suffix.starts_with('/') {
         format!("{}{}", ssm_prefix, &name_suffix[1..])
     } else if ssm_prefix.ends_with('/') || name_suffix.starts_with('/') {
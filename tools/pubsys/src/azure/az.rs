@@ -0,0 +1,181 @@
+//! The az module handles the process of building and executing calls to the Azure CLI, `az`,
+//! which we use to upload VHDs and manage Shared Image Gallery image versions.
+use duct::cmd;
+use log::{debug, trace};
+use snafu::{ensure, ResultExt};
+use std::path::Path;
+
+const AZ: &str = "az";
+
+/// The location of an image version in a Shared Image Gallery
+pub(crate) struct Gallery<'a> {
+    pub(crate) subscription_id: &'a str,
+    pub(crate) resource_group: &'a str,
+    pub(crate) gallery: &'a str,
+    pub(crate) image_definition: &'a str,
+}
+
+impl Gallery<'_> {
+    /// The common arguments `az sig image-version` needs to find an image version
+    fn args<'a>(&'a self, image_version: &'a str) -> Vec<&'a str> {
+        vec![
+            "--subscription",
+            self.subscription_id,
+            "--resource-group",
+            self.resource_group,
+            "--gallery-name",
+            self.gallery,
+            "--gallery-image-definition",
+            self.image_definition,
+            "--gallery-image-version",
+            image_version,
+        ]
+    }
+}
+
+/// Uploads the given VHD to a page blob, which is the only blob type SIG can import from.
+pub(crate) fn upload_vhd(
+    subscription_id: &str,
+    storage_account: &str,
+    container: &str,
+    blob: &str,
+    vhd: &Path,
+) -> Result<()> {
+    let vhd = vhd.display().to_string();
+    run(&[
+        "storage",
+        "blob",
+        "upload",
+        "--subscription",
+        subscription_id,
+        "--auth-mode",
+        "login",
+        "--account-name",
+        storage_account,
+        "--container-name",
+        container,
+        "--name",
+        blob,
+        "--file",
+        &vhd,
+        "--type",
+        "page",
+        "--overwrite",
+        "true",
+    ])
+    .map(|_| ())
+}
+
+/// Returns the resource ID of the given image version, or None if it doesn't exist.
+pub(crate) fn image_version_id(
+    gallery: &Gallery<'_>,
+    image_version: &str,
+) -> Result<Option<String>> {
+    let mut args = vec!["sig", "image-version", "list"];
+    args.extend(&[
+        "--subscription",
+        gallery.subscription_id,
+        "--resource-group",
+        gallery.resource_group,
+        "--gallery-name",
+        gallery.gallery,
+        "--gallery-image-definition",
+        gallery.image_definition,
+    ]);
+    let query = format!("[?name=='{}'].id | [0]", image_version);
+    args.extend(&["--query", &query, "--output", "tsv"]);
+
+    let id = run(&args)?;
+    Ok(if id.is_empty() { None } else { Some(id) })
+}
+
+/// Creates an image version from a VHD blob, returning its resource ID.
+pub(crate) fn create_image_version(
+    gallery: &Gallery<'_>,
+    image_version: &str,
+    vhd_uri: &str,
+    storage_account_id: &str,
+    target_regions: &[String],
+    latest: bool,
+) -> Result<String> {
+    let mut args = vec!["sig", "image-version", "create"];
+    args.extend(gallery.args(image_version));
+    args.extend(&[
+        "--os-vhd-uri",
+        vhd_uri,
+        "--os-vhd-storage-account",
+        storage_account_id,
+        "--exclude-from-latest",
+        exclude_from_latest(latest),
+    ]);
+    if !target_regions.is_empty() {
+        args.push("--target-regions");
+        args.extend(target_regions.iter().map(|r| r.as_str()));
+    }
+    args.extend(&["--query", "id", "--output", "tsv"]);
+
+    run(&args)
+}
+
+/// Updates whether an existing image version is considered for the gallery's "latest", returning
+/// its resource ID.
+pub(crate) fn update_image_version(
+    gallery: &Gallery<'_>,
+    image_version: &str,
+    latest: bool,
+) -> Result<String> {
+    let exclude = format!(
+        "publishingProfile.excludeFromLatest={}",
+        exclude_from_latest(latest)
+    );
+    let mut args = vec!["sig", "image-version", "update"];
+    args.extend(gallery.args(image_version));
+    args.extend(&["--set", &exclude, "--query", "id", "--output", "tsv"]);
+
+    run(&args)
+}
+
+fn exclude_from_latest(latest: bool) -> &'static str {
+    if latest {
+        "false"
+    } else {
+        "true"
+    }
+}
+
+/// Runs `az` with the given arguments, returning its trimmed stdout.
+fn run(args: &[&str]) -> Result<String> {
+    debug!("Running: {} {}", AZ, args.join(" "));
+    let output = cmd(AZ, args)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    trace!("{}", stdout);
+    ensure!(
+        output.status.success(),
+        error::AzSnafu {
+            output: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+    );
+    Ok(stdout)
+}
+
+pub(crate) mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("az invocation failed: {}", output))]
+        Az { output: String },
+
+        #[snafu(display("Failed to start az: {}", source))]
+        CommandStart { source: std::io::Error },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
@@ -0,0 +1,2 @@
+pub(crate) mod az;
+pub(crate) mod publish_image;
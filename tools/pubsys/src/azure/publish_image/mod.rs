@@ -0,0 +1,197 @@
+//! The publish_image module owns the 'publish-image' subcommand and is responsible for uploading
+//! a VHD to Azure storage and making it an image version in a Shared Image Gallery.
+use crate::azure::az::{self, Gallery};
+use crate::{output, Args};
+use log::{info, trace};
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Publishes a VHD as an Azure Shared Image Gallery image version
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct PublishImageArgs {
+    /// Path to the VHD image; it must be a fixed-size VHD
+    #[structopt(long, parse(from_os_str))]
+    vhd: PathBuf,
+
+    /// The image version to create, in Azure's MAJOR.MINOR.PATCH form
+    #[structopt(long)]
+    image_version: String,
+
+    /// Make this version the one used when the image definition is referenced without a version
+    #[structopt(long)]
+    latest: bool,
+}
+
+/// Summary of the published image version, for reporting results
+#[derive(Debug, Serialize)]
+struct PublishImageSummary<'a> {
+    image_version: &'a str,
+    id: &'a str,
+    latest: bool,
+    created: bool,
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, publish_args: &PublishImageArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(error::InfraConfigSnafu)?;
+    trace!("Using infra config: {:?}", infra_config);
+
+    let azure = infra_config
+        .azure
+        .context(error::MissingConfigSnafu { missing: "azure" })?;
+    let subscription_id = azure
+        .subscription_id
+        .as_deref()
+        .context(error::MissingConfigSnafu {
+            missing: "azure.subscription_id",
+        })?;
+    let resource_group = azure
+        .resource_group
+        .as_deref()
+        .context(error::MissingConfigSnafu {
+            missing: "azure.resource_group",
+        })?;
+    let gallery = Gallery {
+        subscription_id,
+        resource_group,
+        gallery: azure
+            .gallery
+            .as_deref()
+            .context(error::MissingConfigSnafu {
+                missing: "azure.gallery",
+            })?,
+        image_definition: azure
+            .image_definition
+            .as_deref()
+            .context(error::MissingConfigSnafu {
+                missing: "azure.image_definition",
+            })?,
+    };
+
+    // Azure requires gallery image versions to be three dot-separated integers
+    let image_version = &publish_args.image_version;
+    ensure!(
+        image_version.split('.').count() == 3
+            && image_version.split('.').all(|p| p.parse::<u32>().is_ok()),
+        error::InvalidVersionSnafu {
+            version: image_version
+        }
+    );
+
+    // If the version already exists, we only need to update whether it's the latest; image
+    // versions can't be re-created from a different VHD.
+    let (id, created) = match az::image_version_id(&gallery, image_version)
+        .context(error::AzSnafu)?
+    {
+        Some(_) => {
+            info!(
+                "Image version {} already exists in gallery '{}', updating it",
+                image_version, gallery.gallery
+            );
+            let id = az::update_image_version(&gallery, image_version, publish_args.latest)
+                .context(error::AzSnafu)?;
+            (id, false)
+        }
+        None => {
+            let storage_account =
+                azure
+                    .storage_account
+                    .as_deref()
+                    .context(error::MissingConfigSnafu {
+                        missing: "azure.storage_account",
+                    })?;
+            let container =
+                azure
+                    .storage_container
+                    .as_deref()
+                    .context(error::MissingConfigSnafu {
+                        missing: "azure.storage_container",
+                    })?;
+            let storage_resource_group = azure
+                .storage_resource_group
+                .as_deref()
+                .unwrap_or(resource_group);
+
+            let blob = format!("{}-{}.vhd", gallery.image_definition, image_version);
+            info!(
+                "Uploading '{}' to storage account '{}' as {}",
+                publish_args.vhd.display(),
+                storage_account,
+                blob
+            );
+            az::upload_vhd(
+                subscription_id,
+                storage_account,
+                container,
+                &blob,
+                &publish_args.vhd,
+            )
+            .context(error::AzSnafu)?;
+
+            let vhd_uri = format!(
+                "https://{}.blob.core.windows.net/{}/{}",
+                storage_account, container, blob
+            );
+            let storage_account_id = format!(
+                "/subscriptions/{}/resourceGroups/{}/providers/Microsoft.Storage/storageAccounts/{}",
+                subscription_id, storage_resource_group, storage_account
+            );
+            info!(
+                "Creating image version {} in gallery '{}'",
+                image_version, gallery.gallery
+            );
+            let id = az::create_image_version(
+                &gallery,
+                image_version,
+                &vhd_uri,
+                &storage_account_id,
+                &azure.target_regions,
+                publish_args.latest,
+            )
+            .context(error::AzSnafu)?;
+            (id, true)
+        }
+    };
+
+    info!("Image version ID: {}", id);
+    let summary = PublishImageSummary {
+        image_version,
+        id: &id,
+        latest: publish_args.latest,
+        created,
+    };
+    output::report(args.output, &summary)?;
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("{}", source))]
+        Az { source: crate::azure::az::Error },
+
+        #[snafu(display("Error reading config: {}", source))]
+        InfraConfig { source: pubsys_config::Error },
+
+        #[snafu(display(
+            "Invalid image version '{}', Azure requires MAJOR.MINOR.PATCH integers",
+            version
+        ))]
+        InvalidVersion { version: String },
+
+        #[snafu(display("Infra.toml is missing {}", missing))]
+        MissingConfig { missing: String },
+
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
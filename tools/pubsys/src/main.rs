@@ -4,98 +4,561 @@
 Currently implemented:
 * building repos, whether starting from an existing repo or from scratch
 * validating repos by loading them and retrieving their targets
-* checking for repository metadata expirations within specified number of days
+* validating the migrations listed in Release.toml
+* downloading a single verified target from a repo
+* mirroring a whole repo, metadata and verified targets, to a local directory, resumably
+* exporting a JSON manifest of every target in a repo, with lengths, hashes, and custom metadata
+* finding, and optionally deleting, targets no valid metadata refers to
+* creating an unsigned root.json for a new repo from the given keys and thresholds
+* signing an already-built repo's metadata, so repos can be built online and signed offline
+* checking offline that each role's metadata has enough valid signatures to meet its threshold
+* checking for repository metadata expirations within specified number of days, for one repo or
+  every repo listed in a file
 * refreshing and re-signing repos' non-root metadata files
 * registering and copying EC2 AMIs
 * Marking EC2 AMIs public (or private again)
+* moving a "latest" tag to newly published EC2 AMIs
 * setting SSM parameters based on built AMIs
 * promoting SSM parameters from versioned entries to named (e.g. 'latest')
+* reading back SSM parameters to check they exist in every region
+* comparing the SSM parameters of two versions, to check before promoting that they exist in the
+  same regions
+* publishing VHDs to Azure Shared Image Galleries
 
 To be implemented:
 * high-level document describing pubsys usage with examples
 
 Configuration comes from:
 * command-line parameters, to specify basic options and paths to the below files
-* Infra.toml, for repo and AMI configuration
+* Infra.toml, for repo and AMI configuration, from a local path or an s3:// URL; single values can
+  be replaced for one run with `--config-override dotted.key=value`
 * Release.toml, for migrations
 * Policy files for repo metadata expiration and update wave timing
+
+Outbound connections go through the HTTP proxy given by `HTTPS_PROXY` (or `HTTP_PROXY` for plain
+HTTP), except for hosts listed in `NO_PROXY`; `--proxy` and `--no-proxy` override them.  This
+covers AWS calls, including fetching Infra.toml from S3, repo metadata and target downloads, and
+tools run for us, like govc.  Signing with a KMS or SSM key in Infra.toml uses clients built by
+tough, which don't use the proxy.
+
+AWS calls carry a `pubsys/<version>` User-Agent.  Tags given with `--request-tag KEY=VALUE` are
+added to the AMIs and snapshots `pubsys ami` registers, and to the AMI copies it makes.
+
+Exit codes let automation tell kinds of failure apart:
+* 0: success, including runs that found nothing to do
+* 1: any failure not listed below
+* 2: partial failure; some regions or items succeeded and others failed
+* 3: configuration error, like a missing or invalid Infra.toml, Release.toml, or region
+* 4: AWS throttled our requests too many times
+* 5: the run took longer than `--operation-timeout`
+* 130: the run was interrupted with Ctrl-C, like a shell reports; in-flight requests are
+  cancelled, and the work that finished is listed before exiting
 */
 
 #![deny(rust_2018_idioms)]
 
 mod aws;
+mod azure;
+mod output;
+mod progress;
+mod proxy;
 mod repo;
 mod vmware;
 
+use log::warn;
+use output::OutputFormat;
+use pubsys_config::{AssumeRoleConfig, AwsConfig, ConfigOverride, InfraConfig};
 use semver::Version;
-use simplelog::{Config as LogConfig, LevelFilter, SimpleLogger};
-use snafu::ResultExt;
+use simplelog::{Config as LogConfig, LevelFilter, SimpleLogger, WriteLogger};
+use snafu::{ensure, ResultExt};
+use std::future::Future;
+use std::io;
 use std::path::PathBuf;
 use std::process;
+use std::time::Duration;
 use structopt::StructOpt;
 use tokio::runtime::Runtime;
+use url::Url;
 
 fn run() -> Result<()> {
     // Parse and store the args passed to the program
-    let args = Args::from_args();
+    let mut args = Args::from_args();
+
+    // SimpleLogger will send errors to stderr and anything less to stdout.  If we're reporting
+    // results as JSON, stdout is reserved for that, so send all logs to stderr.
+    match args.output {
+        OutputFormat::Text => {
+            SimpleLogger::init(args.log_level, LogConfig::default()).context(error::LoggerSnafu)?
+        }
+        OutputFormat::Json => WriteLogger::init(args.log_level, LogConfig::default(), io::stderr())
+            .context(error::LoggerSnafu)?,
+    }
+
+    // Proxy settings are read from the environment when connections are first made, so any
+    // overrides have to be in place before then.
+    proxy::set_overrides(args.proxy.as_ref(), args.no_proxy.as_deref());
 
-    // SimpleLogger will send errors to stderr and anything less to stdout.
-    SimpleLogger::init(args.log_level, LogConfig::default()).context(error::LoggerSnafu)?;
+    aws::client::set_request_tags(args.request_tags.clone());
+
+    // If Infra.toml lives in S3, download it and point the subcommands at the local copy.  The
+    // temporary directory holding it is removed when this is dropped, at the end of the run.
+    let _infra_config_dir = match args
+        .infra_config_path
+        .to_str()
+        .and_then(aws::s3::infra_config_url)
+    {
+        Some(url) => {
+            let (dir, path) = block_on(&args, async {
+                aws::s3::fetch_infra_config(&url, &args.base_aws_config())
+                    .await
+                    .context(error::FetchInfraConfigSnafu)
+            })?;
+            args.infra_config_path = path;
+            Some(dir)
+        }
+        None => None,
+    };
 
     match args.subcommand {
-        SubCommand::Repo(ref repo_args) => repo::run(&args, &repo_args).context(error::RepoSnafu),
+        SubCommand::Repo(ref repo_args) => repo::run(&args, repo_args).context(error::RepoSnafu),
         SubCommand::ValidateRepo(ref validate_repo_args) => {
-            repo::validate_repo::run(&args, &validate_repo_args).context(error::ValidateRepoSnafu)
+            repo::validate_repo::run(&args, validate_repo_args).context(error::ValidateRepoSnafu)
+        }
+        SubCommand::ValidateMigrations(ref validate_migrations_args) => {
+            repo::validate_migrations::run(&args, validate_migrations_args)
+                .context(error::ValidateMigrationsSnafu)
         }
         SubCommand::CheckRepoExpirations(ref check_expirations_args) => {
-            repo::check_expirations::run(&args, &check_expirations_args)
+            repo::check_expirations::run(&args, check_expirations_args)
                 .context(error::CheckExpirationsSnafu)
         }
         SubCommand::RefreshRepo(ref refresh_repo_args) => {
-            repo::refresh_repo::run(&args, &refresh_repo_args).context(error::RefreshRepoSnafu)
+            repo::refresh_repo::run(&args, refresh_repo_args).context(error::RefreshRepoSnafu)
         }
-        SubCommand::Ami(ref ami_args) => {
-            let rt = Runtime::new().context(error::RuntimeSnafu)?;
-            rt.block_on(async {
-                aws::ami::run(&args, &ami_args)
-                    .await
-                    .context(error::AmiSnafu)
-            })
+        SubCommand::DownloadTarget(ref download_args) => {
+            repo::download_target::run(&args, download_args).context(error::DownloadTargetSnafu)
         }
-        SubCommand::PublishAmi(ref publish_args) => {
-            let rt = Runtime::new().context(error::RuntimeSnafu)?;
-            rt.block_on(async {
-                aws::publish_ami::run(&args, &publish_args)
-                    .await
-                    .context(error::PublishAmiSnafu)
-            })
+        SubCommand::RepoMirror(ref mirror_args) => {
+            repo::mirror::run(&args, mirror_args).context(error::RepoMirrorSnafu)
         }
-        SubCommand::Ssm(ref ssm_args) => {
-            let rt = Runtime::new().context(error::RuntimeSnafu)?;
-            rt.block_on(async {
-                aws::ssm::run(&args, &ssm_args)
-                    .await
-                    .context(error::SsmSnafu)
-            })
+        SubCommand::ExportTargetsManifest(ref export_args) => {
+            repo::export_targets_manifest::run(&args, export_args)
+                .context(error::ExportTargetsManifestSnafu)
         }
-        SubCommand::PromoteSsm(ref promote_args) => {
-            let rt = Runtime::new().context(error::RuntimeSnafu)?;
-            rt.block_on(async {
-                aws::promote_ssm::run(&args, &promote_args)
-                    .await
-                    .context(error::PromoteSsmSnafu)
-            })
+        SubCommand::RepoGc(ref gc_args) => {
+            repo::gc::run(&args, gc_args).context(error::RepoGcSnafu)
+        }
+        SubCommand::CreateRoot(ref create_root_args) => {
+            repo::create_root::run(&args, create_root_args).context(error::CreateRootSnafu)
+        }
+        SubCommand::RepoSign(ref sign_args) => {
+            repo::sign::run(&args, sign_args).context(error::RepoSignSnafu)
+        }
+        SubCommand::RepoVerifySignatures(ref verify_args) => {
+            repo::verify_signatures::run(&args, verify_args)
+                .context(error::RepoVerifySignaturesSnafu)
         }
+        SubCommand::Ami(ref ami_args) => block_on(&args, async {
+            aws::ami::run(&args, ami_args)
+                .await
+                .context(error::AmiSnafu)
+        }),
+        SubCommand::PublishAmi(ref publish_args) => block_on(&args, async {
+            aws::publish_ami::run(&args, publish_args)
+                .await
+                .context(error::PublishAmiSnafu)
+        }),
+        SubCommand::LatestAmi(ref latest_args) => block_on(&args, async {
+            aws::latest_ami::run(&args, latest_args)
+                .await
+                .context(error::LatestAmiSnafu)
+        }),
+        SubCommand::Ssm(ref ssm_args) => block_on(&args, async {
+            aws::ssm::run(&args, ssm_args)
+                .await
+                .context(error::SsmSnafu)
+        }),
+        SubCommand::GetSsm(ref get_args) => block_on(&args, async {
+            aws::get_ssm::run(&args, get_args)
+                .await
+                .context(error::GetSsmSnafu)
+        }),
+        SubCommand::DiffSsm(ref diff_args) => block_on(&args, async {
+            aws::diff_ssm::run(&args, diff_args)
+                .await
+                .context(error::DiffSsmSnafu)
+        }),
+        SubCommand::PromoteSsm(ref promote_args) => block_on(&args, async {
+            aws::promote_ssm::run(&args, promote_args)
+                .await
+                .context(error::PromoteSsmSnafu)
+        }),
         SubCommand::UploadOva(ref upload_args) => {
-            vmware::upload_ova::run(&args, &upload_args).context(error::UploadOvaSnafu)
+            vmware::upload_ova::run(&args, upload_args).context(error::UploadOvaSnafu)
+        }
+        SubCommand::PublishImage(ref publish_args) => {
+            azure::publish_image::run(&args, publish_args).context(error::PublishImageSnafu)
+        }
+    }
+}
+
+/// How long in-flight work gets to unwind after Ctrl-C or a timeout before we exit anyway.
+const INTERRUPT_GRACE: Duration = Duration::from_secs(5);
+
+/// Runs a subcommand's async work to completion on a new runtime.  If the user gave
+/// --operation-timeout, it's abandoned once that much time has passed, so a hung AWS call can't
+/// stall a pipeline forever.  Ctrl-C abandons it the same way, so aborting a misfired release
+/// cancels its outstanding requests, rather than being a hard kill.  Either way, we say what was
+/// already done.
+fn block_on<F, T>(args: &Args, future: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let rt = Runtime::new().context(error::RuntimeSnafu)?;
+    let result = rt.block_on(async {
+        // Dropping the subcommand's future when interrupted cancels the requests it's waiting on.
+        tokio::select! {
+            result = with_timeout(args, future) => result,
+            _ = interrupted() => error::InterruptedSnafu.fail(),
+        }
+    });
+
+    let reason = match result {
+        Err(error::Error::Interrupted) => "interrupt",
+        Err(error::Error::OperationTimedOut { .. }) => "timeout",
+        _ => return result,
+    };
+    warn!(
+        "Stopped by {}; waiting up to {}s for in-flight work to stop",
+        reason,
+        INTERRUPT_GRACE.as_secs()
+    );
+    rt.shutdown_timeout(INTERRUPT_GRACE);
+    report_completed(reason);
+    result
+}
+
+/// Completes when the user presses Ctrl-C.  If we can't listen for it, it never completes, and
+/// Ctrl-C falls back to killing the process.
+async fn interrupted() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("Unable to listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Logs the work subcommands recorded as finished before an interrupt or timeout, so the user
+/// knows, for example, which regions got their AMI copies.  Logs go to stderr with JSON output, as
+/// usual.
+fn report_completed(reason: &str) {
+    let completed = progress::completed();
+    if completed.is_empty() {
+        warn!("No work finished before the {}", reason);
+        return;
+    }
+    warn!("Work that finished before the {}:", reason);
+    for description in completed {
+        warn!("  {}", description);
+    }
+    warn!("Requests already accepted by AWS, like AMI copies, may still complete");
+}
+
+/// Runs the future, abandoning it after --operation-timeout, if the user gave one.
+async fn with_timeout<F, T>(args: &Args, future: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match args.operation_timeout {
+        Some(limit) => {
+            match tokio::time::timeout(limit, future).await {
+                Ok(result) => result,
+                // block_on reports the work that finished before we gave up.
+                Err(_) => error::OperationTimedOutSnafu {
+                    seconds: limit.as_secs(),
+                }
+                .fail(),
+            }
         }
+        None => future.await,
     }
 }
 
+/// Parses a duration for --operation-timeout, given as a number of seconds, or with a unit
+/// suffix of 's', 'm', or 'h', like "90s", "30m", or "2h".
+fn parse_timeout(input: &str) -> std::result::Result<Duration, String> {
+    let (count, unit_seconds) = match input.char_indices().last() {
+        Some((i, 's')) => (&input[..i], 1),
+        Some((i, 'm')) => (&input[..i], 60),
+        Some((i, 'h')) => (&input[..i], 60 * 60),
+        _ => (input, 1),
+    };
+    let seconds = count
+        .parse::<u64>()
+        .ok()
+        .and_then(|count| count.checked_mul(unit_seconds))
+        .filter(|seconds| *seconds > 0)
+        .ok_or_else(|| {
+            format!(
+                "Invalid timeout '{}'; expected a positive number of seconds, or something like \
+                 '30m' or '2h'",
+                input
+            )
+        })?;
+    Ok(Duration::from_secs(seconds))
+}
+
 fn main() {
     if let Err(e) = run() {
         eprintln!("{}", e);
-        process::exit(1);
+        process::exit(exit_code(&e));
+    }
+}
+
+/// Exit codes for each kind of failure; see the module documentation.
+mod exit_code {
+    pub(super) const FAILURE: i32 = 1;
+    pub(super) const PARTIAL: i32 = 2;
+    pub(super) const CONFIG: i32 = 3;
+    pub(super) const THROTTLED: i32 = 4;
+    pub(super) const TIMED_OUT: i32 = 5;
+    pub(super) const INTERRUPTED: i32 = 130;
+}
+
+/// Returns the exit code for the given error.  Every subcommand's errors are classified here, so
+/// the codes stay consistent across subcommands.
+fn exit_code(error: &error::Error) -> i32 {
+    use error::Error;
+    match error {
+        Error::FetchInfraConfig { .. } => exit_code::CONFIG,
+        Error::Ami { source } => {
+            use aws::ami::Error as E;
+            match source {
+                E::AmiCopy { .. } => exit_code::PARTIAL,
+                E::Config { .. }
+                | E::IncompatibleBootMode { .. }
+                | E::MissingArg { .. }
+                | E::MissingConfig { .. }
+                | E::ParseOnExists { .. }
+                | E::ParseRegion { .. }
+                | E::RegionsFile { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::PublishAmi { source } => {
+            use aws::publish_ami::Error as E;
+            match source {
+                E::ModifyImagesAttributes { .. }
+                | E::ModifyKeyGrants { .. }
+                | E::ModifySnapshotAttributes { .. } => exit_code::PARTIAL,
+                E::Config { .. }
+                | E::MissingConfig { .. }
+                | E::ParseRegion { .. }
+                | E::RegionsFile { .. }
+                | E::UnknownRegions { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::LatestAmi { source } => {
+            use aws::latest_ami::Error as E;
+            match source {
+                E::MoveTag { .. } => exit_code::PARTIAL,
+                E::Config { .. }
+                | E::MissingConfig { .. }
+                | E::ParseRegion { .. }
+                | E::RegionsFile { .. }
+                | E::UnknownRegion { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::Ssm { source } => {
+            use aws::ssm::Error as E;
+            match source {
+                E::FetchSsm { source } | E::SetSsm { source } | E::ValidateSsm { source } => {
+                    ssm_exit_code(source)
+                }
+                E::Config { .. }
+                | E::MissingConfig { .. }
+                | E::ParseRegion { .. }
+                | E::RegionsFile { .. }
+                | E::UnknownRegions { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::GetSsm { source } => {
+            use aws::get_ssm::Error as E;
+            match source {
+                E::FetchSsm { source } => ssm_exit_code(source),
+                E::MissingParameters { .. } => exit_code::PARTIAL,
+                E::Config { .. }
+                | E::MissingConfig { .. }
+                | E::ParseRegion { .. }
+                | E::RegionsFile { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::DiffSsm { source } => {
+            use aws::diff_ssm::Error as E;
+            match source {
+                E::FetchSsm { source } => ssm_exit_code(source),
+                E::ShapeMismatch { .. } => exit_code::PARTIAL,
+                E::Config { .. }
+                | E::MissingConfig { .. }
+                | E::ParseRegion { .. }
+                | E::RegionsFile { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::PromoteSsm { source } => {
+            use aws::promote_ssm::Error as E;
+            match source {
+                E::FetchSsm { source } | E::SetSsm { source } | E::ValidateSsm { source } => {
+                    ssm_exit_code(source)
+                }
+                E::RegionsFailed { promoted, .. } if *promoted > 0 => exit_code::PARTIAL,
+                E::Config { .. }
+                | E::MissingConfig { .. }
+                | E::ParseRegion { .. }
+                | E::RegionsFile { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::Repo { source } => repo_exit_code(source),
+        Error::ValidateRepo { source } => {
+            use repo::validate_repo::Error as E;
+            match source {
+                E::Repo { source } => repo_exit_code(source),
+                E::BaseUrl { .. } | E::MissingRepo | E::NotBaseUrl { .. } => exit_code::CONFIG,
+                E::TargetMismatch { .. } => exit_code::PARTIAL,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::CheckExpirations { source } => {
+            use repo::check_expirations::Error as E;
+            match source {
+                E::Repo { source } => repo_exit_code(source),
+                E::InvalidThreshold { .. }
+                | E::MissingRepo
+                | E::ReposFileEmpty { .. }
+                | E::ReposFileParse { .. }
+                | E::ReposFileRead { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::RefreshRepo { source } => {
+            use repo::refresh_repo::Error as E;
+            match source {
+                E::Repo { source } => repo_exit_code(source),
+                E::RoleDependency { .. } | E::UnknownRole { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::DownloadTarget { source } => {
+            use repo::download_target::Error as E;
+            match source {
+                E::Repo { source } => repo_exit_code(source),
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::RepoMirror { source } => {
+            use repo::mirror::Error as E;
+            match source {
+                E::Repo { source } => repo_exit_code(source),
+                E::RepoExists { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::ExportTargetsManifest { source } => {
+            use repo::export_targets_manifest::Error as E;
+            match source {
+                E::Repo { source } => repo_exit_code(source),
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::RepoGc { source } => {
+            use repo::gc::Error as E;
+            match source {
+                E::MissingTargetsDir { .. } | E::NoMetadata { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::CreateRoot { source } => {
+            use repo::create_root::Error as E;
+            match source {
+                E::KeyProfile { .. }
+                | E::KeyUrl { .. }
+                | E::Threshold { .. }
+                | E::ThresholdKeys { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::RepoSign { source } => {
+            use repo::sign::Error as E;
+            match source {
+                E::MissingRole { .. } | E::NoAuthorizedKeys { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::RepoVerifySignatures { source } => {
+            use repo::verify_signatures::Error as E;
+            match source {
+                E::MissingRole { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::ValidateMigrations { source } => {
+            use repo::validate_migrations::Error as E;
+            match source {
+                E::Problems { .. } | E::ReleaseConfig { .. } => exit_code::CONFIG,
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::UploadOva { source } => {
+            use vmware::upload_ova::Error as E;
+            match source {
+                E::InfraConfig { .. } | E::MissingConfig { .. } | E::VmwareConfig { .. } => {
+                    exit_code::CONFIG
+                }
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::PublishImage { source } => {
+            use azure::publish_image::Error as E;
+            match source {
+                E::InfraConfig { .. } | E::InvalidVersion { .. } | E::MissingConfig { .. } => {
+                    exit_code::CONFIG
+                }
+                _ => exit_code::FAILURE,
+            }
+        }
+        Error::Interrupted => exit_code::INTERRUPTED,
+        Error::OperationTimedOut { .. } => exit_code::TIMED_OUT,
+        Error::Logger { .. } | Error::Runtime { .. } => exit_code::FAILURE,
+    }
+}
+
+/// Returns the exit code for an error from the shared SSM functions.
+fn ssm_exit_code(error: &aws::ssm::ssm::Error) -> i32 {
+    use aws::ssm::ssm::Error as E;
+    match error {
+        E::SetParameters { .. } | E::ValidateParameters { .. } => exit_code::PARTIAL,
+        E::Throttled { .. } => exit_code::THROTTLED,
+        _ => exit_code::FAILURE,
+    }
+}
+
+/// Returns the exit code for an error from the shared repo functions.
+fn repo_exit_code(error: &repo::Error) -> i32 {
+    use repo::Error as E;
+    match error {
+        E::Config { .. }
+        | E::InvalidImagePath { .. }
+        | E::InvalidJson { .. }
+        | E::MissingConfig { .. }
+        | E::MissingRepoUrls { .. }
+        | E::NoMatchingTargets { .. }
+        | E::ParseUrl { .. }
+        | E::UpdateMetadataRead { .. } => exit_code::CONFIG,
+        _ => exit_code::FAILURE,
     }
 }
 
@@ -107,39 +570,147 @@ struct Args {
     /// How much detail to log; from least to most: ERROR, WARN, INFO, DEBUG, TRACE
     log_level: LevelFilter,
 
+    #[structopt(global = true, long, default_value = "text")]
+    /// How to report results: 'text' for log lines only, or 'json' to also print a summary to stdout
+    output: OutputFormat,
+
+    #[structopt(global = true, long)]
+    /// ARN of a role to assume for all AWS calls, before any roles given in Infra.toml
+    assume_role: Option<String>,
+
+    #[structopt(global = true, long, requires = "assume-role")]
+    /// External ID to give when assuming --assume-role
+    external_id: Option<String>,
+
+    #[structopt(global = true, long, default_value = aws::client::DEFAULT_SESSION_NAME)]
+    /// Session name to give when assuming --assume-role
+    session_name: String,
+
+    #[structopt(
+        global = true,
+        long = "request-tag",
+        number_of_values = 1,
+        parse(try_from_str = aws::client::parse_request_tag)
+    )]
+    /// KEY=VALUE tag to add to the AWS resources we create, where the API allows it, for finding
+    /// them in CloudTrail; may be given more than once
+    request_tags: Vec<(String, String)>,
+
+    #[structopt(global = true, long, parse(try_from_str = parse_timeout))]
+    /// Give up on AWS subcommands, and fetching Infra.toml from S3, after this long; seconds, or
+    /// with a unit, like "30m" or "2h"
+    operation_timeout: Option<Duration>,
+
+    #[structopt(global = true, long = "config-override", number_of_values = 1)]
+    /// dotted.key=value to use in place of what Infra.toml says, like "aws.ssm_prefix=/test";
+    /// may be given more than once
+    config_overrides: Vec<ConfigOverride>,
+
+    #[structopt(global = true, long, parse(try_from_str = proxy::parse_proxy))]
+    /// http:// URL of a proxy for all outbound connections, overriding HTTPS_PROXY and HTTP_PROXY
+    proxy: Option<Url>,
+
+    #[structopt(global = true, long)]
+    /// Comma-separated hosts and domains to reach without the proxy, overriding NO_PROXY; "*"
+    /// means all of them
+    no_proxy: Option<String>,
+
     #[structopt(long, parse(from_os_str))]
-    /// Path to Infra.toml  (NOTE: must be specified before subcommand)
+    /// Path to Infra.toml, or an s3://bucket/key URL to download it from  (NOTE: must be
+    /// specified before subcommand)
     infra_config_path: PathBuf,
 
     #[structopt(subcommand)]
     subcommand: SubCommand,
 }
 
+impl Args {
+    /// Loads the infra config the way subcommands need it, from Infra.lock or Infra.toml, with any
+    /// --config-override values applied
+    pub(crate) fn infra_config(&self, default: bool) -> pubsys_config::Result<InfraConfig> {
+        let mut infra_config = InfraConfig::from_path_or_lock(&self.infra_config_path, default)?
+            .with_overrides(&self.config_overrides)?;
+        if let Some(role) = self.base_role() {
+            infra_config
+                .aws
+                .get_or_insert_with(Default::default)
+                .base_role = Some(role);
+        }
+        Ok(infra_config)
+    }
+
+    /// The AWS config to use before we have an Infra.toml, with only the --assume-role settings
+    fn base_aws_config(&self) -> AwsConfig {
+        AwsConfig {
+            base_role: self.base_role(),
+            ..Default::default()
+        }
+    }
+
+    /// The role every AWS call is made from, if the user gave --assume-role
+    fn base_role(&self) -> Option<AssumeRoleConfig> {
+        self.assume_role.as_ref().map(|arn| AssumeRoleConfig {
+            arn: arn.clone(),
+            external_id: self.external_id.clone(),
+            session_name: self.session_name.clone(),
+        })
+    }
+}
+
 #[derive(Debug, StructOpt)]
 enum SubCommand {
     Repo(repo::RepoArgs),
     ValidateRepo(repo::validate_repo::ValidateRepoArgs),
+    ValidateMigrations(repo::validate_migrations::ValidateMigrationsArgs),
     CheckRepoExpirations(repo::check_expirations::CheckExpirationsArgs),
     RefreshRepo(repo::refresh_repo::RefreshRepoArgs),
+    DownloadTarget(repo::download_target::DownloadTargetArgs),
+    RepoMirror(repo::mirror::RepoMirrorArgs),
+    ExportTargetsManifest(repo::export_targets_manifest::ExportTargetsManifestArgs),
+    RepoGc(repo::gc::RepoGcArgs),
+    CreateRoot(repo::create_root::CreateRootArgs),
+    RepoSign(repo::sign::RepoSignArgs),
+    RepoVerifySignatures(repo::verify_signatures::VerifySignaturesArgs),
 
     Ami(aws::ami::AmiArgs),
     PublishAmi(aws::publish_ami::PublishArgs),
+    LatestAmi(aws::latest_ami::LatestAmiArgs),
 
     Ssm(aws::ssm::SsmArgs),
+    GetSsm(aws::get_ssm::GetSsmArgs),
+    DiffSsm(aws::diff_ssm::DiffSsmArgs),
     PromoteSsm(aws::promote_ssm::PromoteArgs),
 
     UploadOva(vmware::upload_ova::UploadArgs),
+
+    PublishImage(azure::publish_image::PublishImageArgs),
 }
 
-/// Parses a SemVer, stripping a leading 'v' if present
+/// Parses a SemVer, stripping a leading 'v' if present.  Only one 'v' is allowed, and there must
+/// be a version after it.
 pub(crate) fn friendly_version(
-    mut version_str: &str,
-) -> std::result::Result<Version, semver::Error> {
-    if version_str.starts_with('v') {
-        version_str = &version_str[1..];
-    };
+    version_str: &str,
+) -> std::result::Result<Version, error::VersionError> {
+    let stripped = version_str.strip_prefix('v').unwrap_or(version_str);
+    ensure!(
+        !stripped.starts_with('v'),
+        error::VersionPrefixSnafu { input: version_str }
+    );
+    ensure!(
+        !stripped.is_empty(),
+        error::EmptyVersionSnafu { input: version_str }
+    );
+    Version::parse(stripped).context(error::VersionParseSnafu { input: version_str })
+}
 
-    Version::parse(version_str)
+/// Returns the canonical form of a version string, for use anywhere a version is embedded in a
+/// name, like SSM parameter paths.  SemVers lose any leading 'v'; anything else, like "latest",
+/// is returned as-is.
+pub(crate) fn normalize_version(version_str: &str) -> String {
+    match friendly_version(version_str) {
+        Ok(version) => version.to_string(),
+        Err(_) => version_str.to_string(),
+    }
 }
 
 mod error {
@@ -151,14 +722,54 @@ mod error {
         #[snafu(display("Failed to build AMI: {}", source))]
         Ami { source: crate::aws::ami::Error },
 
+        #[snafu(display("Failed to create root role: {}", source))]
+        CreateRoot {
+            source: crate::repo::create_root::Error,
+        },
+
+        #[snafu(display("Failed to compare SSM parameters: {}", source))]
+        DiffSsm { source: crate::aws::diff_ssm::Error },
+
+        #[snafu(display("Failed to download target: {}", source))]
+        DownloadTarget {
+            source: crate::repo::download_target::Error,
+        },
+
+        #[snafu(display("Failed to export targets manifest: {}", source))]
+        ExportTargetsManifest {
+            source: crate::repo::export_targets_manifest::Error,
+        },
+
+        #[snafu(display("Failed to fetch infra config: {}", source))]
+        FetchInfraConfig { source: crate::aws::s3::Error },
+
+        #[snafu(display("Failed to get SSM parameters: {}", source))]
+        GetSsm { source: crate::aws::get_ssm::Error },
+
+        #[snafu(display("Interrupted by Ctrl-C"))]
+        Interrupted,
+
+        #[snafu(display("Failed to move latest AMI tag: {}", source))]
+        LatestAmi {
+            source: crate::aws::latest_ami::Error,
+        },
+
         #[snafu(display("Logger setup error: {}", source))]
         Logger { source: log::SetLoggerError },
 
+        #[snafu(display("Operation timed out after {} seconds", seconds))]
+        OperationTimedOut { seconds: u64 },
+
         #[snafu(display("Failed to publish AMI: {}", source))]
         PublishAmi {
             source: crate::aws::publish_ami::Error,
         },
 
+        #[snafu(display("Failed to publish Azure image: {}", source))]
+        PublishImage {
+            source: crate::azure::publish_image::Error,
+        },
+
         #[snafu(display("Failed to promote SSM: {}", source))]
         PromoteSsm {
             source: crate::aws::promote_ssm::Error,
@@ -167,6 +778,25 @@ mod error {
         #[snafu(display("Failed to build repo: {}", source))]
         Repo { source: crate::repo::Error },
 
+        #[snafu(display("Failed to find unreferenced targets: {}", source))]
+        RepoGc { source: crate::repo::gc::Error },
+
+        #[snafu(display("Failed to mirror repo: {}", source))]
+        RepoMirror { source: crate::repo::mirror::Error },
+
+        #[snafu(display("Failed to sign repo: {}", source))]
+        RepoSign { source: crate::repo::sign::Error },
+
+        #[snafu(display("Failed to verify repo signatures: {}", source))]
+        RepoVerifySignatures {
+            source: crate::repo::verify_signatures::Error,
+        },
+
+        #[snafu(display("Failed to validate migrations: {}", source))]
+        ValidateMigrations {
+            source: crate::repo::validate_migrations::Error,
+        },
+
         #[snafu(display("Failed to validate repository: {}", source))]
         ValidateRepo {
             source: crate::repo::validate_repo::Error,
@@ -193,5 +823,150 @@ mod error {
             source: crate::vmware::upload_ova::Error,
         },
     }
+
+    /// Why a version string can't be used as a SemVer
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum VersionError {
+        #[snafu(display("Version '{}' has no version number", input))]
+        EmptyVersion { input: String },
+
+        #[snafu(display("Version '{}' is not a SemVer like 1.2.3: {}", input, source))]
+        VersionParse {
+            input: String,
+            source: semver::Error,
+        },
+
+        #[snafu(display("Version '{}' may only have one leading 'v'", input))]
+        VersionPrefix { input: String },
+    }
 }
 type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{
+        error, exit_code, friendly_version, normalize_version, parse_timeout, ssm_exit_code,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn friendly_version_strips_v() {
+        assert_eq!(
+            friendly_version("v1.2.3").unwrap(),
+            friendly_version("1.2.3").unwrap()
+        );
+    }
+
+    #[test]
+    fn friendly_version_table() {
+        use error::VersionError as E;
+        let cases: &[(&str, Option<&str>)] = &[
+            ("1.2.3", Some("1.2.3")),
+            ("v1.2.3", Some("1.2.3")),
+            ("v1.2.3-rc.1", Some("1.2.3-rc.1")),
+            ("1.2.3+abcdef", Some("1.2.3+abcdef")),
+            ("vv1.2.3", None),
+            ("v", None),
+            ("", None),
+            ("v1.2", None),
+            ("V1.2.3", None),
+            (" v1.2.3", None),
+        ];
+        for (input, expected) in cases {
+            let parsed = friendly_version(input);
+            assert_eq!(
+                parsed.as_ref().ok().map(|v| v.to_string()).as_deref(),
+                *expected,
+                "parsing {:?}: {:?}",
+                input,
+                parsed
+            );
+        }
+        assert!(matches!(
+            friendly_version("vv1.2.3"),
+            Err(E::VersionPrefix { .. })
+        ));
+        assert!(matches!(friendly_version("v"), Err(E::EmptyVersion { .. })));
+        assert!(matches!(
+            friendly_version("v1.2"),
+            Err(E::VersionParse { .. })
+        ));
+    }
+
+    #[test]
+    fn normalize_semver() {
+        assert_eq!(normalize_version("v1.2.3"), "1.2.3");
+        assert_eq!(normalize_version("1.2.3"), "1.2.3");
+    }
+
+    #[test]
+    fn normalize_prerelease_and_build() {
+        assert_eq!(normalize_version("v1.2.3-rc.1"), "1.2.3-rc.1");
+        assert_eq!(
+            normalize_version("v1.2.3-rc.1+build.5"),
+            "1.2.3-rc.1+build.5"
+        );
+        assert_eq!(normalize_version("1.2.3+abcdef"), "1.2.3+abcdef");
+    }
+
+    #[test]
+    fn normalize_non_semver() {
+        // Named versions like the promote-ssm 'latest' alias are left alone
+        assert_eq!(normalize_version("latest"), "latest");
+        assert_eq!(normalize_version("v1.2"), "v1.2");
+    }
+
+    #[test]
+    fn normalized_forms_agree() {
+        // Parameters written under one form must be found when looked up under the other
+        for (a, b) in &[
+            ("v1.2.3", "1.2.3"),
+            ("v1.2.3-rc.1", "1.2.3-rc.1"),
+            ("v1.2.3-rc.1+build.5", "1.2.3-rc.1+build.5"),
+        ] {
+            assert_eq!(normalize_version(a), normalize_version(b));
+        }
+    }
+
+    #[test]
+    fn ssm_failures_classified() {
+        use crate::aws::ssm::ssm::Error as E;
+        assert_eq!(
+            ssm_exit_code(&E::Throttled { min_rate: 1.0 }),
+            exit_code::THROTTLED
+        );
+        assert_eq!(
+            ssm_exit_code(&E::SetParameters {
+                failure_count: 1,
+                total_count: 2,
+                failures: vec!["/a in us-west-2: ValidationException".to_string()],
+            }),
+            exit_code::PARTIAL
+        );
+    }
+
+    #[test]
+    fn nested_errors_classified() {
+        let error = error::Error::GetSsm {
+            source: crate::aws::get_ssm::Error::MissingConfig {
+                missing: "aws.regions".to_string(),
+            },
+        };
+        assert_eq!(super::exit_code(&error), exit_code::CONFIG);
+    }
+
+    #[test]
+    fn timeout_units() {
+        assert_eq!(parse_timeout("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_timeout("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_timeout("30m").unwrap(), Duration::from_secs(30 * 60));
+        assert_eq!(
+            parse_timeout("2h").unwrap(),
+            Duration::from_secs(2 * 60 * 60)
+        );
+        assert!(parse_timeout("0").is_err());
+        assert!(parse_timeout("2d").is_err());
+        assert!(parse_timeout("m").is_err());
+    }
+}
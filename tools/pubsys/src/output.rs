@@ -0,0 +1,61 @@
+//! The output module controls how subcommands report their results.  By default, results are
+//! only described in log lines meant for humans; with `--output json`, each subcommand also
+//! prints a structured summary to stdout so automation doesn't have to scrape logs.
+
+use serde::Serialize;
+use snafu::ResultExt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// The ways we can report subcommand results
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => error::UnknownFormatSnafu { format: s }.fail(),
+        }
+    }
+}
+
+/// Reports the summary of a subcommand in the requested format.  In text mode, results have
+/// already been logged, so this does nothing; in JSON mode, the summary is written to stdout.
+pub(crate) fn report<S: Serialize>(format: OutputFormat, summary: &S) -> Result<()> {
+    match format {
+        OutputFormat::Text => Ok(()),
+        OutputFormat::Json => {
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            serde_json::to_writer_pretty(&mut handle, summary).context(error::SerializeSnafu)?;
+            writeln!(handle).context(error::WriteSnafu)
+        }
+    }
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::io;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Failed to serialize output: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Unknown output format '{}', expected 'text' or 'json'", format))]
+        UnknownFormat { format: String },
+
+        #[snafu(display("Failed to write output: {}", source))]
+        Write { source: io::Error },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
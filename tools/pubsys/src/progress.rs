@@ -0,0 +1,25 @@
+//! Keeps a record of the work that finished during a run, like the regions an AMI was copied to,
+//! so that if the run is interrupted or times out we can still say what completed.  Subcommands
+//! record each item as its request returns, rather than after collecting every result, since
+//! stopping early drops whatever hasn't been collected yet.
+
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// Descriptions of the work that has finished so far, in the order it finished.
+    static ref COMPLETED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+/// Records that the described work, like "Registered ami-123 in us-west-2", has finished.
+pub(crate) fn record<S: Into<String>>(description: S) {
+    // A poisoned lock means a thread panicked while recording; the record is only used for
+    // reporting, so we keep going with whatever is there.
+    let mut completed = COMPLETED.lock().unwrap_or_else(|e| e.into_inner());
+    completed.push(description.into());
+}
+
+/// Returns descriptions of the work that has finished so far.
+pub(crate) fn completed() -> Vec<String> {
+    COMPLETED.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
@@ -1,34 +1,50 @@
 //! The repo module owns the 'repo' subcommand and controls the process of building a repository.
 
 pub(crate) mod check_expirations;
+pub(crate) mod create_root;
+pub(crate) mod download_target;
+pub(crate) mod export_targets_manifest;
+pub(crate) mod gc;
+pub(crate) mod mirror;
 pub(crate) mod refresh_repo;
+pub(crate) mod sign;
+pub(crate) mod validate_migrations;
 pub(crate) mod validate_repo;
+pub(crate) mod verify_signatures;
 
-use crate::{friendly_version, Args};
+use crate::repo::validate_repo::MAX_DOWNLOAD_THREADS;
+use crate::{friendly_version, output, Args};
 use chrono::{DateTime, Utc};
+use glob::Pattern;
 use lazy_static::lazy_static;
 use log::{debug, info, trace, warn};
 use parse_datetime::parse_datetime;
-use pubsys_config::{
-    InfraConfig, KMSKeyConfig, RepoConfig, RepoExpirationPolicy, SigningKeyConfig,
-};
-use rusoto_core::Region;
+use pubsys_config::{KMSKeyConfig, RepoConfig, RepoExpirationPolicy, SigningKeyConfig};
+use rusoto_core::{Region, RusotoError};
 use rusoto_kms::KmsClient;
 use semver::Version;
-use snafu::{ensure, OptionExt, ResultExt};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256, Sha512};
+use snafu::{ensure, IntoError, OptionExt, ResultExt};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::num::NonZeroU64;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
-use tempfile::NamedTempFile;
+use tempfile::{NamedTempFile, TempDir};
 use tough::{
     editor::signed::PathExists,
     editor::RepositoryEditor,
     key_source::{KeySource, LocalKeySource},
-    schema::Target,
-    RepositoryLoader, TransportErrorKind,
+    schema::{decoded::Decoded, Hashes, Target, Targets},
+    DefaultTransport, RepositoryLoader, TargetName, Transport, TransportErrorKind,
 };
 use tough_kms::{KmsKeySource, KmsSigningAlgorithm};
 use tough_ssm::SsmKeySource;
@@ -39,6 +55,9 @@ lazy_static! {
     static ref DEFAULT_START_TIME: DateTime<Utc> = Utc::now();
 }
 
+/// How often we log a summary line about target progress, if the user asked for it
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
 /// Builds Bottlerocket repos using latest build artifacts
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
@@ -75,6 +94,14 @@ pub(crate) struct RepoArgs {
     #[structopt(long = "copy-target", parse(from_os_str))]
     /// Optional paths to add as targets and copy into repo
     copy_targets: Vec<PathBuf>,
+    #[structopt(long = "remote-target")]
+    /// Optional http(s) URLs of files to download, verify, and add as targets and copy into repo,
+    /// each given with its expected sha256 like "https://example.com/file#sha256=<hex>"
+    remote_targets: Vec<RemoteTarget>,
+    #[structopt(long, use_delimiter = true, default_value = "sha256")]
+    /// Hash algorithms to compute and record for each target, comma separated, from "sha256" and
+    /// "sha512"; the sha256 that TUF clients check is always recorded
+    target_hash: Vec<TargetHash>,
 
     // Policies that pubsys interprets to set repo parameters
     #[structopt(long, parse(from_os_str))]
@@ -99,9 +126,189 @@ pub(crate) struct RepoArgs {
     /// When the waves and expiration timer will start; RFC3339 date or "in X hours/days/weeks"
     release_start_time: Option<DateTime<Utc>>,
 
-    #[structopt(long, parse(from_os_str))]
-    /// Where to store the created repo
+    #[structopt(long, alias = "output-dir", parse(from_os_str))]
+    /// Where to store the created repo; it's built in a staging directory inside and only moved
+    /// into place once complete
     outdir: PathBuf,
+
+    #[structopt(long)]
+    /// Periodically log a summary of how many targets have been processed
+    progress: bool,
+
+    #[structopt(long, parse(try_from_str = Pattern::new))]
+    /// Only add or update --copy-target and --link-target targets whose file names match this
+    /// glob; other given targets must already be in the existing repo.  The update's images and
+    /// manifest are always added, since the manifest refers to them
+    only_targets: Option<Pattern>,
+    #[structopt(long)]
+    /// Build on the existing repo, keeping the entries of targets whose contents are unchanged,
+    /// and the targets metadata version if nothing changed.  This is useful on its own, so it
+    /// doesn't require --only-targets, but with it, also allows leaving out given targets that
+    /// aren't in the existing repo
+    incremental: bool,
+}
+
+/// A target file served over HTTP(S), and the sha256 we expect it to have
+#[derive(Debug)]
+struct RemoteTarget {
+    url: Url,
+    sha256: String,
+}
+
+impl RemoteTarget {
+    /// The name the target is given in the repo, from the last segment of its URL path
+    fn name(&self) -> Result<&str> {
+        self.url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|name| !name.is_empty())
+            .context(error::RemoteTargetNameSnafu {
+                url: self.url.clone(),
+            })
+    }
+}
+
+impl FromStr for RemoteTarget {
+    type Err = Error;
+
+    /// Parses a URL with the expected sha256 given as its fragment, like pip's
+    /// "https://example.com/file#sha256=<hex>"
+    fn from_str(input: &str) -> Result<Self> {
+        let mut url = Url::parse(input).context(error::ParseUrlSnafu { input })?;
+        ensure!(
+            url.scheme() == "http" || url.scheme() == "https",
+            error::RemoteTargetSchemeSnafu { url }
+        );
+        let sha256 = url
+            .fragment()
+            .and_then(|fragment| fragment.strip_prefix("sha256="))
+            .filter(|sha256| sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(str::to_ascii_lowercase)
+            .context(error::RemoteTargetChecksumMissingSnafu { url: url.clone() })?;
+        url.set_fragment(None);
+        Ok(Self { url, sha256 })
+    }
+}
+
+/// Downloads one remote target to the given path, hashing it as we write it, and makes sure the
+/// sha256 is the one we expected.  tough's transport retries failed requests for us.
+fn download_remote_target(target: &RemoteTarget, path: &Path) -> Result<()> {
+    let url = &target.url;
+    let mut reader = DefaultTransport::new()
+        .fetch(url.clone())
+        .context(error::RemoteTargetFetchSnafu { url: url.clone() })?;
+    let mut file = File::create(path).context(error::RemoteTargetWriteSnafu { path })?;
+    let mut digest = Sha256::new();
+    let mut buf = [0; 8192];
+    loop {
+        let count = reader
+            .read(&mut buf)
+            .context(error::RemoteTargetDownloadSnafu { url: url.clone() })?;
+        if count == 0 {
+            break;
+        }
+        digest.update(&buf[..count]);
+        file.write_all(&buf[..count])
+            .context(error::RemoteTargetWriteSnafu { path })?;
+    }
+    let actual = hex::encode(digest.finalize());
+    ensure!(
+        actual == target.sha256,
+        error::RemoteTargetMismatchSnafu {
+            url: url.as_str(),
+            expected: &target.sha256,
+            actual,
+        }
+    );
+    Ok(())
+}
+
+/// Downloads the given remote targets into `dir` in parallel, returning the paths of the verified
+/// files so they can be added like local targets.  We use a Rayon thread pool for the same reason
+/// as validate-repo: `reqwest::blocking` creates its own tokio runtime.
+fn download_remote_targets(remote_targets: &[RemoteTarget], dir: &Path) -> Result<Vec<PathBuf>> {
+    // Targets are named by file name, so two URLs ending in the same name would collide.
+    let mut names = HashSet::new();
+    for target in remote_targets {
+        let name = target.name()?;
+        ensure!(
+            names.insert(name),
+            error::RemoteTargetDuplicateSnafu { name }
+        );
+    }
+
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(min(num_cpus::get(), MAX_DOWNLOAD_THREADS))
+        .build()
+        .context(error::ThreadPoolSnafu)?;
+    let (tx, rx) = mpsc::channel();
+    thread_pool.scope(|scope| {
+        for target in remote_targets {
+            let tx = tx.clone();
+            scope.spawn(move |_| {
+                info!("Downloading remote target: {}", target.url);
+                let result = target.name().and_then(|name| {
+                    let path = dir.join(name);
+                    download_remote_target(target, &path).map(|()| path)
+                });
+                // inability to send on this channel is unrecoverable
+                tx.send(result).unwrap();
+            });
+        }
+    });
+    drop(tx);
+
+    let mut paths = rx.into_iter().collect::<Result<Vec<PathBuf>>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Tracks how many of a known number of targets have been handled during one phase of the repo
+/// build, so users can tell a slow build from a stuck one.
+struct TargetProgress {
+    phase: &'static str,
+    total: usize,
+    done: usize,
+    summary: bool,
+    last_summary: Instant,
+}
+
+impl TargetProgress {
+    fn new(phase: &'static str, total: usize, summary: bool) -> Self {
+        Self {
+            phase,
+            total,
+            done: 0,
+            summary,
+            last_summary: Instant::now(),
+        }
+    }
+
+    /// Records that the target at the given path was handled.  Every target is logged at DEBUG;
+    /// if summaries were requested, we also log at INFO every PROGRESS_INTERVAL and once the
+    /// phase is complete.
+    fn tick<P>(&mut self, path: P)
+    where
+        P: AsRef<Path>,
+    {
+        self.done += 1;
+        debug!(
+            "{} target {} of {}: {}",
+            self.phase,
+            self.done,
+            self.total,
+            path.as_ref().display()
+        );
+        if self.summary
+            && (self.done == self.total || self.last_summary.elapsed() >= PROGRESS_INTERVAL)
+        {
+            info!(
+                "{}: {} of {} targets done",
+                self.phase, self.done, self.total
+            );
+            self.last_summary = Instant::now();
+        }
+    }
 }
 
 /// Adds update, migrations, and waves to the Manifest
@@ -215,11 +422,16 @@ fn set_expirations(
     Ok(())
 }
 
-/// Set versions of all role metadata; the version will be the UNIX timestamp of the current time.
-fn set_versions(editor: &mut RepositoryEditor) -> Result<()> {
+/// Returns the version to give refreshed role metadata: the UNIX timestamp of the current time.
+fn repo_version() -> NonZeroU64 {
     let seconds = Utc::now().timestamp();
     let unsigned_seconds = seconds.try_into().expect("System clock before 1970??");
-    let version = NonZeroU64::new(unsigned_seconds).expect("System clock exactly 1970??");
+    NonZeroU64::new(unsigned_seconds).expect("System clock exactly 1970??")
+}
+
+/// Set versions of all role metadata; the version will be the UNIX timestamp of the current time.
+fn set_versions(editor: &mut RepositoryEditor) -> Result<()> {
+    let version = repo_version();
     debug!("Repo version: {}", version);
     editor
         .snapshot_version(version)
@@ -230,36 +442,193 @@ fn set_versions(editor: &mut RepositoryEditor) -> Result<()> {
     Ok(())
 }
 
-/// Adds targets, expirations, and version to the RepositoryEditor
+/// A hash algorithm we can record in the entry for each target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TargetHash {
+    Sha256,
+    Sha512,
+}
+
+impl TargetHash {
+    /// The key for this hash in a target's hashes, as given in the TUF spec
+    fn key(self) -> &'static str {
+        match self {
+            TargetHash::Sha256 => "sha256",
+            TargetHash::Sha512 => "sha512",
+        }
+    }
+}
+
+impl FromStr for TargetHash {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        match input.trim() {
+            "sha256" => Ok(TargetHash::Sha256),
+            "sha512" => Ok(TargetHash::Sha512),
+            _ => error::TargetHashSnafu { input }.fail(),
+        }
+    }
+}
+
+/// Builds the entry for the target file at `path`, reading it once to compute its length and each
+/// requested hash.  tough only knows about sha256, so other hashes go in the extra fields of the
+/// hashes; they're signed along with the rest of the targets metadata.
+fn build_target(path: &Path, hashes: &[TargetHash]) -> Result<Target> {
+    let mut file = File::open(path).context(error::FileSnafu { path })?;
+    let mut sha256 = Sha256::new();
+    let mut sha512 = hashes.contains(&TargetHash::Sha512).then(Sha512::new);
+    let mut buf = [0; 8192];
+    let mut length = 0;
+    loop {
+        let count = file.read(&mut buf).context(error::FileSnafu { path })?;
+        if count == 0 {
+            break;
+        }
+        sha256.update(&buf[..count]);
+        if let Some(sha512) = &mut sha512 {
+            sha512.update(&buf[..count]);
+        }
+        length += count as u64;
+    }
+
+    let mut extra_hashes = HashMap::new();
+    if let Some(sha512) = sha512 {
+        extra_hashes.insert(
+            TargetHash::Sha512.key().to_string(),
+            Value::String(hex::encode(sha512.finalize())),
+        );
+    }
+    Ok(Target {
+        length,
+        hashes: Hashes {
+            sha256: Decoded::from(sha256.finalize().to_vec()),
+            _extra: extra_hashes,
+        },
+        custom: HashMap::new(),
+        _extra: HashMap::new(),
+    })
+}
+
+/// Returns the name a target file gets in the repo: its file name.
+fn target_name(path: &Path) -> Result<&str> {
+    path.file_name()
+        .context(error::InvalidImagePathSnafu { path })?
+        .to_str()
+        .context(error::NonUtf8PathSnafu { path })
+}
+
+/// Returns the entry for the named target in the given targets metadata, if it has one.
+fn existing_target<'a>(existing: &'a Targets, name: &str) -> Result<Option<&'a Target>> {
+    let target_name =
+        TargetName::new(name).context(error::ParseTargetNameSnafu { target: name })?;
+    Ok(existing.targets.get(&target_name))
+}
+
+/// Adds the given targets and the manifest to the RepositoryEditor, recording the requested hashes
+/// for each.  When building incrementally on the given existing targets metadata, a target whose
+/// length and hashes match an existing entry keeps that entry.  Every target is hashed to tell;
+/// some targets, like migrations, keep their names across builds, so a file can change without
+/// its name or size changing.  Returns whether the targets metadata changed from the existing
+/// one, which is always true for a fresh build.
+fn add_targets(
+    editor: &mut RepositoryEditor,
+    targets: &[&PathBuf],
+    manifest_path: &Path,
+    existing: Option<&Targets>,
+    hashes: &[TargetHash],
+    progress: &mut TargetProgress,
+) -> Result<bool> {
+    let mut changed = existing.is_none();
+
+    let named_targets = targets
+        .iter()
+        .map(|path| target_name(path).map(|name| (name, path.as_path())))
+        .chain(std::iter::once(Ok(("manifest.json", manifest_path))));
+    for named_target in named_targets {
+        let (name, path) = named_target?;
+        let target = build_target(path, hashes)?;
+        let unchanged = match existing {
+            Some(existing) => existing_target(existing, name)?.is_some_and(|existing| {
+                existing.length == target.length && existing.hashes == target.hashes
+            }),
+            None => false,
+        };
+        if unchanged {
+            debug!("Keeping existing entry for unchanged target '{}'", name);
+        } else {
+            changed = true;
+            editor
+                .add_target(name, target)
+                .context(error::AddTargetSnafu { path })?;
+        }
+        progress.tick(path);
+    }
+
+    Ok(changed)
+}
+
+/// Sets expirations and versions of all non-root role metadata for a repo build.  If given the
+/// existing targets metadata because none of its targets changed, the targets role keeps that
+/// version and expiration, so it's rebuilt byte for byte; snapshot and timestamp always get new
+/// ones, since clients need them refreshed to see the rebuilt repo.
+fn set_build_expirations_and_versions(
+    editor: &mut RepositoryEditor,
+    expiration_policy: &RepoExpirationPolicy,
+    expiration_start_time: DateTime<Utc>,
+    unchanged_targets: Option<&Targets>,
+) -> Result<()> {
+    set_expirations(editor, expiration_policy, expiration_start_time)?;
+    set_versions(editor)?;
+
+    if let Some(existing) = unchanged_targets {
+        info!(
+            "Targets are unchanged, keeping targets version {} and expiration {}",
+            existing.version, existing.expires
+        );
+        editor
+            .targets_version(existing.version)
+            .context(error::SetTargetsVersionSnafu {
+                version: existing.version,
+            })?
+            .targets_expires(existing.expires)
+            .context(error::SetTargetsExpirationSnafu {
+                expiration: existing.expires,
+            })?;
+    }
+
+    Ok(())
+}
+
+/// Adds targets, expirations, and version to the RepositoryEditor.  Given the existing targets
+/// metadata for an incremental build, reuses what it can from it; see `add_targets`.
 fn update_editor<'a, P>(
     repo_args: &'a RepoArgs,
     editor: &mut RepositoryEditor,
     targets: impl Iterator<Item = &'a PathBuf>,
     manifest_path: P,
+    existing: Option<&Targets>,
 ) -> Result<()>
 where
     P: AsRef<Path>,
 {
     // Add targets   =^..^=   =^..^=   =^..^=   =^..^=
 
-    for target_path in targets {
-        debug!("Adding target from path: {}", target_path.display());
-        editor
-            .add_target_path(&target_path)
-            .context(error::AddTargetSnafu { path: &target_path })?;
-    }
-
-    let manifest_target = Target::from_path(&manifest_path).context(error::BuildTargetSnafu {
-        path: manifest_path.as_ref(),
-    })?;
-    debug!("Adding target for manifest.json");
-    editor
-        .add_target("manifest.json", manifest_target)
-        .context(error::AddTargetSnafu {
-            path: "manifest.json",
-        })?;
+    // Adding a target means reading and hashing it, which can take a while for big images, so we
+    // track progress through the list.  (+1 for the manifest.)
+    let targets: Vec<&PathBuf> = targets.collect();
+    info!("Adding {} targets to repo", targets.len() + 1);
+    let mut progress = TargetProgress::new("Added", targets.len() + 1, repo_args.progress);
+    let changed = add_targets(
+        editor,
+        &targets,
+        manifest_path.as_ref(),
+        existing,
+        &repo_args.target_hash,
+        &mut progress,
+    )?;
 
-    // Add expirations   =^..^=   =^..^=   =^..^=   =^..^=
+    // Add expirations and versions   =^..^=   =^..^=   =^..^=   =^..^=
 
     info!(
         "Using repo expiration policy from path: {}",
@@ -267,36 +636,13 @@ where
     );
     let expiration = RepoExpirationPolicy::from_path(&repo_args.repo_expiration_policy_path)
         .context(error::ConfigSnafu)?;
-
     let expiration_start_time = repo_args.release_start_time.unwrap_or(*DEFAULT_START_TIME);
-    let snapshot_expiration = expiration_start_time + expiration.snapshot_expiration;
-    let targets_expiration = expiration_start_time + expiration.targets_expiration;
-    let timestamp_expiration = expiration_start_time + expiration.timestamp_expiration;
-    info!(
-        "Repo expiration times:\n\tsnapshot:  {}\n\ttargets:   {}\n\ttimestamp: {}",
-        snapshot_expiration, targets_expiration, timestamp_expiration
-    );
-    editor
-        .snapshot_expires(snapshot_expiration)
-        .targets_expires(targets_expiration)
-        .context(error::SetTargetsExpirationSnafu {
-            expiration: targets_expiration,
-        })?
-        .timestamp_expires(timestamp_expiration);
-
-    // Add version   =^..^=   =^..^=   =^..^=   =^..^=
-
-    let seconds = Utc::now().timestamp();
-    let unsigned_seconds = seconds.try_into().expect("System clock before 1970??");
-    let version = NonZeroU64::new(unsigned_seconds).expect("System clock exactly 1970??");
-    debug!("Repo version: {}", version);
-    editor
-        .snapshot_version(version)
-        .targets_version(version)
-        .context(error::SetTargetsVersionSnafu { version })?
-        .timestamp_version(version);
-
-    Ok(())
+    set_build_expirations_and_versions(
+        editor,
+        &expiration,
+        expiration_start_time,
+        existing.filter(|_| !changed),
+    )
 }
 
 /// If the infra config has a repo section defined for the given repo, and it has metadata base and
@@ -309,17 +655,7 @@ fn repo_urls<'a>(
     // Check if both URLs are set
     if let Some(metadata_base_url) = repo_config.metadata_base_url.as_ref() {
         if let Some(targets_url) = repo_config.targets_url.as_ref() {
-            let base_slash = if metadata_base_url.as_str().ends_with('/') {
-                ""
-            } else {
-                "/"
-            };
-            let metadata_url_str =
-                format!("{}{}{}/{}", metadata_base_url, base_slash, variant, arch);
-            let metadata_url = Url::parse(&metadata_url_str).context(error::ParseUrlSnafu {
-                input: &metadata_url_str,
-            })?;
-
+            let metadata_url = metadata_url(metadata_base_url, variant, arch)?;
             debug!("Using metadata url: {}", metadata_url);
             return Ok(Some((metadata_url, targets_url)));
         }
@@ -328,14 +664,28 @@ fn repo_urls<'a>(
     Ok(None)
 }
 
-/// Builds an editor and manifest; will start from an existing repo if one is specified in the
-/// configuration.  Returns Err if we fail to read from the repo.  Returns Ok(None) if we detect
-/// that the repo does not exist.
+/// Returns the URL of the metadata for the given variant and arch under the metadata base URL,
+/// which is where the 'repo' subcommand puts it relative to its output directory.
+fn metadata_url(metadata_base_url: &Url, variant: &str, arch: &str) -> Result<Url> {
+    let base_slash = if metadata_base_url.as_str().ends_with('/') {
+        ""
+    } else {
+        "/"
+    };
+    let metadata_url_str = format!("{}{}{}/{}", metadata_base_url, base_slash, variant, arch);
+    Url::parse(&metadata_url_str).context(error::ParseUrlSnafu {
+        input: &metadata_url_str,
+    })
+}
+
+/// Builds an editor and manifest, and returns the targets metadata already in the repo; will
+/// start from an existing repo if one is specified in the configuration.  Returns Err if we fail
+/// to read from the repo.  Returns Ok(None) if we detect that the repo does not exist.
 fn load_editor_and_manifest<'a, P>(
     root_role_path: P,
     metadata_url: &'a Url,
     targets_url: &'a Url,
-) -> Result<Option<(RepositoryEditor, Manifest)>>
+) -> Result<Option<(RepositoryEditor, Manifest, Targets)>>
 where
     P: AsRef<Path>,
 {
@@ -369,11 +719,12 @@ where
             let manifest = serde_json::from_reader(reader).context(error::InvalidJsonSnafu {
                 path: "manifest.json",
             })?;
+            let existing_targets = repo.targets().signed.clone();
 
             let editor = RepositoryEditor::from_repo(root_role_path, repo)
                 .context(error::EditorFromRepoSnafu)?;
 
-            Ok(Some((editor, manifest)))
+            Ok(Some((editor, manifest, existing_targets)))
         }
         // If we fail to load, but we only failed because the repo doesn't exist yet, then start
         // fresh by signalling that there is no known repo.  Otherwise, fail hard.
@@ -413,7 +764,7 @@ fn get_signing_key_source(signing_key_config: &SigningKeyConfig) -> Result<Box<d
                     .context(error::MissingConfigSnafu { missing: "key_id" })?;
                 config
                     .as_ref()
-                    .map_or(Ok(None), |config_val| get_client(&config_val, &key_id_val))?
+                    .map_or(Ok(None), |config_val| get_client(config_val, &key_id_val))?
             },
             signing_algorithm: KmsSigningAlgorithm::RsassaPssSha256,
         })),
@@ -436,8 +787,204 @@ fn get_client(config: &KMSKeyConfig, key_id: &String) -> Result<Option<KmsClient
     }
 }
 
+/// Converts a failure to sign the repo into our error type, calling out KMS permission problems
+/// specifically, since they're the most common reason for online signing to fail.
+pub(crate) fn sign_error(source: tough::error::Error) -> Error {
+    if let Some(key_id) = kms_access_denied_key(&source) {
+        return error::KmsSignAccessDeniedSnafu { key_id }.into_error(source);
+    }
+    error::RepoSignSnafu.into_error(source)
+}
+
+/// Walks the error chain looking for a KMS Sign request that was rejected because the caller
+/// doesn't have permission to use the key, and returns the key ID if so.
+fn kms_access_denied_key(e: &tough::error::Error) -> Option<String> {
+    let mut current: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(err) = current {
+        if let Some(tough_kms::error::Error::KmsSignMessage { key_id, source, .. }) =
+            err.downcast_ref::<tough_kms::error::Error>()
+        {
+            let denied = match source {
+                RusotoError::Unknown(response) => {
+                    response.status.as_u16() == 403
+                        || response.body_as_str().contains("AccessDenied")
+                }
+                other => other.to_string().contains("AccessDenied"),
+            };
+            return if denied { Some(key_id.clone()) } else { None };
+        }
+        current = err.source();
+    }
+    None
+}
+
+/// Splits the given copy and link targets by whether their file names match the --only-targets
+/// pattern, returning the matching ones to add to the repo.  The existing repo metadata is kept
+/// for the others, so each one we skip must already be a target there; otherwise the new repo
+/// would be missing it, which we only allow for incremental builds.  The update images aren't
+/// given here; see `select_targets`.
+fn filter_targets<'a>(
+    pattern: &Pattern,
+    incremental: bool,
+    existing_targets: &HashSet<String>,
+    copy_targets: Vec<&'a PathBuf>,
+    link_targets: Vec<&'a PathBuf>,
+) -> Result<(Vec<&'a PathBuf>, Vec<&'a PathBuf>)> {
+    let mut missing = Vec::new();
+    let mut split = |targets: Vec<&'a PathBuf>| -> Result<Vec<&'a PathBuf>> {
+        let mut selected = Vec::new();
+        for target in targets {
+            let name = target_name(target)?;
+            if pattern.matches(name) {
+                selected.push(target);
+            } else if existing_targets.contains(name) {
+                debug!("Keeping existing repo entry for target '{}'", name);
+            } else {
+                missing.push(name.to_string());
+            }
+        }
+        Ok(selected)
+    };
+    let given = copy_targets.len() + link_targets.len();
+    let copy_targets = split(copy_targets)?;
+    let link_targets = split(link_targets)?;
+
+    ensure!(
+        given == 0 || !copy_targets.is_empty() || !link_targets.is_empty(),
+        error::NoMatchingTargetsSnafu {
+            pattern: pattern.as_str()
+        }
+    );
+    if !missing.is_empty() {
+        ensure!(
+            incremental,
+            error::IncompleteRepoSnafu {
+                pattern: pattern.as_str(),
+                missing: missing.join(", "),
+            }
+        );
+        warn!(
+            "Leaving targets out of the repo because they don't match '{}': {}",
+            pattern,
+            missing.join(", ")
+        );
+    }
+    info!(
+        "Adding {} of the given targets that match '{}'",
+        copy_targets.len() + link_targets.len(),
+        pattern
+    );
+
+    Ok((copy_targets, link_targets))
+}
+
+/// Returns the copy and link targets to add to the repo: the given extra targets, filtered by the
+/// --only-targets pattern if there is one, and the update images, which are linked.  The images
+/// are always added, whatever the pattern, since the manifest we write points at them; leaving
+/// out new ones would give the repo an update it can't serve.
+fn select_targets<'a>(
+    only_targets: Option<&Pattern>,
+    incremental: bool,
+    existing_targets: &HashSet<String>,
+    copy_targets: Vec<&'a PathBuf>,
+    link_targets: Vec<&'a PathBuf>,
+    images: [&'a PathBuf; 3],
+) -> Result<(Vec<&'a PathBuf>, Vec<&'a PathBuf>)> {
+    let (copy_targets, mut link_targets) = match only_targets {
+        Some(pattern) => filter_targets(
+            pattern,
+            incremental,
+            existing_targets,
+            copy_targets,
+            link_targets,
+        )?,
+        None => (copy_targets, link_targets),
+    };
+    link_targets.extend(images.iter().copied());
+    Ok((copy_targets, link_targets))
+}
+
+/// Writes a repo with `write_repo` into a staging directory inside `outdir`, and only moves it
+/// into place once it's complete: targets into `targets_out_dir`, then metadata to
+/// `metadata_out_dir`.  If anything fails before that, the staging directory is removed and the
+/// output directory is left as it was.
+///
+/// The targets directory is shared by repos, so staged targets are moved in one at a time, and
+/// any that already exist are kept; their names include their digest, so they're the same file.
+/// The metadata directory is moved last, in a single rename, so it never refers to a target
+/// that isn't in place yet, and a build that fails while moving targets leaves only extra,
+/// unreferenced targets behind.
+fn write_staged<F>(
+    outdir: &Path,
+    metadata_out_dir: &Path,
+    targets_out_dir: &Path,
+    write_repo: F,
+) -> Result<()>
+where
+    F: FnOnce(&Path, &Path) -> Result<()>,
+{
+    fs::create_dir_all(outdir).context(error::CreateDirSnafu { path: outdir })?;
+    // Staging inside the output directory keeps it on the same filesystem, so moves are renames.
+    let staging = tempfile::Builder::new()
+        .prefix(".pubsys-repo-")
+        .tempdir_in(outdir)
+        .context(error::TempFileSnafu)?;
+    let staged_targets = staging.path().join("targets");
+    let staged_metadata = staging.path().join("metadata");
+    for dir in &[&staged_targets, &staged_metadata] {
+        fs::create_dir(dir).context(error::CreateDirSnafu { path: dir })?;
+    }
+
+    write_repo(&staged_targets, &staged_metadata)?;
+
+    fs::create_dir_all(targets_out_dir).context(error::CreateDirSnafu {
+        path: targets_out_dir,
+    })?;
+    let entries = fs::read_dir(&staged_targets).context(error::ReadDirSnafu {
+        path: &staged_targets,
+    })?;
+    for entry in entries {
+        let entry = entry.context(error::ReadDirSnafu {
+            path: &staged_targets,
+        })?;
+        let path = targets_out_dir.join(entry.file_name());
+        // symlink_metadata, so an existing link to a missing file still counts as there
+        if fs::symlink_metadata(&path).is_ok() {
+            trace!("Target already exists in output: {}", path.display());
+            continue;
+        }
+        fs::rename(entry.path(), &path).context(error::PublishSnafu { path })?;
+    }
+
+    if let Some(parent) = metadata_out_dir.parent() {
+        fs::create_dir_all(parent).context(error::CreateDirSnafu { path: parent })?;
+    }
+    fs::rename(&staged_metadata, metadata_out_dir).context(error::PublishSnafu {
+        path: metadata_out_dir,
+    })?;
+    Ok(())
+}
+
+/// Summary of the repo we built, for reporting results
+#[derive(Debug, Serialize)]
+struct RepoSummary<'a> {
+    metadata_dir: &'a Path,
+    targets_dir: &'a Path,
+    /// The number of targets written to targets_dir, including the manifest
+    targets: usize,
+}
+
 /// Common entrypoint from main()
 pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
+    // Pre-release and build metadata are allowed, but unusual for an update meant for release,
+    // and the '+' of build metadata isn't allowed in some names derived from the version.
+    if !repo_args.version.pre.is_empty() || !repo_args.version.build.is_empty() {
+        warn!(
+            "Version {} has pre-release or build metadata; make sure that's intended for a release",
+            repo_args.version
+        );
+    }
+
     let metadata_out_dir = repo_args
         .outdir
         .join(&repo_args.variant)
@@ -456,8 +1003,7 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
     // Build repo   =^..^=   =^..^=   =^..^=   =^..^=
 
     // If a lock file exists, use that, otherwise use Infra.toml or default
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, true)
-        .context(error::ConfigSnafu)?;
+    let infra_config = args.infra_config(true).context(error::ConfigSnafu)?;
     trace!("Using infra config: {:?}", infra_config);
 
     // If the user has the requested (or "default") repo defined in their Infra.toml, use it,
@@ -481,12 +1027,15 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
     };
 
     // Build a repo editor and manifest, from an existing repo if available, otherwise fresh
-    let maybe_urls = repo_urls(&repo_config, &repo_args.variant, &repo_args.arch)?;
-    let (mut editor, mut manifest) = if let Some((metadata_url, targets_url)) = maybe_urls.as_ref()
+    let maybe_urls = repo_urls(repo_config, &repo_args.variant, &repo_args.arch)?;
+    let (mut editor, mut manifest, existing_targets) = if let Some((metadata_url, targets_url)) =
+        maybe_urls.as_ref()
     {
         info!("Found metadata and target URLs, loading existing repository");
-        match load_editor_and_manifest(&repo_args.root_role_path, &metadata_url, &targets_url)? {
-            Some((editor, manifest)) => (editor, manifest),
+        match load_editor_and_manifest(&repo_args.root_role_path, metadata_url, targets_url)? {
+            Some((editor, manifest, existing_targets)) => {
+                (editor, manifest, Some(existing_targets))
+            }
             None => {
                 warn!(
                     "Did not find repo at '{}', starting a new one",
@@ -496,6 +1045,7 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
                     RepositoryEditor::new(&repo_args.root_role_path)
                         .context(error::NewEditorSnafu)?,
                     Manifest::default(),
+                    None,
                 )
             }
         }
@@ -504,11 +1054,12 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
         (
             RepositoryEditor::new(&repo_args.root_role_path).context(error::NewEditorSnafu)?,
             Manifest::default(),
+            None,
         )
     };
 
     // Add update information to manifest
-    update_manifest(&repo_args, &mut manifest)?;
+    update_manifest(repo_args, &mut manifest)?;
     // Write manifest to tempfile so it can be copied in as target later
     let manifest_path = NamedTempFile::new()
         .context(error::TempFileSnafu)?
@@ -517,16 +1068,51 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
         path: &manifest_path,
     })?;
 
-    // Add manifest and targets to editor
-    let copy_targets = &repo_args.copy_targets;
-    let link_targets = repo_args.link_targets.iter().chain(vec![
-        &repo_args.boot_image,
-        &repo_args.root_image,
-        &repo_args.hash_image,
-    ]);
-    let all_targets = copy_targets.iter().chain(link_targets.clone());
+    // Download any remote targets so they can be added like local ones; the directory has to
+    // live until we've copied them into the repo.
+    let (_remote_dir, remote_paths) = if repo_args.remote_targets.is_empty() {
+        (None, Vec::new())
+    } else {
+        let remote_dir = TempDir::new().context(error::TempFileSnafu)?;
+        let remote_paths = download_remote_targets(&repo_args.remote_targets, remote_dir.path())?;
+        (Some(remote_dir), remote_paths)
+    };
 
-    update_editor(&repo_args, &mut editor, all_targets, &manifest_path)?;
+    // Add manifest and targets to editor
+    let copy_targets: Vec<&PathBuf> = repo_args
+        .copy_targets
+        .iter()
+        .chain(remote_paths.iter())
+        .collect();
+    let link_targets: Vec<&PathBuf> = repo_args.link_targets.iter().collect();
+    let existing_names = existing_targets
+        .iter()
+        .flat_map(|existing| existing.targets.keys())
+        .map(|name| name.raw().to_string())
+        .collect();
+    let (copy_targets, link_targets) = select_targets(
+        repo_args.only_targets.as_ref(),
+        repo_args.incremental,
+        &existing_names,
+        copy_targets,
+        link_targets,
+        [
+            &repo_args.boot_image,
+            &repo_args.root_image,
+            &repo_args.hash_image,
+        ],
+    )?;
+    let all_targets = copy_targets.iter().chain(link_targets.iter()).copied();
+
+    // Incremental builds reuse what they can from the existing targets metadata.
+    let reuse_targets = existing_targets.as_ref().filter(|_| repo_args.incremental);
+    update_editor(
+        repo_args,
+        &mut editor,
+        all_targets,
+        &manifest_path,
+        reuse_targets,
+    )?;
 
     // Sign repo   =^..^=   =^..^=   =^..^=   =^..^=
 
@@ -548,73 +1134,93 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
         })
     };
 
-    let signed_repo = editor.sign(&[key_source]).context(error::RepoSignSnafu)?;
+    let signed_repo = editor.sign(&[key_source]).map_err(sign_error)?;
 
     // Write repo   =^..^=   =^..^=   =^..^=   =^..^=
 
-    // Write targets first so we don't have invalid metadata if targets fail
-    info!("Writing repo targets to: {}", targets_out_dir.display());
-    fs::create_dir_all(&targets_out_dir).context(error::CreateDirSnafu {
-        path: &targets_out_dir,
-    })?;
-
-    // Copy manifest with proper name instead of tempfile name
-    debug!("Copying manifest.json into {}", targets_out_dir.display());
-    let target = "manifest.json";
-    let target = target
-        .try_into()
-        .context(error::ParseTargetNameSnafu { target })?;
-    signed_repo
-        .copy_target(
-            &manifest_path,
-            &targets_out_dir,
-            // We should never have matching manifests from different repos
-            PathExists::Fail,
-            Some(&target),
-        )
-        .context(error::CopyTargetSnafu {
-            target: &manifest_path,
-            path: &targets_out_dir,
-        })?;
-
-    // Copy / link any other user requested targets
-    for copy_target in copy_targets {
-        debug!(
-            "Copying target '{}' into {}",
-            copy_target.display(),
-            targets_out_dir.display()
-        );
-        signed_repo
-            .copy_target(copy_target, &targets_out_dir, PathExists::Skip, None)
-            .context(error::CopyTargetSnafu {
-                target: copy_target,
-                path: &targets_out_dir,
-            })?;
-    }
-    for link_target in link_targets {
-        debug!(
-            "Linking target '{}' into {}",
-            link_target.display(),
-            targets_out_dir.display()
-        );
-        signed_repo
-            .link_target(link_target, &targets_out_dir, PathExists::Skip, None)
-            .context(error::LinkTargetSnafu {
-                target: link_target,
-                path: &targets_out_dir,
-            })?;
-    }
+    let mut progress = TargetProgress::new(
+        "Wrote",
+        copy_targets.len() + link_targets.len() + 1,
+        repo_args.progress,
+    );
+    write_staged(
+        &repo_args.outdir,
+        &metadata_out_dir,
+        &targets_out_dir,
+        |targets_dir, metadata_dir| {
+            // Write targets first so we don't have invalid metadata if targets fail
+            info!("Writing repo targets to: {}", targets_dir.display());
+
+            // Copy manifest with proper name instead of tempfile name
+            debug!("Copying manifest.json into {}", targets_dir.display());
+            let target = "manifest.json";
+            let target = target
+                .try_into()
+                .context(error::ParseTargetNameSnafu { target })?;
+            signed_repo
+                .copy_target(
+                    &manifest_path,
+                    targets_dir,
+                    // We should never have matching manifests from different repos
+                    PathExists::Fail,
+                    Some(&target),
+                )
+                .context(error::CopyTargetSnafu {
+                    target: &manifest_path,
+                    path: targets_dir,
+                })?;
+            progress.tick("manifest.json");
+
+            // Copy / link any other user requested targets
+            for copy_target in &copy_targets {
+                debug!(
+                    "Copying target '{}' into {}",
+                    copy_target.display(),
+                    targets_dir.display()
+                );
+                signed_repo
+                    .copy_target(copy_target, targets_dir, PathExists::Skip, None)
+                    .context(error::CopyTargetSnafu {
+                        target: copy_target,
+                        path: targets_dir,
+                    })?;
+                progress.tick(copy_target);
+            }
+            for link_target in &link_targets {
+                debug!(
+                    "Linking target '{}' into {}",
+                    link_target.display(),
+                    targets_dir.display()
+                );
+                signed_repo
+                    .link_target(link_target, targets_dir, PathExists::Skip, None)
+                    .context(error::LinkTargetSnafu {
+                        target: link_target,
+                        path: targets_dir,
+                    })?;
+                progress.tick(link_target);
+            }
 
-    info!("Writing repo metadata to: {}", metadata_out_dir.display());
-    fs::create_dir_all(&metadata_out_dir).context(error::CreateDirSnafu {
-        path: &metadata_out_dir,
-    })?;
-    signed_repo
-        .write(&metadata_out_dir)
-        .context(error::RepoWriteSnafu {
-            path: &repo_args.outdir,
-        })?;
+            info!("Writing repo metadata to: {}", metadata_dir.display());
+            signed_repo
+                .write(metadata_dir)
+                .context(error::RepoWriteSnafu {
+                    path: &repo_args.outdir,
+                })
+        },
+    )?;
+    info!(
+        "Published repo metadata to {} and targets to {}",
+        metadata_out_dir.display(),
+        targets_out_dir.display()
+    );
 
+    let summary = RepoSummary {
+        metadata_dir: &metadata_out_dir,
+        targets_dir: &targets_out_dir,
+        targets: progress.total,
+    };
+    output::report(args.output, &summary)?;
     Ok(())
 }
 
@@ -630,26 +1236,23 @@ mod error {
     pub(crate) enum Error {
         #[snafu(display("Failed to add new update to manifest: {}", source))]
         AddUpdate {
-            source: update_metadata::error::Error,
+            #[snafu(source(from(update_metadata::error::Error, Box::new)))]
+            source: Box<update_metadata::error::Error>,
         },
 
         #[snafu(display("Failed to add new target '{}' to repo: {}", path.display(), source))]
         AddTarget {
             path: PathBuf,
-            source: tough::error::Error,
-        },
-
-        #[snafu(display("Failed to build target metadata from path '{}': {}", path.display(), source))]
-        BuildTarget {
-            path: PathBuf,
-            source: tough::schema::Error,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
         },
 
         #[snafu(display("Failed to copy target '{}' to '{}': {}", target.display(), path.display(), source))]
         CopyTarget {
             target: PathBuf,
             path: PathBuf,
-            source: tough::error::Error,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
         },
 
         #[snafu(display("Error reading config: {}", source))]
@@ -659,11 +1262,21 @@ mod error {
         CreateDir { path: PathBuf, source: io::Error },
 
         #[snafu(display("Failed to create repo editor from given repo: {}", source))]
-        EditorFromRepo { source: tough::error::Error },
+        EditorFromRepo {
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
+        },
 
         #[snafu(display("Failed to read '{}': {}", path.display(), source))]
         File { path: PathBuf, source: io::Error },
 
+        #[snafu(display(
+            "Targets not matching '{}' aren't in the existing repo, so it would be incomplete; use --incremental to allow this: {}",
+            pattern,
+            missing
+        ))]
+        IncompleteRepo { pattern: String, missing: String },
+
         #[snafu(display("Invalid path given for image file: '{}'", path.display()))]
         InvalidImagePath { path: PathBuf },
 
@@ -677,13 +1290,15 @@ mod error {
         LinkTarget {
             target: PathBuf,
             path: PathBuf,
-            source: tough::error::Error,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
         },
 
         #[snafu(display("Failed to write Manifest to '{}': {}", path.display(), source))]
         ManifestWrite {
             path: PathBuf,
-            source: update_metadata::error::Error,
+            #[snafu(source(from(update_metadata::error::Error, Box::new)))]
+            source: Box<update_metadata::error::Error>,
         },
 
         #[snafu(display("Infra.toml is missing {}", missing))]
@@ -693,7 +1308,13 @@ mod error {
         MissingRepoUrls { repo: String },
 
         #[snafu(display("Failed to create new repo editor: {}", source))]
-        NewEditor { source: tough::error::Error },
+        NewEditor {
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
+        },
+
+        #[snafu(display("No given targets match '{}'", pattern))]
+        NoMatchingTargets { pattern: String },
 
         #[snafu(display("Repo does not have a manifest.json: {}", metadata_url))]
         NoManifest { metadata_url: Url },
@@ -701,6 +1322,9 @@ mod error {
         #[snafu(display("Non-UTF8 path '{}' not supported", path.display()))]
         NonUtf8Path { path: PathBuf },
 
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
         #[snafu(display("Failed to parse {} to a valid rusoto region: {}", what, source))]
         ParseRegion {
             what: String,
@@ -713,18 +1337,61 @@ mod error {
             source: url::ParseError,
         },
 
+        #[snafu(display("Failed to move built repo into place at '{}': {}", path.display(), source))]
+        Publish { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to read directory '{}': {}", path.display(), source))]
+        ReadDir { path: PathBuf, source: io::Error },
+
         #[snafu(display("Failed to read target '{}' from repo: {}", target, source))]
         ReadTarget {
             target: String,
-            source: tough::error::Error,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
         },
 
         #[snafu(display("Failed to parse target name from string '{}': {}", target, source))]
         ParseTargetName {
             target: String,
-            source: tough::error::Error,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
         },
 
+        #[snafu(display(
+            "Remote target URL '{}' needs its expected sha256, like '#sha256=<hex>'",
+            url
+        ))]
+        RemoteTargetChecksumMissing { url: Url },
+
+        #[snafu(display("Failed to download remote target '{}': {}", url, source))]
+        RemoteTargetDownload { url: Url, source: io::Error },
+
+        #[snafu(display("More than one remote target is named '{}'", name))]
+        RemoteTargetDuplicate { name: String },
+
+        #[snafu(display("Failed to fetch remote target '{}': {}", url, source))]
+        RemoteTargetFetch {
+            url: Url,
+            #[snafu(source(from(tough::TransportError, Box::new)))]
+            source: Box<tough::TransportError>,
+        },
+
+        #[snafu(display("Remote target '{}' has sha256 {}, expected {}", url, actual, expected))]
+        RemoteTargetMismatch {
+            url: String,
+            expected: String,
+            actual: String,
+        },
+
+        #[snafu(display("Remote target URL '{}' has no file name", url))]
+        RemoteTargetName { url: Url },
+
+        #[snafu(display("Remote target URL '{}' must be http or https", url))]
+        RemoteTargetScheme { url: Url },
+
+        #[snafu(display("Failed to write remote target to '{}': {}", path.display(), source))]
+        RemoteTargetWrite { path: PathBuf, source: io::Error },
+
         #[snafu(display("Repo exists at '{}' - remove it and try again", path.display()))]
         RepoExists { path: PathBuf },
 
@@ -738,48 +1405,499 @@ mod error {
         ))]
         RepoLoad {
             metadata_base_url: Url,
-            source: tough::error::Error,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
         },
 
         #[snafu(display("Requested repository does not exist: '{}'", url))]
         RepoNotFound { url: Url },
 
         #[snafu(display("Failed to sign repository: {}", source))]
-        RepoSign { source: tough::error::Error },
+        RepoSign {
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
+        },
+
+        #[snafu(display(
+            "Failed to sign repository with KMS key '{}'; make sure the current principal is allowed kms:Sign on it: {}",
+            key_id,
+            source
+        ))]
+        KmsSignAccessDenied {
+            key_id: String,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
+        },
 
         #[snafu(display("Failed to write repository to {}: {}", path.display(), source))]
         RepoWrite {
             path: PathBuf,
-            source: tough::error::Error,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
         },
 
         #[snafu(display("Failed to set targets expiration to {}: {}", expiration, source))]
         SetTargetsExpiration {
             expiration: DateTime<Utc>,
-            source: tough::error::Error,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
         },
 
         #[snafu(display("Failed to set targets version to {}: {}", version, source))]
         SetTargetsVersion {
             version: u64,
-            source: tough::error::Error,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
         },
 
         #[snafu(display("Failed to set waves from '{}': {}", wave_policy_path.display(), source))]
         SetWaves {
             wave_policy_path: PathBuf,
-            source: update_metadata::error::Error,
+            #[snafu(source(from(update_metadata::error::Error, Box::new)))]
+            source: Box<update_metadata::error::Error>,
         },
 
+        #[snafu(display("Unknown target hash algorithm '{}'; expected sha256 or sha512", input))]
+        TargetHash { input: String },
+
         #[snafu(display("Failed to create temporary file: {}", source))]
         TempFile { source: io::Error },
 
+        #[snafu(display("Unable to create thread pool: {}", source))]
+        ThreadPool { source: rayon::ThreadPoolBuildError },
+
         #[snafu(display("Failed to read update metadata '{}': {}", path.display(), source))]
         UpdateMetadataRead {
             path: PathBuf,
-            source: update_metadata::error::Error,
+            #[snafu(source(from(update_metadata::error::Error, Box::new)))]
+            source: Box<update_metadata::error::Error>,
         },
     }
 }
 pub(crate) use error::Error;
 type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{
+        add_targets, select_targets, set_build_expirations_and_versions, write_staged,
+        RemoteTarget, TargetHash, TargetProgress,
+    };
+    use chrono::{DateTime, Duration, Utc};
+    use glob::Pattern;
+    use pubsys_config::RepoExpirationPolicy;
+    use ring::rand::SystemRandom;
+    use ring::signature::Ed25519KeyPair;
+    use sha2::{Digest, Sha256, Sha512};
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::io::Read;
+    use std::num::NonZeroU64;
+    use std::path::{Path, PathBuf};
+    use tough::editor::signed::SignedRole;
+    use tough::editor::RepositoryEditor;
+    use tough::key_source::{KeySource, LocalKeySource};
+    use tough::schema::{KeyHolder, RoleKeys, RoleType, Root};
+    use tough::{RepositoryLoader, TargetName};
+    use url::Url;
+
+    /// Writes a signed root.json giving every role to one new Ed25519 key, which makes
+    /// deterministic signatures, and returns the paths of the root and key.
+    pub(crate) fn write_root(dir: &Path) -> (PathBuf, PathBuf) {
+        let key_path = dir.join("key.pk8");
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        fs::write(&key_path, pkcs8.as_ref()).unwrap();
+        let key_source: Box<dyn KeySource> = Box::new(LocalKeySource {
+            path: key_path.clone(),
+        });
+        let key = key_source.as_sign().unwrap().tuf_key();
+        let key_id = key.key_id().unwrap();
+
+        let roles = [
+            RoleType::Root,
+            RoleType::Snapshot,
+            RoleType::Targets,
+            RoleType::Timestamp,
+        ]
+        .iter()
+        .map(|role| {
+            let keys = RoleKeys {
+                keyids: vec![key_id.clone()],
+                threshold: NonZeroU64::new(1).unwrap(),
+                _extra: HashMap::new(),
+            };
+            (*role, keys)
+        })
+        .collect();
+        let root = Root {
+            spec_version: "1.0.0".to_string(),
+            consistent_snapshot: false,
+            version: NonZeroU64::new(1).unwrap(),
+            expires: Utc::now() + Duration::weeks(1),
+            keys: vec![(key_id, key)].into_iter().collect(),
+            roles,
+            _extra: HashMap::new(),
+        };
+        let holder = KeyHolder::Root(root.clone());
+        let signed = SignedRole::new(root, &holder, &[key_source], &SystemRandom::new()).unwrap();
+        let root_path = dir.join("root.json");
+        fs::write(&root_path, signed.buffer()).unwrap();
+        (root_path, key_path)
+    }
+
+    /// Adds the targets and manifest to the editor, sets expirations and versions the way a repo
+    /// build does, and writes the signed metadata to `outdir`.  Returns whether targets changed.
+    pub(crate) fn build(
+        mut editor: RepositoryEditor,
+        key_path: &Path,
+        targets: &[&PathBuf],
+        manifest_path: &Path,
+        existing: Option<&tough::schema::Targets>,
+        hashes: &[TargetHash],
+        outdir: &Path,
+    ) -> bool {
+        let mut progress = TargetProgress::new("Added", targets.len() + 1, false);
+        let changed = add_targets(
+            &mut editor,
+            targets,
+            manifest_path,
+            existing,
+            hashes,
+            &mut progress,
+        )
+        .unwrap();
+        let policy = RepoExpirationPolicy {
+            snapshot_expiration: Duration::days(7),
+            targets_expiration: Duration::days(14),
+            timestamp_expiration: Duration::days(1),
+        };
+        // Rebuilds start later than the first build, like a rebuild with default start time.
+        let start: DateTime<Utc> =
+            Utc::now() + Duration::seconds(if existing.is_some() { 60 } else { 0 });
+        set_build_expirations_and_versions(
+            &mut editor,
+            &policy,
+            start,
+            existing.filter(|_| !changed),
+        )
+        .unwrap();
+        let key_source: Box<dyn KeySource> = Box::new(LocalKeySource {
+            path: key_path.to_owned(),
+        });
+        editor.sign(&[key_source]).unwrap().write(outdir).unwrap();
+        changed
+    }
+
+    /// Reads the written targets metadata, including signatures.  tough writes maps in no fixed
+    /// order, so we compare parsed JSON; the signatures cover the canonical form.
+    fn read_targets(dir: &Path) -> serde_json::Value {
+        serde_json::from_slice(&fs::read(dir.join("targets.json")).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn unchanged_incremental_rebuild() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (root_path, key_path) = write_root(dir);
+        let targets_dir = dir.join("targets");
+        fs::create_dir(&targets_dir).unwrap();
+        let image = targets_dir.join("bottlerocket-v1.0.0-abcdef.img");
+        fs::write(&image, b"image").unwrap();
+        let manifest = targets_dir.join("manifest.json");
+        fs::write(&manifest, b"{}").unwrap();
+        let targets = [&image];
+
+        let first_dir = dir.join("first");
+        let editor = RepositoryEditor::new(&root_path).unwrap();
+        assert!(build(
+            editor,
+            &key_path,
+            &targets,
+            &manifest,
+            None,
+            &[TargetHash::Sha256],
+            &first_dir
+        ));
+
+        let load = || {
+            RepositoryLoader::new(
+                fs::File::open(&root_path).unwrap(),
+                Url::from_directory_path(&first_dir).unwrap(),
+                Url::from_directory_path(&targets_dir).unwrap(),
+            )
+            .load()
+            .unwrap()
+        };
+
+        // Rebuilding with the same targets and manifest gives the same targets metadata.
+        let repo = load();
+        let existing = repo.targets().signed.clone();
+        let editor = RepositoryEditor::from_repo(&root_path, repo).unwrap();
+        let second_dir = dir.join("second");
+        assert!(!build(
+            editor,
+            &key_path,
+            &targets,
+            &manifest,
+            Some(&existing),
+            &[TargetHash::Sha256],
+            &second_dir
+        ));
+        assert_eq!(read_targets(&first_dir), read_targets(&second_dir));
+
+        // A new manifest changes the targets metadata.
+        fs::write(&manifest, b"{\"updates\":[]}").unwrap();
+        let repo = load();
+        let existing = repo.targets().signed.clone();
+        let editor = RepositoryEditor::from_repo(&root_path, repo).unwrap();
+        let third_dir = dir.join("third");
+        assert!(build(
+            editor,
+            &key_path,
+            &targets,
+            &manifest,
+            Some(&existing),
+            &[TargetHash::Sha256],
+            &third_dir
+        ));
+        assert_ne!(read_targets(&first_dir), read_targets(&third_dir));
+
+        // A target that keeps its name and size, like a migration, still gets its new hash.
+        fs::write(&image, b"IMAGE").unwrap();
+        let repo = load();
+        let existing = repo.targets().signed.clone();
+        let editor = RepositoryEditor::from_repo(&root_path, repo).unwrap();
+        let fourth_dir = dir.join("fourth");
+        assert!(build(
+            editor,
+            &key_path,
+            &targets,
+            &manifest,
+            Some(&existing),
+            &[TargetHash::Sha256],
+            &fourth_dir
+        ));
+        assert_eq!(
+            read_targets(&fourth_dir)["signed"]["targets"]["bottlerocket-v1.0.0-abcdef.img"]
+                ["hashes"]["sha256"],
+            hex::encode(Sha256::digest(b"IMAGE"))
+        );
+    }
+
+    #[test]
+    fn records_requested_target_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (root_path, key_path) = write_root(dir);
+        let targets_dir = dir.join("targets");
+        fs::create_dir(&targets_dir).unwrap();
+        let name = "bottlerocket-v1.0.0-abcdef.img";
+        let image = targets_dir.join(name);
+        fs::write(&image, b"image").unwrap();
+        let manifest = targets_dir.join("manifest.json");
+        fs::write(&manifest, b"{}").unwrap();
+        let targets = [&image];
+        let hashes = [TargetHash::Sha256, TargetHash::Sha512];
+        let load = |metadata_dir: &Path| {
+            RepositoryLoader::new(
+                fs::File::open(&root_path).unwrap(),
+                Url::from_directory_path(metadata_dir).unwrap(),
+                Url::from_directory_path(&targets_dir).unwrap(),
+            )
+            .load()
+            .unwrap()
+        };
+
+        let first_dir = dir.join("first");
+        let editor = RepositoryEditor::new(&root_path).unwrap();
+        build(
+            editor,
+            &key_path,
+            &targets,
+            &manifest,
+            None,
+            &[TargetHash::Sha256],
+            &first_dir,
+        );
+
+        // Asking for a hash the existing entries don't have means hashing them again, even when
+        // building incrementally.
+        let repo = load(&first_dir);
+        let existing = repo.targets().signed.clone();
+        let editor = RepositoryEditor::from_repo(&root_path, repo).unwrap();
+        let second_dir = dir.join("second");
+        assert!(build(
+            editor,
+            &key_path,
+            &targets,
+            &manifest,
+            Some(&existing),
+            &hashes,
+            &second_dir
+        ));
+        let written = read_targets(&second_dir);
+        let entry = &written["signed"]["targets"][name]["hashes"];
+        assert_eq!(entry["sha256"], hex::encode(Sha256::digest(b"image")));
+        assert_eq!(entry["sha512"], hex::encode(Sha512::digest(b"image")));
+        assert_eq!(
+            written["signed"]["targets"]["manifest.json"]["hashes"]["sha512"],
+            hex::encode(Sha512::digest(b"{}"))
+        );
+
+        // The extra hashes are signed with the rest of the metadata, so clients load the repo and
+        // verify targets as before, and the next incremental build keeps the entries.
+        let repo = load(&second_dir);
+        let mut reader = repo
+            .read_target(&TargetName::new(name).unwrap())
+            .unwrap()
+            .unwrap();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"image");
+        let existing = repo.targets().signed.clone();
+        let editor = RepositoryEditor::from_repo(&root_path, repo).unwrap();
+        assert!(!build(
+            editor,
+            &key_path,
+            &targets,
+            &manifest,
+            Some(&existing),
+            &hashes,
+            &dir.join("third")
+        ));
+    }
+
+    #[test]
+    fn only_targets_keeps_update_images() {
+        let boot = PathBuf::from("bottlerocket-v1.1.0-boot.ext4.lz4");
+        let root = PathBuf::from("bottlerocket-v1.1.0-root.ext4.lz4");
+        let hash = PathBuf::from("bottlerocket-v1.1.0-root.verity.lz4");
+        let migration = PathBuf::from("migrate_v1.1.0_new-setting.lz4");
+        let old_migration = PathBuf::from("migrate_v1.0.0_old-setting.lz4");
+        let existing: HashSet<String> = [old_migration.display().to_string()]
+            .iter()
+            .cloned()
+            .collect();
+        let pattern = Pattern::new("migrate_v1.1.0_*").unwrap();
+
+        // The new images aren't in the existing repo and don't match, but the manifest refers to
+        // them, so they're still added; only the extra targets are filtered.
+        let (copy_targets, link_targets) = select_targets(
+            Some(&pattern),
+            true,
+            &existing,
+            vec![&migration, &old_migration],
+            Vec::new(),
+            [&boot, &root, &hash],
+        )
+        .unwrap();
+        assert_eq!(copy_targets, vec![&migration]);
+        assert_eq!(link_targets, vec![&boot, &root, &hash]);
+    }
+
+    #[test]
+    fn target_hash_names() {
+        assert_eq!("sha256".parse::<TargetHash>().unwrap(), TargetHash::Sha256);
+        assert_eq!(" sha512".parse::<TargetHash>().unwrap(), TargetHash::Sha512);
+        assert!("md5".parse::<TargetHash>().is_err());
+        assert!("SHA-512".parse::<TargetHash>().is_err());
+    }
+
+    #[test]
+    fn remote_target_urls() {
+        let sha256 = "AB".repeat(32);
+        let target: RemoteTarget = format!("https://example.com/a/b.lz4?x=1#sha256={}", sha256)
+            .parse()
+            .unwrap();
+        assert_eq!(target.url.as_str(), "https://example.com/a/b.lz4?x=1");
+        assert_eq!(target.sha256, "ab".repeat(32));
+        assert_eq!(target.name().unwrap(), "b.lz4");
+
+        // The checksum is required, and must look like a sha256.
+        assert!("https://example.com/b.lz4".parse::<RemoteTarget>().is_err());
+        assert!("https://example.com/b.lz4#sha256=abc"
+            .parse::<RemoteTarget>()
+            .is_err());
+        // Only HTTP(S) is allowed; local files can be given with --copy-target.
+        assert!(format!("file:///b.lz4#sha256={}", sha256)
+            .parse::<RemoteTarget>()
+            .is_err());
+        // We need a file name to use as the target name.
+        let target: RemoteTarget = format!("https://example.com/#sha256={}", sha256)
+            .parse()
+            .unwrap();
+        assert!(target.name().is_err());
+    }
+
+    /// Lists every path under `dir`, relative to `root`, for comparing directory trees.
+    fn list_tree(root: &Path, dir: &Path) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            paths.push(path.strip_prefix(root).unwrap().to_owned());
+            if path.is_dir() {
+                paths.extend(list_tree(root, &path));
+            }
+        }
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn staged_build_failure_leaves_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let outdir = dir.path();
+        let metadata_out_dir = outdir.join("aws-k8s-1.21").join("x86_64");
+        let targets_out_dir = outdir.join("targets");
+        fs::create_dir_all(&targets_out_dir).unwrap();
+        fs::write(targets_out_dir.join("existing.img"), "old").unwrap();
+        let before = list_tree(outdir, outdir);
+
+        // Fail after writing some targets, the way a signing or copy failure would.
+        let result = write_staged(
+            outdir,
+            &metadata_out_dir,
+            &targets_out_dir,
+            |targets_dir, _| {
+                fs::write(targets_dir.join("new.img"), "new").unwrap();
+                super::error::MissingConfigSnafu { missing: "test" }.fail()
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(list_tree(outdir, outdir), before);
+
+        // On success, new targets join existing ones, which are kept as they were.
+        write_staged(
+            outdir,
+            &metadata_out_dir,
+            &targets_out_dir,
+            |targets_dir, metadata_dir| {
+                fs::write(targets_dir.join("existing.img"), "other").unwrap();
+                fs::write(targets_dir.join("new.img"), "new").unwrap();
+                fs::write(metadata_dir.join("timestamp.json"), "{}").unwrap();
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(targets_out_dir.join("existing.img")).unwrap(),
+            "old"
+        );
+        assert!(targets_out_dir.join("new.img").exists());
+        assert!(metadata_out_dir.join("timestamp.json").exists());
+        assert_eq!(
+            list_tree(outdir, outdir),
+            [
+                "aws-k8s-1.21",
+                "aws-k8s-1.21/x86_64",
+                "aws-k8s-1.21/x86_64/timestamp.json",
+                "targets",
+                "targets/existing.img",
+                "targets/new.img",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+    }
+}
@@ -1,17 +1,23 @@
 //! The check_expirations module owns the 'check-repo-expirations' subcommand and provide methods for
-//! checking the metadata expirations of a given TUF repository.
+//! checking the metadata expirations of a given TUF repository.  Given a file listing several
+//! repos, it checks every one of them and reports all expiring roles together, so it can be run
+//! as a monitor across all of our repos.
 
+use crate::output::{self, OutputFormat};
 use crate::repo::{error as repo_error, repo_urls};
 use crate::Args;
 use chrono::{DateTime, Utc};
 use log::{error, info, trace, warn};
 use parse_datetime::parse_datetime;
-use pubsys_config::InfraConfig;
-use snafu::{OptionExt, ResultExt};
+use pubsys_config::RepoConfig;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::HashMap;
-use std::fs::File;
-use std::path::PathBuf;
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
+use tough::schema::RoleType;
 use tough::{ExpirationEnforcement, Repository, RepositoryLoader};
 use url::Url;
 
@@ -19,67 +25,185 @@ use url::Url;
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
 pub(crate) struct CheckExpirationsArgs {
-    #[structopt(long)]
+    #[structopt(long, required_unless = "repos-file", conflicts_with = "repos-file")]
     /// Use this named repo infrastructure from Infra.toml
-    repo: String,
+    repo: Option<String>,
 
-    #[structopt(long)]
+    #[structopt(long, required_unless = "repos-file", conflicts_with = "repos-file")]
     /// The architecture of the repo being checked for expirations
-    arch: String,
-    #[structopt(long)]
+    arch: Option<String>,
+    #[structopt(long, required_unless = "repos-file", conflicts_with = "repos-file")]
     /// The variant of the repo being checked for expirations
-    variant: String,
+    variant: Option<String>,
 
-    #[structopt(long, parse(from_os_str))]
+    #[structopt(
+        long,
+        parse(from_os_str),
+        required_unless = "repos-file",
+        conflicts_with = "repos-file"
+    )]
     /// Path to root.json for this repo
-    root_role_path: PathBuf,
+    root_role_path: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// TOML file listing repos to check instead of --repo, each a [[repos]] table with repo,
+    /// variant, arch, and root-role-path keys; relative paths are relative to the file
+    repos_file: Option<PathBuf>,
+
+    #[structopt(long, default_value = "false", parse(try_from_str))]
+    /// With --repos-file, stop at the first repo with expiring metadata, or that can't be
+    /// checked, rather than checking every repo; "true" or "false"
+    fail_fast: bool,
 
     #[structopt(long, parse(try_from_str = parse_datetime))]
     /// Finds metadata files expiring between now and a specified time; RFC3339 date or "in X hours/days/weeks"
     expiration_limit: DateTime<Utc>,
+
+    #[structopt(long = "threshold", number_of_values = 1, parse(try_from_str = parse_threshold))]
+    /// Per-role limit overriding --expiration-limit, like "root=30" (days) or "timestamp=in 12 hours"; may be repeated
+    thresholds: Vec<(RoleType, DateTime<Utc>)>,
+}
+
+/// Parses a per-role threshold in the form ROLE=LIMIT, where LIMIT is a number of days, or
+/// anything accepted by --expiration-limit.
+fn parse_threshold(input: &str) -> Result<(RoleType, DateTime<Utc>)> {
+    let (role, limit) = input
+        .split_once('=')
+        .context(error::InvalidThresholdSnafu { input })?;
+    let role = match role.trim() {
+        "root" => RoleType::Root,
+        "snapshot" => RoleType::Snapshot,
+        "targets" => RoleType::Targets,
+        "timestamp" => RoleType::Timestamp,
+        _ => return error::InvalidThresholdSnafu { input }.fail(),
+    };
+    let limit = limit.trim();
+    let limit = if limit.chars().all(|c| c.is_ascii_digit()) && !limit.is_empty() {
+        parse_datetime(&format!("in {} days", limit))
+    } else {
+        parse_datetime(limit)
+    }
+    .context(error::ThresholdDateSnafu { input })?;
+    Ok((role, limit))
+}
+
+/// One repo to check, from the command line or a repos file
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct RepoSpec {
+    repo: String,
+    variant: String,
+    arch: String,
+    root_role_path: PathBuf,
+}
+
+impl fmt::Display for RepoSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} {})", self.repo, self.variant, self.arch)
+    }
+}
+
+/// The format of --repos-file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReposFile {
+    repos: Vec<RepoSpec>,
+}
+
+/// Reads the repos listed in the given file.  Relative root role paths are taken relative to the
+/// file, so a repos file can be kept next to the roots it refers to.
+fn read_repos_file(path: &Path) -> Result<Vec<RepoSpec>> {
+    let contents = fs::read_to_string(path).context(error::ReposFileReadSnafu { path })?;
+    let repos_file: ReposFile =
+        toml::from_str(&contents).context(error::ReposFileParseSnafu { path })?;
+    ensure!(
+        !repos_file.repos.is_empty(),
+        error::ReposFileEmptySnafu { path }
+    );
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    Ok(repos_file
+        .repos
+        .into_iter()
+        .map(|mut spec| {
+            spec.root_role_path = dir.join(&spec.root_role_path);
+            spec
+        })
+        .collect())
+}
+
+/// Summary of a repo's metadata expirations, for reporting results
+#[derive(Debug, Serialize)]
+struct ExpirationSummary {
+    repo: String,
+    variant: String,
+    arch: String,
+    metadata_url: Url,
+    /// Expiration of every top-level role
+    expirations: HashMap<RoleType, DateTime<Utc>>,
+    /// The limit each role was checked against
+    limits: HashMap<RoleType, DateTime<Utc>>,
+    /// Roles expiring before their limit, including any that have already expired
+    upcoming: Vec<RoleType>,
 }
 
 /// Checks for upcoming role expirations, gathering them in a map of role to expiration datetime.
+/// Each role is checked against its own limit.
 fn find_upcoming_metadata_expiration(
     repo: &Repository,
-    end_date: DateTime<Utc>,
+    limits: &HashMap<RoleType, DateTime<Utc>>,
 ) -> HashMap<tough::schema::RoleType, DateTime<Utc>> {
     let mut expirations = HashMap::new();
-    info!(
-        "Looking for metadata expirations happening from now to {}",
-        end_date
-    );
-    if repo.root().signed.expires <= end_date {
-        expirations.insert(tough::schema::RoleType::Root, repo.root().signed.expires);
-    }
-    if repo.snapshot().signed.expires <= end_date {
-        expirations.insert(
-            tough::schema::RoleType::Snapshot,
-            repo.snapshot().signed.expires,
-        );
-    }
-    if repo.targets().signed.expires <= end_date {
-        expirations.insert(
-            tough::schema::RoleType::Targets,
-            repo.targets().signed.expires,
-        );
-    }
-    if repo.timestamp().signed.expires <= end_date {
-        expirations.insert(
-            tough::schema::RoleType::Timestamp,
-            repo.timestamp().signed.expires,
+    for (role, expires) in role_expirations(repo) {
+        let end_date = limits[&role];
+        info!(
+            "Looking for {} expiration happening from now to {}",
+            role, end_date
         );
+        if expires <= end_date {
+            expirations.insert(role, expires);
+        }
     }
 
     expirations
 }
 
+/// A repo from the repos file that couldn't be checked, for reporting results
+#[derive(Debug, Serialize)]
+struct RepoFailure<'a> {
+    repo: &'a str,
+    variant: &'a str,
+    arch: &'a str,
+    error: String,
+}
+
+/// Summary of every repo checked from a repos file, for reporting results
+#[derive(Debug, Serialize)]
+struct ReposSummary<'a> {
+    repos: &'a [ExpirationSummary],
+    failed: &'a [RepoFailure<'a>],
+}
+
+/// Returns the expiration of every top-level role.
+fn role_expirations(repo: &Repository) -> Vec<(RoleType, DateTime<Utc>)> {
+    vec![
+        (RoleType::Root, repo.root().signed.expires),
+        (RoleType::Snapshot, repo.snapshot().signed.expires),
+        (RoleType::Targets, repo.targets().signed.expires),
+        (RoleType::Timestamp, repo.timestamp().signed.expires),
+    ]
+}
+
+/// Loads the given repo and checks each of its roles against its limit, logging any that are
+/// expiring or expired.  Every role is checked; the summary lists all the expiring ones.
 fn check_expirations(
-    root_role_path: &PathBuf,
-    metadata_url: &Url,
-    targets_url: &Url,
-    expiration_limit: DateTime<Utc>,
-) -> Result<()> {
+    spec: &RepoSpec,
+    repo_config: &RepoConfig,
+    limits: &HashMap<RoleType, DateTime<Utc>>,
+) -> Result<ExpirationSummary> {
+    let (metadata_url, targets_url) = repo_urls(repo_config, &spec.variant, &spec.arch)?
+        .context(repo_error::MissingRepoUrlsSnafu { repo: &spec.repo })?;
+    let root_role_path = &spec.root_role_path;
+
     // Load the repository
     let repo = RepositoryLoader::new(
         File::open(root_role_path).context(repo_error::FileSnafu {
@@ -101,82 +225,288 @@ fn check_expirations(
     info!("Targets expiration:\t{}", repo.targets().signed.expires);
     info!("Timestamp expiration:\t{}", repo.timestamp().signed.expires);
     // Check for upcoming metadata expirations if a timeframe is specified
-    let upcoming_expirations = find_upcoming_metadata_expiration(&repo, expiration_limit);
-    if !upcoming_expirations.is_empty() {
-        let now = Utc::now();
-        for (role, expiration_date) in upcoming_expirations {
-            if expiration_date < now {
-                error!(
-                    "Repo '{}': '{}' expired on {}",
-                    metadata_url, role, expiration_date
-                )
-            } else {
-                warn!(
-                    "Repo '{}': '{}' expiring in {} at {}",
-                    metadata_url,
-                    role,
-                    expiration_date - now,
-                    expiration_date
-                )
+    let upcoming_expirations = find_upcoming_metadata_expiration(&repo, limits);
+
+    let mut upcoming: Vec<RoleType> = upcoming_expirations.keys().copied().collect();
+    upcoming.sort_by_key(|role| role.to_string());
+
+    let now = Utc::now();
+    for (role, expiration_date) in upcoming_expirations {
+        if expiration_date < now {
+            error!(
+                "Repo '{}': '{}' expired on {}",
+                metadata_url, role, expiration_date
+            )
+        } else {
+            warn!(
+                "Repo '{}': '{}' expiring in {} at {}",
+                metadata_url,
+                role,
+                expiration_date - now,
+                expiration_date
+            )
+        }
+    }
+
+    Ok(ExpirationSummary {
+        repo: spec.repo.clone(),
+        variant: spec.variant.clone(),
+        arch: spec.arch.clone(),
+        expirations: role_expirations(&repo).into_iter().collect(),
+        limits: limits.clone(),
+        upcoming,
+        metadata_url,
+    })
+}
+
+/// Lists the expiring roles of the repo in the summary, for error messages.
+fn upcoming_roles(summary: &ExpirationSummary) -> String {
+    summary
+        .upcoming
+        .iter()
+        .map(|role| role.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Checks every repo in the repos file, even after finding expiring metadata or failing to load
+/// one, unless asked to stop at the first, and reports them all before failing if any did.
+fn check_repos_file(
+    path: &Path,
+    repos_config: &HashMap<String, RepoConfig>,
+    limits: &HashMap<RoleType, DateTime<Utc>>,
+    fail_fast: bool,
+    output_format: OutputFormat,
+) -> Result<()> {
+    let specs = read_repos_file(path)?;
+    info!("Checking {} repos from {}", specs.len(), path.display());
+
+    let mut summaries = Vec::new();
+    let mut failures = Vec::new();
+    for spec in &specs {
+        let result = repos_config
+            .get(&spec.repo)
+            .with_context(|| repo_error::MissingConfigSnafu {
+                missing: format!("definition for repo {}", &spec.repo),
+            })
+            .map_err(Error::from)
+            .and_then(|repo_config| check_expirations(spec, repo_config, limits));
+        let passed = match result {
+            Ok(summary) => {
+                let passed = summary.upcoming.is_empty();
+                summaries.push(summary);
+                passed
             }
+            Err(e) => {
+                error!("Failed to check repo {}: {}", spec, e);
+                failures.push(RepoFailure {
+                    repo: &spec.repo,
+                    variant: &spec.variant,
+                    arch: &spec.arch,
+                    error: e.to_string(),
+                });
+                false
+            }
+        };
+        if !passed && fail_fast {
+            warn!("Stopping at the first repo that didn't pass, as requested by --fail-fast");
+            break;
         }
-        return Err(Error::RepoExpirations {
-            metadata_url: metadata_url.clone(),
-        });
     }
 
+    let summary = ReposSummary {
+        repos: &summaries,
+        failed: &failures,
+    };
+    output::report(output_format, &summary)?;
+
+    let expiring: Vec<String> = summaries
+        .iter()
+        .filter(|summary| !summary.upcoming.is_empty())
+        .map(|summary| format!("{} ({})", summary.metadata_url, upcoming_roles(summary)))
+        .collect();
+    ensure!(
+        expiring.is_empty() && failures.is_empty(),
+        error::ReposFailedSnafu {
+            expiring: expiring.join("; "),
+            failed: failures
+                .iter()
+                .map(|failure| format!("{} ({} {})", failure.repo, failure.variant, failure.arch))
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    );
     Ok(())
 }
 
 /// Common entrypoint from main()
 pub(crate) fn run(args: &Args, check_expirations_args: &CheckExpirationsArgs) -> Result<()> {
     // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(repo_error::ConfigSnafu)?;
+    let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
     trace!("Parsed infra config: {:?}", infra_config);
-    let repo_config = infra_config
+    let repos_config = infra_config
         .repo
         .as_ref()
         .context(repo_error::MissingConfigSnafu {
             missing: "repo section",
-        })?
-        .get(&check_expirations_args.repo)
-        .with_context(|| repo_error::MissingConfigSnafu {
-            missing: format!("definition for repo {}", &check_expirations_args.repo),
         })?;
 
-    let repo_urls = repo_urls(
-        &repo_config,
-        &check_expirations_args.variant,
-        &check_expirations_args.arch,
-    )?
-    .context(repo_error::MissingRepoUrlsSnafu {
-        repo: &check_expirations_args.repo,
-    })?;
-    check_expirations(
-        &check_expirations_args.root_role_path,
-        &repo_urls.0,
-        repo_urls.1,
-        check_expirations_args.expiration_limit,
-    )?;
+    // Roles without their own threshold fall back to --expiration-limit.
+    let mut limits: HashMap<RoleType, DateTime<Utc>> = [
+        RoleType::Root,
+        RoleType::Snapshot,
+        RoleType::Targets,
+        RoleType::Timestamp,
+    ]
+    .iter()
+    .map(|role| (*role, check_expirations_args.expiration_limit))
+    .collect();
+    limits.extend(check_expirations_args.thresholds.iter().copied());
+
+    if let Some(path) = &check_expirations_args.repos_file {
+        return check_repos_file(
+            path,
+            repos_config,
+            &limits,
+            check_expirations_args.fail_fast,
+            args.output,
+        );
+    }
+
+    // structopt requires these without --repos-file.
+    let spec = match check_expirations_args {
+        CheckExpirationsArgs {
+            repo: Some(repo),
+            variant: Some(variant),
+            arch: Some(arch),
+            root_role_path: Some(root_role_path),
+            ..
+        } => RepoSpec {
+            repo: repo.clone(),
+            variant: variant.clone(),
+            arch: arch.clone(),
+            root_role_path: root_role_path.clone(),
+        },
+        _ => return error::MissingRepoSnafu.fail(),
+    };
+    let repo_config =
+        repos_config
+            .get(&spec.repo)
+            .with_context(|| repo_error::MissingConfigSnafu {
+                missing: format!("definition for repo {}", &spec.repo),
+            })?;
+    let summary = check_expirations(&spec, repo_config, &limits)?;
+    output::report(args.output, &summary)?;
+    ensure!(
+        summary.upcoming.is_empty(),
+        error::RepoExpirationsSnafu {
+            metadata_url: summary.metadata_url.clone(),
+            roles: upcoming_roles(&summary),
+        }
+    );
 
     Ok(())
 }
 
 mod error {
     use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
     use url::Url;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub(crate) enum Error {
+        #[snafu(display(
+            "Invalid threshold '{}', expected ROLE=LIMIT with ROLE one of root, snapshot, targets, timestamp",
+            input
+        ))]
+        InvalidThreshold { input: String },
+
+        #[snafu(display("Either --repos-file or all of --repo, --variant, --arch, and --root-role-path are required"))]
+        MissingRepo,
+
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
         #[snafu(context(false), display("{}", source))]
         Repo { source: crate::repo::Error },
 
-        #[snafu(display("Found expiring/expired metadata in '{}'", metadata_url))]
-        RepoExpirations { metadata_url: Url },
+        #[snafu(display("Found expiring/expired metadata in '{}': {}", metadata_url, roles))]
+        RepoExpirations { metadata_url: Url, roles: String },
+
+        #[snafu(display("No repos listed in repos file '{}'", path.display()))]
+        ReposFileEmpty { path: PathBuf },
+
+        #[snafu(display("Failed to parse repos file '{}': {}", path.display(), source))]
+        ReposFileParse {
+            path: PathBuf,
+            source: toml::de::Error,
+        },
+
+        #[snafu(display("Failed to read repos file '{}': {}", path.display(), source))]
+        ReposFileRead { path: PathBuf, source: io::Error },
+
+        #[snafu(display(
+            "Found expiring/expired metadata in: [{}]; failed to check repos: [{}]",
+            expiring,
+            failed
+        ))]
+        ReposFailed { expiring: String, failed: String },
+
+        #[snafu(display("Invalid limit in threshold '{}': {}", input, source))]
+        ThresholdDate {
+            input: String,
+            source: parse_datetime::Error,
+        },
     }
 }
 pub(crate) use error::Error;
 
 type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::read_repos_file;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn repos_file_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("repos.toml");
+        fs::write(
+            &path,
+            r#"
+[[repos]]
+repo = "default"
+variant = "aws-k8s-1.24"
+arch = "x86_64"
+root-role-path = "roots/root.json"
+
+[[repos]]
+repo = "default"
+variant = "aws-ecs-1"
+arch = "aarch64"
+root-role-path = "/etc/pubsys/root.json"
+"#,
+        )
+        .unwrap();
+        let specs = read_repos_file(&path).unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].to_string(), "default (aws-k8s-1.24 x86_64)");
+        // Relative roots are found next to the repos file.
+        assert_eq!(specs[0].root_role_path, dir.path().join("roots/root.json"));
+        assert_eq!(specs[1].root_role_path, Path::new("/etc/pubsys/root.json"));
+
+        // An empty file or a misspelled key is an error, not a passing check of nothing.
+        fs::write(&path, "repos = []").unwrap();
+        assert!(read_repos_file(&path).is_err());
+        fs::write(
+            &path,
+            "[[repos]]\nrepo = \"default\"\nvariant = \"v\"\narch = \"x86_64\"\nroot_role_path = \"root.json\"\n",
+        )
+        .unwrap();
+        assert!(read_repos_file(&path).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+//! The create_root module owns the 'create-root' subcommand and builds an unsigned root.json for
+//! a new repo from the keys and thresholds given, ready to be signed by the root keys.
+
+use crate::repo::get_signing_key_source;
+use crate::{output, Args};
+use chrono::{DateTime, Utc};
+use log::{info, trace};
+use parse_datetime::parse_datetime;
+use pubsys_config::SigningKeyConfig;
+use serde::Serialize;
+use snafu::{ensure, IntoError, ResultExt};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use structopt::StructOpt;
+use tough::schema::decoded::{Decoded, Hex};
+use tough::schema::key::Key;
+use tough::schema::{RoleKeys, RoleType, Root, Signed};
+use url::Url;
+
+/// The TUF spec version we write, matching what tough uses for the rest of the repo metadata
+const SPEC_VERSION: &str = "1.0.0";
+
+/// The top-level roles a root.json has to assign keys to
+const ROLES: [RoleType; 4] = [
+    RoleType::Root,
+    RoleType::Snapshot,
+    RoleType::Targets,
+    RoleType::Timestamp,
+];
+
+/// Creates an unsigned root.json for a new repo
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct CreateRootArgs {
+    #[structopt(long = "root-key", required = true, parse(try_from_str = parse_key))]
+    /// Key for the root role, as a file path or an aws-kms:///KEY-ID or aws-ssm:///PARAMETER URL;
+    /// may be given more than once
+    root_keys: Vec<SigningKeyConfig>,
+
+    #[structopt(long = "signing-key", parse(try_from_str = parse_key))]
+    /// Key for the snapshot, targets, and timestamp roles, in the same forms as --root-key; may be
+    /// given more than once.  If not given, the root keys are used for every role
+    signing_keys: Vec<SigningKeyConfig>,
+
+    #[structopt(long = "threshold", parse(try_from_str = parse_threshold))]
+    /// ROLE=N, the number of signatures the role needs; may be given once per role, and roles not
+    /// given need 1
+    thresholds: Vec<(RoleType, NonZeroU64)>,
+
+    #[structopt(long, default_value = "in 52 weeks", parse(try_from_str = parse_datetime))]
+    /// When root.json expires; RFC3339 date or "in X hours/days/weeks"
+    expires: DateTime<Utc>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Where to write root.json; it must not exist yet
+    outfile: PathBuf,
+}
+
+/// Summary of the created root.json, for reporting results
+#[derive(Debug, Serialize)]
+struct CreateRootSummary<'a> {
+    outfile: &'a Path,
+    expires: DateTime<Utc>,
+    /// Map of role to its key IDs and threshold
+    roles: BTreeMap<String, &'a RoleKeys>,
+}
+
+/// Parses a key given on the command line.  URLs take the form tuftool uses, without a profile,
+/// which Infra.toml keys don't support either; anything else is a path to a local key file.
+pub(crate) fn parse_key(input: &str) -> Result<SigningKeyConfig> {
+    let url = match Url::parse(input) {
+        Ok(url) => url,
+        Err(_) => {
+            return Ok(SigningKeyConfig::file {
+                path: PathBuf::from(input),
+            })
+        }
+    };
+    let path = url.path();
+    match url.scheme() {
+        "file" => Ok(SigningKeyConfig::file {
+            path: PathBuf::from(path),
+        }),
+        "aws-kms" | "aws-ssm" => {
+            ensure!(
+                url.host_str().unwrap_or_default().is_empty(),
+                error::KeyProfileSnafu { input }
+            );
+            ensure!(path.len() > 1, error::KeyUrlSnafu { input });
+            if url.scheme() == "aws-kms" {
+                Ok(SigningKeyConfig::kms {
+                    key_id: Some(path.trim_start_matches('/').to_string()),
+                    config: None,
+                })
+            } else {
+                Ok(SigningKeyConfig::ssm {
+                    parameter: path.to_string(),
+                })
+            }
+        }
+        _ => error::KeyUrlSnafu { input }.fail(),
+    }
+}
+
+/// Parses a ROLE=N threshold given on the command line.
+fn parse_threshold(input: &str) -> Result<(RoleType, NonZeroU64)> {
+    let (role, threshold) = input
+        .split_once('=')
+        .ok_or_else(|| error::ThresholdSnafu { input }.build())?;
+    let role = RoleType::from_str(role)
+        .ok()
+        .filter(|role| ROLES.contains(role))
+        .ok_or_else(|| error::ThresholdSnafu { input }.build())?;
+    let threshold = threshold
+        .parse()
+        .ok()
+        .ok_or_else(|| error::ThresholdSnafu { input }.build())?;
+    Ok((role, threshold))
+}
+
+/// Loads each key and returns its TUF key, keyed by key ID; a key given twice is only listed once.
+fn load_keys(keys: &[SigningKeyConfig]) -> Result<BTreeMap<Decoded<Hex>, Key>> {
+    let mut loaded = BTreeMap::new();
+    for key in keys {
+        let key_source = get_signing_key_source(key).context(error::KeySourceSnafu)?;
+        let tuf_key = key_source
+            .as_sign()
+            .map_err(|source| error::KeyLoadSnafu { key: key.clone() }.into_error(source))?
+            .tuf_key();
+        let key_id = tuf_key
+            .key_id()
+            .context(error::KeyIdSnafu { key: key.clone() })?;
+        trace!("Loaded key {}: {:?}", hex::encode(&key_id), key);
+        loaded.insert(key_id, tuf_key);
+    }
+    Ok(loaded)
+}
+
+/// Assigns the given key IDs to each role, making sure each role has enough keys to meet its
+/// threshold; otherwise the role could never be signed.
+fn role_keys(
+    root_key_ids: &[Decoded<Hex>],
+    signing_key_ids: &[Decoded<Hex>],
+    thresholds: &[(RoleType, NonZeroU64)],
+) -> Result<HashMap<RoleType, RoleKeys>> {
+    let thresholds: HashMap<RoleType, NonZeroU64> = thresholds.iter().cloned().collect();
+    let mut roles = HashMap::new();
+    for role in ROLES.iter() {
+        let keyids = if *role == RoleType::Root {
+            root_key_ids.to_vec()
+        } else {
+            signing_key_ids.to_vec()
+        };
+        let threshold = thresholds
+            .get(role)
+            .cloned()
+            .unwrap_or_else(|| NonZeroU64::new(1).unwrap());
+        ensure!(
+            threshold.get() <= keyids.len() as u64,
+            error::ThresholdKeysSnafu {
+                role: *role,
+                threshold,
+                keys: keyids.len(),
+            }
+        );
+        roles.insert(
+            *role,
+            RoleKeys {
+                keyids,
+                threshold,
+                _extra: HashMap::new(),
+            },
+        );
+    }
+    Ok(roles)
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, create_args: &CreateRootArgs) -> Result<()> {
+    info!("Loading {} root keys", create_args.root_keys.len());
+    let root_keys = load_keys(&create_args.root_keys)?;
+    let signing_keys = if create_args.signing_keys.is_empty() {
+        info!("No signing keys given, so root keys will sign every role");
+        root_keys.clone()
+    } else {
+        info!("Loading {} signing keys", create_args.signing_keys.len());
+        load_keys(&create_args.signing_keys)?
+    };
+
+    let roles = role_keys(
+        &root_keys.keys().cloned().collect::<Vec<_>>(),
+        &signing_keys.keys().cloned().collect::<Vec<_>>(),
+        &create_args.thresholds,
+    )?;
+    let root = Root {
+        spec_version: SPEC_VERSION.to_string(),
+        consistent_snapshot: true,
+        version: NonZeroU64::new(1).unwrap(),
+        expires: create_args.expires,
+        keys: root_keys.into_iter().chain(signing_keys).collect(),
+        roles,
+        _extra: HashMap::new(),
+    };
+    let signed = Signed {
+        signed: root,
+        signatures: Vec::new(),
+    };
+
+    let mut data = serde_json::to_vec_pretty(&signed).context(error::SerializeSnafu)?;
+    data.push(b'\n');
+    let outfile = &create_args.outfile;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(outfile)
+        .context(error::WriteSnafu { path: outfile })?;
+    file.write_all(&data)
+        .context(error::WriteSnafu { path: outfile })?;
+    info!(
+        "Wrote unsigned root role to {}; sign it with the root keys before use",
+        outfile.display()
+    );
+
+    let summary = CreateRootSummary {
+        outfile,
+        expires: signed.signed.expires,
+        roles: signed
+            .signed
+            .roles
+            .iter()
+            .map(|(role, keys)| (role.to_string(), keys))
+            .collect(),
+    };
+    output::report(args.output, &summary)?;
+    Ok(())
+}
+
+mod error {
+    use pubsys_config::SigningKeyConfig;
+    use snafu::Snafu;
+    use std::io;
+    use std::num::NonZeroU64;
+    use std::path::PathBuf;
+    use tough::schema::RoleType;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Failed to compute the key ID of {:?}: {}", key, source))]
+        KeyId {
+            key: Box<SigningKeyConfig>,
+            #[snafu(source(from(tough::schema::Error, Box::new)))]
+            source: Box<tough::schema::Error>,
+        },
+
+        #[snafu(display("Failed to load key {:?}: {}", key, source))]
+        KeyLoad {
+            key: Box<SigningKeyConfig>,
+            source: Box<dyn std::error::Error + Send + Sync + 'static>,
+        },
+
+        #[snafu(display("Key '{}' names an AWS profile, which isn't supported", input))]
+        KeyProfile { input: String },
+
+        #[snafu(display("Failed to get key source: {}", source))]
+        KeySource { source: crate::repo::Error },
+
+        #[snafu(display(
+            "Invalid key URL '{}'; expected aws-kms:///KEY-ID, aws-ssm:///PARAMETER, or a path",
+            input
+        ))]
+        KeyUrl { input: String },
+
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
+        #[snafu(display("Failed to serialize root role: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display(
+            "Invalid threshold '{}'; expected ROLE=N with a role of root, snapshot, targets, or \
+             timestamp, and N at least 1",
+            input
+        ))]
+        Threshold { input: String },
+
+        #[snafu(display(
+            "The {} role needs {} signatures but only has {} keys",
+            role,
+            threshold,
+            keys
+        ))]
+        ThresholdKeys {
+            role: RoleType,
+            threshold: NonZeroU64,
+            keys: usize,
+        },
+
+        #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+        Write { path: PathBuf, source: io::Error },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{parse_key, parse_threshold, role_keys};
+    use pubsys_config::SigningKeyConfig;
+    use std::num::NonZeroU64;
+    use tough::schema::decoded::{Decoded, Hex};
+    use tough::schema::RoleType;
+
+    fn key_ids(count: u8) -> Vec<Decoded<Hex>> {
+        (0..count).map(|i| Decoded::from(vec![i])).collect()
+    }
+
+    #[test]
+    fn thresholds_checked_against_keys() {
+        let thresholds = vec![
+            (RoleType::Root, NonZeroU64::new(2).unwrap()),
+            (RoleType::Targets, NonZeroU64::new(1).unwrap()),
+        ];
+        let roles = role_keys(&key_ids(2), &key_ids(1), &thresholds).unwrap();
+        assert_eq!(roles[&RoleType::Root].threshold.get(), 2);
+        assert_eq!(roles[&RoleType::Root].keyids.len(), 2);
+        assert_eq!(roles[&RoleType::Timestamp].threshold.get(), 1);
+        assert_eq!(roles[&RoleType::Timestamp].keyids.len(), 1);
+
+        assert!(role_keys(&key_ids(1), &key_ids(1), &thresholds).is_err());
+    }
+
+    #[test]
+    fn parse_args() {
+        assert_eq!(
+            parse_threshold("snapshot=3").unwrap(),
+            (RoleType::Snapshot, NonZeroU64::new(3).unwrap())
+        );
+        assert!(parse_threshold("snapshot=0").is_err());
+        assert!(parse_threshold("delegated-targets=1").is_err());
+
+        assert_eq!(
+            parse_key("aws-kms:///alias/root").unwrap(),
+            SigningKeyConfig::kms {
+                key_id: Some("alias/root".to_string()),
+                config: None,
+            }
+        );
+        assert_eq!(
+            parse_key("aws-ssm:///keys/root").unwrap(),
+            SigningKeyConfig::ssm {
+                parameter: "/keys/root".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_key("keys/root.pem").unwrap(),
+            SigningKeyConfig::file {
+                path: "keys/root.pem".into(),
+            }
+        );
+        assert!(parse_key("aws-kms://profile/alias/root").is_err());
+    }
+}
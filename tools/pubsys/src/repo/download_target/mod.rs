@@ -0,0 +1,205 @@
+//! The download_target module owns the 'download-target' subcommand and provides a way to pull a
+//! single target out of a TUF repository, verified against the repo's metadata, for inspection.
+
+use crate::repo::validate_repo::load_repo;
+use crate::repo::{error as repo_error, repo_urls};
+use crate::{output, Args};
+use log::{info, trace};
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::convert::TryInto;
+use std::io;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use tempfile::NamedTempFile;
+use tough::TargetName;
+use url::Url;
+
+/// Downloads a single target from a TUF repository, verifying it against the repo metadata
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct DownloadTargetArgs {
+    #[structopt(long)]
+    /// Use this named repo infrastructure from Infra.toml
+    repo: String,
+
+    #[structopt(long)]
+    /// The architecture of the repo holding the target
+    arch: String,
+    #[structopt(long)]
+    /// The variant of the repo holding the target
+    variant: String,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Path to root.json for this repo
+    root_role_path: PathBuf,
+
+    #[structopt(long)]
+    /// The name of the target to download, as listed in the targets metadata
+    target: String,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Where to write the target; must not already exist
+    outfile: PathBuf,
+
+    #[structopt(long)]
+    /// Load the repo even if its metadata has expired
+    allow_expired: bool,
+}
+
+/// Summary of the downloaded target, for reporting results
+#[derive(Debug, Serialize)]
+struct DownloadSummary<'a> {
+    metadata_url: &'a Url,
+    target: &'a str,
+    path: &'a Path,
+    /// The length in bytes of the target, which matched the targets metadata
+    length: u64,
+    /// The sha256 of the target, which matched the targets metadata
+    sha256: String,
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, download_args: &DownloadTargetArgs) -> Result<()> {
+    ensure!(
+        !download_args.outfile.exists(),
+        error::OutfileExistsSnafu {
+            path: &download_args.outfile
+        }
+    );
+
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+    let repo_config = infra_config
+        .repo
+        .as_ref()
+        .context(repo_error::MissingConfigSnafu {
+            missing: "repo section",
+        })?
+        .get(&download_args.repo)
+        .context(repo_error::MissingConfigSnafu {
+            missing: format!("definition for repo {}", &download_args.repo),
+        })?;
+
+    let (metadata_url, targets_url) =
+        repo_urls(repo_config, &download_args.variant, &download_args.arch)?.context(
+            repo_error::MissingRepoUrlsSnafu {
+                repo: &download_args.repo,
+            },
+        )?;
+    let repo = load_repo(
+        &download_args.root_role_path,
+        metadata_url.clone(),
+        targets_url,
+        download_args.allow_expired,
+    )
+    .context(error::LoadRepoSnafu)?;
+
+    // Look the target up in the metadata first, so a typo gets a clear error rather than a
+    // download failure.
+    let target_name: TargetName =
+        download_args
+            .target
+            .as_str()
+            .try_into()
+            .context(repo_error::ParseTargetNameSnafu {
+                target: &download_args.target,
+            })?;
+    let target =
+        repo.targets()
+            .signed
+            .targets
+            .get(&target_name)
+            .context(error::TargetMissingSnafu {
+                target: &download_args.target,
+            })?;
+    let length = target.length;
+    let sha256 = hex::encode(&target.hashes.sha256);
+
+    // tough's reader checks the length and hash as it goes, and fails the read on a mismatch, so
+    // we write to a temporary file next to the destination and only move it into place once the
+    // whole target has been read.
+    info!("Downloading target: {}", download_args.target);
+    let mut reader = repo
+        .read_target(&target_name)
+        .context(repo_error::ReadTargetSnafu {
+            target: &download_args.target,
+        })?
+        .context(error::TargetMissingSnafu {
+            target: &download_args.target,
+        })?;
+    let outdir = match download_args.outfile.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut tempfile = NamedTempFile::new_in(outdir).context(error::TempFileSnafu)?;
+    io::copy(&mut reader, &mut tempfile).context(error::TargetDownloadSnafu {
+        target: &download_args.target,
+    })?;
+    tempfile
+        .persist_noclobber(&download_args.outfile)
+        .context(error::PersistSnafu {
+            path: &download_args.outfile,
+        })?;
+    info!(
+        "Verified target '{}' and wrote it to {}",
+        download_args.target,
+        download_args.outfile.display()
+    );
+
+    let summary = DownloadSummary {
+        metadata_url: &metadata_url,
+        target: &download_args.target,
+        path: &download_args.outfile,
+        length,
+        sha256,
+    };
+    output::report(args.output, &summary)?;
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("{}", source))]
+        LoadRepo {
+            source: crate::repo::validate_repo::Error,
+        },
+
+        #[snafu(display("Output file '{}' already exists", path.display()))]
+        OutfileExists { path: PathBuf },
+
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
+        #[snafu(display("Failed to move target into place at '{}': {}", path.display(), source))]
+        Persist {
+            path: PathBuf,
+            source: tempfile::PersistError,
+        },
+
+        #[snafu(context(false), display("{}", source))]
+        Repo { source: crate::repo::Error },
+
+        #[snafu(display(
+            "Failed to download target '{}'; it may not match the targets metadata: {}",
+            target,
+            source
+        ))]
+        TargetDownload { target: String, source: io::Error },
+
+        #[snafu(display("Target '{}' is not listed in the targets metadata", target))]
+        TargetMissing { target: String },
+
+        #[snafu(display("Failed to create temporary file: {}", source))]
+        TempFile { source: io::Error },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
@@ -0,0 +1,267 @@
+//! The export_targets_manifest module owns the 'export-targets-manifest' subcommand and writes a
+//! JSON listing of every target in a repo, for tools like vulnerability scanners that need an
+//! inventory of what the repo serves.
+
+use crate::repo::validate_repo::load_repo;
+use crate::repo::{error as repo_error, repo_urls};
+use crate::{output, Args};
+use chrono::{DateTime, Utc};
+use log::{info, trace};
+use serde::Serialize;
+use serde_json::Value;
+use snafu::{OptionExt, ResultExt};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use tempfile::NamedTempFile;
+use tough::schema::Target;
+use url::Url;
+
+/// Writes a JSON manifest of every target in a TUF repository
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct ExportTargetsManifestArgs {
+    #[structopt(long)]
+    /// Use this named repo infrastructure from Infra.toml
+    repo: String,
+
+    #[structopt(long)]
+    /// The architecture of the repo to list
+    arch: String,
+    #[structopt(long)]
+    /// The variant of the repo to list
+    variant: String,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Path to root.json for this repo
+    root_role_path: PathBuf,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Where to write the manifest; without this, it's written to stdout, in place of the
+    /// --output summary
+    outfile: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Load the repo even if its metadata has expired
+    allow_expired: bool,
+}
+
+/// The manifest of a repo's targets
+#[derive(Debug, Serialize)]
+struct TargetsManifest<'a> {
+    metadata_url: &'a Url,
+    targets_url: &'a Url,
+    /// The version of the targets metadata listing these targets
+    targets_version: u64,
+    targets_expires: DateTime<Utc>,
+    targets: Vec<ManifestTarget<'a>>,
+}
+
+/// One target in the manifest
+#[derive(Debug, Serialize)]
+struct ManifestTarget<'a> {
+    name: &'a str,
+    /// Where the repo serves the target, under the targets URL
+    path: String,
+    length: u64,
+    /// Every hash the targets metadata gives, by algorithm, as hex or as given
+    hashes: BTreeMap<&'a str, Value>,
+    /// Any custom metadata the targets metadata gives for the target
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    custom: &'a HashMap<String, Value>,
+}
+
+/// Summary of the exported manifest, for reporting results when it's written to a file
+#[derive(Debug, Serialize)]
+struct ExportSummary<'a> {
+    metadata_url: &'a Url,
+    path: &'a Path,
+    targets: usize,
+}
+
+impl<'a> ManifestTarget<'a> {
+    fn new(name: &'a str, target: &'a Target, consistent_snapshot: bool) -> Self {
+        let sha256 = hex::encode(&target.hashes.sha256);
+        let mut hashes: BTreeMap<&str, Value> = target
+            .hashes
+            ._extra
+            .iter()
+            .map(|(algorithm, hash)| (algorithm.as_str(), hash.clone()))
+            .collect();
+        // With consistent snapshots, targets are stored under their sha256.
+        let path = if consistent_snapshot {
+            format!("{}.{}", sha256, name)
+        } else {
+            name.to_string()
+        };
+        hashes.insert("sha256", Value::String(sha256));
+        Self {
+            name,
+            path,
+            length: target.length,
+            hashes,
+            custom: &target.custom,
+        }
+    }
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, export_args: &ExportTargetsManifestArgs) -> Result<()> {
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+    let repo_config = infra_config
+        .repo
+        .as_ref()
+        .context(repo_error::MissingConfigSnafu {
+            missing: "repo section",
+        })?
+        .get(&export_args.repo)
+        .context(repo_error::MissingConfigSnafu {
+            missing: format!("definition for repo {}", &export_args.repo),
+        })?;
+
+    let (metadata_url, targets_url) =
+        repo_urls(repo_config, &export_args.variant, &export_args.arch)?.context(
+            repo_error::MissingRepoUrlsSnafu {
+                repo: &export_args.repo,
+            },
+        )?;
+    let repo = load_repo(
+        &export_args.root_role_path,
+        metadata_url.clone(),
+        targets_url,
+        export_args.allow_expired,
+    )
+    .context(error::LoadRepoSnafu)?;
+
+    let consistent_snapshot = repo.root().signed.consistent_snapshot;
+    let targets = &repo.targets().signed;
+    let mut manifest_targets: Vec<ManifestTarget<'_>> = targets
+        .targets
+        .iter()
+        .map(|(name, target)| ManifestTarget::new(name.raw(), target, consistent_snapshot))
+        .collect();
+    manifest_targets.sort_by(|a, b| a.name.cmp(b.name));
+    let manifest = TargetsManifest {
+        metadata_url: &metadata_url,
+        targets_url,
+        targets_version: targets.version.get(),
+        targets_expires: targets.expires,
+        targets: manifest_targets,
+    };
+    let count = manifest.targets.len();
+
+    let outfile = match &export_args.outfile {
+        Some(outfile) => outfile,
+        None => {
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            serde_json::to_writer_pretty(&mut handle, &manifest).context(error::SerializeSnafu)?;
+            return writeln!(handle).context(error::StdoutSnafu);
+        }
+    };
+
+    // Write to a temporary file next to the destination, so a failure never leaves a partial
+    // manifest for other tools to pick up.
+    let outdir = match outfile.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut tempfile = NamedTempFile::new_in(outdir).context(error::TempFileSnafu)?;
+    serde_json::to_writer_pretty(&mut tempfile, &manifest).context(error::SerializeSnafu)?;
+    writeln!(tempfile).context(error::WriteSnafu { path: outfile })?;
+    tempfile
+        .persist(outfile)
+        .context(error::PersistSnafu { path: outfile })?;
+    info!(
+        "Wrote manifest of {} targets to {}",
+        count,
+        outfile.display()
+    );
+
+    let summary = ExportSummary {
+        metadata_url: &metadata_url,
+        path: outfile,
+        targets: count,
+    };
+    output::report(args.output, &summary)?;
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("{}", source))]
+        LoadRepo {
+            source: crate::repo::validate_repo::Error,
+        },
+
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
+        #[snafu(display("Failed to move manifest into place at '{}': {}", path.display(), source))]
+        Persist {
+            path: PathBuf,
+            source: tempfile::PersistError,
+        },
+
+        #[snafu(context(false), display("{}", source))]
+        Repo { source: crate::repo::Error },
+
+        #[snafu(display("Failed to serialize manifest: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to write manifest to stdout: {}", source))]
+        Stdout { source: io::Error },
+
+        #[snafu(display("Failed to create temporary file: {}", source))]
+        TempFile { source: io::Error },
+
+        #[snafu(display("Failed to write manifest to '{}': {}", path.display(), source))]
+        Write { path: PathBuf, source: io::Error },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::ManifestTarget;
+    use serde_json::json;
+    use tough::schema::Target;
+
+    #[test]
+    fn manifest_target_fields() {
+        let sha256 = "ab".repeat(32);
+        let target: Target = serde_json::from_value(json!({
+            "length": 4,
+            "hashes": { "sha256": sha256, "sha512": "cd" },
+            "custom": { "kind": "image" },
+        }))
+        .unwrap();
+
+        let entry = ManifestTarget::new("root.ext4.lz4", &target, false);
+        assert_eq!(entry.path, "root.ext4.lz4");
+        assert_eq!(
+            serde_json::to_value(&entry).unwrap(),
+            json!({
+                "name": "root.ext4.lz4",
+                "path": "root.ext4.lz4",
+                "length": 4,
+                "hashes": { "sha256": sha256, "sha512": "cd" },
+                "custom": { "kind": "image" },
+            })
+        );
+
+        // Consistent snapshots store targets under their hash.
+        let entry = ManifestTarget::new("root.ext4.lz4", &target, true);
+        assert_eq!(entry.path, format!("{}.root.ext4.lz4", sha256));
+    }
+}
@@ -0,0 +1,252 @@
+//! The gc module owns the 'repo-gc' subcommand and finds target files in a local repo directory
+//! that no valid targets metadata refers to anymore, optionally deleting them.
+
+use crate::repo::validate_repo::load_repo;
+use crate::{output, Args};
+use chrono::Utc;
+use log::{debug, info, trace, warn};
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use tough::schema::{Signed, Targets};
+use url::Url;
+
+/// The name of the directory holding targets, shared by every variant and arch in a repo
+const TARGETS_DIR: &str = "targets";
+
+/// Finds, and optionally deletes, target files that no valid targets metadata refers to
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct RepoGcArgs {
+    #[structopt(long, parse(from_os_str))]
+    /// Path to root.json for this repo
+    root_role_path: PathBuf,
+
+    #[structopt(long, parse(from_os_str))]
+    /// The local repo directory, holding a metadata directory for each variant and arch, and the
+    /// shared targets directory
+    repo_dir: PathBuf,
+
+    #[structopt(long)]
+    /// Delete the unreferenced targets; without this, they're only listed
+    delete: bool,
+}
+
+/// Summary of the unreferenced targets, for reporting results
+#[derive(Debug, Serialize)]
+struct GcSummary<'a> {
+    targets_dir: &'a Path,
+    /// The metadata directories whose targets we kept
+    metadata_dirs: &'a [PathBuf],
+    referenced: usize,
+    unreferenced: &'a [String],
+    deleted: bool,
+}
+
+/// Returns every directory under `dir` holding targets metadata, skipping the targets directory.
+fn find_metadata_dirs(dir: &Path, targets_dir: &Path, found: &mut Vec<PathBuf>) -> Result<()> {
+    let mut has_targets = false;
+    for entry in fs::read_dir(dir).context(error::ReadDirSnafu { path: dir })? {
+        let path = entry.context(error::ReadDirSnafu { path: dir })?.path();
+        if path.is_dir() {
+            if path != targets_dir {
+                find_metadata_dirs(&path, targets_dir, found)?;
+            }
+        } else if targets_metadata_version(&path).is_some() {
+            has_targets = true;
+        }
+    }
+    if has_targets {
+        found.push(dir.to_owned());
+    }
+    Ok(())
+}
+
+/// If the given path is a targets metadata file, like `targets.json` or, with consistent
+/// snapshots, `3.targets.json`, returns its version from the name; unversioned files get 0.
+fn targets_metadata_version(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    if name == "targets.json" {
+        return Some(0);
+    }
+    name.strip_suffix(".targets.json")?.parse().ok()
+}
+
+/// Adds the file names of every target in the given targets metadata to `referenced`.  We add
+/// both the plain name and the consistent-snapshot name, prefixed by the sha256, so we keep the
+/// file whichever way the repo stores it.
+fn add_referenced(targets: &Targets, referenced: &mut BTreeSet<String>) {
+    for (name, target) in &targets.targets {
+        let name = name.resolved();
+        referenced.insert(name.to_string());
+        referenced.insert(format!("{}.{}", hex::encode(&target.hashes.sha256), name));
+    }
+}
+
+/// Returns the file names of the targets referenced by the metadata in the given directory: the
+/// current targets metadata, which we verify by loading the repo, and any older versions that
+/// haven't expired, since clients may still be using them.
+fn referenced_targets(
+    root_role_path: &Path,
+    metadata_dir: &Path,
+    targets_dir: &Path,
+    referenced: &mut BTreeSet<String>,
+) -> Result<()> {
+    let metadata_url = Url::from_directory_path(metadata_dir)
+        .ok()
+        .context(error::DirUrlSnafu { path: metadata_dir })?;
+    let targets_url = Url::from_directory_path(targets_dir)
+        .ok()
+        .context(error::DirUrlSnafu { path: targets_dir })?;
+    // We only keep files based on this metadata, but make sure it's sound before trusting it to
+    // decide what to delete.  It's fine if it has expired; its targets are still current.
+    let repo = load_repo(root_role_path, metadata_url, &targets_url, true)
+        .context(error::LoadRepoSnafu)?;
+    let current_version = repo.targets().signed.version.get();
+    add_referenced(&repo.targets().signed, referenced);
+
+    let mut older: BTreeMap<u64, PathBuf> = BTreeMap::new();
+    for entry in fs::read_dir(metadata_dir).context(error::ReadDirSnafu { path: metadata_dir })? {
+        let path = entry
+            .context(error::ReadDirSnafu { path: metadata_dir })?
+            .path();
+        match targets_metadata_version(&path) {
+            Some(version) if version != 0 && version < current_version => {
+                older.insert(version, path);
+            }
+            _ => {}
+        }
+    }
+    for (version, path) in older {
+        let data = fs::read(&path).context(error::ReadMetadataSnafu { path: &path })?;
+        let targets: Signed<Targets> =
+            serde_json::from_slice(&data).context(error::ParseMetadataSnafu { path: &path })?;
+        if targets.signed.expires > Utc::now() {
+            debug!(
+                "Keeping targets of unexpired version {} in {}",
+                version,
+                metadata_dir.display()
+            );
+            add_referenced(&targets.signed, referenced);
+        }
+    }
+    Ok(())
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, gc_args: &RepoGcArgs) -> Result<()> {
+    // Directory URLs for loading the repo need absolute paths.
+    let repo_dir = fs::canonicalize(&gc_args.repo_dir).context(error::ReadDirSnafu {
+        path: &gc_args.repo_dir,
+    })?;
+    let targets_dir = repo_dir.join(TARGETS_DIR);
+    ensure!(
+        targets_dir.is_dir(),
+        error::MissingTargetsDirSnafu { path: &targets_dir }
+    );
+
+    // The targets directory is shared, so a target is only unreferenced if no variant or arch
+    // refers to it.
+    let mut metadata_dirs = Vec::new();
+    find_metadata_dirs(&repo_dir, &targets_dir, &mut metadata_dirs)?;
+    metadata_dirs.sort();
+    ensure!(
+        !metadata_dirs.is_empty(),
+        error::NoMetadataSnafu { path: &repo_dir }
+    );
+
+    let mut referenced = BTreeSet::new();
+    for metadata_dir in &metadata_dirs {
+        info!("Loading targets metadata from {}", metadata_dir.display());
+        referenced_targets(
+            &gc_args.root_role_path,
+            metadata_dir,
+            &targets_dir,
+            &mut referenced,
+        )?;
+    }
+    trace!("Referenced targets: {:?}", referenced);
+
+    let mut unreferenced = Vec::new();
+    for entry in fs::read_dir(&targets_dir).context(error::ReadDirSnafu { path: &targets_dir })? {
+        let entry = entry.context(error::ReadDirSnafu { path: &targets_dir })?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !referenced.contains(&name) {
+            unreferenced.push(name);
+        }
+    }
+    unreferenced.sort();
+
+    for name in &unreferenced {
+        let path = targets_dir.join(name);
+        if gc_args.delete {
+            info!("Deleting unreferenced target {}", path.display());
+            fs::remove_file(&path).context(error::DeleteSnafu { path: &path })?;
+        } else {
+            info!("Unreferenced target: {}", path.display());
+        }
+    }
+    if !gc_args.delete && !unreferenced.is_empty() {
+        warn!(
+            "Found {} unreferenced targets; use --delete to remove them",
+            unreferenced.len()
+        );
+    }
+
+    let summary = GcSummary {
+        targets_dir: &targets_dir,
+        metadata_dirs: &metadata_dirs,
+        referenced: referenced.len(),
+        unreferenced: &unreferenced,
+        deleted: gc_args.delete,
+    };
+    output::report(args.output, &summary)?;
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Failed to delete '{}': {}", path.display(), source))]
+        Delete { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to make a URL for directory '{}'", path.display()))]
+        DirUrl { path: PathBuf },
+
+        #[snafu(display("Failed to load repo: {}", source))]
+        LoadRepo {
+            source: crate::repo::validate_repo::Error,
+        },
+
+        #[snafu(display("No targets directory at '{}'", path.display()))]
+        MissingTargetsDir { path: PathBuf },
+
+        #[snafu(display("No targets metadata found under '{}'", path.display()))]
+        NoMetadata { path: PathBuf },
+
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
+        #[snafu(display("Failed to parse targets metadata '{}': {}", path.display(), source))]
+        ParseMetadata {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to read directory '{}': {}", path.display(), source))]
+        ReadDir { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to read targets metadata '{}': {}", path.display(), source))]
+        ReadMetadata { path: PathBuf, source: io::Error },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
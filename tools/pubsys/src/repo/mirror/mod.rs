@@ -0,0 +1,320 @@
+//! The mirror module owns the 'repo-mirror' subcommand and downloads a whole TUF repository,
+//! metadata and every target, to a local directory, verifying each target against the repo's
+//! metadata.  The result is laid out like the output of the 'repo' subcommand, so it can be
+//! served or copied elsewhere as is, for example across an air gap.
+
+use crate::repo::validate_repo::{load_repo, MAX_DOWNLOAD_THREADS};
+use crate::repo::{error as repo_error, repo_urls};
+use crate::{output, Args};
+use log::{info, trace, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::cmp::min;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use structopt::StructOpt;
+use tempfile::NamedTempFile;
+use tough::Repository;
+use url::Url;
+
+/// Downloads a whole TUF repository, metadata and verified targets, to a local directory
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct RepoMirrorArgs {
+    #[structopt(long)]
+    /// Use this named repo infrastructure from Infra.toml
+    repo: String,
+
+    #[structopt(long)]
+    /// The architecture of the repo being mirrored
+    arch: String,
+    #[structopt(long)]
+    /// The variant of the repo being mirrored
+    variant: String,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Path to root.json for this repo
+    root_role_path: PathBuf,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Where to write the repo; metadata goes in <outdir>/<variant>/<arch> and targets in
+    /// <outdir>/targets, like the 'repo' subcommand
+    outdir: PathBuf,
+
+    #[structopt(long)]
+    /// Continue an earlier mirror into the same outdir, skipping targets that were already
+    /// downloaded and still match the targets metadata
+    resume: bool,
+
+    #[structopt(long)]
+    /// Load the repo even if its metadata has expired
+    allow_expired: bool,
+}
+
+/// Summary of the mirrored repo, for reporting results
+#[derive(Debug, Serialize)]
+struct MirrorSummary<'a> {
+    metadata_url: &'a Url,
+    metadata_dir: &'a Path,
+    targets_dir: &'a Path,
+    /// The number of targets listed in the repo
+    targets: usize,
+    /// Targets downloaded during this run
+    downloaded: usize,
+    /// Targets already in the targets directory from an earlier run, with --resume
+    skipped: usize,
+}
+
+/// Returns whether the file at `path` exists and has the given length and sha256, so it doesn't
+/// need to be downloaded again.
+fn verified_target(path: &Path, length: u64, sha256: &str) -> Result<bool> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).context(error::TargetReadSnafu { path }),
+    };
+    let mut digest = Sha256::new();
+    let actual_length =
+        io::copy(&mut file, &mut digest).context(error::TargetReadSnafu { path })?;
+    let actual_sha256 = hex::encode(digest.finalize());
+    if actual_length == length && actual_sha256 == sha256 {
+        Ok(true)
+    } else {
+        warn!(
+            "Existing target {} doesn't match the targets metadata; downloading it again",
+            path.display()
+        );
+        Ok(false)
+    }
+}
+
+/// Writes a target from tough's reader to `path`.  The reader checks the length and hash as it
+/// goes, and fails the read on a mismatch, so we write to a temporary file next to the
+/// destination and only move it into place once the whole target has been read; an interrupted
+/// mirror never leaves a partial target under the target's name.
+fn write_target<R: io::Read>(reader: &mut R, target: &str, path: &Path) -> Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut tempfile = NamedTempFile::new_in(dir).context(error::TempFileSnafu)?;
+    io::copy(reader, &mut tempfile).context(error::TargetDownloadSnafu { target })?;
+    tempfile
+        .persist(path)
+        .context(error::PersistSnafu { path })?;
+    Ok(())
+}
+
+/// Downloads every listed target into `targets_dir`, under the name the repo stores it with,
+/// returning how many were downloaded and how many were skipped because they were already
+/// there.  We use a Rayon thread pool for the same reason as validate-repo: `reqwest::blocking`
+/// creates its own tokio runtime.
+fn mirror_targets(repo: &Repository, targets_dir: &Path, resume: bool) -> Result<(usize, usize)> {
+    let consistent_snapshot = repo.root().signed.consistent_snapshot;
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(min(num_cpus::get(), MAX_DOWNLOAD_THREADS))
+        .build()
+        .context(error::ThreadPoolSnafu)?;
+    let (tx, rx) = mpsc::channel();
+
+    let mut skipped = 0;
+    for (name, target) in &repo.targets().signed.targets {
+        let sha256 = hex::encode(&target.hashes.sha256);
+        // With consistent snapshots, targets are stored under a name prefixed by their digest.
+        let filename = if consistent_snapshot {
+            format!("{}.{}", sha256, name.resolved())
+        } else {
+            name.resolved().to_owned()
+        };
+        let path = targets_dir.join(filename);
+        if resume && verified_target(&path, target.length, &sha256)? {
+            trace!("Skipping already downloaded target: {}", name.raw());
+            skipped += 1;
+            continue;
+        }
+
+        let mut reader = repo
+            .read_target(name)
+            .with_context(|_| repo_error::ReadTargetSnafu { target: name.raw() })?
+            .with_context(|| error::TargetMissingSnafu { target: name.raw() })?;
+        let target = name.raw().to_owned();
+        let tx = tx.clone();
+        info!("Downloading target: {}", target);
+        thread_pool.spawn(move || {
+            // inability to send on this channel is unrecoverable
+            tx.send(write_target(&mut reader, &target, &path)).unwrap();
+        });
+    }
+    drop(tx);
+
+    let mut downloaded = 0;
+    for result in rx {
+        result?;
+        downloaded += 1;
+    }
+    Ok((downloaded, skipped))
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, mirror_args: &RepoMirrorArgs) -> Result<()> {
+    let metadata_dir = mirror_args
+        .outdir
+        .join(&mirror_args.variant)
+        .join(&mirror_args.arch);
+    let targets_dir = mirror_args.outdir.join("targets");
+    ensure!(
+        mirror_args.resume || !metadata_dir.exists(),
+        error::RepoExistsSnafu {
+            path: &metadata_dir
+        }
+    );
+
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+    let repo_config = infra_config
+        .repo
+        .as_ref()
+        .context(repo_error::MissingConfigSnafu {
+            missing: "repo section",
+        })?
+        .get(&mirror_args.repo)
+        .context(repo_error::MissingConfigSnafu {
+            missing: format!("definition for repo {}", &mirror_args.repo),
+        })?;
+
+    let (metadata_url, targets_url) =
+        repo_urls(repo_config, &mirror_args.variant, &mirror_args.arch)?.context(
+            repo_error::MissingRepoUrlsSnafu {
+                repo: &mirror_args.repo,
+            },
+        )?;
+    let repo = load_repo(
+        &mirror_args.root_role_path,
+        metadata_url.clone(),
+        targets_url,
+        mirror_args.allow_expired,
+    )
+    .context(error::LoadRepoSnafu)?;
+
+    info!("Writing repo targets to: {}", targets_dir.display());
+    fs::create_dir_all(&targets_dir).context(error::CreateDirSnafu { path: &targets_dir })?;
+    let (downloaded, skipped) = mirror_targets(&repo, &targets_dir, mirror_args.resume)?;
+
+    // Metadata is written last, so a new mirror's metadata directory only exists once every
+    // target it lists is in place, and clients of the mirror never see a partial repo.  The full chain of root
+    // versions is included, so clients with an older root.json can still update to this one.
+    info!("Writing repo metadata to: {}", metadata_dir.display());
+    repo.cache_metadata(&metadata_dir, true)
+        .context(error::CacheMetadataSnafu {
+            path: &metadata_dir,
+        })?;
+    info!(
+        "Mirrored {} targets ({} downloaded, {} already present) from {}",
+        downloaded + skipped,
+        downloaded,
+        skipped,
+        metadata_url
+    );
+
+    let summary = MirrorSummary {
+        metadata_url: &metadata_url,
+        metadata_dir: &metadata_dir,
+        targets_dir: &targets_dir,
+        targets: repo.targets().signed.targets.len(),
+        downloaded,
+        skipped,
+    };
+    output::report(args.output, &summary)?;
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Failed to write repo metadata to '{}': {}", path.display(), source))]
+        CacheMetadata {
+            path: PathBuf,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
+        },
+
+        #[snafu(display("Failed to create directory '{}': {}", path.display(), source))]
+        CreateDir { path: PathBuf, source: io::Error },
+
+        #[snafu(display("{}", source))]
+        LoadRepo {
+            source: crate::repo::validate_repo::Error,
+        },
+
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
+        #[snafu(display("Failed to move target into place at '{}': {}", path.display(), source))]
+        Persist {
+            path: PathBuf,
+            source: tempfile::PersistError,
+        },
+
+        #[snafu(context(false), display("{}", source))]
+        Repo { source: crate::repo::Error },
+
+        #[snafu(display(
+            "Repo metadata already exists at '{}'; use --resume to continue mirroring into it",
+            path.display()
+        ))]
+        RepoExists { path: PathBuf },
+
+        #[snafu(display(
+            "Failed to download target '{}'; it may not match the targets metadata: {}",
+            target,
+            source
+        ))]
+        TargetDownload { target: String, source: io::Error },
+
+        #[snafu(display("Target '{}' is not listed in the targets metadata", target))]
+        TargetMissing { target: String },
+
+        #[snafu(display("Failed to read existing target '{}': {}", path.display(), source))]
+        TargetRead { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to create temporary file: {}", source))]
+        TempFile { source: io::Error },
+
+        #[snafu(display("Unable to create thread pool: {}", source))]
+        ThreadPool { source: rayon::ThreadPoolBuildError },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::verified_target;
+    use sha2::{Digest, Sha256};
+    use std::fs;
+
+    #[test]
+    fn resume_checks_existing_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("target");
+        let data = b"target contents";
+        let sha256 = hex::encode(Sha256::digest(data));
+
+        // Missing targets are downloaded.
+        assert!(!verified_target(&path, data.len() as u64, &sha256).unwrap());
+
+        fs::write(&path, data).unwrap();
+        assert!(verified_target(&path, data.len() as u64, &sha256).unwrap());
+
+        // A target left from an older repo, or otherwise changed, is downloaded again.
+        assert!(!verified_target(&path, data.len() as u64 + 1, &sha256).unwrap());
+        fs::write(&path, b"other contents!").unwrap();
+        assert!(!verified_target(&path, data.len() as u64, &sha256).unwrap());
+    }
+}
@@ -1,28 +1,88 @@
 //! The refresh_repo module owns the 'refresh-repo' subcommand and provide methods for
 //! refreshing and re-signing the metadata files of a given TUF repository.
 
+use crate::repo::sign::{
+    load_signing_keys, read_metadata, role_file_name, sign_metadata, write_role, RepoMetadata,
+    SigningKey,
+};
 use crate::repo::{
-    error as repo_error, get_signing_key_source, repo_urls, set_expirations, set_versions,
+    error as repo_error, get_signing_key_source, repo_urls, repo_version, set_expirations,
+    set_versions, sign_error,
 };
-use crate::Args;
+use crate::{output, Args};
 use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
 use log::{info, trace};
-use pubsys_config::{InfraConfig, RepoExpirationPolicy};
+use pubsys_config::{RepoExpirationPolicy, SigningKeyConfig};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
 use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::BTreeSet;
+use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use structopt::StructOpt;
 use tough::editor::RepositoryEditor;
-use tough::key_source::{KeySource, LocalKeySource};
-use tough::{ExpirationEnforcement, RepositoryLoader};
+use tough::schema::{Hashes, Role, Root, Signed};
+use tough::{ExpirationEnforcement, Repository, RepositoryLoader};
 use url::Url;
 
 lazy_static! {
     static ref EXPIRATION_START_TIME: DateTime<Utc> = Utc::now();
 }
 
+/// The non-root roles that refresh-repo can refresh
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RefreshRole {
+    Targets,
+    Snapshot,
+    Timestamp,
+}
+
+impl RefreshRole {
+    const ALL: [RefreshRole; 3] = [
+        RefreshRole::Targets,
+        RefreshRole::Snapshot,
+        RefreshRole::Timestamp,
+    ];
+
+    /// The role whose metadata refers to this role's, and so must be refreshed along with it.
+    fn required_by(self) -> Option<RefreshRole> {
+        match self {
+            RefreshRole::Targets => Some(RefreshRole::Snapshot),
+            RefreshRole::Snapshot => Some(RefreshRole::Timestamp),
+            RefreshRole::Timestamp => None,
+        }
+    }
+}
+
+impl FromStr for RefreshRole {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "targets" => Ok(RefreshRole::Targets),
+            "snapshot" => Ok(RefreshRole::Snapshot),
+            "timestamp" => Ok(RefreshRole::Timestamp),
+            "root" => error::RootRoleSnafu.fail(),
+            _ => error::UnknownRoleSnafu { role: s }.fail(),
+        }
+    }
+}
+
+impl fmt::Display for RefreshRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefreshRole::Targets => write!(f, "targets"),
+            RefreshRole::Snapshot => write!(f, "snapshot"),
+            RefreshRole::Timestamp => write!(f, "timestamp"),
+        }
+    }
+}
+
 /// Refreshes and re-sign TUF repositories' non-root metadata files with new expiration dates
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
@@ -58,27 +118,70 @@ pub(crate) struct RefreshRepoArgs {
     /// If this flag is set, repositories will succeed in loading and be refreshed even if they have
     /// expired metadata files.
     unsafe_refresh: bool,
+
+    #[structopt(long, use_delimiter = true)]
+    /// Comma-separated list of roles to refresh: timestamp, snapshot, targets; defaults to all.
+    /// A role's dependents must be included too, e.g. refreshing snapshot requires timestamp.
+    /// Other roles' metadata is copied unchanged, so the signing key only needs to be allowed to
+    /// sign the refreshed roles
+    only_roles: Vec<RefreshRole>,
+
+    #[structopt(long)]
+    /// Re-version and re-sign the requested roles even if their content is unchanged, e.g. for a
+    /// scheduled expiration reset; this bumps their version numbers.  Refreshing always does
+    /// this, so the flag only makes the intent explicit
+    force: bool,
+}
+
+/// Returns the set of roles to refresh, making sure that every role that refers to a refreshed
+/// role is refreshed as well.
+fn roles_to_refresh(only_roles: &[RefreshRole]) -> Result<BTreeSet<RefreshRole>, Error> {
+    if only_roles.is_empty() {
+        return Ok(RefreshRole::ALL.iter().copied().collect());
+    }
+    let roles: BTreeSet<RefreshRole> = only_roles.iter().copied().collect();
+    check_dependencies(&roles)?;
+    Ok(roles)
+}
+
+/// Makes sure that every role that refers to one of the given roles is among them, since its
+/// metadata lists the version and hashes of the refreshed role.
+fn check_dependencies(roles: &BTreeSet<RefreshRole>) -> Result<(), Error> {
+    for role in roles {
+        if let Some(dependent) = role.required_by() {
+            ensure!(
+                roles.contains(&dependent),
+                error::RoleDependencySnafu {
+                    role: *role,
+                    dependent,
+                }
+            );
+        }
+    }
+    Ok(())
 }
 
 fn refresh_repo(
-    root_role_path: &PathBuf,
+    refresh_repo_args: &RefreshRepoArgs,
     metadata_out_dir: &PathBuf,
     metadata_url: &Url,
     targets_url: &Url,
-    key_source: Box<dyn KeySource>,
+    signing_key: &SigningKeyConfig,
     expiration: &RepoExpirationPolicy,
-    unsafe_refresh: bool,
+    roles: &BTreeSet<RefreshRole>,
 ) -> Result<(), Error> {
+    let root_role_path = &refresh_repo_args.root_role_path;
+
     // If the given metadata directory exists, throw an error.  We don't want to overwrite a user's
     // existing repository.
     ensure!(
-        !Path::exists(&metadata_out_dir),
+        !Path::exists(metadata_out_dir),
         repo_error::RepoExistsSnafu {
             path: metadata_out_dir
         }
     );
 
-    let expiration_enforcement = if unsafe_refresh {
+    let expiration_enforcement = if refresh_repo_args.unsafe_refresh {
         ExpirationEnforcement::Unsafe
     } else {
         ExpirationEnforcement::Safe
@@ -97,28 +200,47 @@ fn refresh_repo(
     .context(repo_error::RepoLoadSnafu {
         metadata_base_url: metadata_url.clone(),
     })?;
-    let mut repo_editor = RepositoryEditor::from_repo(&root_role_path, repo)
-        .context(repo_error::EditorFromRepoSnafu)?;
     info!("Loaded TUF repo: {}", metadata_url);
+    if refresh_repo_args.force {
+        info!("Refreshing the requested roles whether or not their content changed, as always");
+    }
+
+    // The editor re-signs every non-root role, so it's only used when refreshing all of them;
+    // otherwise we'd need the keys for roles we aren't refreshing, like offline targets keys.
+    if roles.len() < RefreshRole::ALL.len() {
+        let keys =
+            load_signing_keys(std::slice::from_ref(signing_key)).context(error::SignSnafu)?;
+        refresh_partial(
+            &repo,
+            root_role_path,
+            metadata_out_dir,
+            &keys,
+            expiration,
+            roles,
+        )?;
+        return Ok(());
+    }
+
+    let mut repo_editor = RepositoryEditor::from_repo(root_role_path, repo)
+        .context(repo_error::EditorFromRepoSnafu)?;
 
     // Refresh the expiration dates of all non-root metadata files
-    set_expirations(&mut repo_editor, &expiration, *EXPIRATION_START_TIME)?;
+    set_expirations(&mut repo_editor, expiration, *EXPIRATION_START_TIME)?;
 
     // Refresh the versions of all non-root metadata files
     set_versions(&mut repo_editor)?;
 
     // Sign the repository
-    let signed_repo = repo_editor
-        .sign(&[key_source])
-        .context(repo_error::RepoSignSnafu)?;
+    let key_source = get_signing_key_source(signing_key)?;
+    let signed_repo = repo_editor.sign(&[key_source]).map_err(sign_error)?;
 
     // Write out the metadata files for the repository
     info!("Writing repo metadata to: {}", metadata_out_dir.display());
-    fs::create_dir_all(&metadata_out_dir).context(repo_error::CreateDirSnafu {
+    fs::create_dir_all(metadata_out_dir).context(repo_error::CreateDirSnafu {
         path: &metadata_out_dir,
     })?;
     signed_repo
-        .write(&metadata_out_dir)
+        .write(metadata_out_dir)
         .context(repo_error::RepoWriteSnafu {
             path: &metadata_out_dir,
         })?;
@@ -126,11 +248,125 @@ fn refresh_repo(
     Ok(())
 }
 
+/// Refreshes only the given roles, working on the signed metadata directly.  The existing
+/// metadata is copied as is, so the roles we aren't refreshing keep their exact contents and
+/// signatures, and only the given roles get new versions, expirations, and signatures.  Targets
+/// is always refreshed along with the others, so only snapshot and timestamp are handled here.
+fn refresh_partial(
+    repo: &Repository,
+    root_role_path: &Path,
+    metadata_out_dir: &Path,
+    keys: &[SigningKey],
+    expiration: &RepoExpirationPolicy,
+    roles: &BTreeSet<RefreshRole>,
+) -> Result<(), Error> {
+    check_dependencies(roles)?;
+    ensure!(
+        !roles.contains(&RefreshRole::Targets),
+        error::RoleDependencySnafu {
+            role: RefreshRole::Targets,
+            dependent: RefreshRole::Snapshot,
+        }
+    );
+    info!(
+        "Refreshing only {}",
+        roles
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    // Copy the existing metadata, and root.json under the name tough gives it.
+    let root = &repo.root().signed;
+    info!("Writing repo metadata to: {}", metadata_out_dir.display());
+    repo.cache_metadata(metadata_out_dir, false)
+        .context(error::CopyMetadataSnafu {
+            path: metadata_out_dir,
+        })?;
+    let root_path = metadata_out_dir.join(format!("{}.root.json", root.version));
+    fs::copy(root_role_path, &root_path).context(repo_error::FileSnafu { path: root_path })?;
+
+    let RepoMetadata {
+        timestamp_path,
+        mut timestamp,
+        snapshot_path,
+        mut snapshot,
+        ..
+    } = read_metadata(metadata_out_dir, root.consistent_snapshot).context(error::SignSnafu)?;
+    let version = repo_version();
+    let rng = SystemRandom::new();
+
+    if roles.contains(&RefreshRole::Snapshot) {
+        snapshot.signed.version = version;
+        snapshot.signed.expires = *EXPIRATION_START_TIME + expiration.snapshot_expiration;
+        // With consistent snapshots, the new version gets a new file name.
+        let path = metadata_out_dir.join(role_file_name(
+            "snapshot",
+            version,
+            root.consistent_snapshot,
+        ));
+        let (length, hashes) = sign_role(root, &path, &mut snapshot, keys, &rng)?;
+        if path != snapshot_path {
+            fs::remove_file(&snapshot_path).context(repo_error::FileSnafu {
+                path: &snapshot_path,
+            })?;
+        }
+        // Unwrap is safe because read_metadata looked up this entry.
+        let meta = timestamp.signed.meta.get_mut("snapshot.json").unwrap();
+        meta.version = version;
+        meta.length = length;
+        meta.hashes = hashes;
+    }
+
+    if roles.contains(&RefreshRole::Timestamp) {
+        timestamp.signed.version = version;
+        timestamp.signed.expires = *EXPIRATION_START_TIME + expiration.timestamp_expiration;
+        sign_role(root, &timestamp_path, &mut timestamp, keys, &rng)?;
+    }
+
+    Ok(())
+}
+
+/// Signs the changed role with the given keys and writes it to `path`, returning the length and
+/// hashes its parent role's metadata needs.  The old signatures don't match the changed role, so
+/// they're dropped, and the keys have to meet the role's threshold on their own.
+fn sign_role<T: Role + Serialize + Clone>(
+    root: &Root,
+    path: &Path,
+    role: &mut Signed<T>,
+    keys: &[SigningKey],
+    rng: &dyn SecureRandom,
+) -> Result<(u64, Hashes), Error> {
+    role.signatures.clear();
+    let (_, _, signatures) = sign_metadata(root, role, keys, rng).context(error::SignSnafu)?;
+    // sign_metadata already checked that root.json has keys for the role.
+    let threshold = root.roles[&T::TYPE].threshold;
+    ensure!(
+        signatures >= threshold.get() as usize,
+        error::UnderSignedSnafu {
+            role: T::TYPE.to_string(),
+            signatures,
+            threshold,
+        }
+    );
+    write_role(path, role).context(error::SignSnafu)
+}
+
+/// Summary of the refreshed repo, for reporting results
+#[derive(Debug, Serialize)]
+struct RefreshSummary<'a> {
+    metadata_url: &'a Url,
+    metadata_dir: &'a Path,
+    roles: &'a BTreeSet<RefreshRole>,
+}
+
 /// Common entrypoint from main()
 pub(crate) fn run(args: &Args, refresh_repo_args: &RefreshRepoArgs) -> Result<(), Error> {
+    let roles = roles_to_refresh(&refresh_repo_args.only_roles)?;
+
     // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(repo_error::ConfigSnafu)?;
+    let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
     trace!("Parsed infra config: {:?}", infra_config);
 
     let repo_config = infra_config
@@ -148,8 +384,8 @@ pub(crate) fn run(args: &Args, refresh_repo_args: &RefreshRepoArgs) -> Result<()
     // generated local key.
     let signing_key_config = repo_config.signing_keys.as_ref();
 
-    let key_source = if let Some(signing_key_config) = signing_key_config {
-        get_signing_key_source(signing_key_config)?
+    let signing_key = if let Some(signing_key_config) = signing_key_config {
+        signing_key_config.clone()
     } else {
         ensure!(
             refresh_repo_args.default_key_path.exists(),
@@ -157,9 +393,9 @@ pub(crate) fn run(args: &Args, refresh_repo_args: &RefreshRepoArgs) -> Result<()
                 missing: "signing_keys in repo config, and we found no local key",
             }
         );
-        Box::new(LocalKeySource {
+        SigningKeyConfig::file {
             path: refresh_repo_args.default_key_path.clone(),
-        })
+        }
     };
 
     // Get the expiration policy
@@ -172,41 +408,216 @@ pub(crate) fn run(args: &Args, refresh_repo_args: &RefreshRepoArgs) -> Result<()
             .context(repo_error::ConfigSnafu)?;
 
     let repo_urls = repo_urls(
-        &repo_config,
+        repo_config,
         &refresh_repo_args.variant,
         &refresh_repo_args.arch,
     )?
     .context(repo_error::MissingRepoUrlsSnafu {
         repo: &refresh_repo_args.repo,
     })?;
+    let metadata_out_dir = refresh_repo_args
+        .outdir
+        .join(&refresh_repo_args.variant)
+        .join(&refresh_repo_args.arch);
     refresh_repo(
-        &refresh_repo_args.root_role_path,
-        &refresh_repo_args
-            .outdir
-            .join(&refresh_repo_args.variant)
-            .join(&refresh_repo_args.arch),
+        refresh_repo_args,
+        &metadata_out_dir,
         &repo_urls.0,
         repo_urls.1,
-        key_source,
+        &signing_key,
         &expiration,
-        refresh_repo_args.unsafe_refresh,
+        &roles,
     )?;
 
+    let summary = RefreshSummary {
+        metadata_url: &repo_urls.0,
+        metadata_dir: &metadata_out_dir,
+        roles: &roles,
+    };
+    output::report(args.output, &summary)?;
+
     Ok(())
 }
 
 mod error {
+    use super::RefreshRole;
     use snafu::Snafu;
+    use std::num::NonZeroU64;
+    use std::path::PathBuf;
     use url::Url;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub(crate) enum Error {
+        #[snafu(display("Failed to copy repo metadata to '{}': {}", path.display(), source))]
+        CopyMetadata {
+            path: PathBuf,
+            #[snafu(source(from(tough::error::Error, Box::new)))]
+            source: Box<tough::error::Error>,
+        },
+
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
         #[snafu(context(false), display("{}", source))]
         Repo { source: crate::repo::Error },
 
         #[snafu(display("Failed to refresh & re-sign metadata for: {:#?}", list_of_urls))]
         RepoRefresh { list_of_urls: Vec<Url> },
+
+        #[snafu(display(
+            "Refreshing {} requires refreshing {} too, since it refers to {}",
+            role,
+            dependent,
+            role
+        ))]
+        RoleDependency {
+            role: RefreshRole,
+            dependent: RefreshRole,
+        },
+
+        #[snafu(display("The root role can't be refreshed with refresh-repo; use tuftool root"))]
+        RootRole,
+
+        #[snafu(display("{}", source))]
+        Sign { source: crate::repo::sign::Error },
+
+        #[snafu(display(
+            "{} metadata has {} of {} needed signatures; is the signing key allowed to sign it?",
+            role,
+            signatures,
+            threshold
+        ))]
+        UnderSigned {
+            role: String,
+            signatures: usize,
+            threshold: NonZeroU64,
+        },
+
+        #[snafu(display(
+            "Unknown role '{}', expected one of: timestamp, snapshot, targets",
+            role
+        ))]
+        UnknownRole { role: String },
     }
 }
 pub(crate) use error::Error;
+
+#[cfg(test)]
+mod test {
+    use super::{refresh_partial, RefreshRole, EXPIRATION_START_TIME};
+    use crate::repo::sign::load_signing_keys;
+    use crate::repo::test::{build, write_root};
+    use crate::repo::TargetHash;
+    use chrono::Duration;
+    use pubsys_config::{RepoExpirationPolicy, SigningKeyConfig};
+    use std::fs;
+    use tough::editor::RepositoryEditor;
+    use tough::RepositoryLoader;
+    use url::Url;
+
+    #[test]
+    fn refreshes_only_given_roles() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (root_path, key_path) = write_root(dir);
+        let targets_dir = dir.join("targets");
+        fs::create_dir(&targets_dir).unwrap();
+        let image = targets_dir.join("bottlerocket-v1.0.0-abcdef.img");
+        fs::write(&image, b"image").unwrap();
+        let manifest = targets_dir.join("manifest.json");
+        fs::write(&manifest, b"{}").unwrap();
+        let built_dir = dir.join("built");
+        build(
+            RepositoryEditor::new(&root_path).unwrap(),
+            &key_path,
+            &[&image],
+            &manifest,
+            None,
+            &[TargetHash::Sha256],
+            &built_dir,
+        );
+
+        let load = |metadata_dir: &std::path::Path| {
+            RepositoryLoader::new(
+                fs::File::open(&root_path).unwrap(),
+                Url::from_directory_path(metadata_dir).unwrap(),
+                Url::from_directory_path(&targets_dir).unwrap(),
+            )
+            .load()
+            .unwrap()
+        };
+        let keys = load_signing_keys(&[SigningKeyConfig::file {
+            path: key_path.clone(),
+        }])
+        .unwrap();
+        let policy = RepoExpirationPolicy {
+            snapshot_expiration: Duration::days(7),
+            targets_expiration: Duration::days(14),
+            timestamp_expiration: Duration::days(1),
+        };
+        let read = |dir: &std::path::Path, file: &str| fs::read(dir.join(file)).unwrap();
+
+        // Refreshing timestamp leaves the bytes of targets and snapshot alone.
+        let timestamp_dir = dir.join("timestamp");
+        let roles = [RefreshRole::Timestamp].iter().copied().collect();
+        refresh_partial(
+            &load(&built_dir),
+            &root_path,
+            &timestamp_dir,
+            &keys,
+            &policy,
+            &roles,
+        )
+        .unwrap();
+        for file in ["targets.json", "snapshot.json"] {
+            assert_eq!(read(&built_dir, file), read(&timestamp_dir, file));
+        }
+        assert_ne!(
+            read(&built_dir, "timestamp.json"),
+            read(&timestamp_dir, "timestamp.json")
+        );
+        // Versions are in seconds, so they may match the build's; the new expiration doesn't.
+        assert_eq!(
+            load(&timestamp_dir).timestamp().signed.expires,
+            *EXPIRATION_START_TIME + policy.timestamp_expiration
+        );
+
+        // Refreshing snapshot too still leaves targets alone, and clients can load the result.
+        let snapshot_dir = dir.join("snapshot");
+        let roles = [RefreshRole::Snapshot, RefreshRole::Timestamp]
+            .iter()
+            .copied()
+            .collect();
+        refresh_partial(
+            &load(&built_dir),
+            &root_path,
+            &snapshot_dir,
+            &keys,
+            &policy,
+            &roles,
+        )
+        .unwrap();
+        assert_eq!(
+            read(&built_dir, "targets.json"),
+            read(&snapshot_dir, "targets.json")
+        );
+        assert_ne!(
+            read(&built_dir, "snapshot.json"),
+            read(&snapshot_dir, "snapshot.json")
+        );
+        load(&snapshot_dir);
+
+        // Snapshot can't be refreshed without the timestamp that refers to it.
+        let roles = [RefreshRole::Snapshot].iter().copied().collect();
+        assert!(refresh_partial(
+            &load(&built_dir),
+            &root_path,
+            &dir.join("invalid"),
+            &keys,
+            &policy,
+            &roles,
+        )
+        .is_err());
+    }
+}
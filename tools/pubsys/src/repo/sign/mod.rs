@@ -0,0 +1,556 @@
+//! The sign module owns the 'repo-sign' subcommand and adds signatures to the metadata of an
+//! already-built repo, so a repo can be built online and signed offline by keys that never touch
+//! the build host.
+
+use crate::repo::create_root::parse_key;
+use crate::repo::get_signing_key_source;
+use crate::{output, Args};
+use log::{debug, info, trace, warn};
+use olpc_cjson::CanonicalFormatter;
+use pubsys_config::SigningKeyConfig;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use snafu::{ensure, IntoError, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fs;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use tough::schema::decoded::{Decoded, Hex};
+use tough::schema::{Hashes, Role, Root, Signature, Signed, Snapshot, Targets, Timestamp};
+use tough::sign::Sign;
+
+/// Adds signatures to the metadata of an already-built repo
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct RepoSignArgs {
+    #[structopt(long, parse(from_os_str))]
+    /// Path to root.json for this repo, which lists the keys allowed to sign each role
+    root_role_path: PathBuf,
+
+    #[structopt(long, parse(from_os_str))]
+    /// The repo's metadata directory for one variant and arch, holding timestamp.json; signed
+    /// metadata is written back in place
+    metadata_dir: PathBuf,
+
+    #[structopt(long = "key", required = true, parse(try_from_str = parse_key))]
+    /// Key to sign with, as a file path or an aws-kms:///KEY-ID or aws-ssm:///PARAMETER URL; may
+    /// be given more than once
+    keys: Vec<SigningKeyConfig>,
+}
+
+/// What we did to one role's metadata, for reporting results
+#[derive(Debug, Serialize)]
+struct RoleSigning {
+    role: String,
+    path: PathBuf,
+    /// Signatures we added
+    added: usize,
+    /// Signatures we removed because they no longer matched the metadata
+    dropped: usize,
+    /// Valid signatures the role has now
+    signatures: usize,
+    threshold: NonZeroU64,
+}
+
+/// Summary of the signing, for reporting results
+#[derive(Debug, Serialize)]
+struct RepoSignSummary<'a> {
+    metadata_dir: &'a Path,
+    roles: &'a [RoleSigning],
+}
+
+/// A key we can sign with, and its ID in root.json
+pub(crate) struct SigningKey {
+    key_id: Decoded<Hex>,
+    sign: Box<dyn Sign>,
+}
+
+/// Loads each key and computes its key ID; a key given twice is only loaded once.
+pub(crate) fn load_signing_keys(keys: &[SigningKeyConfig]) -> Result<Vec<SigningKey>> {
+    let mut loaded: Vec<SigningKey> = Vec::new();
+    for key in keys {
+        let key_source = get_signing_key_source(key).context(error::KeySourceSnafu)?;
+        let sign = key_source
+            .as_sign()
+            .map_err(|source| error::KeyLoadSnafu { key: key.clone() }.into_error(source))?;
+        let key_id = sign
+            .tuf_key()
+            .key_id()
+            .context(error::KeyIdSnafu { key: key.clone() })?;
+        trace!("Loaded key {}: {:?}", hex::encode(&key_id), key);
+        if !loaded.iter().any(|k| k.key_id == key_id) {
+            loaded.push(SigningKey { key_id, sign });
+        }
+    }
+    Ok(loaded)
+}
+
+/// Returns the name of a role's metadata file; with consistent snapshots, snapshot and targets
+/// metadata files are prefixed with their version.
+pub(crate) fn role_file_name(role: &str, version: NonZeroU64, consistent_snapshot: bool) -> String {
+    if consistent_snapshot {
+        format!("{}.{}.json", version, role)
+    } else {
+        format!("{}.json", role)
+    }
+}
+
+pub(crate) fn read_role<T: DeserializeOwned>(path: &Path) -> Result<Signed<T>> {
+    let data = fs::read(path).context(error::ReadSnafu { path })?;
+    serde_json::from_slice(&data).context(error::ParseSnafu { path })
+}
+
+/// The metadata files clients fetch from one repo, and their paths
+pub(crate) struct RepoMetadata {
+    pub(crate) timestamp_path: PathBuf,
+    pub(crate) timestamp: Signed<Timestamp>,
+    pub(crate) snapshot_path: PathBuf,
+    pub(crate) snapshot: Signed<Snapshot>,
+    pub(crate) targets_path: PathBuf,
+    pub(crate) targets: Signed<Targets>,
+}
+
+/// Reads the timestamp, snapshot, and targets metadata in the given directory, following the
+/// versions each role lists for the one below it, so we get the metadata that clients will
+/// actually fetch.
+pub(crate) fn read_metadata(
+    metadata_dir: &Path,
+    consistent_snapshot: bool,
+) -> Result<RepoMetadata> {
+    let timestamp_path = metadata_dir.join("timestamp.json");
+    let timestamp: Signed<Timestamp> = read_role(&timestamp_path)?;
+    let snapshot_version = timestamp
+        .signed
+        .meta
+        .get("snapshot.json")
+        .context(error::MissingMetaSnafu {
+            path: &timestamp_path,
+            file: "snapshot.json",
+        })?
+        .version;
+    let snapshot_path = metadata_dir.join(role_file_name(
+        "snapshot",
+        snapshot_version,
+        consistent_snapshot,
+    ));
+    let snapshot: Signed<Snapshot> = read_role(&snapshot_path)?;
+    let targets_version = snapshot
+        .signed
+        .meta
+        .get("targets.json")
+        .context(error::MissingMetaSnafu {
+            path: &snapshot_path,
+            file: "targets.json",
+        })?
+        .version;
+    let targets_path = metadata_dir.join(role_file_name(
+        "targets",
+        targets_version,
+        consistent_snapshot,
+    ));
+    let targets: Signed<Targets> = read_role(&targets_path)?;
+    Ok(RepoMetadata {
+        timestamp_path,
+        timestamp,
+        snapshot_path,
+        snapshot,
+        targets_path,
+        targets,
+    })
+}
+
+/// Writes the role metadata the way tough does, returning the length and hashes of what we wrote
+/// so the parent role's metadata can refer to it.
+pub(crate) fn write_role<T: Serialize>(path: &Path, role: &Signed<T>) -> Result<(u64, Hashes)> {
+    let mut buffer = serde_json::to_vec_pretty(role).context(error::SerializeSnafu)?;
+    buffer.push(b'\n');
+    fs::write(path, &buffer).context(error::WriteSnafu { path })?;
+    let hashes = Hashes {
+        sha256: Decoded::from(Sha256::digest(&buffer).to_vec()),
+        _extra: HashMap::new(),
+    };
+    Ok((buffer.len() as u64, hashes))
+}
+
+/// Returns the canonical JSON form of the role's signed content, which is what signatures cover.
+fn canonical_form<T: Role + Serialize>(role: &Signed<T>) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut ser = serde_json::Serializer::with_formatter(&mut data, CanonicalFormatter::new());
+    role.signed
+        .serialize(&mut ser)
+        .context(error::SerializeSnafu)?;
+    Ok(data)
+}
+
+/// Returns whether the signature is valid for the role and made by a key root.json allows to sign
+/// it.  tough only verifies whole roles against their threshold, so we check the one signature
+/// against a threshold of 1.
+pub(crate) fn valid_signature<T: Role + Serialize + Clone>(
+    root: &Root,
+    role: &Signed<T>,
+    signature: &Signature,
+) -> bool {
+    let mut root = root.clone();
+    if let Some(role_keys) = root.roles.get_mut(&T::TYPE) {
+        role_keys.threshold = NonZeroU64::new(1).unwrap();
+    }
+    let single = Signed {
+        signed: role.signed.clone(),
+        signatures: vec![signature.clone()],
+    };
+    root.verify_role(&single).is_ok()
+}
+
+/// Signs the role with the given keys that root.json allows to sign it, adding only as many
+/// signatures as the role needs to meet its threshold, and none from a key that already signed
+/// it.  Signatures that no longer match the role, as when a role it lists changed, are removed
+/// first.  Returns how many signatures we added and removed, and how many valid ones it has.
+pub(crate) fn sign_metadata<T: Role + Serialize + Clone>(
+    root: &Root,
+    role: &mut Signed<T>,
+    keys: &[SigningKey],
+    rng: &dyn SecureRandom,
+) -> Result<(usize, usize, usize)> {
+    let role_type = T::TYPE;
+    let role_keys = root
+        .roles
+        .get(&role_type)
+        .context(error::MissingRoleSnafu { role: role_type })?;
+
+    let before = role.signatures.len();
+    let mut kept: Vec<Signature> = Vec::with_capacity(before);
+    for signature in &role.signatures {
+        if !kept.iter().any(|s| s.keyid == signature.keyid)
+            && valid_signature(root, role, signature)
+        {
+            kept.push(signature.clone());
+        }
+    }
+    role.signatures = kept;
+    let dropped = before - role.signatures.len();
+    if dropped > 0 {
+        warn!(
+            "Removed {} signatures from {} metadata that are invalid or duplicated",
+            dropped, role_type
+        );
+    }
+
+    let threshold = role_keys.threshold.get() as usize;
+    let needed = threshold.saturating_sub(role.signatures.len());
+    if needed == 0 {
+        info!(
+            "{} metadata already has {} of {} needed signatures, not signing it again",
+            role_type,
+            role.signatures.len(),
+            threshold
+        );
+        return Ok((0, dropped, role.signatures.len()));
+    }
+
+    let data = canonical_form(role)?;
+    let mut added = 0;
+    for key in keys {
+        if added == needed {
+            break;
+        }
+        if !role_keys.keyids.contains(&key.key_id) {
+            continue;
+        }
+        if role.signatures.iter().any(|s| s.keyid == key.key_id) {
+            debug!(
+                "Key {} already signed {} metadata",
+                hex::encode(&key.key_id),
+                role_type
+            );
+            continue;
+        }
+        let sig = key
+            .sign
+            .sign(&data, rng)
+            .map_err(|source| error::SignSnafu { role: role_type }.into_error(source))?;
+        role.signatures.push(Signature {
+            keyid: key.key_id.clone(),
+            sig: sig.into(),
+        });
+        added += 1;
+        info!(
+            "Signed {} metadata with key {}",
+            role_type,
+            hex::encode(&key.key_id)
+        );
+    }
+
+    Ok((added, dropped, role.signatures.len()))
+}
+
+/// Signs the role metadata read from `path` and writes it back if its signatures changed.
+/// Returns what we did, and the new length and hashes of the file if we rewrote it, which the
+/// parent role's metadata has to be updated with.
+fn sign_file<T: Role + Serialize + Clone>(
+    root: &Root,
+    path: &Path,
+    role: &mut Signed<T>,
+    keys: &[SigningKey],
+    rng: &dyn SecureRandom,
+) -> Result<(RoleSigning, Option<(u64, Hashes)>)> {
+    let (added, dropped, signatures) = sign_metadata(root, role, keys, rng)?;
+    // We only rewrite changed files; tough doesn't write maps in a fixed order, so even an
+    // unchanged role could get new hashes.
+    let written = if added > 0 || dropped > 0 {
+        info!("Writing signed {} metadata to {}", T::TYPE, path.display());
+        Some(write_role(path, role)?)
+    } else {
+        None
+    };
+    let signing = RoleSigning {
+        role: T::TYPE.to_string(),
+        path: path.to_owned(),
+        added,
+        dropped,
+        signatures,
+        threshold: root.roles[&T::TYPE].threshold,
+    };
+    Ok((signing, written))
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, sign_args: &RepoSignArgs) -> Result<()> {
+    let root: Signed<Root> = read_role(&sign_args.root_role_path)?;
+    let root = root.signed;
+    let keys = load_signing_keys(&sign_args.keys)?;
+    ensure!(
+        keys.iter().any(|key| root
+            .roles
+            .values()
+            .any(|role_keys| role_keys.keyids.contains(&key.key_id))),
+        error::NoAuthorizedKeysSnafu {
+            path: &sign_args.root_role_path
+        }
+    );
+
+    let metadata_dir = &sign_args.metadata_dir;
+    let RepoMetadata {
+        timestamp_path,
+        mut timestamp,
+        snapshot_path,
+        mut snapshot,
+        targets_path,
+        mut targets,
+    } = read_metadata(metadata_dir, root.consistent_snapshot)?;
+
+    // Sign from the bottom up, since snapshot lists the length and hashes of targets, and
+    // timestamp those of snapshot.  Updating them invalidates the parent's old signatures, which
+    // sign_metadata then removes.
+    let rng = SystemRandom::new();
+    let mut roles = Vec::with_capacity(3);
+
+    let (signing, written) = sign_file(&root, &targets_path, &mut targets, &keys, &rng)?;
+    roles.push(signing);
+    if let Some((length, hashes)) = written {
+        // Unwrap is safe because we looked up this entry above.
+        let meta = snapshot.signed.meta.get_mut("targets.json").unwrap();
+        if meta.length.is_some() {
+            meta.length = Some(length);
+        }
+        if meta.hashes.is_some() {
+            meta.hashes = Some(hashes);
+        }
+    }
+
+    let (signing, written) = sign_file(&root, &snapshot_path, &mut snapshot, &keys, &rng)?;
+    roles.push(signing);
+    if let Some((length, hashes)) = written {
+        let meta = timestamp.signed.meta.get_mut("snapshot.json").unwrap();
+        meta.length = length;
+        meta.hashes = hashes;
+    }
+
+    let (signing, _) = sign_file(&root, &timestamp_path, &mut timestamp, &keys, &rng)?;
+    roles.push(signing);
+
+    for role in &roles {
+        if role.signatures < role.threshold.get() as usize {
+            warn!(
+                "{} metadata has {} of {} needed signatures; sign it with more keys before use",
+                role.role, role.signatures, role.threshold
+            );
+        }
+    }
+
+    let summary = RepoSignSummary {
+        metadata_dir,
+        roles: &roles,
+    };
+    output::report(args.output, &summary)?;
+    Ok(())
+}
+
+mod error {
+    use pubsys_config::SigningKeyConfig;
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+    use tough::schema::RoleType;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Failed to compute the key ID of {:?}: {}", key, source))]
+        KeyId {
+            key: Box<SigningKeyConfig>,
+            #[snafu(source(from(tough::schema::Error, Box::new)))]
+            source: Box<tough::schema::Error>,
+        },
+
+        #[snafu(display("Failed to load key {:?}: {}", key, source))]
+        KeyLoad {
+            key: Box<SigningKeyConfig>,
+            source: Box<dyn std::error::Error + Send + Sync + 'static>,
+        },
+
+        #[snafu(display("Failed to get key source: {}", source))]
+        KeySource { source: crate::repo::Error },
+
+        #[snafu(display("'{}' has no entry for '{}'", path.display(), file))]
+        MissingMeta { path: PathBuf, file: String },
+
+        #[snafu(display("Root role has no keys for the {} role", role))]
+        MissingRole { role: RoleType },
+
+        #[snafu(display("None of the given keys may sign any role in '{}'", path.display()))]
+        NoAuthorizedKeys { path: PathBuf },
+
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
+        #[snafu(display("Failed to parse metadata '{}': {}", path.display(), source))]
+        Parse {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+        Read { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to serialize metadata: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to sign {} metadata: {}", role, source))]
+        Sign {
+            role: RoleType,
+            source: Box<dyn std::error::Error + Send + Sync + 'static>,
+        },
+
+        #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+        Write { path: PathBuf, source: io::Error },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{canonical_form, load_signing_keys, sign_metadata, SigningKey};
+    use chrono::{Duration, Utc};
+    use pubsys_config::SigningKeyConfig;
+    use ring::rand::SecureRandom;
+    use ring::rand::SystemRandom;
+    use ring::signature::Ed25519KeyPair;
+    use serde::Serialize;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::num::NonZeroU64;
+    use std::path::Path;
+    use tough::schema::{Role, RoleKeys, RoleType, Root, Signature, Signed, Targets};
+
+    /// Writes and loads `count` new Ed25519 keys.
+    pub(crate) fn keys(dir: &Path, count: usize) -> Vec<SigningKey> {
+        let configs: Vec<SigningKeyConfig> = (0..count)
+            .map(|i| {
+                let path = dir.join(format!("key{}.pk8", i));
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+                fs::write(&path, pkcs8.as_ref()).unwrap();
+                SigningKeyConfig::file { path }
+            })
+            .collect();
+        load_signing_keys(&configs).unwrap()
+    }
+
+    /// Returns a root allowing the given keys to sign targets, needing two signatures.
+    pub(crate) fn root(keys: &[SigningKey]) -> Root {
+        let mut roles = HashMap::new();
+        roles.insert(
+            RoleType::Targets,
+            RoleKeys {
+                keyids: keys.iter().map(|k| k.key_id.clone()).collect(),
+                threshold: NonZeroU64::new(2).unwrap(),
+                _extra: HashMap::new(),
+            },
+        );
+        Root {
+            spec_version: "1.0.0".to_string(),
+            consistent_snapshot: true,
+            version: NonZeroU64::new(1).unwrap(),
+            expires: Utc::now() + Duration::weeks(1),
+            keys: keys
+                .iter()
+                .map(|k| (k.key_id.clone(), k.sign.tuf_key()))
+                .collect(),
+            roles,
+            _extra: HashMap::new(),
+        }
+    }
+
+    /// Adds the key's signature to the role, whether or not it may sign it or already has.
+    pub(crate) fn sign_with<T: Role + Serialize>(
+        role: &mut Signed<T>,
+        key: &SigningKey,
+        rng: &dyn SecureRandom,
+    ) {
+        let sig = key.sign.sign(&canonical_form(role).unwrap(), rng).unwrap();
+        role.signatures.push(Signature {
+            keyid: key.key_id.clone(),
+            sig: sig.into(),
+        });
+    }
+
+    #[test]
+    fn signs_up_to_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut keys = keys(dir.path(), 4);
+        // The last key isn't in root.json, so it can't sign anything.
+        let outsider = keys.pop().unwrap();
+        let root = root(&keys);
+        let rng = SystemRandom::new();
+        let mut targets = Signed {
+            signed: Targets::new(
+                "1.0.0".to_string(),
+                NonZeroU64::new(1).unwrap(),
+                Utc::now() + Duration::weeks(1),
+            ),
+            signatures: Vec::new(),
+        };
+
+        let counts = sign_metadata(&root, &mut targets, &[outsider], &rng).unwrap();
+        assert_eq!(counts, (0, 0, 0));
+        let counts = sign_metadata(&root, &mut targets, &keys[..1], &rng).unwrap();
+        assert_eq!(counts, (1, 0, 1));
+        // Signing again with the same key adds nothing; with every key, only enough to meet the
+        // threshold of 2.
+        let counts = sign_metadata(&root, &mut targets, &keys[..1], &rng).unwrap();
+        assert_eq!(counts, (0, 0, 1));
+        let counts = sign_metadata(&root, &mut targets, &keys, &rng).unwrap();
+        assert_eq!(counts, (1, 0, 2));
+        let counts = sign_metadata(&root, &mut targets, &keys, &rng).unwrap();
+        assert_eq!(counts, (0, 0, 2));
+        root.verify_role(&targets).unwrap();
+
+        // Changing the role invalidates its signatures, so they're replaced.
+        targets.signed.version = NonZeroU64::new(2).unwrap();
+        let counts = sign_metadata(&root, &mut targets, &keys[2..], &rng).unwrap();
+        assert_eq!(counts, (1, 2, 1));
+    }
+}
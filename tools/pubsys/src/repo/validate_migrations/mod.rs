@@ -0,0 +1,259 @@
+//! The validate_migrations module owns the 'validate-migrations' subcommand and checks the
+//! migrations listed in Release.toml against the built migration files, so wiring mistakes are
+//! caught before they reach a repo build.
+
+use crate::{output, Args};
+use log::{error, info, trace};
+use semver::Version;
+use serde::Serialize;
+use snafu::{ensure, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use update_metadata::Release;
+
+/// Checks the migrations listed in Release.toml
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct ValidateMigrationsArgs {
+    #[structopt(long, parse(from_os_str))]
+    /// Path to Release.toml
+    release_config_path: PathBuf,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Directory holding the built migration files
+    migrations_dir: PathBuf,
+}
+
+/// Summary of the checks, for reporting results
+#[derive(Debug, Serialize)]
+struct ValidateMigrationsSummary<'a> {
+    release_config_path: &'a Path,
+    version: &'a Version,
+    migrations: usize,
+    problems: &'a [String],
+}
+
+/// Returns a description of each problem with the migrations listed in the release.  Migration
+/// files must be in `present`, the version ranges must go forward and not overlap, and each
+/// migration must be named `migrate_vX.Y.Z_name`, where X.Y.Z is the version it migrates to.
+fn find_problems(release: &Release, present: &HashSet<String>) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut seen: HashMap<&str, &(Version, Version)> = HashMap::new();
+    // The map is sorted by range, so each range only needs checking against the one before it.
+    let mut previous: Option<&(Version, Version)> = None;
+
+    for (range, migrations) in &release.migrations {
+        let (from, to) = range;
+        if from >= to {
+            problems.push(format!(
+                "Range ({}, {}) doesn't go to a later version",
+                from, to
+            ));
+        }
+        if let Some((prev_from, prev_to)) = previous {
+            if prev_to > from {
+                problems.push(format!(
+                    "Range ({}, {}) overlaps range ({}, {})",
+                    from, to, prev_from, prev_to
+                ));
+            }
+        }
+        previous = Some(range);
+        if to > &release.version {
+            problems.push(format!(
+                "Range ({}, {}) goes past the release version {}",
+                from, to, release.version
+            ));
+        }
+
+        for migration in migrations {
+            if let Some((other_from, other_to)) = seen.insert(migration, range) {
+                problems.push(format!(
+                    "{} is listed for both ({}, {}) and ({}, {})",
+                    migration, other_from, other_to, from, to
+                ));
+            }
+            if !present.contains(migration) {
+                problems.push(format!(
+                    "{} doesn't exist in the migrations directory",
+                    migration
+                ));
+            }
+            match migration_version(migration) {
+                Some(version) if &version == to => {}
+                Some(version) => problems.push(format!(
+                    "{} is named for version {}, but is listed for ({}, {})",
+                    migration, version, from, to
+                )),
+                None => problems.push(format!(
+                    "{} isn't named like migrate_vX.Y.Z_name",
+                    migration
+                )),
+            }
+        }
+    }
+    problems
+}
+
+/// Returns the version in a migration name like `migrate_v1.0.5_add-lockdown.lz4`, or None if it's
+/// not named that way.
+fn migration_version(migration: &str) -> Option<Version> {
+    let (version, name) = migration.strip_prefix("migrate_v")?.split_once('_')?;
+    if name.is_empty() {
+        return None;
+    }
+    Version::parse(version)
+        .ok()
+        .filter(|v| v.pre.is_empty() && v.build.is_empty())
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, validate_args: &ValidateMigrationsArgs) -> Result<(), Error> {
+    info!(
+        "Using release config from path: {}",
+        validate_args.release_config_path.display()
+    );
+    let release = Release::from_path(&validate_args.release_config_path).context(
+        error::ReleaseConfigSnafu {
+            path: &validate_args.release_config_path,
+        },
+    )?;
+    trace!("Parsed release config: {:?}", release);
+
+    let migrations_dir = &validate_args.migrations_dir;
+    let mut present = HashSet::new();
+    for entry in fs::read_dir(migrations_dir).context(error::ReadDirSnafu {
+        path: migrations_dir,
+    })? {
+        let entry = entry.context(error::ReadDirSnafu {
+            path: migrations_dir,
+        })?;
+        if entry.path().is_file() {
+            present.insert(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+
+    let problems = find_problems(&release, &present);
+    for problem in &problems {
+        error!("{}", problem);
+    }
+
+    let summary = ValidateMigrationsSummary {
+        release_config_path: &validate_args.release_config_path,
+        version: &release.version,
+        migrations: release.migrations.values().map(Vec::len).sum(),
+        problems: &problems,
+    };
+    output::report(args.output, &summary)?;
+
+    ensure!(
+        problems.is_empty(),
+        error::ProblemsSnafu {
+            count: problems.len()
+        }
+    );
+    info!(
+        "Migrations in {} are valid",
+        validate_args.release_config_path.display()
+    );
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
+        #[snafu(display("Found {} problems with migrations, see above", count))]
+        Problems { count: usize },
+
+        #[snafu(display("Failed to read directory '{}': {}", path.display(), source))]
+        ReadDir { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Failed to read release config from '{}': {}", path.display(), source))]
+        ReleaseConfig {
+            path: PathBuf,
+            #[snafu(source(from(update_metadata::error::Error, Box::new)))]
+            source: Box<update_metadata::error::Error>,
+        },
+    }
+}
+pub(crate) use error::Error;
+
+#[cfg(test)]
+mod test {
+    use super::{find_problems, migration_version};
+    use semver::Version;
+    use std::collections::{BTreeMap, HashSet};
+    use update_metadata::Release;
+
+    fn release(version: &str, migrations: &[((&str, &str), &[&str])]) -> Release {
+        let migrations: BTreeMap<_, _> = migrations
+            .iter()
+            .map(|((from, to), names)| {
+                (
+                    (Version::parse(from).unwrap(), Version::parse(to).unwrap()),
+                    names.iter().map(|n| n.to_string()).collect(),
+                )
+            })
+            .collect();
+        Release {
+            version: Version::parse(version).unwrap(),
+            migrations,
+        }
+    }
+
+    fn present(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn valid_migrations() {
+        let release = release(
+            "1.0.1",
+            &[
+                (("0.5.0", "1.0.0"), &["migrate_v1.0.0_ecr-helper.lz4"]),
+                (("1.0.0", "1.0.1"), &[]),
+            ],
+        );
+        let present = present(&["migrate_v1.0.0_ecr-helper.lz4"]);
+        assert!(find_problems(&release, &present).is_empty());
+    }
+
+    #[test]
+    fn all_problems_reported() {
+        let release = release(
+            "1.0.1",
+            &[
+                // Missing file, and named for the wrong version
+                (("0.5.0", "1.0.0"), &["migrate_v0.9.0_old.lz4"]),
+                // Overlaps the range before it, and has a badly named file
+                (("0.9.0", "1.0.1"), &["add-sysctl.lz4"]),
+                // Goes backward and past the release version
+                (("1.0.3", "1.0.2"), &[]),
+            ],
+        );
+        let present = present(&["add-sysctl.lz4"]);
+        let problems = find_problems(&release, &present);
+        assert_eq!(problems.len(), 6, "{:#?}", problems);
+    }
+
+    #[test]
+    fn migration_names() {
+        assert_eq!(
+            migration_version("migrate_v1.0.5_add-lockdown.lz4"),
+            Some(Version::new(1, 0, 5))
+        );
+        assert_eq!(migration_version("migrate_v1.0.5_"), None);
+        assert_eq!(migration_version("migrate_v1.0_add-lockdown"), None);
+        assert_eq!(migration_version("migrate_1.0.5_add-lockdown"), None);
+    }
+}
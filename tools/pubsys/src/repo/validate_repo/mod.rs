@@ -1,27 +1,33 @@
 //! The validate_repo module owns the 'validate-repo' subcommand and provides methods for validating
-//! a given TUF repository by attempting to load the repository and download its targets.
+//! a given TUF repository by attempting to load the repository and download its targets.  The
+//! repo's URLs come from Infra.toml, or from the command line, for repos served from somewhere
+//! else, like a CDN that keeps metadata and targets under different base paths.
 
-use crate::repo::{error as repo_error, repo_urls};
-use crate::Args;
-use log::{info, trace};
-use pubsys_config::InfraConfig;
-use snafu::{OptionExt, ResultExt};
+use crate::repo::{error as repo_error, metadata_url, repo_urls};
+use crate::{output, Args};
+use chrono::Utc;
+use log::{error, info, trace, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use snafu::{ensure, OptionExt, ResultExt};
 use std::cmp::min;
 use std::fs::File;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use structopt::StructOpt;
-use tough::{Repository, RepositoryLoader};
+use tough::schema::RoleType;
+use tough::{DefaultTransport, ExpirationEnforcement, Repository, RepositoryLoader, Transport};
 use url::Url;
 
 /// Validates a set of TUF repositories
 #[derive(Debug, StructOpt)]
 #[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
 pub(crate) struct ValidateRepoArgs {
-    #[structopt(long)]
-    /// Use this named repo infrastructure from Infra.toml
-    repo: String,
+    #[structopt(long, required_unless = "metadata-url")]
+    /// Use this named repo infrastructure from Infra.toml; not needed, and ignored, with
+    /// --metadata-url
+    repo: Option<String>,
 
     #[structopt(long)]
     /// The architecture of the repo being validated
@@ -34,14 +40,67 @@ pub(crate) struct ValidateRepoArgs {
     /// Path to root.json for this repo
     root_role_path: PathBuf,
 
+    #[structopt(long, parse(try_from_str = parse_base_url))]
+    /// Metadata base URL to use instead of the one in Infra.toml; the metadata for the variant
+    /// and arch is expected under <metadata-url>/<variant>/<arch>, like Infra.toml's
+    metadata_url: Option<Url>,
+
+    #[structopt(long, parse(try_from_str = parse_base_url))]
+    /// Targets URL to use instead of the one in Infra.toml; with --metadata-url, defaults to
+    /// <metadata-url>/targets/, where the 'repo' subcommand puts targets
+    targets_url: Option<Url>,
+
     #[structopt(long)]
     /// Specifies whether to validate all listed targets by attempting to download them
     validate_targets: bool,
+
+    #[structopt(long)]
+    /// Download every listed target and compare its length and sha256 against the targets
+    /// metadata ourselves, reporting all mismatches
+    verify_targets: bool,
+
+    #[structopt(long)]
+    /// Load the repo even if its metadata has expired, warning about each expired role, so an
+    /// expired repo can be inspected and recovered
+    allow_expired: bool,
+}
+
+/// Parses a base URL given on the command line, making sure it can have paths joined onto it.  A
+/// trailing slash is added if it's missing, since joining onto a URL without one would replace
+/// its last path segment.
+fn parse_base_url(input: &str) -> Result<Url, Error> {
+    let mut url = Url::parse(input).context(error::BaseUrlSnafu { input })?;
+    ensure!(!url.cannot_be_a_base(), error::NotBaseUrlSnafu { input });
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    Ok(url)
+}
+
+/// Returns the metadata and targets URLs to validate, from the overrides given on the command
+/// line, falling back to the repo's URLs in Infra.toml.  Targets given with neither default to
+/// the "targets" directory under the metadata base, matching the layout of the 'repo' subcommand.
+fn override_urls(
+    metadata_base_url: &Url,
+    targets_url: Option<&Url>,
+    variant: &str,
+    arch: &str,
+) -> Result<(Url, Url), Error> {
+    let metadata_url = metadata_url(metadata_base_url, variant, arch)?;
+    let targets_url = match targets_url {
+        Some(targets_url) => targets_url.clone(),
+        None => metadata_base_url
+            .join("targets/")
+            .context(repo_error::ParseUrlSnafu {
+                input: format!("{}targets/", metadata_base_url),
+            })?,
+    };
+    Ok((metadata_url, targets_url))
 }
 
 /// If we are on a machine with a large number of cores, then we limit the number of simultaneous
 /// downloads to this arbitrarily chosen maximum.
-const MAX_DOWNLOAD_THREADS: usize = 16;
+pub(crate) const MAX_DOWNLOAD_THREADS: usize = 16;
 
 /// Retrieves listed targets and attempts to download them for validation purposes. We use a Rayon
 /// thread pool instead of tokio for async execution because `reqwest::blocking` creates a tokio
@@ -93,12 +152,142 @@ fn retrieve_targets(repo: &Repository) -> Result<(), Error> {
     Ok(())
 }
 
-fn validate_repo(
-    root_role_path: &PathBuf,
+/// Downloads a single target from `url` and hashes it, returning a description of the problem if
+/// its length or sha256 don't match what we expected.
+fn verify_target(
+    url: Url,
+    target: &str,
+    expected_length: u64,
+    expected_sha256: &str,
+) -> Result<Option<String>, Error> {
+    let mut reader = DefaultTransport::new()
+        .fetch(url.clone())
+        .context(error::TargetFetchSnafu { url })?;
+    let mut d = Sha256::new();
+    let actual_length =
+        io::copy(&mut reader, &mut d).context(error::TargetDownloadSnafu { target })?;
+    let actual_sha256 = hex::encode(d.finalize());
+
+    if actual_length == expected_length && actual_sha256 == expected_sha256 {
+        Ok(None)
+    } else {
+        Ok(Some(format!(
+            "{}: expected length {} sha256 {}, found length {} sha256 {}",
+            target, expected_length, expected_sha256, actual_length, actual_sha256
+        )))
+    }
+}
+
+/// Downloads every listed target without relying on tough's read-time checks, re-hashes it, and
+/// compares the length and sha256 against the targets metadata.  Every mismatch and every target
+/// that couldn't be fetched is logged and reported, rather than stopping at the first one.
+fn verify_targets(repo: &Repository, targets_url: &Url) -> Result<(), Error> {
+    let targets = &repo.targets().signed.targets;
+    let consistent_snapshot = repo.root().signed.consistent_snapshot;
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(min(num_cpus::get(), MAX_DOWNLOAD_THREADS))
+        .build()
+        .context(error::ThreadPoolSnafu)?;
+
+    let (tx, rx) = mpsc::channel();
+
+    for (name, target) in targets {
+        let tx = tx.clone();
+        let expected_length = target.length;
+        let expected_sha256 = hex::encode(&target.hashes.sha256);
+        // With consistent snapshots, targets are stored under a name prefixed by their digest.
+        let filename = if consistent_snapshot {
+            format!("{}.{}", expected_sha256, name.resolved())
+        } else {
+            name.resolved().to_owned()
+        };
+        let url = targets_url
+            .join(&filename)
+            .context(repo_error::ParseUrlSnafu {
+                input: format!("{}{}", targets_url, filename),
+            })?;
+        let target = name.raw().to_owned();
+        info!("Verifying target: {}", target);
+        thread_pool.spawn(move || {
+            tx.send(verify_target(
+                url,
+                &target,
+                expected_length,
+                &expected_sha256,
+            ))
+            // inability to send on this channel is unrecoverable
+            .unwrap();
+        });
+    }
+    drop(tx);
+
+    let mut mismatches = Vec::new();
+    let mut failures = Vec::new();
+    for result in rx {
+        match result {
+            Ok(None) => {}
+            Ok(Some(mismatch)) => {
+                error!("Target mismatch: {}", mismatch);
+                mismatches.push(mismatch);
+            }
+            Err(e) => {
+                error!("Unable to verify target: {}", e);
+                failures.push(e.to_string());
+            }
+        }
+    }
+    mismatches.sort();
+    failures.sort();
+
+    ensure!(
+        failures.is_empty(),
+        error::TargetsUnverifiedSnafu {
+            failures,
+            mismatches
+        }
+    );
+    ensure!(
+        mismatches.is_empty(),
+        error::TargetMismatchSnafu { mismatches }
+    );
+    Ok(())
+}
+
+/// Returns the top-level roles whose metadata has already expired, warning about each one.
+fn expired_roles(repo: &Repository, metadata_url: &Url) -> Vec<RoleType> {
+    let now = Utc::now();
+    let mut expired = Vec::new();
+    for (role, expires) in &[
+        (RoleType::Root, repo.root().signed.expires),
+        (RoleType::Snapshot, repo.snapshot().signed.expires),
+        (RoleType::Targets, repo.targets().signed.expires),
+        (RoleType::Timestamp, repo.timestamp().signed.expires),
+    ] {
+        if *expires < now {
+            warn!(
+                "!!! Repo '{}': '{}' metadata EXPIRED on {} !!!",
+                metadata_url, role, expires
+            );
+            expired.push(*role);
+        }
+    }
+    expired
+}
+
+/// Loads the repository at the given URLs, trusting the given root.json.  If `allow_expired` is
+/// set, expired metadata is loaded rather than rejected.
+pub(crate) fn load_repo(
+    root_role_path: &Path,
     metadata_url: Url,
     targets_url: &Url,
-    validate_targets: bool,
-) -> Result<(), Error> {
+    allow_expired: bool,
+) -> Result<Repository, Error> {
+    let expiration_enforcement = if allow_expired {
+        ExpirationEnforcement::Unsafe
+    } else {
+        ExpirationEnforcement::Safe
+    };
+
     // Load the repository
     let repo = RepositoryLoader::new(
         File::open(root_role_path).context(repo_error::FileSnafu {
@@ -107,68 +296,182 @@ fn validate_repo(
         metadata_url.clone(),
         targets_url.clone(),
     )
+    .expiration_enforcement(expiration_enforcement)
     .load()
     .context(repo_error::RepoLoadSnafu {
         metadata_base_url: metadata_url.clone(),
     })?;
     info!("Loaded TUF repo: {}", metadata_url);
-    if validate_targets {
+    Ok(repo)
+}
+
+fn validate_repo(
+    root_role_path: &Path,
+    metadata_url: Url,
+    targets_url: &Url,
+    validate_targets: bool,
+    verify: bool,
+    allow_expired: bool,
+) -> Result<(usize, Vec<RoleType>), Error> {
+    let repo = load_repo(
+        root_role_path,
+        metadata_url.clone(),
+        targets_url,
+        allow_expired,
+    )?;
+    // A strict load would have failed on expired metadata, so there's only something to report
+    // if we were asked to allow it.
+    let expired = if allow_expired {
+        expired_roles(&repo, &metadata_url)
+    } else {
+        Vec::new()
+    };
+    if verify {
+        // Download listed targets and check them against the metadata ourselves
+        verify_targets(&repo, targets_url)?;
+    } else if validate_targets {
         // Try retrieving listed targets
         retrieve_targets(&repo)?;
     }
 
-    Ok(())
+    Ok((repo.targets().signed.targets.len(), expired))
+}
+
+/// Summary of the validated repo, for reporting results
+#[derive(Debug, Serialize)]
+struct ValidateSummary<'a> {
+    metadata_url: &'a Url,
+    targets_url: &'a Url,
+    /// The number of targets listed in the repo
+    targets: usize,
+    /// Whether listed targets were downloaded (and, if requested, re-hashed) to validate them
+    targets_checked: bool,
+    /// Roles whose metadata has expired; only possible with --allow-expired
+    expired: Vec<RoleType>,
 }
 
 /// Common entrypoint from main()
 pub(crate) fn run(args: &Args, validate_repo_args: &ValidateRepoArgs) -> Result<(), Error> {
-    // If a lock file exists, use that, otherwise use Infra.toml
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
-        .context(repo_error::ConfigSnafu)?;
-    trace!("Parsed infra config: {:?}", infra_config);
-    let repo_config = infra_config
-        .repo
-        .as_ref()
-        .context(repo_error::MissingConfigSnafu {
-            missing: "repo section",
-        })?
-        .get(&validate_repo_args.repo)
-        .context(repo_error::MissingConfigSnafu {
-            missing: format!("definition for repo {}", &validate_repo_args.repo),
-        })?;
-
-    let repo_urls = repo_urls(
-        &repo_config,
-        &validate_repo_args.variant,
-        &validate_repo_args.arch,
-    )?
-    .context(repo_error::MissingRepoUrlsSnafu {
-        repo: &validate_repo_args.repo,
-    })?;
-    validate_repo(
+    let (metadata_url, targets_url) = match &validate_repo_args.metadata_url {
+        Some(metadata_base_url) => override_urls(
+            metadata_base_url,
+            validate_repo_args.targets_url.as_ref(),
+            &validate_repo_args.variant,
+            &validate_repo_args.arch,
+        )?,
+        None => {
+            // structopt requires --repo without --metadata-url.
+            let repo = validate_repo_args
+                .repo
+                .as_ref()
+                .context(error::MissingRepoSnafu)?;
+
+            // If a lock file exists, use that, otherwise use Infra.toml
+            let infra_config = args.infra_config(false).context(repo_error::ConfigSnafu)?;
+            trace!("Parsed infra config: {:?}", infra_config);
+            let repo_config = infra_config
+                .repo
+                .as_ref()
+                .context(repo_error::MissingConfigSnafu {
+                    missing: "repo section",
+                })?
+                .get(repo)
+                .context(repo_error::MissingConfigSnafu {
+                    missing: format!("definition for repo {}", repo),
+                })?;
+
+            let (metadata_url, targets_url) = repo_urls(
+                repo_config,
+                &validate_repo_args.variant,
+                &validate_repo_args.arch,
+            )?
+            .context(repo_error::MissingRepoUrlsSnafu { repo })?;
+            let targets_url = validate_repo_args
+                .targets_url
+                .clone()
+                .unwrap_or_else(|| targets_url.clone());
+            (metadata_url, targets_url)
+        }
+    };
+    info!("Using targets url: {}", targets_url);
+
+    let (targets, expired) = validate_repo(
         &validate_repo_args.root_role_path,
-        repo_urls.0,
-        repo_urls.1,
+        metadata_url.clone(),
+        &targets_url,
         validate_repo_args.validate_targets,
-    )
+        validate_repo_args.verify_targets,
+        validate_repo_args.allow_expired,
+    )?;
+
+    let summary = ValidateSummary {
+        metadata_url: &metadata_url,
+        targets_url: &targets_url,
+        targets,
+        targets_checked: validate_repo_args.validate_targets || validate_repo_args.verify_targets,
+        expired,
+    };
+    output::report(args.output, &summary)?;
+    Ok(())
 }
 
 mod error {
     use snafu::Snafu;
     use std::io;
+    use url::Url;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub(crate) enum Error {
+        #[snafu(display("Invalid URL '{}': {}", input, source))]
+        BaseUrl {
+            input: String,
+            source: url::ParseError,
+        },
+
         #[snafu(display("Invalid percentage specified: {} is greater than 100", percentage))]
         InvalidPercentage { percentage: u8 },
 
+        #[snafu(display("Either --repo or --metadata-url is required"))]
+        MissingRepo,
+
+        #[snafu(display("Invalid URL '{}': it can't have paths under it", input))]
+        NotBaseUrl { input: String },
+
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
         #[snafu(context(false), display("{}", source))]
         Repo { source: crate::repo::Error },
 
         #[snafu(display("Failed to download and write target '{}': {}", target, source))]
         TargetDownload { target: String, source: io::Error },
 
+        #[snafu(display("Failed to fetch target from '{}': {}", url, source))]
+        TargetFetch {
+            url: Url,
+            #[snafu(source(from(tough::TransportError, Box::new)))]
+            source: Box<tough::TransportError>,
+        },
+
+        #[snafu(display(
+            "{} target(s) did not match targets metadata:\n{}",
+            mismatches.len(),
+            mismatches.join("\n")
+        ))]
+        TargetMismatch { mismatches: Vec<String> },
+
+        #[snafu(display(
+            "{} target(s) could not be verified, and {} did not match targets metadata:\n{}",
+            failures.len(),
+            mismatches.len(),
+            failures.iter().chain(mismatches).cloned().collect::<Vec<_>>().join("\n")
+        ))]
+        TargetsUnverified {
+            failures: Vec<String>,
+            mismatches: Vec<String>,
+        },
+
         #[snafu(display("Missing target: {}", target))]
         TargetMissing { target: String },
 
@@ -177,3 +480,83 @@ mod error {
     }
 }
 pub(crate) use error::Error;
+
+#[cfg(test)]
+mod test {
+    use super::{load_repo, override_urls, parse_base_url, verify_targets, Error};
+    use crate::repo::test::{build, write_root};
+    use crate::repo::TargetHash;
+    use std::fs;
+    use tough::editor::RepositoryEditor;
+    use url::Url;
+
+    #[test]
+    fn override_urls_default_targets_to_metadata_base() {
+        // A missing trailing slash is added, so the variant and arch are joined under the path.
+        let base = parse_base_url("https://cdn.example.com/repo").unwrap();
+        let (metadata, targets) = override_urls(&base, None, "aws-k8s", "x86_64").unwrap();
+        assert_eq!(
+            metadata.as_str(),
+            "https://cdn.example.com/repo/aws-k8s/x86_64"
+        );
+        assert_eq!(targets.as_str(), "https://cdn.example.com/repo/targets/");
+
+        let targets_url = parse_base_url("https://targets.example.com/t/").unwrap();
+        let (_, targets) = override_urls(&base, Some(&targets_url), "aws-k8s", "x86_64").unwrap();
+        assert_eq!(targets.as_str(), "https://targets.example.com/t/");
+
+        assert!(parse_base_url("not a url").is_err());
+        assert!(parse_base_url("mailto:repo@example.com").is_err());
+    }
+
+    #[test]
+    fn verify_reports_every_bad_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (root_path, key_path) = write_root(dir);
+        let targets_dir = dir.join("targets");
+        fs::create_dir(&targets_dir).unwrap();
+        let missing = targets_dir.join("bottlerocket-v1.0.0-abcdef.img");
+        let changed = targets_dir.join("bottlerocket-v1.0.0-abcdef-root.ext4.lz4");
+        let good = targets_dir.join("bottlerocket-v1.0.0-abcdef-boot.ext4.lz4");
+        for target in &[&missing, &changed, &good] {
+            fs::write(target, b"image").unwrap();
+        }
+        let manifest = targets_dir.join("manifest.json");
+        fs::write(&manifest, b"{}").unwrap();
+        let metadata_dir = dir.join("metadata");
+        build(
+            RepositoryEditor::new(&root_path).unwrap(),
+            &key_path,
+            &[&missing, &changed, &good],
+            &manifest,
+            None,
+            &[TargetHash::Sha256],
+            &metadata_dir,
+        );
+        let targets_url = Url::from_directory_path(&targets_dir).unwrap();
+        let repo = load_repo(
+            &root_path,
+            Url::from_directory_path(&metadata_dir).unwrap(),
+            &targets_url,
+            false,
+        )
+        .unwrap();
+
+        // A target that can't be fetched doesn't hide the mismatch of another.
+        fs::remove_file(&missing).unwrap();
+        fs::write(&changed, b"IMAGE").unwrap();
+        match verify_targets(&repo, &targets_url) {
+            Err(Error::TargetsUnverified {
+                failures,
+                mismatches,
+            }) => {
+                assert_eq!(failures.len(), 1);
+                assert!(failures[0].contains("abcdef.img"), "{}", failures[0]);
+                assert_eq!(mismatches.len(), 1);
+                assert!(mismatches[0].starts_with("bottlerocket-v1.0.0-abcdef-root"));
+            }
+            other => panic!("expected unverified targets, got {:?}", other),
+        }
+    }
+}
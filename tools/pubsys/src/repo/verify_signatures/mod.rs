@@ -0,0 +1,203 @@
+//! The verify_signatures module owns the 'repo-verify-signatures' subcommand and checks, offline,
+//! that each role's metadata in a repo has enough valid signatures to meet its threshold in
+//! root.json.  Unlike validate-repo, it only reads metadata, so targets don't need to be
+//! available, and it doesn't check expiration.
+
+use crate::repo::sign::{read_metadata, read_role, valid_signature, RepoMetadata};
+use crate::{output, Args};
+use log::{error, info};
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use tough::schema::{Role, Root, Signed};
+
+/// Checks that each role's metadata is signed by enough of the keys root.json lists for it
+#[derive(Debug, StructOpt)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub(crate) struct VerifySignaturesArgs {
+    #[structopt(long, parse(from_os_str))]
+    /// Path to root.json for this repo, which lists the keys allowed to sign each role
+    root_role_path: PathBuf,
+
+    #[structopt(long, parse(from_os_str))]
+    /// The repo's metadata directory for one variant and arch, holding timestamp.json
+    metadata_dir: PathBuf,
+}
+
+/// The signatures on one role's metadata, for reporting results
+#[derive(Debug, Serialize)]
+struct RoleSignatures {
+    role: String,
+    path: PathBuf,
+    version: NonZeroU64,
+    /// Valid signatures from distinct keys that root.json allows to sign the role
+    valid: usize,
+    /// Signatures that don't match the metadata, repeat a key, or are from keys that may not sign
+    /// the role
+    invalid: usize,
+    threshold: NonZeroU64,
+    satisfied: bool,
+}
+
+/// Summary of the checks, for reporting results
+#[derive(Debug, Serialize)]
+struct VerifySignaturesSummary<'a> {
+    metadata_dir: &'a Path,
+    roles: &'a [RoleSignatures],
+}
+
+/// Counts the role's valid signatures, and checks them against its threshold in root.json the
+/// way clients will.
+fn verify_metadata<T: Role + Serialize + Clone>(
+    root: &Root,
+    path: &Path,
+    role: &Signed<T>,
+) -> Result<RoleSignatures> {
+    let role_type = T::TYPE;
+    let role_keys = root
+        .roles
+        .get(&role_type)
+        .context(error::MissingRoleSnafu { role: role_type })?;
+
+    let mut valid_keys = Vec::new();
+    for signature in &role.signatures {
+        if !valid_keys.contains(&&signature.keyid) && valid_signature(root, role, signature) {
+            valid_keys.push(&signature.keyid);
+        }
+    }
+    let satisfied = root.verify_role(role).is_ok();
+    if satisfied {
+        info!(
+            "{} metadata has {} valid signatures, and needs {}",
+            role_type,
+            valid_keys.len(),
+            role_keys.threshold
+        );
+    } else {
+        error!(
+            "{} metadata has {} valid signatures, but needs {}",
+            role_type,
+            valid_keys.len(),
+            role_keys.threshold
+        );
+    }
+
+    Ok(RoleSignatures {
+        role: role_type.to_string(),
+        path: path.to_owned(),
+        version: role.signed.version(),
+        valid: valid_keys.len(),
+        invalid: role.signatures.len() - valid_keys.len(),
+        threshold: role_keys.threshold,
+        satisfied,
+    })
+}
+
+/// Common entrypoint from main()
+pub(crate) fn run(args: &Args, verify_args: &VerifySignaturesArgs) -> Result<()> {
+    let root_path = &verify_args.root_role_path;
+    let root: Signed<Root> = read_role(root_path).context(error::MetadataSnafu)?;
+    let RepoMetadata {
+        timestamp_path,
+        timestamp,
+        snapshot_path,
+        snapshot,
+        targets_path,
+        targets,
+    } = read_metadata(&verify_args.metadata_dir, root.signed.consistent_snapshot)
+        .context(error::MetadataSnafu)?;
+
+    // root.json has to be signed by enough of its own keys, too.
+    let roles = vec![
+        verify_metadata(&root.signed, root_path, &root)?,
+        verify_metadata(&root.signed, &targets_path, &targets)?,
+        verify_metadata(&root.signed, &snapshot_path, &snapshot)?,
+        verify_metadata(&root.signed, &timestamp_path, &timestamp)?,
+    ];
+
+    let summary = VerifySignaturesSummary {
+        metadata_dir: &verify_args.metadata_dir,
+        roles: &roles,
+    };
+    output::report(args.output, &summary)?;
+
+    let under_signed: Vec<_> = roles
+        .iter()
+        .filter(|role| !role.satisfied)
+        .map(|role| format!("{} ({} of {})", role.role, role.valid, role.threshold))
+        .collect();
+    ensure!(
+        under_signed.is_empty(),
+        error::UnderSignedSnafu {
+            roles: under_signed.join(", ")
+        }
+    );
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use tough::schema::RoleType;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Failed to read metadata: {}", source))]
+        Metadata { source: crate::repo::sign::Error },
+
+        #[snafu(display("Root role has no keys for the {} role", role))]
+        MissingRole { role: RoleType },
+
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
+        #[snafu(display("Roles without enough valid signatures: {}", roles))]
+        UnderSigned { roles: String },
+    }
+}
+pub(crate) use error::Error;
+type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::verify_metadata;
+    use crate::repo::sign::test::{keys, root, sign_with};
+    use chrono::{Duration, Utc};
+    use ring::rand::SystemRandom;
+    use std::num::NonZeroU64;
+    use std::path::Path;
+    use tough::schema::{Signed, Targets};
+
+    #[test]
+    fn counts_signatures_against_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = keys(dir.path(), 3);
+        let root = root(&keys[..2]);
+        let rng = SystemRandom::new();
+        let mut targets = Signed {
+            signed: Targets::new(
+                "1.0.0".to_string(),
+                NonZeroU64::new(1).unwrap(),
+                Utc::now() + Duration::weeks(1),
+            ),
+            signatures: Vec::new(),
+        };
+        let path = Path::new("1.targets.json");
+
+        // A signature from a key root.json doesn't list doesn't count.
+        sign_with(&mut targets, &keys[2], &rng);
+        sign_with(&mut targets, &keys[0], &rng);
+        let signatures = verify_metadata(&root, path, &targets).unwrap();
+        assert_eq!((signatures.valid, signatures.invalid), (1, 1));
+        assert!(!signatures.satisfied);
+
+        // Repeating a key doesn't count either; two distinct keys meet the threshold.
+        sign_with(&mut targets, &keys[0], &rng);
+        sign_with(&mut targets, &keys[1], &rng);
+        let signatures = verify_metadata(&root, path, &targets).unwrap();
+        assert_eq!((signatures.valid, signatures.invalid), (2, 2));
+        assert!(signatures.satisfied);
+    }
+}
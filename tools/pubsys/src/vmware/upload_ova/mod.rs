@@ -1,17 +1,19 @@
 //! The upload_ova module owns the 'upload_ova' subcommand and is responsible for collating all of
 //! the config necessary to upload an OVA bundle to VMware datacenters.
 use crate::vmware::govc::Govc;
-use crate::Args;
-use log::{debug, info, trace};
+use crate::{output, Args};
+use log::{debug, info, trace, warn};
 use pubsys_config::vmware::{
     Datacenter, DatacenterBuilder, DatacenterCreds, DatacenterCredsBuilder, DatacenterCredsConfig,
     VMWARE_CREDS_PATH,
 };
-use pubsys_config::InfraConfig;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use snafu::{ensure, OptionExt, ResultExt};
-use std::fs;
-use std::path::PathBuf;
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use tempfile::NamedTempFile;
 use tinytemplate::TinyTemplate;
@@ -41,13 +43,72 @@ pub(crate) struct UploadArgs {
     /// Datacenters to which you want to upload the OVA
     #[structopt(long, use_delimiter = true)]
     datacenters: Vec<String>,
+
+    /// Expected sha256 of the OVA, in hex; if not given, we check against "<ova>.sha256" if it
+    /// exists
+    #[structopt(long)]
+    sha256: Option<String>,
+
+    /// Skip datacenters that a previous, interrupted upload of the same OVA and name completed
+    #[structopt(long)]
+    resume: bool,
+}
+
+/// Progress of an upload across datacenters, persisted so an interrupted upload can be resumed.
+/// govc uploads each datacenter's copy in one shot, so a datacenter is either finished or will be
+/// uploaded again.
+#[derive(Debug, Deserialize, Serialize)]
+struct UploadState {
+    ova: PathBuf,
+    ova_size: u64,
+    name: String,
+    completed: Vec<String>,
+}
+
+impl UploadState {
+    /// Returns the path where state is kept for uploads with the given VM name
+    fn path(name: &str) -> PathBuf {
+        let name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        env::temp_dir().join(format!("pubsys-upload-ova-{}.json", name))
+    }
+
+    /// Loads state from a previous upload, if it was for the same OVA and name
+    fn load(path: &Path, ova: &Path, ova_size: u64, name: &str) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read_to_string(path).context(error::FileSnafu {
+            action: "read",
+            path,
+        })?;
+        let state: Self = serde_json::from_str(&data).context(error::StateParseSnafu { path })?;
+        if state.ova == ova && state.ova_size == ova_size && state.name == name {
+            Ok(Some(state))
+        } else {
+            warn!(
+                "Upload state in '{}' is for a different OVA, starting over",
+                path.display()
+            );
+            Ok(None)
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_string(self).context(error::StateSerializeSnafu)?;
+        fs::write(path, data).context(error::FileSnafu {
+            action: "write",
+            path,
+        })
+    }
 }
 
 /// Common entrypoint from main()
 pub(crate) fn run(args: &Args, upload_args: &UploadArgs) -> Result<()> {
     // If a lock file exists, use that, otherwise use Infra.toml or default
-    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, true)
-        .context(error::InfraConfigSnafu)?;
+    let infra_config = args.infra_config(true).context(error::InfraConfigSnafu)?;
     trace!("Using infra config: {:?}", infra_config);
 
     let vmware = infra_config
@@ -67,6 +128,35 @@ pub(crate) fn run(args: &Args, upload_args: &UploadArgs) -> Result<()> {
         }
     );
 
+    // Make sure we're uploading the OVA we expect before sending it anywhere
+    verify_ova(&upload_args.ova, upload_args.sha256.as_deref())?;
+
+    // Find out which datacenters a previous upload already handled, if we're resuming
+    let ova_size = fs::metadata(&upload_args.ova)
+        .context(error::FileSnafu {
+            action: "read",
+            path: &upload_args.ova,
+        })?
+        .len();
+    let state_path = UploadState::path(&upload_args.name);
+    let previous = if upload_args.resume {
+        UploadState::load(&state_path, &upload_args.ova, ova_size, &upload_args.name)?
+    } else {
+        None
+    };
+    let mut state = previous.unwrap_or_else(|| UploadState {
+        ova: upload_args.ova.clone(),
+        ova_size,
+        name: upload_args.name.clone(),
+        completed: Vec::new(),
+    });
+    if !state.completed.is_empty() {
+        info!(
+            "Resuming upload; already completed: {}",
+            state.completed.join(", ")
+        );
+    }
+
     // Retrieve credentials from GOVC_ environment variables
     let creds_env = DatacenterCredsBuilder::from_env();
     // Retrieve credentials from file. The `home` crate is used to construct the VMWARE_CREDS_PATH,
@@ -105,6 +195,10 @@ pub(crate) fn run(args: &Args, upload_args: &UploadArgs) -> Result<()> {
         &upload_datacenters.join(", ")
     );
     for dc in upload_datacenters {
+        if state.completed.contains(dc) {
+            info!("Skipping datacenter '{}', already uploaded", &dc);
+            continue;
+        }
         debug!("Building config for {}", &dc);
         // If any specific configuration exists for this datacenter, retrieve it from VMware
         // config.  Then build out a complete datacenter config with all values necessary to
@@ -151,8 +245,91 @@ pub(crate) fn run(args: &Args, upload_args: &UploadArgs) -> Result<()> {
         Govc::new(datacenter, creds)
             .upload_ova(&upload_args.name, &upload_args.ova, import_spec)
             .context(error::UploadOvaSnafu)?;
+
+        // Record progress so an interrupted run can pick up from here
+        state.completed.push(dc.clone());
+        state.save(&state_path)?;
     }
 
+    // Everything's uploaded, so there's nothing left to resume
+    if state_path.exists() {
+        fs::remove_file(&state_path).context(error::FileSnafu {
+            action: "remove",
+            path: &state_path,
+        })?;
+    }
+
+    let summary = UploadSummary {
+        name: &upload_args.name,
+        template: upload_args.mark_as_template,
+        datacenters: upload_datacenters,
+    };
+    output::report(args.output, &summary)?;
+    Ok(())
+}
+
+/// Summary of the OVA uploads, for reporting results
+#[derive(Debug, Serialize)]
+struct UploadSummary<'a> {
+    name: &'a str,
+    template: bool,
+    datacenters: &'a [String],
+}
+
+/// Checks the sha256 of the OVA against the given checksum, or the one in the OVA's ".sha256"
+/// sidecar file if no checksum is given.  If neither is available, the check is skipped.
+fn verify_ova(ova: &Path, expected: Option<&str>) -> Result<()> {
+    let expected = match expected {
+        Some(expected) => expected.to_string(),
+        None => {
+            let mut sidecar = ova.as_os_str().to_owned();
+            sidecar.push(".sha256");
+            let sidecar = PathBuf::from(sidecar);
+            if !sidecar.exists() {
+                warn!(
+                    "No checksum given and '{}' doesn't exist, not verifying OVA",
+                    sidecar.display()
+                );
+                return Ok(());
+            }
+            // The sidecar is in sha256sum format: the checksum, then the file name
+            let contents = fs::read_to_string(&sidecar).context(error::FileSnafu {
+                action: "read",
+                path: &sidecar,
+            })?;
+            contents
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        }
+    };
+    let expected = expected.to_lowercase();
+    ensure!(
+        expected.len() == 64 && expected.chars().all(|c| c.is_ascii_hexdigit()),
+        error::InvalidChecksumSnafu { checksum: expected }
+    );
+
+    info!("Verifying sha256 of '{}'", ova.display());
+    let mut file = File::open(ova).context(error::FileSnafu {
+        action: "open",
+        path: ova,
+    })?;
+    let mut digest = Sha256::new();
+    io::copy(&mut file, &mut digest).context(error::FileSnafu {
+        action: "read",
+        path: ova,
+    })?;
+    let actual = hex::encode(digest.finalize());
+    ensure!(
+        actual == expected,
+        error::ChecksumMismatchSnafu {
+            path: ova,
+            expected,
+            actual,
+        }
+    );
+
     Ok(())
 }
 
@@ -193,6 +370,18 @@ mod error {
             source: tinytemplate::error::Error,
         },
 
+        #[snafu(display(
+            "Checksum mismatch for '{}': expected sha256 {}, found {}",
+            path.display(),
+            expected,
+            actual
+        ))]
+        ChecksumMismatch {
+            path: PathBuf,
+            expected: String,
+            actual: String,
+        },
+
         #[snafu(display("Unable to build datacenter credentials: {}", source))]
         CredsBuild {
             source: pubsys_config::vmware::Error,
@@ -219,12 +408,27 @@ mod error {
         #[snafu(display("Error reading config: {}", source))]
         InfraConfig { source: pubsys_config::Error },
 
+        #[snafu(display("Invalid sha256 checksum '{}', expected 64 hex characters", checksum))]
+        InvalidChecksum { checksum: String },
+
         #[snafu(display("Infra.toml is missing {}", missing))]
         MissingConfig { missing: String },
 
+        #[snafu(context(false), display("{}", source))]
+        Output { source: crate::output::Error },
+
         #[snafu(display("Error rendering template: {}", source))]
         RenderTemplate { source: tinytemplate::error::Error },
 
+        #[snafu(display("Failed to parse upload state '{}': {}", path.display(), source))]
+        StateParse {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to serialize upload state: {}", source))]
+        StateSerialize { source: serde_json::Error },
+
         #[snafu(display("Failed to create temporary file: {}", source))]
         TempFile { source: io::Error },
 